@@ -0,0 +1,87 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::attachments::interface::{Attachment, AttachmentBackend};
+use async_trait::async_trait;
+use bson::{doc, Document};
+use futures_util::io::Cursor;
+use futures_util::TryStreamExt;
+use mongodb::gridfs::GridFsBucket;
+use mongodb::options::{GridFsBucketOptions, GridFsUploadOptions};
+use mongodb::Database;
+use std::error::Error;
+
+pub struct GridFS {
+    bucket: GridFsBucket,
+}
+
+impl GridFS {
+    /// new creates a new GridFS struct.
+    ///
+    /// # Arguments
+    /// * `db` - The MongoDB database
+    /// * `bucket` - The GridFS bucket name
+    ///
+    /// # Returns
+    /// * A GridFS struct
+    pub fn new(db: &Database, bucket: &str) -> GridFS {
+        let mut options = GridFsBucketOptions::default();
+        options.bucket_name = Some(bucket.to_string());
+
+        GridFS {
+            bucket: db.gridfs_bucket(options),
+        }
+    }
+}
+
+#[async_trait]
+impl AttachmentBackend for GridFS {
+    async fn exists(&self, key: &str) -> Result<bool, Box<dyn Error>> {
+        let mut cursor = self.bucket.find(doc! { "_id": key }, None).await?;
+
+        Ok(cursor.try_next().await?.is_some())
+    }
+
+    async fn upload(
+        &self,
+        key: &str,
+        attachment: &Attachment,
+        content: Vec<u8>,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut metadata = doc! {
+            "source_id": &attachment.document_id,
+            "source_rev": &attachment.document_rev,
+        };
+        if let Some(content_type) = &attachment.content_type {
+            metadata.insert("content_type", content_type);
+        }
+        let mut options = GridFsUploadOptions::default();
+        options.metadata = Some(metadata);
+
+        self.bucket
+            .upload_from_futures_0_3_reader_with_id(
+                key.into(),
+                &attachment.name,
+                Cursor::new(content),
+                options,
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn location(&self, key: &str) -> Result<Document, Box<dyn Error>> {
+        Ok(doc! { "gridfs_id": key })
+    }
+}
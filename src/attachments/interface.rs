@@ -0,0 +1,46 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use async_trait::async_trait;
+use bson::Document;
+use std::error::Error;
+
+/// Attachment describes an attachment being copied, from its CouchDB stub.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Attachment {
+    pub document_id: String,
+    pub document_rev: String,
+    pub name: String,
+    pub content_type: Option<String>,
+    pub length: Option<u64>,
+    pub digest: String,
+}
+
+#[async_trait]
+pub trait AttachmentBackend: Send + Sync {
+    /// exists returns true if an attachment has already been stored under the key.
+    async fn exists(&self, key: &str) -> Result<bool, Box<dyn Error>>;
+
+    /// upload stores an attachment's content under the key.
+    async fn upload(
+        &self,
+        key: &str,
+        attachment: &Attachment,
+        content: Vec<u8>,
+    ) -> Result<(), Box<dyn Error>>;
+
+    /// location returns the fields written into the Mongo document telling readers where to find
+    /// the stored attachment.
+    async fn location(&self, key: &str) -> Result<Document, Box<dyn Error>>;
+}
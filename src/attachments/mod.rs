@@ -0,0 +1,314 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+pub mod gridfs;
+pub mod interface;
+pub mod s3;
+
+use crate::attachments::interface::{Attachment, AttachmentBackend};
+use crate::couchdb::CouchConnection;
+use crate::settings::config_parser::AttachmentSettings;
+use bson::{doc, Bson, Document};
+use lazy_static::lazy_static;
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use prometheus::{register_int_counter_vec, IntCounterVec};
+use reqwest::Method;
+use std::collections::HashMap;
+use std::error::Error;
+use std::time::Duration;
+use tracing::{debug, info, warn};
+
+lazy_static! {
+    /// Attachments seen, by what happened to them.
+    pub static ref ATTACHMENTS: IntCounterVec = register_int_counter_vec!(
+        "couch2mongo_attachments_total",
+        "Attachments seen, by whether they were uploaded, already stored or skipped",
+        &["outcome"]
+    )
+    .unwrap();
+}
+
+/// AttachmentStore copies CouchDB attachments into an AttachmentBackend.
+///
+/// The changes feed only carries attachment stubs, so each attachment is fetched separately and
+/// stored under a key made from the document ID, attachment name and digest. An attachment that's
+/// already stored isn't fetched again, so unchanged attachments cost one lookup when their
+/// document is updated. Files belonging to old revisions or deleted documents are left in place.
+pub struct AttachmentStore {
+    connection: CouchConnection,
+    database: String,
+    backend: Box<dyn AttachmentBackend>,
+    prefix: String,
+    max_size_bytes: Option<u64>,
+    attempts: u32,
+    retry_delay: Duration,
+}
+
+impl AttachmentStore {
+    /// new creates a new AttachmentStore.
+    ///
+    /// # Arguments
+    /// * `connection` - The CouchDB connection
+    /// * `database` - The source database name
+    /// * `backend` - Where to store attachments
+    /// * `settings` - An AttachmentSettings struct
+    ///
+    /// # Returns
+    /// * An AttachmentStore struct
+    pub fn new(
+        connection: CouchConnection,
+        database: &str,
+        backend: Box<dyn AttachmentBackend>,
+        settings: &AttachmentSettings,
+    ) -> AttachmentStore {
+        AttachmentStore {
+            connection,
+            database: database.to_string(),
+            backend,
+            prefix: settings.prefix.clone(),
+            max_size_bytes: settings.max_size_bytes,
+            attempts: settings.attempts.max(1),
+            retry_delay: Duration::from_millis(settings.retry_delay_ms),
+        }
+    }
+
+    /// replicate stores a document's attachments, replacing each stub in `_attachments` with the
+    /// attachment's filename, content type, length, digest and location.
+    ///
+    /// Attachments over `max_size_bytes` aren't fetched, and are marked as skipped instead.
+    ///
+    /// # Arguments
+    /// * `document` - The document, modified in place
+    ///
+    /// # Returns
+    /// * The number of attachments uploaded
+    pub async fn replicate(&self, document: &mut Document) -> Result<usize, Box<dyn Error>> {
+        let stubs = match document.get_document("_attachments") {
+            Ok(stubs) => stubs.clone(),
+            Err(_) => return Ok(0),
+        };
+        let id = document.get_str("_id")?.to_string();
+        let rev = document.get_str("_rev").unwrap_or_default().to_string();
+
+        let mut uploaded = 0;
+        let mut attachments = Document::new();
+
+        for (name, stub) in stubs {
+            let stub = match stub {
+                Bson::Document(stub) => stub,
+                _ => continue,
+            };
+            let attachment = attachment_from_stub(&id, &rev, &name, &stub);
+            let mut metadata = attachment_metadata(&attachment);
+
+            if self.is_too_large(&attachment) {
+                warn!(
+                    id = id.as_str(),
+                    attachment = name.as_str(),
+                    length = attachment.length,
+                    "attachment is over the size limit, skipping"
+                );
+                ATTACHMENTS.with_label_values(&["skipped"]).inc();
+                metadata.insert("skipped", "size_limit");
+                attachments.insert(name, metadata);
+                continue;
+            }
+
+            let key = self.key(&attachment);
+            if self.store_with_retry(&key, &attachment).await? {
+                uploaded += 1;
+            }
+
+            metadata.extend(self.backend.location(&key).await?);
+            attachments.insert(name, metadata);
+        }
+
+        document.insert("_attachments", attachments);
+        Ok(uploaded)
+    }
+
+    /// key returns the key an attachment is stored under.
+    fn key(&self, attachment: &Attachment) -> String {
+        format!(
+            "{}{}/{}/{}",
+            self.prefix, attachment.document_id, attachment.name, attachment.digest
+        )
+    }
+
+    fn is_too_large(&self, attachment: &Attachment) -> bool {
+        match (self.max_size_bytes, attachment.length) {
+            (Some(max), Some(length)) => length > max,
+            _ => false,
+        }
+    }
+
+    /// store_with_retry stores an attachment, retrying with a doubling delay.
+    ///
+    /// # Returns
+    /// * true if the attachment was uploaded, false if it was already stored
+    async fn store_with_retry(
+        &self,
+        key: &str,
+        attachment: &Attachment,
+    ) -> Result<bool, Box<dyn Error>> {
+        let mut delay = self.retry_delay;
+        let mut attempt = 1;
+
+        loop {
+            match self.store(key, attachment).await {
+                Ok(uploaded) => return Ok(uploaded),
+                Err(e) if attempt < self.attempts => {
+                    warn!(
+                        key,
+                        attempt,
+                        error = e.to_string(),
+                        "failed to store attachment, retrying"
+                    );
+                    tokio::time::sleep(delay).await;
+                    delay *= 2;
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    async fn store(&self, key: &str, attachment: &Attachment) -> Result<bool, Box<dyn Error>> {
+        if self.backend.exists(key).await? {
+            debug!(key, "attachment already stored");
+            ATTACHMENTS.with_label_values(&["existing"]).inc();
+            return Ok(false);
+        }
+
+        let content = self.fetch(attachment).await?;
+        if let Some(max) = self.max_size_bytes {
+            if content.len() as u64 > max {
+                return Err(format!(
+                    "attachment {} is {} bytes, over the {} byte limit",
+                    key,
+                    content.len(),
+                    max
+                )
+                .into());
+            }
+        }
+
+        info!(key, bytes = content.len(), "uploading attachment");
+        self.backend.upload(key, attachment, content).await?;
+        ATTACHMENTS.with_label_values(&["uploaded"]).inc();
+
+        Ok(true)
+    }
+
+    async fn fetch(&self, attachment: &Attachment) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut params = HashMap::new();
+        if !attachment.document_rev.is_empty() {
+            params.insert("rev".to_string(), attachment.document_rev.clone());
+        }
+
+        let request = self.connection.req(
+            Method::GET,
+            &attachment_path(&self.database, &attachment.document_id, &attachment.name),
+            Some(&params),
+        );
+        let content = self
+            .connection
+            .send(request, "attachment")
+            .await?
+            .error_for_status()?
+            .bytes()
+            .await?;
+
+        Ok(content.to_vec())
+    }
+}
+
+/// attachment_from_stub reads an Attachment from its `_attachments` stub.
+fn attachment_from_stub(id: &str, rev: &str, name: &str, stub: &Document) -> Attachment {
+    let length = match stub.get("length") {
+        Some(Bson::Int32(i)) => u64::try_from(*i).ok(),
+        Some(Bson::Int64(i)) => u64::try_from(*i).ok(),
+        _ => None,
+    };
+
+    Attachment {
+        document_id: id.to_string(),
+        document_rev: rev.to_string(),
+        name: name.to_string(),
+        content_type: stub.get_str("content_type").ok().map(str::to_string),
+        length,
+        digest: stub.get_str("digest").unwrap_or_default().to_string(),
+    }
+}
+
+/// attachment_path returns the CouchDB path of an attachment, escaping the document ID and name,
+/// which may themselves contain slashes.
+fn attachment_path(database: &str, id: &str, name: &str) -> String {
+    format!(
+        "{}/{}/{}",
+        database,
+        utf8_percent_encode(id, NON_ALPHANUMERIC),
+        utf8_percent_encode(name, NON_ALPHANUMERIC)
+    )
+}
+
+/// attachment_metadata describes an attachment, in place of its CouchDB stub.
+fn attachment_metadata(attachment: &Attachment) -> Document {
+    let mut metadata = doc! { "filename": &attachment.name };
+    if let Some(content_type) = &attachment.content_type {
+        metadata.insert("content_type", content_type);
+    }
+    if let Some(length) = attachment.length {
+        metadata.insert("length", length as i64);
+    }
+    metadata.insert("digest", &attachment.digest);
+
+    metadata
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_attachment_path() {
+        assert_eq!(
+            attachment_path("animals", "cat/tom", "photo 1.jpg"),
+            "animals/cat%2Ftom/photo%201%2Ejpg"
+        );
+    }
+
+    #[test]
+    fn test_attachment_metadata() {
+        let stub = doc! {
+            "content_type": "image/jpeg",
+            "digest": "md5-abc",
+            "length": 1024,
+            "revpos": 2,
+            "stub": true,
+        };
+        let attachment = attachment_from_stub("cat:tom", "2-def", "photo.jpg", &stub);
+
+        assert_eq!(attachment.length, Some(1024));
+        assert_eq!(
+            attachment_metadata(&attachment),
+            doc! {
+                "filename": "photo.jpg",
+                "content_type": "image/jpeg",
+                "length": 1024_i64,
+                "digest": "md5-abc",
+            }
+        );
+    }
+}
@@ -0,0 +1,152 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::attachments::interface::{Attachment, AttachmentBackend};
+use crate::settings::config_parser::S3AttachmentSettings;
+use async_trait::async_trait;
+use aws_config::BehaviorVersion;
+use aws_sdk_s3::presigning::PresigningConfig;
+use aws_sdk_s3::primitives::ByteStream;
+use aws_sdk_s3::Client;
+use bson::{doc, Document};
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use std::error::Error;
+use std::time::Duration;
+use tracing::info;
+
+/// Characters escaped when a key is put into a URL template. Slashes are kept, so keys read as
+/// paths.
+const KEY_ESCAPE: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'/')
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'.')
+    .remove(b'~');
+
+pub struct S3 {
+    client: Client,
+    bucket: String,
+    url_template: Option<String>,
+    presign_expiry: Duration,
+}
+
+impl S3 {
+    /// new creates a new S3 struct.
+    ///
+    /// # Arguments
+    /// * `settings` - An S3AttachmentSettings struct
+    ///
+    /// # Returns
+    /// * An S3 struct
+    pub async fn new(settings: &S3AttachmentSettings) -> S3 {
+        let shared_config = aws_config::load_defaults(BehaviorVersion::v2023_11_09()).await;
+
+        let actual_config = match &settings.local_url {
+            Some(url) => {
+                info!(url = url.as_str(), "using local S3");
+
+                aws_sdk_s3::config::Builder::from(&shared_config)
+                    .endpoint_url(url)
+                    .force_path_style(true)
+                    .build()
+            }
+            None => aws_sdk_s3::config::Builder::from(&shared_config).build(),
+        };
+
+        S3 {
+            client: Client::from_conf(actual_config),
+            bucket: settings.bucket.clone(),
+            url_template: settings.url_template.clone(),
+            presign_expiry: Duration::from_secs(settings.presign_expiry_secs),
+        }
+    }
+}
+
+#[async_trait]
+impl AttachmentBackend for S3 {
+    async fn exists(&self, key: &str) -> Result<bool, Box<dyn Error>> {
+        let r = self
+            .client
+            .head_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .send()
+            .await;
+
+        match r {
+            Ok(_) => Ok(true),
+            Err(e) if e.as_service_error().map(|e| e.is_not_found()) == Some(true) => Ok(false),
+            Err(e) => Err(Box::new(e)),
+        }
+    }
+
+    async fn upload(
+        &self,
+        key: &str,
+        attachment: &Attachment,
+        content: Vec<u8>,
+    ) -> Result<(), Box<dyn Error>> {
+        self.client
+            .put_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .set_content_type(attachment.content_type.clone())
+            .body(ByteStream::from(content))
+            .send()
+            .await?;
+
+        Ok(())
+    }
+
+    async fn location(&self, key: &str) -> Result<Document, Box<dyn Error>> {
+        let url = match &self.url_template {
+            Some(template) => template_url(template, &self.bucket, key),
+            None => self
+                .client
+                .get_object()
+                .bucket(&self.bucket)
+                .key(key)
+                .presigned(PresigningConfig::expires_in(self.presign_expiry)?)
+                .await?
+                .uri()
+                .to_string(),
+        };
+
+        Ok(doc! { "s3_bucket": &self.bucket, "s3_key": key, "url": url })
+    }
+}
+
+/// template_url fills in the `{bucket}` and `{key}` placeholders of a URL template.
+fn template_url(template: &str, bucket: &str, key: &str) -> String {
+    template
+        .replace("{bucket}", bucket)
+        .replace("{key}", &utf8_percent_encode(key, KEY_ESCAPE).to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_template_url() {
+        assert_eq!(
+            template_url(
+                "https://{bucket}.example.com/{key}",
+                "media",
+                "couch/cat:tom/photo 1.jpg/md5-abc=="
+            ),
+            "https://media.example.com/couch/cat%3Atom/photo%201.jpg/md5-abc%3D%3D"
+        );
+    }
+}
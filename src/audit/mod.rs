@@ -0,0 +1,158 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::pipeline::{AppliedChange, Operation};
+use crate::settings::config_parser::{AuditSettings, AuditStoreInterface};
+use bson::{doc, DateTime, Document};
+use mongodb::{Collection, Database};
+use std::error::Error;
+use std::fs;
+use std::io::Write;
+use std::sync::Mutex;
+
+/// AuditLog records every change the pipeline applies, for traceability.
+///
+/// Entries are appended to a MongoDB collection or a JSON lines file once a change has been
+/// applied, before the pipeline moves on to the next one. An audit write failing stops the
+/// pipeline rather than leaving a gap.
+pub struct AuditLog {
+    target: AuditTarget,
+    database: String,
+}
+
+enum AuditTarget {
+    MongoDB(Collection<Document>),
+    File(Mutex<fs::File>),
+}
+
+impl AuditLog {
+    /// new creates a new AuditLog, opening the file for appending if the store is File.
+    ///
+    /// # Arguments
+    /// * `settings` - An AuditSettings struct
+    /// * `db` - The MongoDB database
+    /// * `source_database` - The CouchDB database name, recorded with each entry
+    ///
+    /// # Returns
+    /// * An AuditLog struct
+    pub fn new(
+        settings: &AuditSettings,
+        db: &Database,
+        source_database: &str,
+    ) -> Result<AuditLog, Box<dyn Error>> {
+        let target = match settings.store {
+            AuditStoreInterface::MongoDB => {
+                AuditTarget::MongoDB(db.collection(&settings.collection))
+            }
+            AuditStoreInterface::File => AuditTarget::File(Mutex::new(
+                fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&settings.path)?,
+            )),
+        };
+
+        Ok(AuditLog {
+            target,
+            database: source_database.to_string(),
+        })
+    }
+
+    /// record appends an entry for an applied change.
+    pub async fn record(&self, applied: &AppliedChange) -> Result<(), Box<dyn Error>> {
+        let entry = entry(applied, &self.database, DateTime::now());
+
+        match &self.target {
+            AuditTarget::MongoDB(collection) => {
+                collection.insert_one(entry, None).await?;
+            }
+            AuditTarget::File(file) => {
+                let mut line = bson::Bson::Document(entry)
+                    .into_relaxed_extjson()
+                    .to_string();
+                line.push('\n');
+                file.lock()
+                    .expect("unable to lock audit log")
+                    .write_all(line.as_bytes())?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// entry returns the audit entry for an applied change.
+///
+/// # Arguments
+/// * `applied` - The applied change
+/// * `database` - The CouchDB database name
+/// * `timestamp` - When the change was applied
+///
+/// # Returns
+/// * The entry, with the operation and its outcome: applied, skipped or dead_lettered
+pub fn entry(applied: &AppliedChange, database: &str, timestamp: DateTime) -> Document {
+    doc! {
+        "id": &applied.id,
+        "seq": &applied.seq,
+        "database": database,
+        "collection": &applied.collection,
+        "operation": applied.operation.as_str(),
+        "outcome": outcome(&applied.operation),
+        "timestamp": timestamp,
+    }
+}
+
+/// outcome groups operations by whether they changed the target.
+fn outcome(operation: &Operation) -> &'static str {
+    match operation {
+        Operation::Replaced | Operation::Inserted | Operation::Deleted | Operation::Published => {
+            "applied"
+        }
+        Operation::Stale
+        | Operation::Unchanged
+        | Operation::Oversized
+        | Operation::Ignored
+        | Operation::Existing => "skipped",
+        Operation::DeadLettered => "dead_lettered",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_entry() {
+        let applied = AppliedChange {
+            id: "tom".to_string(),
+            seq: "12-abc".to_string(),
+            collection: "cats".to_string(),
+            operation: Operation::Unchanged,
+        };
+        let timestamp = DateTime::from_millis(1_700_000_000_000);
+
+        assert_eq!(
+            entry(&applied, "animals", timestamp),
+            doc! {
+                "id": "tom",
+                "seq": "12-abc",
+                "database": "animals",
+                "collection": "cats",
+                "operation": "unchanged",
+                "outcome": "skipped",
+                "timestamp": timestamp,
+            }
+        );
+    }
+}
@@ -0,0 +1,204 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::backfill::Backfill;
+use crate::seqstore::interface::SequenceStore;
+use crate::settings::config_parser::Settings;
+use serde_derive::{Deserialize, Serialize};
+use std::error::Error;
+use std::sync::Arc;
+use tracing::info;
+
+/// Phase is what the replicator is doing for a sequence store key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Phase {
+    // Copying every document through _all_docs; the changes feed isn't followed yet
+    #[serde(alias = "backfill")]
+    Backfill,
+    // Following the changes feed from the checkpoint
+    #[serde(alias = "streaming")]
+    Streaming,
+}
+
+/// CutoverState is the phase, and the sequence the changes feed took over from once streaming.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CutoverState {
+    pub phase: Phase,
+    pub checkpoint: Option<String>,
+}
+
+/// Cutover moves a replicator from the backfill to the changes feed, keeping the phase in the
+/// sequence store so that a restart part way through the backfill resumes it, rather than
+/// following the changes feed from a checkpoint that skips documents not yet copied.
+pub struct Cutover {
+    store: Arc<dyn SequenceStore>,
+    key: String,
+    state_key: String,
+}
+
+impl Cutover {
+    /// new creates a new Cutover.
+    ///
+    /// # Arguments
+    /// * `store` - The sequence store
+    /// * `key` - The key the checkpoint is stored under
+    ///
+    /// # Returns
+    /// * A Cutover struct
+    pub fn new(store: Arc<dyn SequenceStore>, key: &str) -> Cutover {
+        Cutover {
+            store,
+            key: key.to_string(),
+            state_key: format!("{}:cutover", key),
+        }
+    }
+
+    /// state returns the stored phase, if there is one.
+    pub async fn state(&self) -> Result<Option<CutoverState>, Box<dyn Error>> {
+        match self.store.get(&self.state_key).await? {
+            Some(state) => Ok(Some(serde_json::from_str(&state)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// begin returns the phase to start in, storing it the first time.
+    ///
+    /// Without a stored phase, a replicator that already has a checkpoint has been following the
+    /// changes feed, so carries on streaming; one without starts with the backfill.
+    pub async fn begin(&self) -> Result<Phase, Box<dyn Error>> {
+        if let Some(state) = self.state().await? {
+            return Ok(state.phase);
+        }
+
+        let state = match self.store.get(&self.key).await? {
+            Some(checkpoint) => CutoverState {
+                phase: Phase::Streaming,
+                checkpoint: Some(checkpoint),
+            },
+            None => CutoverState {
+                phase: Phase::Backfill,
+                checkpoint: None,
+            },
+        };
+        self.save(&state).await?;
+
+        Ok(state.phase)
+    }
+
+    /// switch moves to streaming from the sequence the backfill started at.
+    ///
+    /// The checkpoint is stored before the phase, so until the phase is stored a restart finishes
+    /// the backfill again and switches with the same checkpoint.
+    ///
+    /// # Arguments
+    /// * `checkpoint` - The update sequence taken before the backfill started
+    pub async fn switch(&self, checkpoint: &str) -> Result<(), Box<dyn Error>> {
+        self.store.set(&self.key, checkpoint).await?;
+        self.save(&CutoverState {
+            phase: Phase::Streaming,
+            checkpoint: Some(checkpoint.to_string()),
+        })
+        .await
+    }
+
+    async fn save(&self, state: &CutoverState) -> Result<(), Box<dyn Error>> {
+        self.store
+            .set(&self.state_key, &serde_json::to_string(state)?)
+            .await
+    }
+}
+
+/// run backfills if that phase isn't finished, then switches to streaming.
+///
+/// # Arguments
+/// * `settings` - A Settings struct
+/// * `store` - The sequence store
+pub async fn run(settings: &Settings, store: Arc<dyn SequenceStore>) -> Result<(), Box<dyn Error>> {
+    let cutover = Cutover::new(store, &settings.get_sequence_store_key());
+    if cutover.begin().await? == Phase::Streaming {
+        return Ok(());
+    }
+
+    info!("backfilling before following the changes feed");
+    let report = Backfill::new(settings).await?.run(false).await?;
+    cutover.switch(&report.update_seq).await?;
+    info!(
+        copied = report.copied,
+        skipped = report.skipped,
+        checkpoint = report.update_seq.as_str(),
+        "backfill finished, following the changes feed"
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::seqstore::file::File;
+    use crate::settings::config_parser::FileSettings;
+    use tokio::runtime::Runtime;
+
+    fn store(name: &str) -> (Arc<dyn SequenceStore>, std::path::PathBuf) {
+        let path = std::env::temp_dir().join(format!(
+            "couch2mongo-cutover-{}-{}.json",
+            name,
+            std::process::id()
+        ));
+        let store = File::new(&FileSettings {
+            path: path.to_string_lossy().to_string(),
+        });
+        (Arc::new(store), path)
+    }
+
+    #[test]
+    fn test_cutover_backfills_then_streams() {
+        let rt = Runtime::new().unwrap();
+        let (store, path) = store("new");
+        let cutover = Cutover::new(store.clone(), "animals");
+
+        rt.block_on(async {
+            assert_eq!(cutover.begin().await.unwrap(), Phase::Backfill);
+            // Restarting mid-backfill carries on with it
+            assert_eq!(cutover.begin().await.unwrap(), Phase::Backfill);
+
+            cutover.switch("42-abc").await.unwrap();
+            assert_eq!(cutover.begin().await.unwrap(), Phase::Streaming);
+            assert_eq!(
+                store.get("animals").await.unwrap(),
+                Some("42-abc".to_string())
+            );
+            assert_eq!(
+                cutover.state().await.unwrap().unwrap().checkpoint,
+                Some("42-abc".to_string())
+            );
+        });
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_cutover_with_checkpoint_streams() {
+        let rt = Runtime::new().unwrap();
+        let (store, path) = store("existing");
+        let cutover = Cutover::new(store.clone(), "animals");
+
+        rt.block_on(async {
+            store.set("animals", "7-def").await.unwrap();
+            assert_eq!(cutover.begin().await.unwrap(), Phase::Streaming);
+        });
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,464 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+pub mod cutover;
+
+use crate::attachments::AttachmentStore;
+use crate::couchdb::CouchConnection;
+use crate::filters::IdFilter;
+use crate::seqstore::interface::SequenceStore;
+use crate::settings::config_parser::{BackfillSettings, Settings};
+use crate::throttle::BackfillThrottle;
+use crate::verify::Verifier;
+use futures_util::future::try_join_all;
+use lazy_static::lazy_static;
+use prometheus::{register_int_counter_vec, IntCounterVec};
+use reqwest::Method;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fmt;
+use std::sync::Arc;
+use tracing::{info, warn};
+
+lazy_static! {
+    /// Documents copied by the backfill, by collection.
+    pub static ref BACKFILL_DOCUMENTS: IntCounterVec = register_int_counter_vec!(
+        "couch2mongo_backfill_documents_total",
+        "Documents copied to MongoDB by the backfill",
+        &["collection"]
+    )
+    .unwrap();
+}
+
+/// KeyRange is a range of document IDs, from `start` up to but not including `end`. A missing
+/// bound is open.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct KeyRange {
+    pub start: Option<String>,
+    pub end: Option<String>,
+}
+
+/// Plan is how a backfill splits the keyspace. It's stored, so a resumed backfill copies the same
+/// ranges.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Plan {
+    // The update sequence when the backfill started; the changes feed takes over from here
+    pub update_seq: String,
+    pub ranges: Vec<KeyRange>,
+}
+
+/// Progress is how far a range has been copied.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Progress {
+    // The last document ID copied
+    pub last_id: Option<String>,
+    pub done: bool,
+}
+
+/// BackfillReport is what a backfill copied.
+#[derive(Debug, Default, Serialize)]
+pub struct BackfillReport {
+    pub update_seq: String,
+    pub ranges: usize,
+    pub copied: u64,
+    // Documents left out, because they would be dead lettered or are oversized
+    pub skipped: u64,
+}
+
+impl fmt::Display for BackfillReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "copied {} documents in {} ranges, skipped {} that would be dead lettered or are \
+             oversized\nthe changes feed can take over from {}",
+            self.copied, self.ranges, self.skipped, self.update_seq
+        )
+    }
+}
+
+/// Backfill copies every document in CouchDB to MongoDB through `_all_docs`, applying the same
+/// filters, transforms and routing as replication does.
+///
+/// The ID keyspace is split into ranges by sampling `_all_docs`, and the ranges are copied
+/// concurrently. Each range is checkpointed in the sequence store after every batch, so an
+/// interrupted backfill resumes where each range left off.
+pub struct Backfill<'a> {
+    settings: &'a Settings,
+    backfill_settings: BackfillSettings,
+    verifier: Verifier<'a>,
+    connection: CouchConnection,
+    store: Arc<dyn SequenceStore>,
+    key: String,
+    id_filter: IdFilter,
+    doc_ids: Option<HashSet<String>>,
+    attachments: Option<AttachmentStore>,
+    throttle: Option<Arc<BackfillThrottle>>,
+}
+
+impl<'a> Backfill<'a> {
+    /// new creates a new Backfill.
+    ///
+    /// # Arguments
+    /// * `settings` - A Settings struct
+    ///
+    /// # Returns
+    /// * A Backfill struct
+    pub async fn new(settings: &'a Settings) -> Result<Backfill<'a>, Box<dyn Error>> {
+        if settings.routes_databases() {
+            return Err(
+                "backfill only writes to mongodb_database, so can't be used with \
+                 mongodb_database_field or routes to another database or target"
+                    .into(),
+            );
+        }
+        if settings.changes_selector.is_some() || !settings.partitions.is_empty() {
+            warn!(
+                "changes_selector and partitions aren't applied by the backfill, so unselected \
+                 documents are copied too"
+            );
+        }
+
+        let db = settings.get_mongodb_database().await?;
+        let attachments = match settings.dry_run {
+            true => None,
+            false => settings.get_attachment_store(&db).await?,
+        };
+
+        Ok(Backfill {
+            settings,
+            backfill_settings: settings.backfill.clone().unwrap_or_default(),
+            verifier: Verifier::new(settings).await?,
+            connection: settings.get_couchdb_connection().await?,
+            store: settings.get_sequence_store().await?,
+            key: format!("{}:backfill", settings.get_sequence_store_key()),
+            id_filter: settings.get_id_filter()?,
+            doc_ids: settings
+                .get_changes_doc_ids()?
+                .map(|ids| ids.into_iter().collect()),
+            attachments,
+            throttle: settings.get_backfill_throttle().await?,
+        })
+    }
+
+    /// run copies every range that isn't done yet.
+    ///
+    /// # Arguments
+    /// * `restart` - Make a new plan and start over, rather than resuming a stored one
+    pub async fn run(&self, restart: bool) -> Result<BackfillReport, Box<dyn Error>> {
+        let plan = match restart {
+            true => None,
+            false => self.stored_plan().await?,
+        };
+        let plan = match plan {
+            Some(plan) => {
+                info!(ranges = plan.ranges.len(), "resuming backfill");
+                plan
+            }
+            None => self.new_plan().await?,
+        };
+
+        if let Some(throttle) = &self.throttle {
+            throttle.start();
+        }
+        let counts = try_join_all(
+            plan.ranges
+                .iter()
+                .enumerate()
+                .map(|(index, range)| self.copy_range(index, range)),
+        )
+        .await;
+        if let Some(throttle) = &self.throttle {
+            throttle.finish();
+        }
+        let counts = counts?;
+
+        Ok(BackfillReport {
+            update_seq: plan.update_seq.clone(),
+            ranges: plan.ranges.len(),
+            copied: counts.iter().map(|(copied, _)| copied).sum(),
+            skipped: counts.iter().map(|(_, skipped)| skipped).sum(),
+        })
+    }
+
+    /// stored_plan returns the plan of a backfill that's already been started, if there is one.
+    pub async fn stored_plan(&self) -> Result<Option<Plan>, Box<dyn Error>> {
+        match self.store.get(&self.key).await? {
+            Some(plan) => Ok(Some(serde_json::from_str(&plan)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// new_plan splits the keyspace into ranges, and stores the plan with no progress made.
+    async fn new_plan(&self) -> Result<Plan, Box<dyn Error>> {
+        // Taken first, so changes made while copying are picked up by the changes feed
+        let update_seq = self
+            .connection
+            .update_seq(&self.settings.source_database)
+            .await?;
+        let boundaries = self.sample(self.backfill_settings.ranges).await?;
+        let plan = Plan {
+            update_seq,
+            ranges: split_ranges(boundaries),
+        };
+
+        for index in 0..plan.ranges.len() {
+            self.save_progress(index, &Progress::default()).await?;
+        }
+        self.store
+            .set(&self.key, &serde_json::to_string(&plan)?)
+            .await?;
+        info!(
+            ranges = plan.ranges.len(),
+            update_seq = plan.update_seq.as_str(),
+            "planned backfill"
+        );
+
+        Ok(plan)
+    }
+
+    /// sample returns the document IDs that split `_all_docs` into about equal ranges.
+    async fn sample(&self, ranges: usize) -> Result<Vec<String>, Box<dyn Error>> {
+        let total = self
+            .all_docs(HashMap::from([("limit", "0".to_string())]))
+            .await?
+            .get("total_rows")
+            .and_then(Value::as_u64)
+            .unwrap_or_default() as usize;
+
+        let mut boundaries: Vec<String> = vec![];
+        for i in 1..ranges {
+            let params = HashMap::from([
+                ("limit", "1".to_string()),
+                ("skip", (total * i / ranges).to_string()),
+            ]);
+            let id = self
+                .all_docs(params)
+                .await?
+                .get("rows")
+                .and_then(|rows| rows.get(0))
+                .and_then(|row| row.get("id"))
+                .and_then(Value::as_str)
+                .map(str::to_string);
+
+            // A small database has fewer distinct boundaries than ranges
+            if let Some(id) = id {
+                if boundaries.last() != Some(&id) {
+                    boundaries.push(id);
+                }
+            }
+        }
+
+        Ok(boundaries)
+    }
+
+    /// copy_range copies a range from where it was left off.
+    ///
+    /// # Returns
+    /// * The number of documents copied and skipped
+    async fn copy_range(
+        &self,
+        index: usize,
+        range: &KeyRange,
+    ) -> Result<(u64, u64), Box<dyn Error>> {
+        let mut progress = self.load_progress(index).await?;
+        let (mut copied, mut skipped) = (0, 0);
+
+        while !progress.done {
+            if let Some(throttle) = &self.throttle {
+                throttle.pause().await;
+            }
+
+            let rows = self.page(range, progress.last_id.as_deref()).await?;
+            progress.done = rows.len() < self.backfill_settings.batch_size;
+
+            for row in &rows {
+                let id = row.get("id").and_then(Value::as_str).unwrap_or_default();
+                progress.last_id = Some(id.to_string());
+
+                let document = match row.get("doc") {
+                    Some(document) if document.is_object() => document,
+                    _ => continue,
+                };
+                if id.starts_with("_design")
+                    || !self.id_filter.is_allowed(id)
+                    || self.doc_ids.as_ref().is_some_and(|ids| !ids.contains(id))
+                {
+                    continue;
+                }
+
+                let (collection, document) = match self.verifier.transform(document)? {
+                    Some(transformed) => transformed,
+                    None => {
+                        skipped += 1;
+                        continue;
+                    }
+                };
+
+                if self.settings.dry_run {
+                    info!(
+                        id,
+                        collection = collection.as_str(),
+                        "dry run, would copy document"
+                    );
+                } else {
+                    self.verifier
+                        .copy(&collection, document, self.attachments.as_ref())
+                        .await?;
+                }
+                BACKFILL_DOCUMENTS
+                    .with_label_values(&[collection.as_str()])
+                    .inc();
+                copied += 1;
+            }
+
+            self.save_progress(index, &progress).await?;
+            info!(
+                range = index,
+                last_id = progress.last_id.as_deref().unwrap_or_default(),
+                copied,
+                done = progress.done,
+                "backfilled batch"
+            );
+        }
+
+        Ok((copied, skipped))
+    }
+
+    /// page reads a batch of `_all_docs` in a range, starting after `after`.
+    async fn page(
+        &self,
+        range: &KeyRange,
+        after: Option<&str>,
+    ) -> Result<Vec<Value>, Box<dyn Error>> {
+        let mut params = HashMap::from([
+            ("include_docs", "true".to_string()),
+            ("limit", self.backfill_settings.batch_size.to_string()),
+        ]);
+        match (after, &range.start) {
+            (Some(after), _) => {
+                params.insert("startkey", Value::from(after).to_string());
+                params.insert("skip", "1".to_string());
+            }
+            (None, Some(start)) => {
+                params.insert("startkey", Value::from(start.as_str()).to_string());
+            }
+            (None, None) => {}
+        }
+        if let Some(end) = &range.end {
+            params.insert("endkey", Value::from(end.as_str()).to_string());
+            params.insert("inclusive_end", "false".to_string());
+        }
+
+        Ok(self
+            .all_docs(params)
+            .await?
+            .get("rows")
+            .and_then(Value::as_array)
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    /// all_docs reads `_all_docs` with the given parameters.
+    async fn all_docs(&self, params: HashMap<&str, String>) -> Result<Value, Box<dyn Error>> {
+        let params = params
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v))
+            .collect();
+        let request = self.connection.req(
+            Method::GET,
+            &format!("{}/_all_docs", self.settings.source_database),
+            Some(&params),
+        );
+
+        Ok(self
+            .connection
+            .send(request, "all_docs")
+            .await?
+            .error_for_status()?
+            .json()
+            .await?)
+    }
+
+    async fn load_progress(&self, index: usize) -> Result<Progress, Box<dyn Error>> {
+        match self.store.get(&self.progress_key(index)).await? {
+            Some(progress) => Ok(serde_json::from_str(&progress)?),
+            None => Ok(Progress::default()),
+        }
+    }
+
+    async fn save_progress(&self, index: usize, progress: &Progress) -> Result<(), Box<dyn Error>> {
+        self.store
+            .set(&self.progress_key(index), &serde_json::to_string(progress)?)
+            .await
+    }
+
+    fn progress_key(&self, index: usize) -> String {
+        format!("{}:{}", self.key, index)
+    }
+}
+
+/// split_ranges turns the IDs splitting the keyspace into ranges covering all of it.
+///
+/// # Arguments
+/// * `boundaries` - The IDs each range after the first starts at, in order
+pub fn split_ranges(boundaries: Vec<String>) -> Vec<KeyRange> {
+    let mut ranges = vec![];
+    let mut start = None;
+
+    for boundary in boundaries {
+        ranges.push(KeyRange {
+            start: start.take(),
+            end: Some(boundary.clone()),
+        });
+        start = Some(boundary);
+    }
+    ranges.push(KeyRange { start, end: None });
+
+    ranges
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_ranges() {
+        assert_eq!(
+            split_ranges(vec![]),
+            vec![KeyRange {
+                start: None,
+                end: None
+            }]
+        );
+        assert_eq!(
+            split_ranges(vec!["h".to_string(), "p".to_string()]),
+            vec![
+                KeyRange {
+                    start: None,
+                    end: Some("h".to_string())
+                },
+                KeyRange {
+                    start: Some("h".to_string()),
+                    end: Some("p".to_string())
+                },
+                KeyRange {
+                    start: Some("p".to_string()),
+                    end: None
+                },
+            ]
+        );
+    }
+}
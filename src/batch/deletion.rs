@@ -0,0 +1,135 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::settings::config_parser::{DeletionSettings, DeletionStrategy};
+use bson::{doc, Bson, DateTime, Document};
+use futures_util::TryStreamExt;
+use mongodb::options::ReplaceOptions;
+use mongodb::Database;
+
+/// tombstone_collection returns the name of the collection the Tombstone strategy moves a
+/// collection's deleted documents to.
+pub fn tombstone_collection(collection: &str, settings: &DeletionSettings) -> String {
+    format!("{}{}", collection, settings.tombstone_suffix)
+}
+
+/// soft_delete_filter narrows a filter to documents that haven't already been soft deleted, so
+/// replaying a deletion keeps the original time.
+pub fn soft_delete_filter(mut filter: Document, settings: &DeletionSettings) -> Document {
+    filter.insert(settings.field.clone(), doc! { "$exists": false });
+    filter
+}
+
+/// soft_delete_update returns the update marking documents as deleted now.
+pub fn soft_delete_update(settings: &DeletionSettings) -> Document {
+    doc! { "$set": { settings.field.as_str(): DateTime::now() } }
+}
+
+/// tombstone returns a deleted document as the Tombstone strategy stores it, with the time it was
+/// deleted.
+pub fn tombstone(mut document: Document, settings: &DeletionSettings) -> Document {
+    document.insert(settings.field.clone(), DateTime::now());
+    document
+}
+
+/// delete_many applies the deletion strategy to documents in a collection.
+///
+/// Each strategy can be safely repeated: soft deletes leave documents already marked alone, and
+/// tombstones are upserted before the originals are deleted, so an interrupted move is finished
+/// by the retry.
+///
+/// # Arguments
+/// * `db` - The MongoDB database
+/// * `collection` - The collection to delete from
+/// * `ids` - The `_id`s of the documents to delete
+/// * `settings` - A DeletionSettings struct
+///
+/// # Returns
+/// * The number of documents deleted, marked or moved
+pub async fn delete_many(
+    db: &Database,
+    collection: &str,
+    ids: Vec<Bson>,
+    settings: &DeletionSettings,
+) -> Result<u64, mongodb::error::Error> {
+    let source = db.collection::<Document>(collection);
+    let filter = doc! { "_id": { "$in": ids } };
+
+    match settings.strategy {
+        DeletionStrategy::Hard => Ok(source.delete_many(filter, None).await?.deleted_count),
+        DeletionStrategy::Soft => Ok(source
+            .update_many(
+                soft_delete_filter(filter, settings),
+                soft_delete_update(settings),
+                None,
+            )
+            .await?
+            .modified_count),
+        DeletionStrategy::Tombstone => {
+            let tombstones = db.collection::<Document>(&tombstone_collection(collection, settings));
+            let mut cursor = source.find(filter.clone(), None).await?;
+            while let Some(document) = cursor.try_next().await? {
+                let id = document.get("_id").cloned().unwrap_or(Bson::Null);
+                tombstones
+                    .replace_one(
+                        doc! { "_id": id },
+                        tombstone(document, settings),
+                        ReplaceOptions::builder().upsert(true).build(),
+                    )
+                    .await?;
+            }
+
+            Ok(source.delete_many(filter, None).await?.deleted_count)
+        }
+        DeletionStrategy::Ignore => Ok(0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tombstone_collection() {
+        let settings = DeletionSettings::default();
+        assert_eq!(tombstone_collection("cats", &settings), "cats_deleted");
+    }
+
+    #[test]
+    fn test_soft_delete() {
+        let settings = DeletionSettings::default();
+
+        let filter = soft_delete_filter(doc! { "_id": "tom" }, &settings);
+        assert_eq!(
+            filter,
+            doc! { "_id": "tom", "deleted_at": { "$exists": false } }
+        );
+
+        let update = soft_delete_update(&settings);
+        assert!(update
+            .get_document("$set")
+            .unwrap()
+            .get_datetime("deleted_at")
+            .is_ok());
+    }
+
+    #[test]
+    fn test_tombstone_keeps_document() {
+        let settings = DeletionSettings::default();
+        let document = tombstone(doc! { "_id": "tom", "colour": "grey" }, &settings);
+
+        assert_eq!(document.get_str("colour").unwrap(), "grey");
+        assert!(document.get_datetime("deleted_at").is_ok());
+    }
+}
@@ -0,0 +1,130 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::routing::databases::Destination;
+use crate::seqstore::interface::SequenceStore;
+use bson::Bson;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::sync::Arc;
+
+/// RoutedDeletes is the document IDs and revisions to delete, by collection.
+pub type RoutedDeletes = BTreeMap<String, Vec<(Bson, String)>>;
+
+/// Intent describes a batch that's about to be applied: the documents it touches, where, and the
+/// range of changes it covers.
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct Intent {
+    pub from_seq: String,
+    pub to_seq: String,
+
+    // Document IDs and revisions to delete, by collection
+    pub deletes: RoutedDeletes,
+
+    // Likewise for documents routed to other databases or targets, by collection
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub routed_deletes: Vec<(Destination, RoutedDeletes)>,
+}
+
+/// IntentLog is a write-ahead log, one record deep, kept in the sequence store.
+///
+/// The intent is written before a batch is applied and cleared once it has been. Finding one at
+/// startup means we stopped part way through a batch, which is then re-applied in full before the
+/// checkpoint is moved past it.
+pub struct IntentLog {
+    store: Arc<dyn SequenceStore>,
+    key: String,
+}
+
+impl IntentLog {
+    /// new creates a new IntentLog.
+    ///
+    /// # Arguments
+    /// * `store` - The shared sequence store
+    /// * `sequence_key` - The checkpoint key; the intent is stored at `<sequence_key>:intent`
+    ///
+    /// # Returns
+    /// * An IntentLog struct
+    pub fn new(store: Arc<dyn SequenceStore>, sequence_key: &str) -> IntentLog {
+        IntentLog {
+            store,
+            key: format!("{}:intent", sequence_key),
+        }
+    }
+
+    /// record persists the intent to apply a batch.
+    pub async fn record(&self, intent: &Intent) -> Result<(), Box<dyn Error>> {
+        self.store
+            .set(&self.key, &serde_json::to_string(intent)?)
+            .await
+    }
+
+    /// load returns the intent left behind by an interrupted batch, if any.
+    pub async fn load(&self) -> Result<Option<Intent>, Box<dyn Error>> {
+        match self.store.get(&self.key).await? {
+            Some(value) if !value.is_empty() => Ok(Some(serde_json::from_str(&value)?)),
+            _ => Ok(None),
+        }
+    }
+
+    /// clear marks the last recorded batch as fully applied.
+    pub async fn clear(&self) -> Result<(), Box<dyn Error>> {
+        // Not every store can delete keys, so an empty value stands for no intent
+        self.store.set(&self.key, "").await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::seqstore::sqlite::SQLite;
+    use crate::settings::config_parser::SQLiteSettings;
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn test_record_load_clear() {
+        let rt = Runtime::new().unwrap();
+        let store: Arc<dyn SequenceStore> = Arc::new(
+            SQLite::new(&SQLiteSettings {
+                path: ":memory:".to_string(),
+            })
+            .unwrap(),
+        );
+        let log = IntentLog::new(store.clone(), "key");
+
+        let mut deletes = BTreeMap::new();
+        deletes.insert(
+            "cats".to_string(),
+            vec![(Bson::String("tom".to_string()), "2-abc".to_string())],
+        );
+        let intent = Intent {
+            from_seq: "1-a".to_string(),
+            to_seq: "3-c".to_string(),
+            deletes,
+            routed_deletes: vec![],
+        };
+
+        rt.block_on(async {
+            assert_eq!(log.load().await.unwrap(), None);
+
+            log.record(&intent).await.unwrap();
+            assert_eq!(log.load().await.unwrap(), Some(intent));
+            assert_eq!(store.get("key").await.unwrap(), None);
+
+            log.clear().await.unwrap();
+            assert_eq!(log.load().await.unwrap(), None);
+        });
+    }
+}
@@ -0,0 +1,291 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+pub mod deletion;
+pub mod intent;
+
+use bson::{doc, Bson};
+use std::collections::BTreeMap;
+use std::error::Error;
+use tracing::{info, warn};
+
+use crate::batch::intent::{Intent, IntentLog, RoutedDeletes};
+use crate::metrics;
+use crate::routing::databases::{Databases, Destination};
+use crate::settings::config_parser::DeletionSettings;
+
+/// DeleteBatch accumulates a run of consecutive deletions so they can be applied with one
+/// `delete_many` per collection rather than one `delete_one` per change. The deletion strategy
+/// decides whether documents are deleted, marked as deleted or moved to a tombstone collection.
+///
+/// To preserve ordering, the batch must be flushed before any other write is applied - a document
+/// deleted and then recreated within the same run must end up existing.
+#[derive(Default)]
+pub struct DeleteBatch {
+    ids: BTreeMap<String, Vec<(Bson, String)>>,
+    // Deletions routed to other databases or targets than mongodb_database
+    routed: BTreeMap<Destination, RoutedDeletes>,
+    len: usize,
+    first_seq: Option<String>,
+    last_seq: Option<String>,
+    intent_log: Option<IntentLog>,
+    deletion: DeletionSettings,
+}
+
+impl DeleteBatch {
+    /// new creates an empty DeleteBatch.
+    pub fn new() -> DeleteBatch {
+        DeleteBatch::default()
+    }
+
+    /// set_intent_log records each batch in the intent log before it's applied.
+    pub fn set_intent_log(&mut self, intent_log: IntentLog) {
+        self.intent_log = Some(intent_log);
+    }
+
+    /// set_deletion sets the deletion strategy the batch applies.
+    pub fn set_deletion(&mut self, deletion: DeletionSettings) {
+        self.deletion = deletion;
+    }
+
+    /// push adds a deletion to the batch.
+    ///
+    /// # Arguments
+    /// * `destination` - The database and target to delete from
+    /// * `collection` - The collection to delete from
+    /// * `id` - The `_id` of the document to delete
+    /// * `rev` - The revision of the deletion
+    /// * `seq` - The sequence of the change
+    pub fn push(
+        &mut self,
+        destination: &Destination,
+        collection: &str,
+        id: Bson,
+        rev: &str,
+        seq: &str,
+    ) {
+        let ids = match destination.is_default() {
+            true => &mut self.ids,
+            false => self.routed.entry(destination.clone()).or_default(),
+        };
+        ids.entry(collection.to_string())
+            .or_default()
+            .push((id, rev.to_string()));
+        self.len += 1;
+        if self.first_seq.is_none() {
+            self.first_seq = Some(seq.to_string());
+        }
+        self.last_seq = Some(seq.to_string());
+    }
+
+    /// len returns the number of deletions in the batch.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// collections returns the number of collections in the batch, ie. how many `delete_many`
+    /// calls flushing it takes.
+    pub fn collections(&self) -> usize {
+        self.ids.len() + self.routed.values().map(BTreeMap::len).sum::<usize>()
+    }
+
+    /// is_empty returns true if there is nothing to delete.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// flush deletes every document in the batch and empties it.
+    ///
+    /// # Arguments
+    /// * `databases` - The MongoDB databases
+    ///
+    /// # Returns
+    /// * The sequence of the last deletion in the batch, which is now safe to checkpoint
+    pub async fn flush(&mut self, databases: &Databases) -> Result<Option<String>, Box<dyn Error>> {
+        if let (Some(intent_log), Some(intent)) = (&self.intent_log, self.intent()) {
+            intent_log.record(&intent).await?;
+        }
+
+        let batches = std::iter::once((Destination::default(), std::mem::take(&mut self.ids)))
+            .chain(std::mem::take(&mut self.routed));
+        for (destination, collections) in batches {
+            let db = databases.get(&destination)?;
+            for (collection_name, entries) in collections {
+                let ids: Vec<Bson> = entries.into_iter().map(|(id, _)| id).collect();
+                let requested = ids.len();
+                let size = bson::to_vec(&doc! { "_id": { "$in": ids.clone() } })?.len();
+
+                let deleted =
+                    deletion::delete_many(&db, &collection_name, ids, &self.deletion).await?;
+                metrics::record_bytes_written(&collection_name, "delete", size);
+
+                info!(
+                    target = destination.target.as_deref(),
+                    database = db.name(),
+                    collection = collection_name.as_str(),
+                    strategy = format!("{:?}", self.deletion.strategy),
+                    requested,
+                    deleted,
+                    "deleted documents",
+                );
+            }
+        }
+
+        if let Some(intent_log) = &self.intent_log {
+            intent_log.clear().await?;
+        }
+
+        self.len = 0;
+        self.first_seq = None;
+        Ok(self.last_seq.take())
+    }
+
+    /// intent describes the batch for the intent log.
+    fn intent(&self) -> Option<Intent> {
+        Some(Intent {
+            from_seq: self.first_seq.clone()?,
+            to_seq: self.last_seq.clone()?,
+            deletes: self.ids.clone(),
+            routed_deletes: self.routed.clone().into_iter().collect(),
+        })
+    }
+}
+
+/// recover re-applies a batch that was interrupted part way through.
+///
+/// # Arguments
+/// * `databases` - The MongoDB databases
+/// * `intent_log` - The intent log
+/// * `deletion` - The deletion strategy to re-apply the batch with
+///
+/// # Returns
+/// * The last sequence of the recovered batch, which is now safe to checkpoint, or None if there
+///   was nothing to recover
+pub async fn recover(
+    databases: &Databases,
+    intent_log: &IntentLog,
+    deletion: &DeletionSettings,
+) -> Result<Option<String>, Box<dyn Error>> {
+    let intent = match intent_log.load().await? {
+        Some(intent) => intent,
+        None => return Ok(None),
+    };
+
+    warn!(
+        from_seq = intent.from_seq.as_str(),
+        to_seq = intent.to_seq.as_str(),
+        "re-applying interrupted delete batch"
+    );
+
+    let mut batch = DeleteBatch {
+        len: intent
+            .deletes
+            .values()
+            .chain(
+                intent
+                    .routed_deletes
+                    .iter()
+                    .flat_map(|(_, ids)| ids.values()),
+            )
+            .map(Vec::len)
+            .sum(),
+        ids: intent.deletes,
+        routed: intent.routed_deletes.into_iter().collect(),
+        first_seq: Some(intent.from_seq),
+        last_seq: Some(intent.to_seq),
+        intent_log: None,
+        deletion: deletion.clone(),
+    };
+    let seq = batch.flush(databases).await?;
+    intent_log.clear().await?;
+
+    Ok(seq)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_push_groups_by_collection() {
+        let mut batch = DeleteBatch::new();
+        assert!(batch.is_empty());
+
+        let default = Destination::default();
+        batch.push(
+            &default,
+            "cats",
+            Bson::String("tom".to_string()),
+            "2-x",
+            "1-a",
+        );
+        batch.push(
+            &default,
+            "mice",
+            Bson::String("jerry".to_string()),
+            "3-y",
+            "2-b",
+        );
+        batch.push(
+            &default,
+            "cats",
+            Bson::String("felix".to_string()),
+            "4-z",
+            "3-c",
+        );
+
+        assert_eq!(batch.len(), 3);
+        assert_eq!(batch.ids.get("cats").unwrap().len(), 2);
+        assert_eq!(batch.ids.get("mice").unwrap().len(), 1);
+
+        let intent = batch.intent().unwrap();
+        assert_eq!(intent.from_seq, "1-a");
+        assert_eq!(intent.to_seq, "3-c");
+        assert_eq!(intent.deletes, batch.ids);
+    }
+
+    #[test]
+    fn test_push_groups_by_destination() {
+        let mut batch = DeleteBatch::new();
+        let acme = Destination {
+            target: Some("archive".to_string()),
+            database: Some("acme".to_string()),
+        };
+        batch.push(
+            &Destination::default(),
+            "cats",
+            Bson::String("tom".to_string()),
+            "2-x",
+            "1-a",
+        );
+        batch.push(
+            &acme,
+            "cats",
+            Bson::String("felix".to_string()),
+            "4-z",
+            "2-b",
+        );
+
+        assert_eq!(batch.len(), 2);
+        assert_eq!(batch.collections(), 2);
+        assert_eq!(batch.ids.get("cats").unwrap().len(), 1);
+        assert_eq!(batch.routed[&acme].get("cats").unwrap().len(), 1);
+
+        let intent = batch.intent().unwrap();
+        assert_eq!(
+            intent.routed_deletes,
+            vec![(acme.clone(), batch.routed[&acme].clone())]
+        );
+    }
+}
@@ -0,0 +1,336 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::couchdb::{preflight, CouchConnection};
+use crate::settings::config_parser::Settings;
+use bson::{doc, Bson, Document};
+use reqwest::{Method, Response, StatusCode};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fmt;
+
+/// Actions on the target database needed to replicate into it.
+const REQUIRED_ACTIONS: [&str; 4] = ["find", "insert", "update", "remove"];
+
+/// CheckResult is the outcome of checking one part of the configuration.
+#[derive(Debug)]
+pub struct CheckResult {
+    pub name: &'static str,
+    pub outcome: Result<String, String>,
+}
+
+impl CheckResult {
+    /// is_ok returns true if the check passed.
+    pub fn is_ok(&self) -> bool {
+        self.outcome.is_ok()
+    }
+}
+
+impl fmt::Display for CheckResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.outcome {
+            Ok(detail) => write!(f, "ok    {:<15} {}", self.name, detail),
+            Err(reason) => write!(f, "FAIL  {:<15} {}", self.name, reason),
+        }
+    }
+}
+
+/// run checks that the configuration is usable, and that CouchDB, MongoDB and the sequence store
+/// can be reached with enough permissions to replicate.
+///
+/// Nothing is changed, except that an existing checkpoint is written back unchanged to check the
+/// sequence store is writable.
+///
+/// # Arguments
+/// * `settings` - A Settings struct
+///
+/// # Returns
+/// * The result of each check, in order
+pub async fn run(settings: &Settings) -> Vec<CheckResult> {
+    vec![
+        CheckResult {
+            name: "config",
+            outcome: check_config(settings),
+        },
+        CheckResult {
+            name: "couchdb",
+            outcome: check_couchdb(settings).await,
+        },
+        CheckResult {
+            name: "mongodb",
+            outcome: check_mongodb(settings).await,
+        },
+        CheckResult {
+            name: "sequence store",
+            outcome: check_sequence_store(settings).await,
+        },
+    ]
+}
+
+/// check_config builds everything that validates its settings, without connecting to anything.
+fn check_config(settings: &Settings) -> Result<String, String> {
+    settings.get_id_filter().map_err(|e| e.to_string())?;
+    settings
+        .get_collection_router()
+        .map_err(|e| e.to_string())?;
+    settings.get_changes_doc_ids().map_err(|e| e.to_string())?;
+
+    Ok(format!(
+        "sequence key {}, config hash {}",
+        settings.get_sequence_store_key(),
+        settings.config_fingerprint()
+    ))
+}
+
+async fn check_couchdb(settings: &Settings) -> Result<String, String> {
+    let connection = settings.get_couchdb_connection().await.map_err(|e| {
+        format!(
+            "unable to create a client for {}: {}",
+            settings.source_url, e
+        )
+    })?;
+
+    let response = get(&connection, settings, "", None).await?;
+    if let Some(reason) = describe_status(response.status(), "the server") {
+        return Err(reason);
+    }
+
+    let database = &settings.source_database;
+    let response = get(&connection, settings, database, None).await?;
+    if let Some(reason) = describe_status(response.status(), &format!("database {}", database)) {
+        return Err(reason);
+    }
+    let info: Value = response
+        .json()
+        .await
+        .map_err(|e| format!("unexpected response for database {}: {}", database, e))?;
+
+    let params = HashMap::from([("limit".to_string(), "0".to_string())]);
+    let response = get(
+        &connection,
+        settings,
+        &format!("{}/_changes", database),
+        Some(&params),
+    )
+    .await?;
+    if let Some(reason) = describe_status(
+        response.status(),
+        &format!("the changes feed of {}", database),
+    ) {
+        return Err(reason);
+    }
+
+    Ok(format!(
+        "database {} is readable, {} documents",
+        database,
+        info.get("doc_count").cloned().unwrap_or(Value::Null)
+    ))
+}
+
+async fn get(
+    connection: &CouchConnection,
+    settings: &Settings,
+    path: &str,
+    params: Option<&HashMap<String, String>>,
+) -> Result<Response, String> {
+    connection
+        .req(Method::GET, path, params)
+        .send()
+        .await
+        .map_err(|e| format!("unable to reach {}: {}", settings.source_url, e))
+}
+
+/// describe_status explains a failed response, or returns None if it succeeded.
+fn describe_status(status: StatusCode, what: &str) -> Option<String> {
+    match status {
+        s if s.is_success() => None,
+        StatusCode::UNAUTHORIZED => Some(format!("credentials were rejected reading {}", what)),
+        StatusCode::FORBIDDEN => Some(format!("not permitted to read {}", what)),
+        StatusCode::NOT_FOUND => Some(format!("{} does not exist", what)),
+        s => Some(format!("unexpected status {} reading {}", s, what)),
+    }
+}
+
+async fn check_mongodb(settings: &Settings) -> Result<String, String> {
+    let db = settings
+        .get_mongodb_database()
+        .await
+        .map_err(|e| format!("invalid connection string: {}", e))?;
+
+    db.run_command(doc! { "ping": 1 }, None)
+        .await
+        .map_err(|e| format!("unable to reach MongoDB: {}", e))?;
+
+    let status = db
+        .run_command(doc! { "connectionStatus": 1, "showPrivileges": true }, None)
+        .await
+        .map_err(|e| format!("unable to read connection status: {}", e))?;
+
+    let auth_info = status.get_document("authInfo").cloned().unwrap_or_default();
+    let authenticated = auth_info
+        .get_array("authenticatedUsers")
+        .map(|users| !users.is_empty())
+        .unwrap_or(false);
+    if !authenticated {
+        return Ok(format!(
+            "connected to {}, not authenticated so privileges weren't checked",
+            settings.mongodb_database
+        ));
+    }
+
+    let missing = missing_actions(&auth_info, &settings.mongodb_database);
+    if !missing.is_empty() {
+        return Err(format!(
+            "missing privileges on database {}: {}",
+            settings.mongodb_database,
+            missing.join(", ")
+        ));
+    }
+
+    Ok(format!(
+        "connected to {} with {} privileges",
+        settings.mongodb_database,
+        REQUIRED_ACTIONS.join(", ")
+    ))
+}
+
+/// missing_actions returns the required actions that `authInfo` doesn't grant on a database.
+fn missing_actions(auth_info: &Document, database: &str) -> Vec<&'static str> {
+    let mut granted: Vec<String> = Vec::new();
+
+    for privilege in auth_info
+        .get_array("authenticatedUserPrivileges")
+        .map(|p| p.as_slice())
+        .unwrap_or_default()
+    {
+        let privilege = match privilege {
+            Bson::Document(privilege) => privilege,
+            _ => continue,
+        };
+        let resource = privilege
+            .get_document("resource")
+            .cloned()
+            .unwrap_or_default();
+        let applies = resource.get_bool("anyResource").unwrap_or(false)
+            || matches!(resource.get_str("db"), Ok(db) if db.is_empty() || db == database);
+        if !applies {
+            continue;
+        }
+
+        for action in privilege
+            .get_array("actions")
+            .map(|a| a.as_slice())
+            .unwrap_or_default()
+        {
+            if let Bson::String(action) = action {
+                granted.push(action.clone());
+            }
+        }
+    }
+
+    REQUIRED_ACTIONS
+        .into_iter()
+        .filter(|action| !granted.iter().any(|g| g == action))
+        .collect()
+}
+
+async fn check_sequence_store(settings: &Settings) -> Result<String, String> {
+    let store = settings
+        .get_sequence_store()
+        .await
+        .map_err(|e| format!("unable to connect: {}", e))?;
+    let key = settings.get_sequence_store_key();
+
+    settings
+        .probe_sequence_store(store.as_ref())
+        .await
+        .map_err(|e| e.to_string())?;
+
+    let sequence = store
+        .get(&key)
+        .await
+        .map_err(|e| format!("unable to read {}: {}", key, e))?;
+
+    let sequence = match sequence {
+        Some(sequence) => sequence,
+        None => {
+            return Ok(format!(
+                "no checkpoint stored under {}, replication will start from the beginning",
+                key
+            ))
+        }
+    };
+
+    match store.set_if_equals(&key, Some(&sequence), &sequence).await {
+        Ok(true) => {}
+        Ok(false) => return Err(format!("checkpoint {} changed while checking it", key)),
+        Err(e) => return Err(format!("unable to write {}: {}", key, e)),
+    }
+
+    let connection = settings
+        .get_couchdb_connection()
+        .await
+        .map_err(|e| e.to_string())?;
+    let status = preflight::validate_checkpoint(&connection, &settings.source_database, &sequence)
+        .await
+        .map_err(|e| format!("unable to validate checkpoint: {}", e))?;
+    if !status.is_valid() {
+        return Err(format!(
+            "{} (checkpoint {}); reset the checkpoint to resync",
+            status, sequence
+        ));
+    }
+
+    Ok(format!("checkpoint {} is {}", key, sequence))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_missing_actions() {
+        let auth_info = doc! {
+            "authenticatedUsers": [{ "user": "couch2mongo", "db": "admin" }],
+            "authenticatedUserPrivileges": [
+                {
+                    "resource": { "db": "animals", "collection": "" },
+                    "actions": ["find", "insert", "update"],
+                },
+                {
+                    "resource": { "db": "other", "collection": "" },
+                    "actions": ["remove"],
+                },
+            ],
+        };
+
+        assert_eq!(missing_actions(&auth_info, "animals"), vec!["remove"]);
+        assert_eq!(
+            missing_actions(&auth_info, "other"),
+            vec!["find", "insert", "update"]
+        );
+    }
+
+    #[test]
+    fn test_any_resource_grants_everything() {
+        let auth_info = doc! {
+            "authenticatedUserPrivileges": [{
+                "resource": { "anyResource": true },
+                "actions": ["find", "insert", "update", "remove"],
+            }],
+        };
+
+        assert!(missing_actions(&auth_info, "animals").is_empty());
+    }
+}
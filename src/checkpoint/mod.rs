@@ -0,0 +1,332 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::seqstore::interface::SequenceStore;
+use crate::settings::config_parser::SequenceMutationPolicy;
+use crate::sink::Sinks;
+use std::error::Error;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{debug, warn};
+
+/// Checkpointer decides when the sequence of applied changes is written to the sequence store.
+///
+/// Persisting after every document doubles the write load on the store, so instead the latest
+/// sequence is held back until `interval_docs` changes have been applied or `interval` has passed
+/// since the last write, whichever comes first. Anything held back is replayed after a crash,
+/// which is safe because every write we make is idempotent.
+///
+/// The checkpoint we last wrote is kept in memory, so nothing is read from the store per change.
+/// It's only read back when a compare-and-set write fails, or when `check` is called (every
+/// `sequence_check_interval_ms` in the pipeline). If the stored checkpoint is moved by something
+/// else, what happens depends on the SequenceMutationPolicy.
+pub struct Checkpointer {
+    store: Arc<dyn SequenceStore>,
+    key: String,
+    interval_docs: usize,
+    interval: Duration,
+    // The checkpoint in the store, as far as we know
+    persisted: Option<String>,
+    pending: Option<String>,
+    pending_docs: usize,
+    last_write: Instant,
+    sinks: Option<Arc<Sinks>>,
+    policy: SequenceMutationPolicy,
+    // Where the checkpoint was moved to, while waiting for it to be adopted
+    moved: Option<Option<String>>,
+}
+
+impl Checkpointer {
+    /// new creates a new Checkpointer.
+    ///
+    /// # Arguments
+    /// * `store` - The shared sequence store
+    /// * `key` - The checkpoint key
+    /// * `interval_docs` - Persist after this many changes
+    /// * `interval_ms` - Persist when this many milliseconds have passed since the last write
+    /// * `persisted` - The checkpoint currently in the store
+    ///
+    /// # Returns
+    /// * A Checkpointer struct
+    pub fn new(
+        store: Arc<dyn SequenceStore>,
+        key: &str,
+        interval_docs: usize,
+        interval_ms: u64,
+        persisted: Option<String>,
+    ) -> Checkpointer {
+        Checkpointer {
+            store,
+            key: key.to_string(),
+            interval_docs: interval_docs.max(1),
+            interval: Duration::from_millis(interval_ms),
+            persisted,
+            pending: None,
+            pending_docs: 0,
+            last_write: Instant::now(),
+            sinks: None,
+            policy: SequenceMutationPolicy::Stop,
+            moved: None,
+        }
+    }
+
+    /// set_mutation_policy sets what to do when the stored checkpoint is moved by something else.
+    pub fn set_mutation_policy(&mut self, policy: SequenceMutationPolicy) {
+        self.policy = policy;
+    }
+
+    /// set_sinks makes the checkpointer flush the sinks before each checkpoint is persisted, so it
+    /// never gets ahead of changes they've buffered.
+    pub fn set_sinks(&mut self, sinks: Arc<Sinks>) {
+        self.sinks = Some(sinks);
+    }
+
+    /// advance records that every change up to `seq` has been applied, persisting it if due.
+    pub async fn advance(&mut self, seq: &str) -> Result<(), Box<dyn Error>> {
+        self.pending = Some(seq.to_string());
+        self.pending_docs += 1;
+
+        if self.pending_docs >= self.interval_docs || self.last_write.elapsed() >= self.interval {
+            self.flush().await?;
+        }
+
+        Ok(())
+    }
+
+    /// time_until_due returns how long until a held back checkpoint should be written, or None if
+    /// nothing is held back.
+    pub fn time_until_due(&self) -> Option<Duration> {
+        self.pending.as_ref()?;
+
+        Some(self.interval.saturating_sub(self.last_write.elapsed()))
+    }
+
+    /// check reads the stored checkpoint, and applies the policy if it isn't the one we last
+    /// wrote.
+    pub async fn check(&mut self) -> Result<(), Box<dyn Error>> {
+        if self.moved.is_some() {
+            return Ok(());
+        }
+
+        let current = self.store.get(&self.key).await?;
+        if current != self.persisted {
+            self.moved_to(current)?;
+        }
+
+        Ok(())
+    }
+
+    /// is_moved returns true if the checkpoint was moved and is waiting to be adopted. Nothing is
+    /// persisted until it is.
+    pub fn is_moved(&self) -> bool {
+        self.moved.is_some()
+    }
+
+    /// take_moved returns where the checkpoint was moved to, if it's waiting to be adopted.
+    pub fn take_moved(&mut self) -> Option<Option<String>> {
+        self.moved.take()
+    }
+
+    /// adopt carries on from `seq`, dropping anything held back.
+    pub fn adopt(&mut self, seq: Option<String>) {
+        self.persisted = seq;
+        self.pending = None;
+        self.pending_docs = 0;
+        self.last_write = Instant::now();
+        self.moved = None;
+    }
+
+    /// moved_to applies the policy to a checkpoint that was moved to `current`.
+    fn moved_to(&mut self, current: Option<String>) -> Result<(), Box<dyn Error>> {
+        let current_seq = current.as_deref().unwrap_or("(none)");
+
+        match self.policy {
+            SequenceMutationPolicy::Stop => {
+                return Err(format!(
+                    "checkpoint {} was moved by another instance to {}, stopping",
+                    self.key, current_seq
+                )
+                .into());
+            }
+            SequenceMutationPolicy::Warn => {
+                warn!(
+                    key = self.key.as_str(),
+                    seq = current_seq,
+                    "checkpoint was moved by something else, it will be overwritten"
+                );
+                self.persisted = current;
+            }
+            SequenceMutationPolicy::Adopt => {
+                warn!(
+                    key = self.key.as_str(),
+                    seq = current_seq,
+                    "checkpoint was moved by something else, carrying on from there"
+                );
+                self.moved = Some(current);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// flush persists any held back checkpoint.
+    ///
+    /// The write only succeeds if the stored checkpoint is still the one we last wrote, so if
+    /// another instance is running against the same key, whichever moves it second is fenced out
+    /// (or, depending on the policy, overwrites it or adopts it).
+    pub async fn flush(&mut self) -> Result<(), Box<dyn Error>> {
+        if self.moved.is_some() {
+            return Ok(());
+        }
+
+        let seq = match self.pending.take() {
+            Some(seq) => seq,
+            None => return Ok(()),
+        };
+
+        if let Some(sinks) = &self.sinks {
+            sinks.flush().await?;
+        }
+
+        debug!(
+            seq = seq.as_str(),
+            docs = self.pending_docs,
+            "checkpointing"
+        );
+        while !self
+            .store
+            .set_checkpoint(&self.key, self.persisted.as_deref(), &seq)
+            .await?
+        {
+            let current = self.store.get(&self.key).await?;
+            // The store refused to move it back
+            if current == self.persisted {
+                return Err(format!(
+                    "checkpoint {} is at {}, ahead of {}, stopping",
+                    self.key,
+                    current.as_deref().unwrap_or("(none)"),
+                    seq
+                )
+                .into());
+            }
+
+            self.moved_to(current)?;
+            if self.moved.is_some() {
+                self.pending = Some(seq);
+                return Ok(());
+            }
+        }
+
+        self.persisted = Some(seq);
+        self.pending_docs = 0;
+        self.last_write = Instant::now();
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::seqstore::sqlite::SQLite;
+    use crate::settings::config_parser::SQLiteSettings;
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn test_checkpoint_every_n_docs() {
+        let rt = Runtime::new().unwrap();
+        let store: Arc<dyn SequenceStore> = Arc::new(
+            SQLite::new(&SQLiteSettings {
+                path: ":memory:".to_string(),
+            })
+            .unwrap(),
+        );
+        let mut checkpointer = Checkpointer::new(store.clone(), "key", 3, 60_000, None);
+
+        rt.block_on(async {
+            checkpointer.advance("1-a").await.unwrap();
+            checkpointer.advance("2-b").await.unwrap();
+            assert_eq!(store.get("key").await.unwrap(), None);
+            assert!(checkpointer.time_until_due().is_some());
+
+            checkpointer.advance("3-c").await.unwrap();
+            assert_eq!(store.get("key").await.unwrap(), Some("3-c".to_string()));
+            assert!(checkpointer.time_until_due().is_none());
+
+            checkpointer.advance("4-d").await.unwrap();
+            checkpointer.flush().await.unwrap();
+            assert_eq!(store.get("key").await.unwrap(), Some("4-d".to_string()));
+        });
+    }
+
+    #[test]
+    fn test_fenced_out() {
+        let rt = Runtime::new().unwrap();
+        let store: Arc<dyn SequenceStore> = Arc::new(
+            SQLite::new(&SQLiteSettings {
+                path: ":memory:".to_string(),
+            })
+            .unwrap(),
+        );
+        let mut checkpointer = Checkpointer::new(store.clone(), "key", 1, 0, None);
+
+        rt.block_on(async {
+            checkpointer.advance("1-a").await.unwrap();
+
+            store.set("key", "5-other").await.unwrap();
+            assert!(checkpointer.advance("2-b").await.is_err());
+            assert_eq!(store.get("key").await.unwrap(), Some("5-other".to_string()));
+        });
+    }
+
+    #[test]
+    fn test_mutation_policies() {
+        let rt = Runtime::new().unwrap();
+        let store: Arc<dyn SequenceStore> = Arc::new(
+            SQLite::new(&SQLiteSettings {
+                path: ":memory:".to_string(),
+            })
+            .unwrap(),
+        );
+
+        rt.block_on(async {
+            let mut checkpointer = Checkpointer::new(store.clone(), "warn", 1, 0, None);
+            checkpointer.set_mutation_policy(SequenceMutationPolicy::Warn);
+            checkpointer.advance("1-a").await.unwrap();
+            store.set("warn", "5-other").await.unwrap();
+            checkpointer.advance("2-b").await.unwrap();
+            assert_eq!(store.get("warn").await.unwrap(), Some("2-b".to_string()));
+
+            let mut checkpointer = Checkpointer::new(store.clone(), "adopt", 1, 0, None);
+            checkpointer.set_mutation_policy(SequenceMutationPolicy::Adopt);
+            checkpointer.advance("1-a").await.unwrap();
+            store.set("adopt", "5-other").await.unwrap();
+            checkpointer.check().await.unwrap();
+            assert!(checkpointer.is_moved());
+
+            // Held back until adopted
+            checkpointer.advance("2-b").await.unwrap();
+            assert_eq!(
+                store.get("adopt").await.unwrap(),
+                Some("5-other".to_string())
+            );
+
+            let seq = checkpointer.take_moved().unwrap();
+            assert_eq!(seq.as_deref(), Some("5-other"));
+            checkpointer.adopt(seq);
+            checkpointer.advance("6-c").await.unwrap();
+            assert_eq!(store.get("adopt").await.unwrap(), Some("6-c".to_string()));
+        });
+    }
+}
@@ -0,0 +1,235 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::settings::config_parser::TokenSettings;
+use std::error::Error;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::process::Command;
+use tracing::{info, warn};
+
+/// IAM_GRANT_TYPE is the OAuth grant type for exchanging an IBM Cloud API key for a token.
+const IAM_GRANT_TYPE: &str = "urn:ibm:params:oauth:grant-type:apikey";
+
+/// IAM_REFRESH_MARGIN is how long before an IAM token expires that it's refreshed.
+const IAM_REFRESH_MARGIN: Duration = Duration::from_secs(300);
+
+/// TokenProvider obtains bearer tokens for CouchDB instances that sit behind an OIDC (or similar)
+/// proxy.
+///
+/// Rather than building every identity provider into the crate, the token is fetched by either
+/// running an external command and reading its stdout, or by calling an HTTP endpoint. The token is
+/// refreshed in the background on a fixed schedule.
+///
+/// Cloudant's IAM authentication is built in: an API key is exchanged for a token at the IAM
+/// endpoint, and the token is refreshed shortly before it expires.
+pub struct TokenProvider {
+    settings: TokenSettings,
+    token: RwLock<Option<String>>,
+}
+
+impl std::fmt::Debug for TokenProvider {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Never print the token itself
+        f.debug_struct("TokenProvider")
+            .field("settings", &self.settings)
+            .finish_non_exhaustive()
+    }
+}
+
+impl TokenProvider {
+    /// new creates a new TokenProvider, fetches the first token and starts the background refresh.
+    ///
+    /// # Arguments
+    /// * `settings` - A TokenSettings struct
+    ///
+    /// # Returns
+    /// * A shared TokenProvider
+    pub async fn new(settings: &TokenSettings) -> Result<Arc<TokenProvider>, Box<dyn Error>> {
+        if settings.command.is_none() && settings.url.is_none() && settings.iam_api_key.is_none() {
+            return Err("couchdb_token requires a command, a url or an iam_api_key".into());
+        }
+
+        let provider = Arc::new(TokenProvider {
+            settings: settings.clone(),
+            token: RwLock::new(None),
+        });
+
+        let mut expires_in = provider.refresh().await.map_err(|e| e as Box<dyn Error>)?;
+
+        let background = provider.clone();
+        tokio::spawn(async move {
+            let period = Duration::from_secs(background.settings.refresh_interval);
+
+            loop {
+                tokio::time::sleep(refresh_delay(period, expires_in)).await;
+
+                match background.refresh().await {
+                    Ok(next_expires_in) => expires_in = next_expires_in,
+                    Err(e) => {
+                        warn!(error = e.to_string(), "unable to refresh bearer token");
+                        expires_in = None;
+                    }
+                }
+            }
+        });
+
+        Ok(provider)
+    }
+
+    /// token returns the current bearer token.
+    pub fn token(&self) -> Option<String> {
+        self.token.read().expect("unable to read token").clone()
+    }
+
+    /// refresh fetches a new token and stores it, returning how long the token is valid for if the
+    /// source says.
+    async fn refresh(&self) -> Result<Option<Duration>, Box<dyn Error + Send + Sync>> {
+        let settings = &self.settings;
+        let (token, expires_in) = match (&settings.iam_api_key, &settings.command, &settings.url) {
+            (Some(api_key), ..) => TokenProvider::from_iam(&settings.iam_url, api_key).await?,
+            (None, Some(command), _) => (TokenProvider::from_command(command).await?, None),
+            (None, None, Some(url)) => (TokenProvider::from_url(url).await?, None),
+            (None, None, None) => return Err("no token source configured".into()),
+        };
+
+        if token.is_empty() {
+            return Err("token source returned an empty token".into());
+        }
+
+        info!("refreshed bearer token");
+        self.token
+            .write()
+            .expect("unable to write token")
+            .replace(token);
+
+        Ok(expires_in)
+    }
+
+    /// from_command runs the command through the shell and uses its trimmed stdout as the token.
+    async fn from_command(command: &str) -> Result<String, Box<dyn Error + Send + Sync>> {
+        let output = Command::new("sh").arg("-c").arg(command).output().await?;
+
+        if !output.status.success() {
+            return Err(format!(
+                "token command exited with {}: {}",
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )
+            .into());
+        }
+
+        Ok(String::from_utf8(output.stdout)?.trim().to_string())
+    }
+
+    /// from_url fetches the token from an HTTP endpoint.
+    ///
+    /// The response may either be the raw token, or a JSON object with an `access_token` field.
+    async fn from_url(url: &str) -> Result<String, Box<dyn Error + Send + Sync>> {
+        let body = reqwest::get(url).await?.error_for_status()?.text().await?;
+
+        Ok(parse_token_response(&body))
+    }
+
+    /// from_iam exchanges an IBM Cloud API key for an IAM access token, returning the token and
+    /// how long it's valid for.
+    async fn from_iam(
+        url: &str,
+        api_key: &str,
+    ) -> Result<(String, Option<Duration>), Box<dyn Error + Send + Sync>> {
+        let body = reqwest::Client::new()
+            .post(url)
+            .header("Accept", "application/json")
+            .form(&[("grant_type", IAM_GRANT_TYPE), ("apikey", api_key)])
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+
+        Ok((parse_token_response(&body), parse_expires_in(&body)))
+    }
+}
+
+/// refresh_delay returns how long to wait before the next refresh: a little before the token
+/// expires if its lifetime is known, otherwise the configured refresh interval.
+fn refresh_delay(period: Duration, expires_in: Option<Duration>) -> Duration {
+    match expires_in {
+        Some(expires_in) if expires_in > IAM_REFRESH_MARGIN * 2 => expires_in - IAM_REFRESH_MARGIN,
+        Some(expires_in) => expires_in / 2,
+        None => period,
+    }
+}
+
+/// parse_expires_in extracts the token lifetime from a token endpoint response body.
+fn parse_expires_in(body: &str) -> Option<Duration> {
+    serde_json::from_str::<serde_json::Value>(body)
+        .ok()?
+        .get("expires_in")?
+        .as_u64()
+        .map(Duration::from_secs)
+}
+
+/// parse_token_response extracts the token from a token endpoint response body.
+fn parse_token_response(body: &str) -> String {
+    match serde_json::from_str::<serde_json::Value>(body) {
+        Ok(serde_json::Value::Object(o)) => o
+            .get("access_token")
+            .and_then(|t| t.as_str())
+            .unwrap_or_default()
+            .to_string(),
+        _ => body.trim().to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_token_response_raw() {
+        assert_eq!(parse_token_response("abc.def\n"), "abc.def");
+    }
+
+    #[test]
+    fn test_parse_token_response_json() {
+        assert_eq!(
+            parse_token_response(r#"{"access_token": "abc.def", "expires_in": 300}"#),
+            "abc.def"
+        );
+    }
+
+    #[test]
+    fn test_parse_expires_in() {
+        assert_eq!(
+            parse_expires_in(r#"{"access_token": "abc.def", "expires_in": 3600}"#),
+            Some(Duration::from_secs(3600))
+        );
+        assert_eq!(parse_expires_in("abc.def"), None);
+    }
+
+    #[test]
+    fn test_refresh_delay() {
+        let period = Duration::from_secs(300);
+        assert_eq!(refresh_delay(period, None), period);
+        assert_eq!(
+            refresh_delay(period, Some(Duration::from_secs(3600))),
+            Duration::from_secs(3300)
+        );
+        assert_eq!(
+            refresh_delay(period, Some(Duration::from_secs(60))),
+            Duration::from_secs(30)
+        );
+    }
+}
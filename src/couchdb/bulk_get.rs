@@ -0,0 +1,126 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::couchdb::CouchConnection;
+use couch_rs::error::{CouchError, CouchResult};
+use reqwest::Method;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use tracing::debug;
+
+/// bulk_get fetches the current revision of several documents in one `_bulk_get` request.
+///
+/// Documents that can't be fetched, eg. because they've been deleted since, are left out.
+///
+/// # Arguments
+/// * `connection` - The CouchDB connection
+/// * `database` - The database holding the documents
+/// * `ids` - The IDs of the documents to fetch
+///
+/// # Returns
+/// * The documents, by ID
+pub async fn bulk_get(
+    connection: &CouchConnection,
+    database: &str,
+    ids: &[String],
+) -> CouchResult<HashMap<String, Value>> {
+    if ids.is_empty() {
+        return Ok(HashMap::new());
+    }
+
+    let docs: Vec<Value> = ids.iter().map(|id| json!({ "id": id })).collect();
+    let request = connection
+        .req(Method::POST, &format!("{}/_bulk_get", database), None)
+        .json(&json!({ "docs": docs }));
+    let response = connection.send(request, "bulk_get").await?;
+
+    let status = response.status();
+    if !status.is_success() {
+        return Err(CouchError::new(
+            response.text().await.unwrap_or_default(),
+            status,
+        ));
+    }
+
+    Ok(parse_bulk_get_response(response.json().await?))
+}
+
+/// parse_bulk_get_response returns the documents in a `_bulk_get` response, by ID.
+fn parse_bulk_get_response(response: Value) -> HashMap<String, Value> {
+    let mut documents = HashMap::new();
+
+    let results = match response {
+        Value::Object(mut o) => match o.remove("results") {
+            Some(Value::Array(results)) => results,
+            _ => return documents,
+        },
+        _ => return documents,
+    };
+
+    for result in results {
+        let docs = result.get("docs").and_then(Value::as_array);
+        for doc in docs.into_iter().flatten() {
+            if let Some(error) = doc.get("error") {
+                debug!(
+                    id = error.get("id").and_then(|id| id.as_str()),
+                    error = error.get("error").and_then(|e| e.as_str()),
+                    "unable to fetch document"
+                );
+                continue;
+            }
+
+            let document = match doc.get("ok") {
+                Some(document) => document,
+                None => continue,
+            };
+            if let Some(id) = document.get("_id").and_then(Value::as_str) {
+                documents.insert(id.to_string(), document.clone());
+            }
+        }
+    }
+
+    documents
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_bulk_get_response() {
+        let response = json!({
+            "results": [
+                {
+                    "id": "cat:tom",
+                    "docs": [{ "ok": { "_id": "cat:tom", "_rev": "2-b", "name": "Tom" } }]
+                },
+                {
+                    "id": "mouse:jerry",
+                    "docs": [{
+                        "error": {
+                            "id": "mouse:jerry",
+                            "rev": "undefined",
+                            "error": "not_found",
+                            "reason": "deleted"
+                        }
+                    }]
+                }
+            ]
+        });
+
+        let documents = parse_bulk_get_response(response);
+        assert_eq!(documents.len(), 1);
+        assert_eq!(documents["cat:tom"]["name"], "Tom");
+    }
+}
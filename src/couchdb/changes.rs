@@ -0,0 +1,740 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::couchdb::bulk_get::bulk_get;
+use crate::couchdb::coalesce::Coalescer;
+use crate::couchdb::{pop_line, sequence_string, CouchConnection};
+use crate::filters::IdFilter;
+use crate::settings::config_parser::{ChangesFeedSettings, CoalesceSettings};
+use crate::systemd;
+use couch_rs::error::{CouchError, CouchResult};
+use couch_rs::types::changes::{ChangeEvent, Event};
+use lazy_static::lazy_static;
+use prometheus::{register_int_counter_vec, register_int_gauge, IntCounterVec, IntGauge};
+use reqwest::{Method, Response, StatusCode};
+use serde_json::{json, Value};
+use std::collections::{HashMap, VecDeque};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tracing::{debug, warn};
+
+lazy_static! {
+    /// Changes read from the feed and waiting to be written to MongoDB.
+    pub static ref CHANGE_QUEUE_DEPTH: IntGauge = register_int_gauge!(
+        "couch2mongo_change_queue_depth",
+        "Changes read from the feed and waiting to be written to MongoDB"
+    )
+    .unwrap();
+
+    /// Reconnections to the changes feed, by reason.
+    pub static ref FEED_RECONNECTS: IntCounterVec = register_int_counter_vec!(
+        "couch2mongo_changes_feed_reconnects_total",
+        "Reconnections to the changes feed after it stalled or failed",
+        &["reason"]
+    )
+    .unwrap();
+}
+
+/// The max timeout value for continuous requests that CouchDB supports.
+///
+/// See https://docs.couchdb.org/en/stable/api/database/changes.html
+const COUCH_MAX_TIMEOUT: usize = 60000;
+
+/// ChangesStream reads the `_changes` feed of a CouchDB database.
+///
+/// couch_rs ships its own changes stream, but it can only issue GET requests. Server-side filters
+/// such as `_selector` need their arguments in a POST body, so we drive the feed ourselves using
+/// the couch_rs client for the connection and authentication details.
+pub struct ChangesStream {
+    connection: CouchConnection,
+    database: String,
+    params: HashMap<String, String>,
+    body: Option<Value>,
+    last_seq: Option<Value>,
+    infinite: bool,
+    response: Option<Response>,
+    buffer: Vec<u8>,
+    heartbeat: Option<Duration>,
+    stall_timeout: Option<Duration>,
+    limit: Option<u64>,
+    reconnect_attempts: u32,
+    reconnect_backoff: Backoff,
+    failures: u32,
+    fetch_batch_size: Option<usize>,
+    fetch_filter: IdFilter,
+    fetched: VecDeque<ChangeEvent>,
+    drained: bool,
+}
+
+impl ChangesStream {
+    /// new creates a new ChangesStream.
+    ///
+    /// # Arguments
+    /// * `connection` - The CouchDB connection
+    /// * `database` - The name of the database to follow
+    /// * `last_seq` - The sequence to start from, or None to start from the beginning
+    ///
+    /// # Returns
+    /// * A ChangesStream struct
+    pub fn new(
+        connection: CouchConnection,
+        database: String,
+        last_seq: Option<Value>,
+    ) -> ChangesStream {
+        let mut params = HashMap::new();
+        params.insert("feed".to_string(), "continuous".to_string());
+        params.insert("timeout".to_string(), "0".to_string());
+        params.insert("include_docs".to_string(), "true".to_string());
+
+        ChangesStream {
+            connection,
+            database,
+            params,
+            body: None,
+            last_seq,
+            infinite: false,
+            response: None,
+            buffer: Vec::new(),
+            heartbeat: None,
+            stall_timeout: None,
+            limit: None,
+            reconnect_attempts: 0,
+            reconnect_backoff: Backoff {
+                initial: Duration::ZERO,
+                max: Duration::ZERO,
+            },
+            failures: 0,
+            fetch_batch_size: None,
+            fetch_filter: IdFilter::default(),
+            fetched: VecDeque::new(),
+            drained: false,
+        }
+    }
+
+    /// set_feed_settings asks CouchDB for heartbeats on an infinite feed, and sets how the stream
+    /// reconnects when the feed stalls or the connection fails.
+    ///
+    /// A feed that sends nothing, not even a heartbeat, for `stall_timeout_ms` is assumed to have
+    /// been dropped without the connection closing. The stream reconnects and resumes after the
+    /// last change it read, waiting longer after each consecutive failure. Errors CouchDB reports
+    /// with a 4xx status aren't retried.
+    ///
+    /// # Arguments
+    /// * `settings` - A ChangesFeedSettings struct
+    pub fn set_feed_settings(&mut self, settings: &ChangesFeedSettings) {
+        self.heartbeat = Some(Duration::from_millis(settings.heartbeat_ms));
+        self.params
+            .insert("style".to_string(), settings.style.as_str().to_string());
+        if let Some(seq_interval) = settings.seq_interval {
+            self.params
+                .insert("seq_interval".to_string(), seq_interval.to_string());
+        }
+        self.limit = settings.limit;
+        if let Some(limit) = settings.limit {
+            self.params.insert("limit".to_string(), limit.to_string());
+        }
+        if !settings.include_docs {
+            self.params
+                .insert("include_docs".to_string(), "false".to_string());
+            self.fetch_batch_size = Some(settings.bulk_get_batch_size.max(1));
+        }
+        self.stall_timeout = Some(Duration::from_millis(settings.stall_timeout_ms));
+        self.reconnect_attempts = settings.reconnect_attempts;
+        self.reconnect_backoff = Backoff {
+            initial: Duration::from_millis(settings.reconnect_delay_ms),
+            max: Duration::from_millis(settings.reconnect_max_delay_ms),
+        };
+    }
+
+    /// set_infinite sets infinite mode.
+    ///
+    /// If set to true, the stream will wait and poll for changes. Otherwise, the stream will
+    /// return all changes until now and then close.
+    pub fn set_infinite(&mut self, infinite: bool) {
+        self.infinite = infinite;
+        let timeout = match infinite {
+            true => COUCH_MAX_TIMEOUT,
+            false => 0,
+        };
+        self.params
+            .insert("timeout".to_string(), timeout.to_string());
+    }
+
+    /// set_selector restricts the feed to documents matching a Mango selector, using the
+    /// built-in `_selector` filter.
+    ///
+    /// # Arguments
+    /// * `selector` - A Mango selector, eg. `{"type": "cat"}`
+    pub fn set_selector(&mut self, selector: Value) {
+        self.params
+            .insert("filter".to_string(), "_selector".to_string());
+        self.body = Some(json!({ "selector": selector }));
+    }
+
+    /// set_doc_ids restricts the feed to the given document IDs, using the built-in `_doc_ids`
+    /// filter.
+    ///
+    /// # Arguments
+    /// * `doc_ids` - The document IDs to follow
+    pub fn set_doc_ids(&mut self, doc_ids: Vec<String>) {
+        self.params
+            .insert("filter".to_string(), "_doc_ids".to_string());
+        self.body = Some(json!({ "doc_ids": doc_ids }));
+    }
+
+    /// set_fetch_filter sets which documents are fetched when the feed is read without them
+    /// (`include_docs = false`). Changes to other documents are passed on without a document.
+    pub fn set_fetch_filter(&mut self, filter: IdFilter) {
+        self.fetch_filter = filter;
+    }
+
+    /// next returns the next change on the feed, or None once a non-infinite feed has been
+    /// drained.
+    ///
+    /// When the feed is read without documents, the changes that have already arrived (up to
+    /// `bulk_get_batch_size`) are read together and their documents fetched with one `_bulk_get`.
+    /// Changes to documents that no longer exist are dropped; a later deletion follows them.
+    pub async fn next(&mut self) -> Option<CouchResult<ChangeEvent>> {
+        let batch_size = match self.fetch_batch_size {
+            Some(batch_size) => batch_size,
+            None => return self.next_change().await,
+        };
+
+        loop {
+            if let Some(change) = self.fetched.pop_front() {
+                return Some(Ok(change));
+            }
+            if self.drained {
+                return None;
+            }
+
+            let resume_seq = self.last_seq.clone();
+            let mut batch = Vec::new();
+            // Only wait for the first change, rather than for a full batch
+            while batch.len() < batch_size && (batch.is_empty() || self.has_buffered_change()) {
+                match self.next_change().await {
+                    Some(Ok(change)) => batch.push(change),
+                    Some(Err(e)) => {
+                        self.rewind(resume_seq);
+                        return Some(Err(e));
+                    }
+                    None => {
+                        self.drained = true;
+                        break;
+                    }
+                }
+            }
+
+            if let Err(e) = self.fetch_documents(&mut batch).await {
+                self.rewind(resume_seq);
+                return Some(Err(e));
+            }
+            self.fetched.extend(batch);
+        }
+    }
+
+    /// fetch_documents fills in the documents for a batch of changes read without them.
+    async fn fetch_documents(&self, batch: &mut Vec<ChangeEvent>) -> CouchResult<()> {
+        let mut ids = Vec::new();
+        for change in batch.iter_mut() {
+            if change.deleted {
+                // Nothing to fetch, and the deleted revision is all there is to write
+                let rev = change.changes.first().map(|c| c.rev.clone());
+                change.doc = Some(json!({ "_id": change.id, "_rev": rev, "_deleted": true }));
+            } else if change.id.starts_with("_design") || self.fetch_filter.is_allowed(&change.id) {
+                ids.push(change.id.clone());
+            }
+        }
+
+        let mut documents = bulk_get(&self.connection, &self.database, &ids).await?;
+        batch.retain_mut(|change| {
+            if change.deleted || !ids.contains(&change.id) {
+                return true;
+            }
+
+            change.doc = documents.remove(&change.id);
+            if change.doc.is_none() {
+                debug!(
+                    id = change.id.as_str(),
+                    "document no longer exists, skipping change"
+                );
+            }
+            change.doc.is_some()
+        });
+
+        Ok(())
+    }
+
+    /// has_buffered_change returns true if a complete, non-empty line has already been read.
+    fn has_buffered_change(&self) -> bool {
+        let mut lines = self.buffer.split(|b| *b == b'\n').rev();
+        // The last piece hasn't been terminated yet
+        lines.next();
+        lines.any(|line| line.iter().any(|b| !b.is_ascii_whitespace()))
+    }
+
+    /// rewind drops the connection and anything read ahead, so the feed is read again from `seq`.
+    fn rewind(&mut self, seq: Option<Value>) {
+        self.last_seq = seq;
+        self.response = None;
+        self.buffer.clear();
+        self.fetched.clear();
+        self.drained = false;
+    }
+
+    /// next_change reads the next change from the feed.
+    async fn next_change(&mut self) -> Option<CouchResult<ChangeEvent>> {
+        loop {
+            if let Some(line) = pop_line(&mut self.buffer) {
+                if line.trim().is_empty() {
+                    continue;
+                }
+
+                match serde_json::from_str::<Event>(&line) {
+                    Ok(Event::Change(mut event)) => {
+                        // With seq_interval most changes have no sequence, so they're given the
+                        // last one seen; resuming from it replays at most seq_interval changes
+                        if event.seq.is_null() {
+                            event.seq = self.last_seq.clone().unwrap_or_else(|| json!("0"));
+                        } else {
+                            event.seq = Value::String(sequence_string(&event.seq));
+                            self.last_seq = Some(event.seq.clone());
+                        }
+                        return Some(Ok(event));
+                    }
+                    Ok(Event::Finished(event)) => {
+                        self.last_seq = Some(Value::String(sequence_string(&event.last_seq)));
+                        self.response = None;
+                        // A limited request stops early, so ask again for the rest
+                        let pending = self.limit.is_some() && event.pending.unwrap_or_default() > 0;
+                        if !self.infinite && !pending {
+                            return None;
+                        }
+                        continue;
+                    }
+                    Err(e) => return Some(Err(e.into())),
+                }
+            }
+
+            let response = match self.response {
+                Some(ref mut response) => response,
+                None => match self.request().await {
+                    Ok(response) => self.response.insert(response),
+                    Err(e) if is_retryable(&e) => {
+                        if let Err(e) = self.wait_to_reconnect(e, "error").await {
+                            return Some(Err(e));
+                        }
+                        continue;
+                    }
+                    Err(e) => return Some(Err(e)),
+                },
+            };
+
+            let chunk = match self.stall_timeout {
+                Some(stall_timeout) => tokio::time::timeout(stall_timeout, response.chunk()).await,
+                None => Ok(response.chunk().await),
+            };
+
+            match chunk {
+                Ok(Ok(Some(chunk))) => {
+                    self.failures = 0;
+                    systemd::record_activity();
+                    self.buffer.extend_from_slice(&chunk);
+                }
+                Ok(Ok(None)) => {
+                    self.response = None;
+                    self.buffer.clear();
+                }
+                Ok(Err(e)) if e.is_timeout() && self.infinite => {
+                    self.response = None;
+                    self.buffer.clear();
+                }
+                Ok(Err(e)) => {
+                    self.response = None;
+                    self.buffer.clear();
+                    if let Err(e) = self.wait_to_reconnect(e.into(), "error").await {
+                        return Some(Err(e));
+                    }
+                }
+                Err(_) => {
+                    self.response = None;
+                    self.buffer.clear();
+                    let e = CouchError::new(
+                        "no data or heartbeat on the changes feed".to_string(),
+                        StatusCode::GATEWAY_TIMEOUT,
+                    );
+                    if let Err(e) = self.wait_to_reconnect(e, "stalled").await {
+                        return Some(Err(e));
+                    }
+                }
+            }
+        }
+    }
+
+    /// restart drops the current connection, so the next read reconnects and resumes after the last
+    /// change read, with a fresh allowance of reconnection attempts.
+    pub fn restart(&mut self) {
+        self.response = None;
+        self.buffer.clear();
+        self.fetched.clear();
+        self.drained = false;
+        self.failures = 0;
+    }
+
+    /// wait_to_reconnect waits before the feed is reconnected, doubling the delay after each
+    /// consecutive failure.
+    ///
+    /// # Returns
+    /// * The error, if it has happened too many times in a row to keep trying
+    async fn wait_to_reconnect(&mut self, error: CouchError, reason: &str) -> CouchResult<()> {
+        self.failures += 1;
+        if self.failures > self.reconnect_attempts {
+            return Err(error);
+        }
+
+        let delay = self.reconnect_backoff.delay(self.failures);
+
+        warn!(
+            reason,
+            attempt = self.failures,
+            delay_ms = delay.as_millis() as u64,
+            error = error.to_string(),
+            "reconnecting to the changes feed"
+        );
+        FEED_RECONNECTS.with_label_values(&[reason]).inc();
+        tokio::time::sleep(delay).await;
+
+        Ok(())
+    }
+
+    /// request opens a new connection to the `_changes` endpoint, resuming from `last_seq`.
+    async fn request(&self) -> CouchResult<Response> {
+        let mut params = self.params.clone();
+        // CouchDB ignores the timeout once there's a heartbeat, so only an infinite feed gets one
+        if let (Some(heartbeat), true) = (self.heartbeat, self.infinite) {
+            params.insert("heartbeat".to_string(), heartbeat.as_millis().to_string());
+        }
+        if let Some(seq) = &self.last_seq {
+            params.insert("since".to_string(), sequence_string(seq));
+        }
+
+        let path = format!("{}/_changes", self.database);
+        let request = match &self.body {
+            Some(body) => self
+                .connection
+                .req(Method::POST, &path, Some(&params))
+                .json(body),
+            None => self.connection.req(Method::GET, &path, Some(&params)),
+        };
+
+        let response = self.connection.send(request, "changes").await?;
+        let status = response.status();
+        if !status.is_success() {
+            return Err(CouchError::new(
+                response.text().await.unwrap_or_default(),
+                status,
+            ));
+        }
+
+        Ok(response)
+    }
+}
+
+/// is_retryable returns true if reconnecting might get past an error, ie. it's a connection
+/// failure or a server error rather than CouchDB refusing the request.
+fn is_retryable(error: &CouchError) -> bool {
+    match error.status() {
+        Some(status) => {
+            status.is_server_error()
+                || status == StatusCode::REQUEST_TIMEOUT
+                || status == StatusCode::TOO_MANY_REQUESTS
+        }
+        None => false,
+    }
+}
+
+/// Backoff is a delay that doubles with each consecutive attempt, up to a limit.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Backoff {
+    pub initial: Duration,
+    pub max: Duration,
+}
+
+impl Backoff {
+    /// delay returns how long to wait before the given attempt, counting from 1.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        self.initial
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+            .min(self.max)
+    }
+}
+
+/// forward puts a change on the queue, returning false if nothing is reading it any more.
+async fn forward(
+    sender: &mpsc::Sender<CouchResult<ChangeEvent>>,
+    change: Option<CouchResult<ChangeEvent>>,
+) -> bool {
+    let change = match change {
+        Some(change) => change,
+        None => return true,
+    };
+
+    CHANGE_QUEUE_DEPTH.inc();
+    if sender.send(change).await.is_err() {
+        CHANGE_QUEUE_DEPTH.dec();
+        return false;
+    }
+
+    true
+}
+
+/// receive takes the next change off the queue.
+async fn receive(
+    receiver: &mut mpsc::Receiver<CouchResult<ChangeEvent>>,
+) -> Option<CouchResult<ChangeEvent>> {
+    let change = receiver.recv().await;
+    if change.is_some() {
+        CHANGE_QUEUE_DEPTH.dec();
+    }
+    change
+}
+
+/// ChangesQueue reads a ChangesStream in the background into a bounded queue.
+///
+/// The feed is read ahead while earlier changes are being written, until the queue is full; a slow
+/// writer then holds the reader back rather than changes piling up in memory.
+pub struct ChangesQueue {
+    receiver: mpsc::Receiver<CouchResult<ChangeEvent>>,
+    reader: JoinHandle<()>,
+    coalescer: Option<Coalescer>,
+    ended: bool,
+}
+
+impl ChangesQueue {
+    /// spawn starts reading the stream into a queue.
+    ///
+    /// # Arguments
+    /// * `stream` - The ChangesStream to read
+    /// * `capacity` - The most changes to hold in the queue
+    /// * `restart` - How long to wait before restarting the stream after an error it couldn't
+    ///   recover from itself, or None to stop there and pass the error on
+    ///
+    /// # Returns
+    /// * A ChangesQueue struct
+    pub fn spawn(
+        mut stream: ChangesStream,
+        capacity: usize,
+        restart: Option<Backoff>,
+    ) -> ChangesQueue {
+        let (sender, receiver) = mpsc::channel(capacity.max(1));
+
+        let reader = tokio::spawn(async move {
+            let mut restarts = 0;
+
+            loop {
+                let change = stream.next().await;
+                let failure = match (&change, &restart) {
+                    (Some(Ok(_)), _) => None,
+                    (Some(Err(e)), Some(backoff)) => Some((e.to_string(), backoff)),
+                    (None, Some(backoff)) if stream.infinite => {
+                        Some(("changes feed ended".to_string(), backoff))
+                    }
+                    // The stream may not be usable after an error, and the writer stops on one
+                    // anyway
+                    (Some(Err(_)), None) => {
+                        forward(&sender, change).await;
+                        return;
+                    }
+                    (None, _) => return,
+                };
+
+                match failure {
+                    Some((error, backoff)) => {
+                        restarts += 1;
+                        let delay = backoff.delay(restarts);
+                        warn!(
+                            attempt = restarts,
+                            delay_ms = delay.as_millis() as u64,
+                            error = error.as_str(),
+                            "restarting the changes feed"
+                        );
+                        FEED_RECONNECTS.with_label_values(&["restart"]).inc();
+                        tokio::time::sleep(delay).await;
+                        stream.restart();
+                    }
+                    None => {
+                        restarts = 0;
+                        if !forward(&sender, change).await {
+                            return;
+                        }
+                    }
+                }
+            }
+        });
+
+        ChangesQueue {
+            receiver,
+            reader,
+            coalescer: None,
+            ended: false,
+        }
+    }
+
+    /// set_coalesce holds changes taken from the queue for a short window, keeping only the latest
+    /// change to each document.
+    ///
+    /// # Arguments
+    /// * `settings` - A CoalesceSettings struct
+    pub fn set_coalesce(&mut self, settings: &CoalesceSettings) {
+        self.coalescer = Some(Coalescer::new(
+            Duration::from_millis(settings.window_ms),
+            settings.max_changes,
+        ));
+    }
+
+    /// next returns the next change from the queue, or None once the stream has ended.
+    pub async fn next(&mut self) -> Option<CouchResult<ChangeEvent>> {
+        let coalescer = match &mut self.coalescer {
+            Some(coalescer) => coalescer,
+            None => return receive(&mut self.receiver).await,
+        };
+
+        loop {
+            if let Some(change) = coalescer.pop_due(tokio::time::Instant::now()) {
+                return Some(Ok(change));
+            }
+            // Nothing more is coming, so there's nothing left to wait for
+            if self.ended {
+                return coalescer.pop().map(Ok);
+            }
+
+            let change = match coalescer.next_due() {
+                Some(due) => {
+                    match tokio::time::timeout_at(due, receive(&mut self.receiver)).await {
+                        Ok(change) => change,
+                        Err(_) => continue,
+                    }
+                }
+                None => receive(&mut self.receiver).await,
+            };
+
+            match change {
+                Some(Ok(change)) => coalescer.push(change, tokio::time::Instant::now()),
+                Some(Err(e)) => return Some(Err(e)),
+                None => self.ended = true,
+            }
+        }
+    }
+}
+
+impl Drop for ChangesQueue {
+    fn drop(&mut self) {
+        self.reader.abort();
+        self.receiver.close();
+        while self.receiver.try_recv().is_ok() {
+            CHANGE_QUEUE_DEPTH.dec();
+        }
+    }
+}
+
+/// partition_selector returns a Mango selector matching the documents in partitions of a
+/// partitioned database, whose IDs all start with `partition:`.
+///
+/// # Arguments
+/// * `partitions` - The partition names
+///
+/// # Returns
+/// * The selector, or an error if a partition name isn't valid
+pub fn partition_selector(partitions: &[String]) -> Result<Value, String> {
+    for partition in partitions {
+        if partition.is_empty() || partition.starts_with('_') || partition.contains(':') {
+            return Err(format!("{:?} is not a valid partition name", partition));
+        }
+    }
+
+    let names: Vec<String> = partitions.iter().map(|p| regex::escape(p)).collect();
+    Ok(json!({ "_id": { "$regex": format!("^({}):", names.join("|")) } }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::config_parser::ChangesStyle;
+
+    #[test]
+    fn test_partition_selector() {
+        let partitions = vec!["sensor-1".to_string(), "eu.west".to_string()];
+        assert_eq!(
+            partition_selector(&partitions).unwrap(),
+            json!({ "_id": { "$regex": "^(sensor\\-1|eu\\.west):" } })
+        );
+        assert!(partition_selector(&["_design".to_string()]).is_err());
+        assert!(partition_selector(&["a:b".to_string()]).is_err());
+    }
+
+    #[test]
+    fn test_is_retryable() {
+        let error = |status| CouchError::new("failed".to_string(), status);
+
+        assert!(is_retryable(&error(StatusCode::SERVICE_UNAVAILABLE)));
+        assert!(is_retryable(&error(StatusCode::TOO_MANY_REQUESTS)));
+        assert!(!is_retryable(&error(StatusCode::UNAUTHORIZED)));
+        assert!(!is_retryable(&error(StatusCode::NOT_FOUND)));
+    }
+
+    #[test]
+    fn test_backoff() {
+        let backoff = Backoff {
+            initial: Duration::from_millis(500),
+            max: Duration::from_secs(3),
+        };
+
+        assert_eq!(backoff.delay(1), Duration::from_millis(500));
+        assert_eq!(backoff.delay(2), Duration::from_secs(1));
+        assert_eq!(backoff.delay(3), Duration::from_secs(2));
+        assert_eq!(backoff.delay(4), Duration::from_secs(3));
+        assert_eq!(backoff.delay(100), Duration::from_secs(3));
+    }
+
+    #[test]
+    fn test_feed_settings_params() {
+        let client = couch_rs::Client::new_no_auth("http://localhost:5984").unwrap();
+        let connection = CouchConnection::new(client, None, None, None);
+        let mut changes = ChangesStream::new(connection, "animals".to_string(), None);
+        changes.set_feed_settings(&ChangesFeedSettings {
+            seq_interval: Some(100),
+            style: ChangesStyle::AllDocs,
+            limit: Some(500),
+            ..ChangesFeedSettings::default()
+        });
+
+        assert_eq!(changes.params["style"], "all_docs");
+        assert_eq!(changes.params["seq_interval"], "100");
+        assert_eq!(changes.params["limit"], "500");
+    }
+
+    #[test]
+    fn test_has_buffered_change() {
+        let client = couch_rs::Client::new_no_auth("http://localhost:5984").unwrap();
+        let connection = CouchConnection::new(client, None, None, None);
+        let mut changes = ChangesStream::new(connection, "animals".to_string(), None);
+
+        changes.buffer = b"\n\n{\"seq\"".to_vec();
+        assert!(!changes.has_buffered_change());
+
+        changes.buffer = b"{\"seq\":\"1\"}\n{\"seq\"".to_vec();
+        assert!(changes.has_buffered_change());
+    }
+}
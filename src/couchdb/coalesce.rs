@@ -0,0 +1,147 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use couch_rs::types::changes::ChangeEvent;
+use lazy_static::lazy_static;
+use prometheus::{register_int_counter, IntCounter};
+use std::collections::{BTreeMap, HashMap};
+use std::time::Duration;
+use tokio::time::Instant;
+
+lazy_static! {
+    /// Changes dropped because a later change to the same document arrived within the window.
+    pub static ref COALESCED_CHANGES: IntCounter = register_int_counter!(
+        "couch2mongo_coalesced_changes_total",
+        "Changes dropped for a later change to the same document within the coalescing window"
+    )
+    .unwrap();
+}
+
+/// Coalescer holds changes for a short window, keeping only the latest change to each document.
+///
+/// Changes are released in feed order. A document that keeps changing is still released one window
+/// after its first change was held, so a hot document can't be held back forever. Changes that are
+/// dropped always come before the change that replaced them, so a checkpoint never moves past a
+/// change whose replacement hasn't been written.
+pub struct Coalescer {
+    window: Duration,
+    max_changes: usize,
+    // Held changes in feed order, with when the first change to their document was held
+    held: BTreeMap<u64, (Instant, ChangeEvent)>,
+    positions: HashMap<String, u64>,
+    next_position: u64,
+}
+
+impl Coalescer {
+    /// new creates a new Coalescer.
+    ///
+    /// # Arguments
+    /// * `window` - How long to hold a change for
+    /// * `max_changes` - The most changes to hold; the oldest are released early beyond this
+    ///
+    /// # Returns
+    /// * A Coalescer struct
+    pub fn new(window: Duration, max_changes: usize) -> Coalescer {
+        Coalescer {
+            window,
+            max_changes: max_changes.max(1),
+            held: BTreeMap::new(),
+            positions: HashMap::new(),
+            next_position: 0,
+        }
+    }
+
+    /// push holds a change, replacing any held change to the same document.
+    pub fn push(&mut self, change: ChangeEvent, now: Instant) {
+        let mut held_since = now;
+        if let Some(position) = self.positions.remove(&change.id) {
+            if let Some((since, _)) = self.held.remove(&position) {
+                held_since = since;
+                COALESCED_CHANGES.inc();
+            }
+        }
+
+        self.positions.insert(change.id.clone(), self.next_position);
+        self.held.insert(self.next_position, (held_since, change));
+        self.next_position += 1;
+    }
+
+    /// next_due returns when the next change is due to be released, if any are held.
+    pub fn next_due(&self) -> Option<Instant> {
+        if self.held.len() > self.max_changes {
+            return Some(Instant::now());
+        }
+
+        self.held
+            .first_key_value()
+            .map(|(_, (since, _))| *since + self.window)
+    }
+
+    /// pop_due releases the next change if it's due.
+    pub fn pop_due(&mut self, now: Instant) -> Option<ChangeEvent> {
+        if self.held.len() > self.max_changes {
+            return self.pop();
+        }
+
+        match self.next_due() {
+            Some(due) if due <= now => self.pop(),
+            _ => None,
+        }
+    }
+
+    /// pop releases the next change, whether it's due or not.
+    pub fn pop(&mut self) -> Option<ChangeEvent> {
+        let (_, (_, change)) = self.held.pop_first()?;
+        self.positions.remove(&change.id);
+
+        Some(change)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn change(id: &str, seq: &str) -> ChangeEvent {
+        serde_json::from_value(json!({ "seq": seq, "id": id, "changes": [] })).unwrap()
+    }
+
+    #[test]
+    fn test_keeps_latest_change_in_feed_order() {
+        let now = Instant::now();
+        let mut coalescer = Coalescer::new(Duration::from_secs(1), 100);
+        coalescer.push(change("cat:tom", "1"), now);
+        coalescer.push(change("mouse:jerry", "2"), now);
+        coalescer.push(change("cat:tom", "3"), now);
+
+        assert!(coalescer.pop_due(now).is_none());
+
+        let later = now + Duration::from_secs(1);
+        assert_eq!(coalescer.pop_due(later).unwrap().seq, "2");
+        assert_eq!(coalescer.pop_due(later).unwrap().seq, "3");
+        assert!(coalescer.pop_due(later).is_none());
+    }
+
+    #[test]
+    fn test_releases_early_when_full() {
+        let now = Instant::now();
+        let mut coalescer = Coalescer::new(Duration::from_secs(60), 1);
+        coalescer.push(change("cat:tom", "1"), now);
+        coalescer.push(change("mouse:jerry", "2"), now);
+
+        assert_eq!(coalescer.pop_due(Instant::now()).unwrap().seq, "1");
+        assert!(coalescer.pop_due(Instant::now()).is_none());
+    }
+}
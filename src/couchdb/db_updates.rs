@@ -0,0 +1,205 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::couchdb::preflight::validate_checkpoint;
+use crate::couchdb::{pop_line, sequence_string, CouchConnection};
+use crate::seqstore::interface::SequenceStore;
+use reqwest::{Method, StatusCode};
+use serde_derive::Deserialize;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{debug, error, info, warn};
+
+const RECONNECT_DELAY: Duration = Duration::from_secs(10);
+
+/// DbUpdate is a single event from the `_db_updates` feed.
+#[derive(Debug, Deserialize)]
+struct DbUpdate {
+    db_name: String,
+    #[serde(rename = "type")]
+    kind: String,
+    seq: Option<serde_json::Value>,
+}
+
+/// DbUpdatesWatcher follows the server-wide `_db_updates` feed and re-validates the checkpoint as
+/// soon as the source database is deleted or recreated, rather than leaving it to the next restart.
+///
+/// `_db_updates` needs admin rights and isn't offered by every CouchDB-compatible server; if it's
+/// unavailable the watcher logs a warning and gives up, and the startup preflight is all we get.
+pub struct DbUpdatesWatcher {
+    connection: CouchConnection,
+    database: String,
+    store: Arc<dyn SequenceStore>,
+    sequence_key: String,
+    invalid: AtomicBool,
+}
+
+impl DbUpdatesWatcher {
+    /// new creates a new DbUpdatesWatcher.
+    ///
+    /// # Arguments
+    /// * `connection` - The CouchDB connection
+    /// * `database` - The source database name
+    /// * `store` - The shared sequence store
+    /// * `sequence_key` - The checkpoint key
+    ///
+    /// # Returns
+    /// * A shared DbUpdatesWatcher
+    pub fn new(
+        connection: CouchConnection,
+        database: &str,
+        store: Arc<dyn SequenceStore>,
+        sequence_key: &str,
+    ) -> Arc<DbUpdatesWatcher> {
+        Arc::new(DbUpdatesWatcher {
+            connection,
+            database: database.to_string(),
+            store,
+            sequence_key: sequence_key.to_string(),
+            invalid: AtomicBool::new(false),
+        })
+    }
+
+    /// is_invalid returns true once the checkpoint has been found to be invalid.
+    pub fn is_invalid(&self) -> bool {
+        self.invalid.load(Ordering::SeqCst)
+    }
+
+    /// start follows the feed in the background.
+    pub fn start(self: &Arc<Self>) {
+        let watcher = self.clone();
+        tokio::spawn(async move { watcher.watch().await });
+    }
+
+    async fn watch(&self) {
+        let mut since = "now".to_string();
+
+        loop {
+            match self.follow(&mut since).await {
+                Ok(true) => return,
+                Ok(false) => debug!("_db_updates feed closed, reconnecting"),
+                Err(e) => warn!(error = e, "_db_updates feed failed, reconnecting"),
+            }
+
+            tokio::time::sleep(RECONNECT_DELAY).await;
+        }
+    }
+
+    /// follow reads the feed until it ends, returning true if watching should stop for good.
+    async fn follow(&self, since: &mut String) -> Result<bool, String> {
+        let mut params = HashMap::new();
+        params.insert("feed".to_string(), "continuous".to_string());
+        params.insert("heartbeat".to_string(), "30000".to_string());
+        params.insert("since".to_string(), since.clone());
+
+        let request = self
+            .connection
+            .req(Method::GET, "_db_updates", Some(&params));
+        let mut response = self
+            .connection
+            .send(request, "db_updates")
+            .await
+            .map_err(|e| e.to_string())?;
+
+        match response.status() {
+            s if s.is_success() => {}
+            StatusCode::UNAUTHORIZED
+            | StatusCode::FORBIDDEN
+            | StatusCode::NOT_FOUND
+            | StatusCode::BAD_REQUEST => {
+                warn!(
+                    status = response.status().as_u16(),
+                    "_db_updates is not available, not watching for database recreation"
+                );
+                return Ok(true);
+            }
+            s => return Err(format!("unexpected status {}", s)),
+        }
+
+        info!(
+            database = self.database.as_str(),
+            "watching _db_updates for database recreation"
+        );
+
+        let mut buffer = Vec::new();
+        while let Some(chunk) = response.chunk().await.map_err(|e| e.to_string())? {
+            buffer.extend_from_slice(&chunk);
+
+            while let Some(line) = pop_line(&mut buffer) {
+                let update: DbUpdate = match serde_json::from_str(line.trim()) {
+                    Ok(update) => update,
+                    // Heartbeats are blank lines
+                    Err(_) => continue,
+                };
+
+                if let Some(seq) = &update.seq {
+                    *since = sequence_string(seq);
+                }
+
+                if update.db_name == self.database
+                    && (update.kind == "created" || update.kind == "deleted")
+                {
+                    info!(
+                        database = update.db_name.as_str(),
+                        kind = update.kind.as_str(),
+                        "source database changed, checking checkpoint"
+                    );
+
+                    if !self.check().await {
+                        return Ok(true);
+                    }
+                }
+            }
+        }
+
+        Ok(false)
+    }
+
+    /// check re-validates the stored checkpoint, returning false if it's no longer valid.
+    async fn check(&self) -> bool {
+        let sequence = match self
+            .store
+            .get(&self.sequence_key)
+            .await
+            .map_err(|e| e.to_string())
+        {
+            Ok(Some(sequence)) => sequence,
+            Ok(None) => return true,
+            Err(e) => {
+                warn!(error = e, "unable to read checkpoint");
+                return true;
+            }
+        };
+
+        match validate_checkpoint(&self.connection, &self.database, &sequence).await {
+            Ok(status) if status.is_valid() => true,
+            Ok(status) => {
+                error!(
+                    database = self.database.as_str(),
+                    sequence = sequence.as_str(),
+                    status = status.to_string(),
+                    "checkpoint is no longer valid"
+                );
+                self.invalid.store(true, Ordering::SeqCst);
+                false
+            }
+            Err(e) => {
+                warn!(error = e.to_string(), "unable to validate checkpoint");
+                true
+            }
+        }
+    }
+}
@@ -0,0 +1,303 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+pub mod auth;
+pub mod bulk_get;
+pub mod changes;
+pub mod coalesce;
+pub mod db_updates;
+pub mod preflight;
+pub mod session;
+
+use crate::couchdb::auth::TokenProvider;
+use crate::couchdb::changes::Backoff;
+use crate::couchdb::session::SessionProvider;
+use crate::settings::config_parser::CouchThrottleSettings;
+use crate::vault::{Credentials, VaultCredentials};
+use couch_rs::error::{CouchError, CouchResult};
+use couch_rs::Client;
+use lazy_static::lazy_static;
+use prometheus::{register_int_counter_vec, IntCounterVec};
+use reqwest::header::{HeaderMap, COOKIE, RETRY_AFTER};
+use reqwest::{Method, RequestBuilder, Response, StatusCode};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::warn;
+
+lazy_static! {
+    /// Requests CouchDB rejected with 429 Too Many Requests, by endpoint.
+    pub static ref COUCHDB_THROTTLED: IntCounterVec = register_int_counter_vec!(
+        "couch2mongo_couchdb_throttled_total",
+        "Requests CouchDB rejected with 429 Too Many Requests",
+        &["endpoint"]
+    )
+    .unwrap();
+}
+
+/// CouchConnection is a couch_rs client plus the authentication details that couch_rs can't
+/// manage itself, such as refreshed bearer tokens, session cookies, rotating Vault credentials and
+/// custom TLS.
+///
+/// Anything talking to CouchDB outside of couch_rs' own helpers should build its requests through
+/// here so they're authenticated consistently.
+#[derive(Clone)]
+pub struct CouchConnection {
+    pub client: Client,
+    pub token_provider: Option<Arc<TokenProvider>>,
+    pub session_provider: Option<Arc<SessionProvider>>,
+    pub credentials: Option<Arc<VaultCredentials>>,
+    http_client: Option<reqwest::Client>,
+    basic_auth: Option<Credentials>,
+    throttle: CouchThrottleSettings,
+}
+
+impl CouchConnection {
+    /// new creates a new CouchConnection.
+    ///
+    /// # Arguments
+    /// * `client` - A couch_rs Client
+    /// * `token_provider` - Optional bearer token provider
+    /// * `session_provider` - Optional CouchDB session, used instead of basic authentication
+    /// * `credentials` - Optional credentials from Vault, used for basic authentication
+    ///
+    /// # Returns
+    /// * A CouchConnection struct
+    pub fn new(
+        client: Client,
+        token_provider: Option<Arc<TokenProvider>>,
+        session_provider: Option<Arc<SessionProvider>>,
+        credentials: Option<Arc<VaultCredentials>>,
+    ) -> CouchConnection {
+        CouchConnection {
+            client,
+            token_provider,
+            session_provider,
+            credentials,
+            http_client: None,
+            basic_auth: None,
+            throttle: CouchThrottleSettings::default(),
+        }
+    }
+
+    /// set_throttle sets how requests CouchDB rejects with 429 Too Many Requests are retried.
+    pub fn set_throttle(&mut self, throttle: CouchThrottleSettings) {
+        self.throttle = throttle;
+    }
+
+    /// set_http_client sends requests through an HTTP client of our own, eg. one with custom TLS
+    /// or a proxy, instead of couch_rs' client.
+    ///
+    /// # Arguments
+    /// * `http_client` - The HTTP client to send requests with
+    /// * `basic_auth` - Credentials for basic authentication, replacing the couch_rs client's
+    pub fn set_http_client(
+        &mut self,
+        http_client: reqwest::Client,
+        basic_auth: Option<Credentials>,
+    ) {
+        self.http_client = Some(http_client);
+        self.basic_auth = basic_auth;
+    }
+
+    /// req builds an authenticated request for a path on the server.
+    pub fn req(
+        &self,
+        method: Method,
+        path: &str,
+        params: Option<&HashMap<String, String>>,
+    ) -> RequestBuilder {
+        let mut request = self.client.req(method.clone(), path, params);
+
+        // couch_rs can't be given a client, so move its request onto ours
+        if let Some(http_client) = &self.http_client {
+            request = match request.build() {
+                Ok(built) => http_client
+                    .request(method, built.url().clone())
+                    .headers(built.headers().clone()),
+                // Fails again, with the error, when sent
+                Err(_) => http_client.request(method, path),
+            };
+
+            if let Some(credentials) = &self.basic_auth {
+                request = request.basic_auth(&credentials.username, Some(&credentials.password));
+            }
+        }
+
+        // Read on every request, so rotated credentials and renewed sessions are picked up
+        // straight away
+        if let Some(session_provider) = &self.session_provider {
+            if let Some(cookie) = session_provider.cookie() {
+                request = request.header(COOKIE, cookie);
+            }
+        } else if let Some(credentials) = &self.credentials {
+            let credentials = credentials.credentials();
+            request = request.basic_auth(credentials.username, Some(credentials.password));
+        }
+
+        match self.token_provider.as_ref().and_then(|p| p.token()) {
+            Some(token) => request.bearer_auth(token),
+            None => request,
+        }
+    }
+
+    /// send sends a request, waiting and retrying while CouchDB rejects it with 429 Too Many
+    /// Requests. It waits as long as Retry-After asks, or backs off exponentially without it.
+    ///
+    /// # Arguments
+    /// * `request` - The request, from `req`
+    /// * `endpoint` - What's being requested, for logs and metrics, eg. `bulk_get`
+    ///
+    /// # Returns
+    /// * The response, which is still the 429 once the retries run out
+    pub async fn send(&self, request: RequestBuilder, endpoint: &str) -> reqwest::Result<Response> {
+        let backoff = Backoff {
+            initial: Duration::from_millis(self.throttle.initial_delay_ms),
+            max: Duration::from_millis(self.throttle.max_delay_ms),
+        };
+        let mut request = request;
+        let mut attempt = 0;
+
+        loop {
+            // A streamed body can't be sent again, so that request isn't retried
+            let retry = request.try_clone();
+            let response = request.send().await?;
+            if response.status() != StatusCode::TOO_MANY_REQUESTS {
+                return Ok(response);
+            }
+
+            COUCHDB_THROTTLED.with_label_values(&[endpoint]).inc();
+            attempt += 1;
+            request = match retry {
+                Some(retry) if attempt <= self.throttle.retries => retry,
+                _ => return Ok(response),
+            };
+
+            let delay = retry_after(response.headers())
+                .unwrap_or_else(|| backoff.delay(attempt))
+                .min(backoff.max);
+            warn!(
+                endpoint,
+                attempt,
+                delay_ms = delay.as_millis() as u64,
+                "throttled by CouchDB, backing off"
+            );
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    /// update_seq returns the current update sequence of a database.
+    pub async fn update_seq(&self, database: &str) -> CouchResult<String> {
+        let response = self
+            .send(self.req(Method::GET, database, None), "database")
+            .await?;
+        let status = response.status();
+        if !status.is_success() {
+            return Err(CouchError::new(
+                response.text().await.unwrap_or_default(),
+                status,
+            ));
+        }
+
+        let info: Value = response.json().await?;
+        Ok(info
+            .get("update_seq")
+            .map(sequence_string)
+            .unwrap_or_default())
+    }
+}
+
+/// retry_after returns how long a Retry-After header asks to wait, if it gives a number of
+/// seconds.
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let seconds = headers
+        .get(RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse()
+        .ok()?;
+    Some(Duration::from_secs(seconds))
+}
+
+/// pop_line removes the next complete line from a buffer of streamed bytes.
+pub fn pop_line(buffer: &mut Vec<u8>) -> Option<String> {
+    let position = buffer.iter().position(|b| *b == b'\n')?;
+    let line: Vec<u8> = buffer.drain(..=position).collect();
+
+    Some(String::from_utf8_lossy(&line).to_string())
+}
+
+/// sequence_string returns a sequence as a string. CouchDB 2 and later send opaque strings, while
+/// CouchDB 1.x and PouchDB Server send numbers; either is stored and resumed from as a string.
+pub fn sequence_string(seq: &Value) -> String {
+    match seq {
+        Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+/// sequence_number returns the numeric part of a sequence, eg. `12` for `12-g1AAAA...`.
+pub fn sequence_number(sequence: &str) -> Option<u64> {
+    sequence.split('-').next()?.trim_matches('"').parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_sequence_number() {
+        assert_eq!(sequence_number("12-g1AAAAEzeJzLYWBg"), Some(12));
+        assert_eq!(sequence_number("7"), Some(7));
+        assert_eq!(sequence_number("\"3\""), Some(3));
+        assert_eq!(sequence_number("now"), None);
+    }
+
+    #[test]
+    fn test_retry_after() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(retry_after(&headers), None);
+
+        headers.insert(RETRY_AFTER, "5".parse().unwrap());
+        assert_eq!(retry_after(&headers), Some(Duration::from_secs(5)));
+
+        headers.insert(
+            RETRY_AFTER,
+            "Wed, 21 Oct 2015 07:28:00 GMT".parse().unwrap(),
+        );
+        assert_eq!(retry_after(&headers), None);
+    }
+
+    #[test]
+    fn test_sequence_string() {
+        assert_eq!(sequence_string(&json!("12-g1AAAA")), "12-g1AAAA");
+        assert_eq!(sequence_string(&json!(12)), "12");
+        assert_eq!(sequence_number(&sequence_string(&json!(12))), Some(12));
+    }
+
+    #[test]
+    fn test_pop_line() {
+        let mut buffer = b"{\"a\":1}\n\n{\"b\"".to_vec();
+
+        assert_eq!(pop_line(&mut buffer), Some("{\"a\":1}\n".to_string()));
+        assert_eq!(pop_line(&mut buffer), Some("\n".to_string()));
+        assert_eq!(pop_line(&mut buffer), None);
+
+        buffer.extend_from_slice(b":2}\n");
+        assert_eq!(pop_line(&mut buffer), Some("{\"b\":2}\n".to_string()));
+    }
+}
@@ -0,0 +1,146 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::couchdb::{sequence_number, sequence_string, CouchConnection};
+use couch_rs::error::{CouchError, CouchResult};
+use lazy_static::lazy_static;
+use prometheus::{register_int_gauge, IntGauge};
+use reqwest::{Method, StatusCode};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fmt;
+
+lazy_static! {
+    /// Whether the stored checkpoint was valid for the source database at the last check.
+    pub static ref CHECKPOINT_VALID: IntGauge = register_int_gauge!(
+        "couch2mongo_checkpoint_valid",
+        "Whether the stored checkpoint was valid for the source database at the last check"
+    )
+    .unwrap();
+}
+
+/// CheckpointStatus is the outcome of validating a checkpoint against the source database.
+#[derive(Debug, PartialEq)]
+pub enum CheckpointStatus {
+    /// The checkpoint can be resumed from.
+    Valid,
+    /// The source database doesn't exist.
+    DatabaseMissing,
+    /// The database exists, but doesn't recognise the checkpoint. The reason says why.
+    InvalidSequence(String),
+}
+
+impl CheckpointStatus {
+    /// is_valid returns true if the checkpoint can be resumed from.
+    pub fn is_valid(&self) -> bool {
+        *self == CheckpointStatus::Valid
+    }
+}
+
+impl fmt::Display for CheckpointStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CheckpointStatus::Valid => write!(f, "checkpoint is valid"),
+            CheckpointStatus::DatabaseMissing => write!(f, "source database does not exist"),
+            CheckpointStatus::InvalidSequence(reason) => {
+                write!(f, "checkpoint is not valid: {}", reason)
+            }
+        }
+    }
+}
+
+/// validate_checkpoint checks that a stored checkpoint still belongs to the source database.
+///
+/// A database that's been deleted and recreated (or restored from a backup) starts its sequences
+/// again, and CouchDB quietly replays from the beginning when given a sequence it doesn't know,
+/// rather than refusing it. We catch that by checking that the database exists, that it accepts
+/// the sequence, and that its current update sequence hasn't gone backwards past the checkpoint.
+///
+/// # Arguments
+/// * `connection` - The CouchDB connection
+/// * `database` - The source database name
+/// * `sequence` - The stored checkpoint
+///
+/// # Returns
+/// * The CheckpointStatus, or an error if CouchDB couldn't be asked
+pub async fn validate_checkpoint(
+    connection: &CouchConnection,
+    database: &str,
+    sequence: &str,
+) -> CouchResult<CheckpointStatus> {
+    let status = check(connection, database, sequence).await?;
+    CHECKPOINT_VALID.set(status.is_valid() as i64);
+
+    Ok(status)
+}
+
+async fn check(
+    connection: &CouchConnection,
+    database: &str,
+    sequence: &str,
+) -> CouchResult<CheckpointStatus> {
+    let response = connection
+        .send(connection.req(Method::GET, database, None), "database")
+        .await?;
+
+    if response.status() == StatusCode::NOT_FOUND {
+        return Ok(CheckpointStatus::DatabaseMissing);
+    }
+    let info: Value = error_for_status(response).await?.json().await?;
+
+    let mut params = HashMap::new();
+    params.insert("since".to_string(), sequence.to_string());
+    params.insert("limit".to_string(), "1".to_string());
+
+    let request = connection.req(
+        Method::GET,
+        &format!("{}/_changes", database),
+        Some(&params),
+    );
+    let response = connection.send(request, "changes").await?;
+
+    if response.status() == StatusCode::BAD_REQUEST {
+        return Ok(CheckpointStatus::InvalidSequence(
+            response.text().await.unwrap_or_default(),
+        ));
+    }
+    error_for_status(response).await?;
+
+    let update_seq = match info.get("update_seq") {
+        Some(update_seq) => sequence_string(update_seq),
+        None => return Ok(CheckpointStatus::Valid),
+    };
+
+    match (sequence_number(sequence), sequence_number(&update_seq)) {
+        (Some(checkpoint), Some(current)) if checkpoint > current => {
+            Ok(CheckpointStatus::InvalidSequence(format!(
+                "checkpoint {} is ahead of the database update sequence {}",
+                checkpoint, current
+            )))
+        }
+        _ => Ok(CheckpointStatus::Valid),
+    }
+}
+
+async fn error_for_status(response: reqwest::Response) -> CouchResult<reqwest::Response> {
+    let status = response.status();
+    if !status.is_success() {
+        return Err(CouchError::new(
+            response.text().await.unwrap_or_default(),
+            status,
+        ));
+    }
+
+    Ok(response)
+}
@@ -0,0 +1,209 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::couchdb::CouchConnection;
+use crate::settings::config_parser::SessionSettings;
+use crate::vault::{Credentials, VaultCredentials};
+use reqwest::header::SET_COOKIE;
+use reqwest::Method;
+use std::error::Error;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// SESSION_COOKIE is the name of CouchDB's session cookie.
+const SESSION_COOKIE: &str = "AuthSession";
+
+/// SessionProvider keeps a CouchDB `_session` cookie current, for servers that can only be reached
+/// through something that strips basic auth headers.
+///
+/// It logs in with the configured username and password (or the current credentials from Vault),
+/// and logs in again in the background before the session times out.
+pub struct SessionProvider {
+    connection: CouchConnection,
+    settings: SessionSettings,
+    credentials: Option<Credentials>,
+    vault_credentials: Option<Arc<VaultCredentials>>,
+    cookie: RwLock<Option<String>>,
+}
+
+impl std::fmt::Debug for SessionProvider {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Never print the cookie itself
+        f.debug_struct("SessionProvider")
+            .field("settings", &self.settings)
+            .finish_non_exhaustive()
+    }
+}
+
+impl SessionProvider {
+    /// new creates a new SessionProvider, logs in and starts the background renewal.
+    ///
+    /// # Arguments
+    /// * `connection` - A CouchConnection, without authentication
+    /// * `settings` - A SessionSettings struct
+    /// * `credentials` - The username and password to log in with
+    /// * `vault_credentials` - Credentials from Vault, used instead of `credentials` if set
+    ///
+    /// # Returns
+    /// * A shared SessionProvider
+    pub async fn new(
+        connection: CouchConnection,
+        settings: &SessionSettings,
+        credentials: Option<Credentials>,
+        vault_credentials: Option<Arc<VaultCredentials>>,
+    ) -> Result<Arc<SessionProvider>, Box<dyn Error>> {
+        if credentials.is_none() && vault_credentials.is_none() {
+            return Err("couchdb_session requires a couchdb_username and couchdb_password".into());
+        }
+
+        let provider = Arc::new(SessionProvider {
+            connection,
+            settings: settings.clone(),
+            credentials,
+            vault_credentials,
+            cookie: RwLock::new(None),
+        });
+
+        let mut max_age = provider.renew().await.map_err(|e| e as Box<dyn Error>)?;
+
+        let background = provider.clone();
+        tokio::spawn(async move {
+            let period = Duration::from_secs(background.settings.renew_interval);
+
+            loop {
+                tokio::time::sleep(renew_delay(period, max_age)).await;
+
+                match background.renew().await {
+                    Ok(next_max_age) => max_age = next_max_age,
+                    Err(e) => {
+                        warn!(error = e.to_string(), "unable to renew CouchDB session");
+                        max_age = None;
+                    }
+                }
+            }
+        });
+
+        Ok(provider)
+    }
+
+    /// cookie returns the current session cookie, ready for a `Cookie` header.
+    pub fn cookie(&self) -> Option<String> {
+        self.cookie
+            .read()
+            .expect("unable to read session cookie")
+            .as_ref()
+            .map(|value| format!("{}={}", SESSION_COOKIE, value))
+    }
+
+    /// renew logs in to `_session` and stores the new cookie, returning how long it's valid for if
+    /// the server says.
+    async fn renew(&self) -> Result<Option<Duration>, Box<dyn Error + Send + Sync>> {
+        // Read on every login, so rotated credentials are picked up
+        let credentials = match (&self.vault_credentials, &self.credentials) {
+            (Some(vault_credentials), _) => vault_credentials.credentials(),
+            (None, Some(credentials)) => credentials.clone(),
+            (None, None) => return Err("no session credentials configured".into()),
+        };
+
+        let request =
+            self.connection
+                .req(Method::POST, "_session", None)
+                .json(&serde_json::json!({
+                    "name": credentials.username,
+                    "password": credentials.password,
+                }));
+        let response = self
+            .connection
+            .send(request, "session")
+            .await?
+            .error_for_status()?;
+
+        let (cookie, max_age) = response
+            .headers()
+            .get_all(SET_COOKIE)
+            .iter()
+            .filter_map(|header| header.to_str().ok())
+            .find_map(parse_session_cookie)
+            .ok_or("_session response didn't set a session cookie")?;
+
+        info!("renewed CouchDB session");
+        self.cookie
+            .write()
+            .expect("unable to write session cookie")
+            .replace(cookie);
+
+        Ok(max_age)
+    }
+}
+
+/// renew_delay returns how long to wait before the next login: halfway through the session if the
+/// cookie says how long it lasts, but never longer than the configured renewal interval.
+fn renew_delay(period: Duration, max_age: Option<Duration>) -> Duration {
+    match max_age {
+        Some(max_age) => period.min(max_age / 2),
+        None => period,
+    }
+}
+
+/// parse_session_cookie extracts the session cookie's value, and its Max-Age if set, from a
+/// `Set-Cookie` header.
+fn parse_session_cookie(header: &str) -> Option<(String, Option<Duration>)> {
+    let mut parts = header.split(';').map(str::trim);
+    let value = parts
+        .next()?
+        .strip_prefix(SESSION_COOKIE)?
+        .strip_prefix('=')?;
+    if value.is_empty() {
+        return None;
+    }
+
+    let max_age = parts
+        .filter_map(|part| part.split_once('='))
+        .find(|(name, _)| name.eq_ignore_ascii_case("Max-Age"))
+        .and_then(|(_, seconds)| seconds.parse().ok())
+        .map(Duration::from_secs);
+
+    Some((value.to_string(), max_age))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_session_cookie() {
+        assert_eq!(
+            parse_session_cookie("AuthSession=YWRtaW46NjVD; Version=1; Path=/; HttpOnly"),
+            Some(("YWRtaW46NjVD".to_string(), None))
+        );
+        assert_eq!(
+            parse_session_cookie("AuthSession=YWRtaW46NjVD; Max-Age=600; Path=/"),
+            Some(("YWRtaW46NjVD".to_string(), Some(Duration::from_secs(600))))
+        );
+        assert_eq!(parse_session_cookie("other=1; Path=/"), None);
+        assert_eq!(parse_session_cookie("AuthSession=; Path=/"), None);
+    }
+
+    #[test]
+    fn test_renew_delay() {
+        let period = Duration::from_secs(300);
+        assert_eq!(renew_delay(period, None), period);
+        assert_eq!(renew_delay(period, Some(Duration::from_secs(3600))), period);
+        assert_eq!(
+            renew_delay(period, Some(Duration::from_secs(120))),
+            Duration::from_secs(60)
+        );
+    }
+}
@@ -0,0 +1,88 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use bson::{doc, DateTime, Document};
+use lazy_static::lazy_static;
+use mongodb::Database;
+use prometheus::{register_int_counter_vec, IntCounterVec};
+use std::error::Error;
+use tracing::warn;
+
+lazy_static! {
+    /// Documents set aside in the dead letter collection, by why they were rejected.
+    pub static ref DEAD_LETTERS: IntCounterVec = register_int_counter_vec!(
+        "couch2mongo_dead_letters_total",
+        "Documents set aside in the dead letter collection",
+        &["kind"]
+    )
+    .unwrap();
+}
+
+/// DeadLetterQueue is a MongoDB collection holding documents we couldn't write, along with why,
+/// so they can be inspected and replayed by hand rather than stopping the stream.
+pub struct DeadLetterQueue {
+    collection: mongodb::Collection<Document>,
+}
+
+impl DeadLetterQueue {
+    /// new creates a new DeadLetterQueue.
+    ///
+    /// # Arguments
+    /// * `db` - The MongoDB database
+    /// * `collection` - The dead letter collection name
+    ///
+    /// # Returns
+    /// * A DeadLetterQueue struct
+    pub fn new(db: &Database, collection: &str) -> DeadLetterQueue {
+        DeadLetterQueue {
+            collection: db.collection(collection),
+        }
+    }
+
+    /// send records a rejected document.
+    ///
+    /// # Arguments
+    /// * `id` - The CouchDB document ID
+    /// * `seq` - The sequence of the change
+    /// * `kind` - A short, fixed name for the check that rejected it, used in metrics
+    /// * `reason` - A description of the problem
+    /// * `document` - The document as it would have been written
+    pub async fn send(
+        &self,
+        id: &str,
+        seq: &str,
+        kind: &str,
+        reason: &str,
+        document: &Document,
+    ) -> Result<(), Box<dyn Error>> {
+        warn!(id, seq, kind, reason, "dead lettering document");
+
+        self.collection
+            .insert_one(
+                doc! {
+                    "source_id": id,
+                    "seq": seq,
+                    "kind": kind,
+                    "reason": reason,
+                    "document": document.clone(),
+                    "created_at": DateTime::now(),
+                },
+                None,
+            )
+            .await?;
+        DEAD_LETTERS.with_label_values(&[kind]).inc();
+
+        Ok(())
+    }
+}
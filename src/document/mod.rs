@@ -0,0 +1,179 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+pub mod revision;
+pub mod shard_key;
+
+use bson::{Bson, Document};
+
+/// get_path returns the value at a dotted path, eg. `meta.type`, inside a document.
+///
+/// A top-level key that itself contains dots takes precedence over walking the path, since
+/// CouchDB allows such keys.
+///
+/// # Arguments
+/// * `document` - The document to search
+/// * `path` - The dotted path
+///
+/// # Returns
+/// * The value, if every segment of the path exists
+pub fn get_path<'a>(document: &'a Document, path: &str) -> Option<&'a Bson> {
+    if let Some(value) = document.get(path) {
+        return Some(value);
+    }
+
+    let mut segments = path.split('.');
+    let mut current = document.get(segments.next()?)?;
+
+    for segment in segments {
+        current = match current {
+            Bson::Document(d) => d.get(segment)?,
+            _ => return None,
+        };
+    }
+
+    Some(current)
+}
+
+/// get_path_mut returns a mutable reference to the value at a dotted path inside a document.
+///
+/// # Arguments
+/// * `document` - The document to search
+/// * `path` - The dotted path
+///
+/// # Returns
+/// * The value, if every segment of the path exists
+pub fn get_path_mut<'a>(document: &'a mut Document, path: &str) -> Option<&'a mut Bson> {
+    if document.contains_key(path) {
+        return document.get_mut(path);
+    }
+
+    let mut segments = path.split('.');
+    let mut current = document.get_mut(segments.next()?)?;
+
+    for segment in segments {
+        current = match current {
+            Bson::Document(d) => d.get_mut(segment)?,
+            _ => return None,
+        };
+    }
+
+    Some(current)
+}
+
+/// insert_path sets the value at a dotted path inside a document, creating sub-documents along
+/// the way. Nothing is inserted if a segment of the path is taken by something other than a
+/// sub-document.
+///
+/// # Arguments
+/// * `document` - The document to change
+/// * `path` - The dotted path
+/// * `value` - The value to set
+pub fn insert_path(document: &mut Document, path: &str, value: Bson) {
+    let (parent, field) = match path.rsplit_once('.') {
+        Some(split) => split,
+        None => {
+            document.insert(path, value);
+            return;
+        }
+    };
+
+    let mut current = document;
+    for segment in parent.split('.') {
+        let entry = current
+            .entry(segment.to_string())
+            .or_insert_with(|| Bson::Document(Document::new()));
+        current = match entry {
+            Bson::Document(d) => d,
+            _ => return,
+        };
+    }
+    current.insert(field, value);
+}
+
+/// remove_path removes the value at a dotted path inside a document. As with get_path, a
+/// top-level key containing dots takes precedence.
+///
+/// # Arguments
+/// * `document` - The document to change
+/// * `path` - The dotted path
+///
+/// # Returns
+/// * The removed value, if there was one
+pub fn remove_path(document: &mut Document, path: &str) -> Option<Bson> {
+    if let Some(value) = document.remove(path) {
+        return Some(value);
+    }
+
+    let (parent, field) = path.rsplit_once('.')?;
+    match get_path_mut(document, parent) {
+        Some(Bson::Document(d)) => d.remove(field),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bson::doc;
+
+    #[test]
+    fn test_get_path() {
+        let d = doc! { "meta": { "type": "cat", "tags": { "colour": "grey" } }, "a.b": 1 };
+
+        assert_eq!(
+            get_path(&d, "meta.type"),
+            Some(&Bson::String("cat".to_string()))
+        );
+        assert_eq!(
+            get_path(&d, "meta.tags.colour"),
+            Some(&Bson::String("grey".to_string()))
+        );
+        assert_eq!(get_path(&d, "a.b"), Some(&Bson::Int32(1)));
+        assert_eq!(get_path(&d, "meta.missing"), None);
+        assert_eq!(get_path(&d, "meta.type.deeper"), None);
+    }
+
+    #[test]
+    fn test_get_path_mut() {
+        let mut d = doc! { "meta": { "type": "cat" } };
+
+        *get_path_mut(&mut d, "meta.type").unwrap() = Bson::String("dog".to_string());
+        assert_eq!(d, doc! { "meta": { "type": "dog" } });
+        assert!(get_path_mut(&mut d, "meta.missing").is_none());
+    }
+
+    #[test]
+    fn test_insert_and_remove_path() {
+        let mut d = doc! { "meta": { "type": "cat" }, "name": "tom" };
+
+        insert_path(&mut d, "meta.tags.colour", Bson::String("grey".to_string()));
+        insert_path(&mut d, "name.first", Bson::String("Tom".to_string()));
+        assert_eq!(
+            d,
+            doc! { "meta": { "type": "cat", "tags": { "colour": "grey" } }, "name": "tom" }
+        );
+
+        assert_eq!(
+            remove_path(&mut d, "meta.type"),
+            Some(Bson::String("cat".to_string()))
+        );
+        assert_eq!(remove_path(&mut d, "meta.missing"), None);
+        assert_eq!(remove_path(&mut d, "name.first"), None);
+        assert_eq!(
+            d,
+            doc! { "meta": { "tags": { "colour": "grey" } }, "name": "tom" }
+        );
+    }
+}
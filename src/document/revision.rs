@@ -0,0 +1,103 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use bson::{doc, Bson, Document};
+
+/// generation returns the generation of a CouchDB revision, the number before the dash in
+/// `3-917fa2381192822767f010b95b45325b`.
+pub fn generation(rev: &str) -> Option<i64> {
+    rev.split_once('-')?.0.parse().ok()
+}
+
+/// stored_generation is an aggregation expression for the generation of the revision stored in
+/// MongoDB. Documents written without a `_rev` are generation 0.
+fn stored_generation() -> Document {
+    doc! {
+        "$convert": {
+            "input": {
+                "$arrayElemAt": [
+                    { "$split": [{ "$ifNull": ["$_rev", "0-"] }, "-"] },
+                    0,
+                ]
+            },
+            "to": "long",
+            "onError": 0_i64,
+            "onNull": 0_i64,
+        }
+    }
+}
+
+/// replace_filter returns a filter matching a document only when the revision stored in MongoDB
+/// is no newer than the incoming one.
+///
+/// Used with an upsert, an existing document with a newer revision doesn't match, so the insert
+/// fails with a duplicate key error and nothing is written.
+///
+/// # Arguments
+/// * `id` - The `_id` of the document
+/// * `rev` - The incoming revision
+///
+/// # Returns
+/// * The filter, which only matches on `_id` if the incoming revision can't be parsed
+pub fn replace_filter(id: &Bson, rev: &str) -> Document {
+    match generation(rev) {
+        Some(generation) => doc! {
+            "_id": id,
+            "$expr": { "$lte": [stored_generation(), generation] },
+        },
+        None => doc! { "_id": id },
+    }
+}
+
+/// newer_filter returns a filter matching a document only when the revision stored in MongoDB is
+/// newer than the incoming one, ie. the incoming revision is stale.
+///
+/// # Arguments
+/// * `id` - The `_id` of the document
+/// * `rev` - The incoming revision
+///
+/// # Returns
+/// * The filter, or None if the incoming revision can't be parsed
+pub fn newer_filter(id: &Bson, rev: &str) -> Option<Document> {
+    Some(doc! {
+        "_id": id,
+        "$expr": { "$gt": [stored_generation(), generation(rev)?] },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generation() {
+        assert_eq!(generation("3-917fa2381192822767f010b95b45325b"), Some(3));
+        assert_eq!(generation("12-abc"), Some(12));
+        assert_eq!(generation("abc"), None);
+        assert_eq!(generation("x-abc"), None);
+    }
+
+    #[test]
+    fn test_replace_filter() {
+        let id = Bson::String("cat:tom".to_string());
+
+        let filter = replace_filter(&id, "10-abc");
+        assert_eq!(filter.get_str("_id").unwrap(), "cat:tom");
+        let comparison = filter.get_document("$expr").unwrap().get_array("$lte");
+        assert_eq!(comparison.unwrap()[1], Bson::Int64(10));
+
+        assert_eq!(replace_filter(&id, ""), doc! { "_id": "cat:tom" });
+        assert!(newer_filter(&id, "").is_none());
+    }
+}
@@ -0,0 +1,105 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::document::get_path;
+use crate::indexes::ALL_COLLECTIONS;
+use bson::{Bson, Document};
+use std::collections::HashMap;
+
+/// ShardKeys holds the shard key fields of each target collection, so writes to a sharded
+/// collection can be routed to a single shard instead of failing or being broadcast.
+#[derive(Debug, Clone, Default)]
+pub struct ShardKeys {
+    keys: HashMap<String, Vec<String>>,
+}
+
+impl ShardKeys {
+    /// new creates a new ShardKeys.
+    ///
+    /// # Arguments
+    /// * `keys` - The shard key fields (or dotted paths), by collection name or `*`
+    pub fn new(keys: HashMap<String, Vec<String>>) -> ShardKeys {
+        ShardKeys { keys }
+    }
+
+    /// fields returns a collection's shard key fields, falling back to those under `*`.
+    pub fn fields(&self, collection: &str) -> &[String] {
+        self.keys
+            .get(collection)
+            .or_else(|| self.keys.get(ALL_COLLECTIONS))
+            .map_or(&[], Vec::as_slice)
+    }
+
+    /// filter returns a filter matching a document by `_id` and the collection's shard key.
+    pub fn filter(&self, collection: &str, id: &Bson, document: &Document) -> Document {
+        let mut filter = bson::doc! { "_id": id };
+        self.add_to_filter(collection, &mut filter, document);
+        filter
+    }
+
+    /// add_to_filter adds the document's shard key values to a filter. A field the document
+    /// hasn't got is matched as null, which is how MongoDB stores a missing shard key.
+    ///
+    /// # Arguments
+    /// * `collection` - The collection the filter is for
+    /// * `filter` - The filter to change
+    /// * `document` - The document the values are taken from
+    pub fn add_to_filter(&self, collection: &str, filter: &mut Document, document: &Document) {
+        for field in self.fields(collection) {
+            if field == "_id" {
+                continue;
+            }
+            let value = get_path(document, field).cloned().unwrap_or(Bson::Null);
+            filter.insert(field.clone(), value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bson::doc;
+
+    fn shard_keys() -> ShardKeys {
+        ShardKeys::new(HashMap::from([
+            (
+                "orders".to_string(),
+                vec!["region".to_string(), "customer.id".to_string()],
+            ),
+            ("*".to_string(), vec!["tenant_id".to_string()]),
+        ]))
+    }
+
+    #[test]
+    fn test_filter() {
+        let document = doc! { "_id": "a", "region": "eu", "customer": { "id": 7 } };
+        assert_eq!(
+            shard_keys().filter("orders", &Bson::from("a"), &document),
+            doc! { "_id": "a", "region": "eu", "customer.id": 7 }
+        );
+    }
+
+    #[test]
+    fn test_filter_falls_back_to_all_collections() {
+        let document = doc! { "_id": "a" };
+        assert_eq!(
+            shard_keys().filter("cats", &Bson::from("a"), &document),
+            doc! { "_id": "a", "tenant_id": null }
+        );
+        assert_eq!(
+            ShardKeys::default().filter("cats", &Bson::from("a"), &document),
+            doc! { "_id": "a" }
+        );
+    }
+}
@@ -0,0 +1,239 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::lease::{now_ms, Lease, LeaseKeeper, LeaseLost};
+use crate::seqstore::interface::SequenceStore;
+use crate::settings::config_parser::{FailoverRole, FailoverSettings};
+use std::error::Error;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{debug, error, info, warn};
+
+/// Failover coordinates an active/passive pair of deployments (typically in different regions)
+/// that share a checkpoint store.
+///
+/// The active deployment holds a lease, stored next to the checkpoint, which it renews in the
+/// background. The standby follows the checkpoint read-only and is promoted either manually (see
+/// `promote`) or automatically once the active's lease expires. The lease is only ever taken or
+/// renewed with a compare-and-set against the lease last read, so two regions can't both believe
+/// they hold it. Before writing, the pipeline must check `is_active`; a deployment that fails to
+/// renew its lease, or finds it held by someone else, stops being active so two regions never
+/// write at the same time.
+pub struct Failover {
+    settings: FailoverSettings,
+    store: Arc<dyn SequenceStore>,
+    sequence_key: String,
+    lease: LeaseKeeper,
+    active: AtomicBool,
+    promote_requested: AtomicBool,
+}
+
+impl Failover {
+    /// new creates a new Failover coordinator.
+    ///
+    /// # Arguments
+    /// * `settings` - A FailoverSettings struct
+    /// * `store` - The shared sequence store
+    /// * `sequence_key` - The checkpoint key; the lease is stored at `<sequence_key>:lease`
+    ///
+    /// # Returns
+    /// * A shared Failover
+    pub fn new(
+        settings: &FailoverSettings,
+        store: Arc<dyn SequenceStore>,
+        sequence_key: &str,
+    ) -> Arc<Failover> {
+        let lease = LeaseKeeper::new(
+            store.clone(),
+            &format!("{}:lease", sequence_key),
+            &settings.region,
+            Duration::from_secs(settings.lease_ttl_secs),
+        );
+
+        Arc::new(Failover {
+            settings: settings.clone(),
+            store,
+            sequence_key: sequence_key.to_string(),
+            lease,
+            active: AtomicBool::new(false),
+            promote_requested: AtomicBool::new(false),
+        })
+    }
+
+    /// is_active returns true while this deployment holds the lease and may write.
+    pub fn is_active(&self) -> bool {
+        self.active.load(Ordering::SeqCst)
+    }
+
+    /// promote asks a standby to take over the lease at its next poll, even if the current lease
+    /// hasn't expired yet.
+    pub fn promote(&self) {
+        warn!(
+            region = self.settings.region.as_str(),
+            "manual promotion requested"
+        );
+        self.promote_requested.store(true, Ordering::SeqCst);
+    }
+
+    /// start blocks until this deployment is active, then keeps the lease renewed in the
+    /// background.
+    ///
+    /// An active deployment refuses to start while another region holds an unexpired lease. A
+    /// standby follows the checkpoint until it is promoted.
+    pub async fn start(self: &Arc<Self>) -> Result<(), Box<dyn Error>> {
+        let held = match self.settings.role {
+            FailoverRole::Active => {
+                if let Some(lease) = self.lease.current().await? {
+                    if lease.holder != self.settings.region && !lease.is_expired(now_ms()) {
+                        return Err(format!(
+                            "lease is held by {} for another {}ms, refusing to start as active",
+                            lease.holder,
+                            lease.expires_at_ms - now_ms()
+                        )
+                        .into());
+                    }
+                }
+
+                self.lease
+                    .try_acquire(false)
+                    .await?
+                    .ok_or("lease was taken by another region while starting as active")?
+            }
+            FailoverRole::Standby => self.standby().await?,
+        };
+
+        info!(
+            region = self.settings.region.as_str(),
+            "holding failover lease, now active"
+        );
+        self.active.store(true, Ordering::SeqCst);
+
+        let failover = self.clone();
+        tokio::spawn(async move { failover.renew(held).await });
+
+        Ok(())
+    }
+
+    /// standby follows the checkpoint until the lease can be taken over, returning the lease
+    /// written.
+    async fn standby(&self) -> Result<Lease, Box<dyn Error>> {
+        info!(
+            region = self.settings.region.as_str(),
+            "starting in standby, following checkpoint"
+        );
+
+        loop {
+            let checkpoint = self.store.get(&self.sequence_key).await?;
+            let lease = self.lease.current().await?;
+            debug!(
+                checkpoint = checkpoint,
+                lease = format!("{:?}", lease),
+                "standby"
+            );
+
+            let expired = match &lease {
+                Some(lease) => lease.holder == self.settings.region || lease.is_expired(now_ms()),
+                None => true,
+            };
+
+            let promote = self.promote_requested.swap(false, Ordering::SeqCst);
+            if promote || (expired && self.settings.auto_promote) {
+                info!(
+                    region = self.settings.region.as_str(),
+                    manual = promote,
+                    "taking over failover lease"
+                );
+                match self.lease.try_acquire(promote).await? {
+                    Some(held) => return Ok(held),
+                    None => warn!("lost the race for the failover lease, staying in standby"),
+                }
+            }
+
+            tokio::time::sleep(self.lease.renew_interval()).await;
+        }
+    }
+
+    /// renew keeps the lease alive, and stops this deployment being active once it can't.
+    async fn renew(&self, held: Lease) {
+        match self.lease.keep(held).await {
+            LeaseLost::Taken => {
+                error!("failover lease was taken by another region, no longer active")
+            }
+            LeaseLost::Expired => {
+                error!("failover lease expired before it could be renewed, no longer active")
+            }
+        }
+        self.active.store(false, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::seqstore::null::Null;
+    use crate::seqstore::sqlite::SQLite;
+    use crate::settings::config_parser::SQLiteSettings;
+    use tokio::runtime::Runtime;
+
+    fn settings(role: FailoverRole, region: &str) -> FailoverSettings {
+        FailoverSettings {
+            role,
+            region: region.to_string(),
+            lease_ttl_secs: 30,
+            auto_promote: true,
+        }
+    }
+
+    #[test]
+    fn test_active_refuses_to_start_against_held_lease() {
+        let rt = Runtime::new().unwrap();
+        let store: Arc<dyn SequenceStore> = Arc::new(Null::new());
+
+        rt.block_on(async {
+            let first = Failover::new(&settings(FailoverRole::Active, "eu"), store.clone(), "k");
+            first.start().await.unwrap();
+            assert!(first.is_active());
+
+            let second = Failover::new(&settings(FailoverRole::Active, "us"), store.clone(), "k");
+            assert!(second.start().await.is_err());
+            assert!(!second.is_active());
+        });
+    }
+
+    #[test]
+    fn test_promoted_standby_takes_over_held_lease() {
+        let rt = Runtime::new().unwrap();
+        let store: Arc<dyn SequenceStore> = Arc::new(
+            SQLite::new(&SQLiteSettings {
+                path: ":memory:".to_string(),
+            })
+            .unwrap(),
+        );
+
+        rt.block_on(async {
+            let active = Failover::new(&settings(FailoverRole::Active, "eu"), store.clone(), "k");
+            active.start().await.unwrap();
+
+            let standby = Failover::new(&settings(FailoverRole::Standby, "us"), store.clone(), "k");
+            standby.promote();
+            standby.start().await.unwrap();
+            assert!(standby.is_active());
+            assert_eq!(
+                standby.lease.current().await.unwrap().unwrap().holder,
+                "us".to_string()
+            );
+        });
+    }
+}
@@ -0,0 +1,166 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use regex::Regex;
+use sha2::{Digest, Sha256};
+use std::error::Error;
+
+/// IdFilter decides whether a document should be replicated based on its ID.
+///
+/// Patterns are globs (`*` matches any run of characters, `?` matches a single character) unless
+/// wrapped in slashes, in which case they are regular expressions, eg. `/^migration:\d+$/`.
+///
+/// If any include patterns are configured, an ID must match at least one of them. An ID matching
+/// any exclude pattern is always skipped.
+///
+/// When sharding is set, only IDs hashing to this instance's shard are replicated. The hash is the
+/// SHA-256 of the ID, so every instance agrees on which shard an ID belongs to.
+#[derive(Debug, Default)]
+pub struct IdFilter {
+    include: Vec<Regex>,
+    exclude: Vec<Regex>,
+    shard: Option<(u64, u64)>,
+}
+
+impl IdFilter {
+    /// new creates a new IdFilter.
+    ///
+    /// # Arguments
+    /// * `include` - Patterns an ID must match (any of) to be replicated
+    /// * `exclude` - Patterns that cause an ID to be skipped
+    ///
+    /// # Returns
+    /// * An IdFilter, or an error if a pattern is not a valid regular expression
+    pub fn new(include: &[String], exclude: &[String]) -> Result<IdFilter, Box<dyn Error>> {
+        Ok(IdFilter {
+            include: include
+                .iter()
+                .map(|p| compile_pattern(p))
+                .collect::<Result<_, _>>()?,
+            exclude: exclude
+                .iter()
+                .map(|p| compile_pattern(p))
+                .collect::<Result<_, _>>()?,
+            shard: None,
+        })
+    }
+
+    /// set_shard only allows IDs that hash to shard `index` of `count`.
+    pub fn set_shard(&mut self, index: u64, count: u64) {
+        self.shard = Some((index, count));
+    }
+
+    /// is_allowed returns true if the document ID should be replicated.
+    pub fn is_allowed(&self, id: &str) -> bool {
+        if let Some((index, count)) = self.shard {
+            if shard_of(id, count) != index {
+                return false;
+            }
+        }
+
+        if !self.include.is_empty() && !self.include.iter().any(|r| r.is_match(id)) {
+            return false;
+        }
+
+        !self.exclude.iter().any(|r| r.is_match(id))
+    }
+}
+
+/// shard_of returns which of `count` shards an ID belongs to.
+pub fn shard_of(id: &str, count: u64) -> u64 {
+    let digest = Sha256::digest(id.as_bytes());
+    let mut prefix = [0u8; 8];
+    prefix.copy_from_slice(&digest[..8]);
+
+    u64::from_be_bytes(prefix) % count
+}
+
+/// compile_pattern compiles a glob or `/regex/` pattern.
+fn compile_pattern(pattern: &str) -> Result<Regex, regex::Error> {
+    if pattern.len() > 1 && pattern.starts_with('/') && pattern.ends_with('/') {
+        return Regex::new(&pattern[1..pattern.len() - 1]);
+    }
+
+    let mut re = String::from("^");
+    for c in pattern.chars() {
+        match c {
+            '*' => re.push_str(".*"),
+            '?' => re.push('.'),
+            c => re.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    re.push('$');
+
+    Regex::new(&re)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn patterns(p: &[&str]) -> Vec<String> {
+        p.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_empty_filter_allows_everything() {
+        let filter = IdFilter::new(&[], &[]).unwrap();
+        assert!(filter.is_allowed("anything"));
+    }
+
+    #[test]
+    fn test_exclude_glob() {
+        let filter = IdFilter::new(&[], &patterns(&["migration:*", "_local/*"])).unwrap();
+        assert!(!filter.is_allowed("migration:0001"));
+        assert!(!filter.is_allowed("_local/checkpoint"));
+        assert!(filter.is_allowed("cat:tom"));
+        assert!(filter.is_allowed("my-migration:0001"));
+    }
+
+    #[test]
+    fn test_include_and_exclude() {
+        let filter = IdFilter::new(&patterns(&["cat:*"]), &patterns(&["cat:?"])).unwrap();
+        assert!(filter.is_allowed("cat:tom"));
+        assert!(!filter.is_allowed("cat:x"));
+        assert!(!filter.is_allowed("mouse:jerry"));
+    }
+
+    #[test]
+    fn test_regex_pattern() {
+        let filter = IdFilter::new(&[], &patterns(&[r"/^tmp\d+$/"])).unwrap();
+        assert!(!filter.is_allowed("tmp123"));
+        assert!(filter.is_allowed("tmp123a"));
+    }
+
+    #[test]
+    fn test_shards_partition_ids() {
+        let filters: Vec<IdFilter> = (0..3)
+            .map(|index| {
+                let mut filter = IdFilter::new(&[], &[]).unwrap();
+                filter.set_shard(index, 3);
+                filter
+            })
+            .collect();
+
+        for id in ["cat:tom", "mouse:jerry", "dog:spike", "", "cat:felix"] {
+            assert_eq!(filters.iter().filter(|f| f.is_allowed(id)).count(), 1);
+        }
+        assert_eq!(shard_of("cat:tom", 3), shard_of("cat:tom", 3));
+    }
+
+    #[test]
+    fn test_invalid_regex() {
+        assert!(IdFilter::new(&patterns(&["/(/"]), &[]).is_err());
+    }
+}
@@ -0,0 +1,309 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::settings::config_parser::{IndexSettings, TimeSeriesGranularity, TimeSeriesSettings};
+use bson::{Bson, Document};
+use mongodb::error::ErrorKind;
+use mongodb::options::{
+    CreateCollectionOptions,
+    IndexOptions,
+    TimeseriesGranularity,
+    TimeseriesOptions,
+};
+use mongodb::{Database, IndexModel};
+use regex::Regex;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::time::Duration;
+use tracing::info;
+
+/// Collection name whose indexes are created on every collection documents are routed to.
+pub const ALL_COLLECTIONS: &str = "*";
+
+/// MongoDB's error code for a collection that already exists.
+const NAMESPACE_EXISTS: i32 = 48;
+
+/// IndexManager makes sure the configured indexes, and time-series collections, exist on the
+/// target collections.
+///
+/// Indexes for named collections are created at startup, and those for `*` on each collection the
+/// first time a document is routed to it. Creating an index that already exists does nothing;
+/// indexes are never dropped or changed. Time-series collections are created the first time a
+/// document is routed to them; an existing collection is left as it is.
+pub struct IndexManager {
+    models: HashMap<String, Vec<IndexModel>>,
+    time_series: Option<(TimeseriesOptions, Option<Regex>)>,
+    ensured: HashSet<String>,
+}
+
+impl IndexManager {
+    /// new creates a new IndexManager.
+    ///
+    /// # Arguments
+    /// * `indexes` - The index specs, by collection name or `*`
+    /// * `time_series` - How to create time-series collections, if they're wanted
+    ///
+    /// # Returns
+    /// * An IndexManager struct, or an error if a spec is invalid
+    pub fn new(
+        indexes: &HashMap<String, Vec<IndexSettings>>,
+        time_series: Option<&TimeSeriesSettings>,
+    ) -> Result<IndexManager, Box<dyn Error>> {
+        let mut models = HashMap::new();
+        for (collection, specs) in indexes {
+            let collection_models = specs
+                .iter()
+                .map(index_model)
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| format!("indexes.{}: {}", collection, e))?;
+            models.insert(collection.clone(), collection_models);
+        }
+
+        let time_series = match time_series {
+            Some(time_series_settings) => Some((
+                timeseries_options(time_series_settings),
+                time_series_settings
+                    .collection_pattern
+                    .as_deref()
+                    .map(Regex::new)
+                    .transpose()
+                    .map_err(|e| format!("time_series.collection_pattern: {}", e))?,
+            )),
+            None => None,
+        };
+
+        Ok(IndexManager {
+            models,
+            time_series,
+            ensured: HashSet::new(),
+        })
+    }
+
+    /// ensure_configured creates the indexes of every named collection.
+    pub async fn ensure_configured(&mut self, db: &Database) -> Result<(), Box<dyn Error>> {
+        let mut collections: Vec<String> = self
+            .models
+            .keys()
+            .filter(|c| *c != ALL_COLLECTIONS)
+            .cloned()
+            .collect();
+        collections.sort();
+
+        for collection in collections {
+            self.ensure(db, &collection, None).await?;
+        }
+
+        Ok(())
+    }
+
+    /// ensure creates a collection's indexes, unless they've already been created in that database
+    /// by this process.
+    ///
+    /// # Arguments
+    /// * `db` - The MongoDB database
+    /// * `collection` - The collection name
+    /// * `target` - The MongoDB target the database is on, or None for the main cluster
+    pub async fn ensure(
+        &mut self,
+        db: &Database,
+        collection: &str,
+        target: Option<&str>,
+    ) -> Result<(), Box<dyn Error>> {
+        let namespace = format!(
+            "{}/{}.{}",
+            target.unwrap_or_default(),
+            db.name(),
+            collection
+        );
+        if self.ensured.contains(&namespace) {
+            return Ok(());
+        }
+
+        if let Some((options, pattern)) = &self.time_series {
+            if pattern.as_ref().map_or(true, |p| p.is_match(collection)) {
+                create_time_series(db, collection, options).await?;
+            }
+        }
+
+        let models = self
+            .models
+            .get(collection)
+            .into_iter()
+            .chain(self.models.get(ALL_COLLECTIONS))
+            .flatten();
+        for model in models {
+            let result = db
+                .collection::<Document>(collection)
+                .create_index(model.clone(), None)
+                .await
+                .map_err(|e| format!("unable to create index on {}: {}", collection, e))?;
+
+            info!(
+                collection,
+                index = result.index_name.as_str(),
+                keys = model.keys.to_string(),
+                "ensured index"
+            );
+        }
+
+        self.ensured.insert(namespace);
+        Ok(())
+    }
+}
+
+/// create_time_series creates a time-series collection, unless there's already a collection with
+/// that name.
+async fn create_time_series(
+    db: &Database,
+    collection: &str,
+    options: &TimeseriesOptions,
+) -> Result<(), Box<dyn Error>> {
+    let create_options = CreateCollectionOptions::builder()
+        .timeseries(options.clone())
+        .build();
+
+    match db.create_collection(collection, create_options).await {
+        Ok(_) => {
+            info!(
+                collection,
+                time_field = options.time_field.as_str(),
+                "created time-series collection"
+            );
+            Ok(())
+        }
+        Err(e) if matches!(e.kind.as_ref(), ErrorKind::Command(c) if c.code == NAMESPACE_EXISTS) => {
+            Ok(())
+        }
+        Err(e) => Err(format!(
+            "unable to create time-series collection {}: {}",
+            collection, e
+        )
+        .into()),
+    }
+}
+
+/// timeseries_options returns the options time-series collections are created with.
+fn timeseries_options(settings: &TimeSeriesSettings) -> TimeseriesOptions {
+    TimeseriesOptions::builder()
+        .time_field(settings.time_field.clone())
+        .meta_field(settings.meta_field.clone())
+        .granularity(settings.granularity.as_ref().map(|g| match g {
+            TimeSeriesGranularity::Seconds => TimeseriesGranularity::Seconds,
+            TimeSeriesGranularity::Minutes => TimeseriesGranularity::Minutes,
+            TimeSeriesGranularity::Hours => TimeseriesGranularity::Hours,
+        }))
+        .build()
+}
+
+/// index_model returns the IndexModel for an index spec.
+fn index_model(spec: &IndexSettings) -> Result<IndexModel, String> {
+    let options = IndexOptions::builder()
+        .name(spec.name.clone())
+        .unique(spec.unique.then_some(true))
+        .sparse(spec.sparse.then_some(true))
+        .expire_after(spec.expire_after_secs.map(Duration::from_secs))
+        .build();
+
+    Ok(IndexModel::builder()
+        .keys(index_keys(&spec.keys)?)
+        .options(options)
+        .build())
+}
+
+/// index_keys turns a list of fields into index keys: `field` is ascending, `-field` descending
+/// and `field:kind` an index of that kind, eg. `location:2dsphere` or `body:text`.
+///
+/// # Returns
+/// * The keys, or an error if the list is empty or has a field twice
+pub fn index_keys(keys: &[String]) -> Result<Document, String> {
+    if keys.is_empty() {
+        return Err("an index needs at least one key".to_string());
+    }
+
+    let mut document = Document::new();
+    for key in keys {
+        let (field, value) = match key.split_once(':') {
+            Some((field, kind)) => (field, Bson::String(kind.to_string())),
+            None => match key.strip_prefix('-') {
+                Some(field) => (field, Bson::Int32(-1)),
+                None => (key.as_str(), Bson::Int32(1)),
+            },
+        };
+
+        if field.is_empty() {
+            return Err(format!("index key {} has no field", key));
+        }
+        if document.insert(field, value).is_some() {
+            return Err(format!("index key {} is repeated", field));
+        }
+    }
+
+    Ok(document)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bson::doc;
+
+    fn keys(keys: &[&str]) -> Vec<String> {
+        keys.iter().map(|k| k.to_string()).collect()
+    }
+
+    #[test]
+    fn test_index_keys() {
+        assert_eq!(
+            index_keys(&keys(&["type", "-created_at", "location:2dsphere"])),
+            Ok(doc! { "type": 1, "created_at": -1, "location": "2dsphere" })
+        );
+    }
+
+    #[test]
+    fn test_invalid_index_keys() {
+        assert!(index_keys(&[]).is_err());
+        assert!(index_keys(&keys(&["-"])).is_err());
+        assert!(index_keys(&keys(&["type", "-type"])).is_err());
+    }
+
+    #[test]
+    fn test_index_options() {
+        let spec = IndexSettings {
+            keys: keys(&["expires_at"]),
+            name: Some("expiry".to_string()),
+            unique: false,
+            sparse: true,
+            expire_after_secs: Some(0),
+        };
+        let options = index_model(&spec).unwrap().options.unwrap();
+
+        assert_eq!(options.name.as_deref(), Some("expiry"));
+        assert_eq!(options.unique, None);
+        assert_eq!(options.sparse, Some(true));
+        assert_eq!(options.expire_after, Some(Duration::from_secs(0)));
+    }
+
+    #[test]
+    fn test_timeseries_options() {
+        let options = timeseries_options(&TimeSeriesSettings {
+            time_field: "recorded_at".to_string(),
+            meta_field: Some("device".to_string()),
+            granularity: Some(TimeSeriesGranularity::Minutes),
+            collection_pattern: None,
+        });
+
+        assert_eq!(options.time_field, "recorded_at");
+        assert_eq!(options.meta_field.as_deref(), Some("device"));
+        assert_eq!(options.granularity, Some(TimeseriesGranularity::Minutes));
+    }
+}
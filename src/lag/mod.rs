@@ -0,0 +1,313 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::couchdb::{sequence_number, CouchConnection};
+use crate::metrics;
+use lazy_static::lazy_static;
+use prometheus::{register_int_gauge_vec, IntGaugeVec};
+use serde_derive::Serialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+lazy_static! {
+    /// How far the last processed sequence is behind the source database's update sequence.
+    pub static ref REPLICATION_LAG_SEQUENCES: IntGaugeVec = register_int_gauge_vec!(
+        "couch2mongo_replication_lag_sequences",
+        "Difference between the source update sequence and the last processed sequence",
+        &["pipeline"]
+    )
+    .unwrap();
+
+    /// How long replication has been behind the source database.
+    pub static ref REPLICATION_LAG_SECONDS: IntGaugeVec = register_int_gauge_vec!(
+        "couch2mongo_replication_lag_seconds",
+        "Seconds since replication last caught up with the source database",
+        &["pipeline"]
+    )
+    .unwrap();
+}
+
+/// ProgressReport is how quickly replication was going at the last poll, and when it's projected
+/// to catch up.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ProgressReport {
+    pub update_seq: String,
+    pub processed_seq: Option<String>,
+    pub remaining_changes: u64,
+    pub docs_per_sec: f64,
+    pub bytes_per_sec: f64,
+    // Only while behind and making progress
+    pub eta_secs: Option<u64>,
+    pub caught_up_by: Option<String>,
+}
+
+/// Sample is what had been done at a poll, to work out rates from.
+#[derive(Debug, Clone)]
+struct Sample {
+    at: Instant,
+    docs: u64,
+    bytes: u64,
+    seq: u64,
+}
+
+/// LagMonitor periodically compares the last processed sequence with the source database's
+/// update sequence, publishing the difference as metrics. While replication is behind, it also
+/// logs its throughput and projected catch-up time.
+///
+/// The sequence lag compares the numeric parts of the sequences, so it's an estimate on clustered
+/// CouchDB, and counts changes a selector or doc ID filter leaves out of the feed. The wall-clock
+/// lag is the time since a poll last found replication caught up, or since the monitor started.
+pub struct LagMonitor {
+    connection: CouchConnection,
+    pipeline: String,
+    database: String,
+    interval: Duration,
+    processed: Mutex<Option<String>>,
+    caught_up_at: Mutex<Instant>,
+    docs: AtomicU64,
+    previous: Mutex<Option<Sample>>,
+    progress: Mutex<Option<ProgressReport>>,
+}
+
+impl LagMonitor {
+    /// new creates a new LagMonitor.
+    ///
+    /// # Arguments
+    /// * `connection` - The CouchDB connection
+    /// * `pipeline` - The name of the pipeline, for metrics and logs
+    /// * `database` - The source database
+    /// * `interval` - How often to poll the update sequence
+    /// * `processed` - The sequence replication is resuming from, if any
+    ///
+    /// # Returns
+    /// * A shared LagMonitor
+    pub fn new(
+        connection: CouchConnection,
+        pipeline: &str,
+        database: &str,
+        interval: Duration,
+        processed: Option<String>,
+    ) -> Arc<LagMonitor> {
+        Arc::new(LagMonitor {
+            connection,
+            pipeline: pipeline.to_string(),
+            database: database.to_string(),
+            interval,
+            processed: Mutex::new(processed),
+            caught_up_at: Mutex::new(Instant::now()),
+            docs: AtomicU64::new(0),
+            previous: Mutex::new(None),
+            progress: Mutex::new(None),
+        })
+    }
+
+    /// record notes the sequence of a change that has been processed.
+    pub fn record(&self, seq: &str) {
+        *self
+            .processed
+            .lock()
+            .expect("unable to lock processed sequence") = Some(seq.to_string());
+        self.docs.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// progress returns the report from the last poll, if there's been one.
+    pub fn progress(&self) -> Option<ProgressReport> {
+        self.progress
+            .lock()
+            .expect("unable to lock progress")
+            .clone()
+    }
+
+    /// start polls the update sequence in the background.
+    pub fn start(self: &Arc<Self>) {
+        let monitor = self.clone();
+        tokio::spawn(async move {
+            loop {
+                if let Err(e) = monitor.poll().await {
+                    warn!(error = e, "unable to measure replication lag");
+                }
+
+                tokio::time::sleep(monitor.interval).await;
+            }
+        });
+    }
+
+    /// poll measures and publishes the lag once.
+    async fn poll(&self) -> Result<(), String> {
+        let update_seq = self
+            .connection
+            .update_seq(&self.database)
+            .await
+            .map_err(|e| e.to_string())?;
+        let processed = self
+            .processed
+            .lock()
+            .expect("unable to lock processed sequence")
+            .clone();
+
+        let lag = sequence_lag(&update_seq, processed.as_deref())
+            .ok_or_else(|| format!("unable to compare sequences at {}", update_seq))?;
+
+        let mut caught_up_at = self
+            .caught_up_at
+            .lock()
+            .expect("unable to lock caught up time");
+        if lag == 0 {
+            *caught_up_at = Instant::now();
+        }
+        let lag_secs = caught_up_at.elapsed().as_secs();
+
+        REPLICATION_LAG_SEQUENCES
+            .with_label_values(&[self.pipeline.as_str()])
+            .set(lag as i64);
+        REPLICATION_LAG_SECONDS
+            .with_label_values(&[self.pipeline.as_str()])
+            .set(lag_secs as i64);
+        info!(
+            pipeline = self.pipeline.as_str(),
+            database = self.database.as_str(),
+            lag_sequences = lag,
+            lag_secs,
+            "replication lag"
+        );
+
+        let sample = Sample {
+            at: Instant::now(),
+            docs: self.docs.load(Ordering::Relaxed),
+            bytes: metrics::bytes_written(),
+            seq: processed.as_deref().and_then(sequence_number).unwrap_or(0),
+        };
+        let previous = self
+            .previous
+            .lock()
+            .expect("unable to lock previous sample")
+            .replace(sample.clone());
+        let report = progress_report(update_seq, processed, lag, previous.as_ref(), &sample);
+
+        if lag > 0 {
+            info!(
+                pipeline = self.pipeline.as_str(),
+                database = self.database.as_str(),
+                remaining_changes = report.remaining_changes,
+                docs_per_sec = report.docs_per_sec,
+                bytes_per_sec = report.bytes_per_sec,
+                eta_secs = report.eta_secs,
+                caught_up_by = report.caught_up_by,
+                "catching up"
+            );
+        }
+
+        *self.progress.lock().expect("unable to lock progress") = Some(report);
+
+        Ok(())
+    }
+}
+
+/// progress_report works out the rates between two samples, and from the rate sequences are being
+/// processed at, when the remaining changes will have been.
+fn progress_report(
+    update_seq: String,
+    processed_seq: Option<String>,
+    remaining_changes: u64,
+    previous: Option<&Sample>,
+    current: &Sample,
+) -> ProgressReport {
+    let elapsed = previous
+        .map(|p| current.at.duration_since(p.at).as_secs_f64())
+        .unwrap_or_default();
+    let rate = |from: Option<u64>, to: u64| match (from, elapsed > 0.0) {
+        (Some(from), true) => to.saturating_sub(from) as f64 / elapsed,
+        _ => 0.0,
+    };
+
+    let seqs_per_sec = rate(previous.map(|p| p.seq), current.seq);
+    let eta_secs = (remaining_changes > 0 && seqs_per_sec > 0.0)
+        .then(|| (remaining_changes as f64 / seqs_per_sec).ceil() as u64);
+    let caught_up_by = eta_secs.and_then(|secs| {
+        bson::DateTime::from_millis(bson::DateTime::now().timestamp_millis() + secs as i64 * 1000)
+            .try_to_rfc3339_string()
+            .ok()
+    });
+
+    ProgressReport {
+        update_seq,
+        processed_seq,
+        remaining_changes,
+        docs_per_sec: rate(previous.map(|p| p.docs), current.docs),
+        bytes_per_sec: rate(previous.map(|p| p.bytes), current.bytes),
+        eta_secs,
+        caught_up_by,
+    }
+}
+
+/// sequence_lag returns how far the numeric part of a processed sequence is behind the update
+/// sequence. No sequence at all is the start of the feed.
+pub fn sequence_lag(update_seq: &str, processed: Option<&str>) -> Option<u64> {
+    let current = sequence_number(update_seq)?;
+    let processed = match processed {
+        Some(processed) => sequence_number(processed)?,
+        None => 0,
+    };
+
+    Some(current.saturating_sub(processed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sequence_lag() {
+        assert_eq!(sequence_lag("120-g1AAAA", Some("100-g1BBBB")), Some(20));
+        assert_eq!(sequence_lag("120-g1AAAA", None), Some(120));
+        assert_eq!(sequence_lag("100-g1AAAA", Some("120-g1BBBB")), Some(0));
+        assert_eq!(sequence_lag("now", Some("1")), None);
+    }
+
+    #[test]
+    fn test_progress_report() {
+        let at = Instant::now();
+        let previous = Sample {
+            at,
+            docs: 100,
+            bytes: 10_000,
+            seq: 1000,
+        };
+        let current = Sample {
+            at: at + Duration::from_secs(10),
+            docs: 600,
+            bytes: 60_000,
+            seq: 2000,
+        };
+
+        let report = progress_report(
+            "5000-a".to_string(),
+            Some("2000-b".to_string()),
+            3000,
+            Some(&previous),
+            &current,
+        );
+        assert_eq!(report.docs_per_sec, 50.0);
+        assert_eq!(report.bytes_per_sec, 5000.0);
+        assert_eq!(report.eta_secs, Some(30));
+        assert!(report.caught_up_by.is_some());
+
+        // Nothing to compare the first poll with
+        let report = progress_report("5000-a".to_string(), None, 5000, None, &current);
+        assert_eq!(report.docs_per_sec, 0.0);
+        assert_eq!(report.eta_secs, None);
+    }
+}
@@ -0,0 +1,320 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::seqstore::interface::SequenceStore;
+use std::error::Error;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{debug, warn};
+
+/// Lease is the record, stored alongside the checkpoint, naming who may write.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Lease {
+    pub holder: String,
+    pub expires_at_ms: u128,
+}
+
+impl Lease {
+    /// parse reads a lease from its stored `holder|expires_at_ms` form.
+    pub fn parse(value: &str) -> Option<Lease> {
+        let (holder, expires_at_ms) = value.rsplit_once('|')?;
+
+        Some(Lease {
+            holder: holder.to_string(),
+            expires_at_ms: expires_at_ms.parse().ok()?,
+        })
+    }
+
+    /// encode returns the stored form of the lease.
+    pub fn encode(&self) -> String {
+        format!("{}|{}", self.holder, self.expires_at_ms)
+    }
+
+    /// is_expired returns true if the lease has lapsed at `now_ms`.
+    pub fn is_expired(&self, now_ms: u128) -> bool {
+        now_ms >= self.expires_at_ms
+    }
+}
+
+/// LeaseLost is why a held lease was given up.
+#[derive(Debug, PartialEq)]
+pub enum LeaseLost {
+    // Someone else wrote the lease
+    Taken,
+    // The store couldn't be reached to renew it in time
+    Expired,
+}
+
+/// LeaseKeeper takes and renews a lease kept in the sequence store.
+///
+/// The lease is only ever written with a compare-and-set against the value last read, so two
+/// holders can't both believe they have it. Expiry uses wall clock time, so the clocks of everyone
+/// sharing the lease need to be roughly in sync.
+pub struct LeaseKeeper {
+    store: Arc<dyn SequenceStore>,
+    key: String,
+    holder: String,
+    ttl: Duration,
+}
+
+impl LeaseKeeper {
+    /// new creates a new LeaseKeeper.
+    ///
+    /// # Arguments
+    /// * `store` - The shared sequence store
+    /// * `key` - The key the lease is stored under
+    /// * `holder` - Who we are, written into the lease
+    /// * `ttl` - How long the lease stays valid without renewal
+    ///
+    /// # Returns
+    /// * A LeaseKeeper struct
+    pub fn new(
+        store: Arc<dyn SequenceStore>,
+        key: &str,
+        holder: &str,
+        ttl: Duration,
+    ) -> LeaseKeeper {
+        LeaseKeeper {
+            store,
+            key: key.to_string(),
+            holder: holder.to_string(),
+            ttl,
+        }
+    }
+
+    /// current returns the stored lease, whoever holds it.
+    pub async fn current(&self) -> Result<Option<Lease>, Box<dyn Error>> {
+        Ok(self
+            .store
+            .get(&self.key)
+            .await?
+            .as_deref()
+            .and_then(Lease::parse))
+    }
+
+    /// try_acquire takes the lease if it's free, expired or already ours, or regardless with
+    /// `force`.
+    ///
+    /// # Returns
+    /// * The lease written, or None if it's held by someone else or they changed it first
+    pub async fn try_acquire(&self, force: bool) -> Result<Option<Lease>, Box<dyn Error>> {
+        let current = self.store.get(&self.key).await?;
+
+        if let Some(lease) = current.as_deref().and_then(Lease::parse) {
+            if !force && lease.holder != self.holder && !lease.is_expired(now_ms()) {
+                debug!(
+                    holder = lease.holder.as_str(),
+                    key = self.key.as_str(),
+                    "lease is held by someone else"
+                );
+                return Ok(None);
+            }
+        }
+
+        let lease = self.next_lease();
+        if self
+            .store
+            .set_if_equals(&self.key, current.as_deref(), &lease.encode())
+            .await?
+        {
+            Ok(Some(lease))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// keep renews a held lease every `renew_interval` until it's lost.
+    ///
+    /// # Returns
+    /// * Why the lease was lost
+    pub async fn keep(&self, mut held: Lease) -> LeaseLost {
+        loop {
+            tokio::time::sleep(self.renew_interval()).await;
+
+            match self.renew(&held).await.map_err(|e| e.to_string()) {
+                Ok(Some(lease)) => held = lease,
+                Ok(None) => return LeaseLost::Taken,
+                // If the store is unreachable, carry on until our own expiry passes
+                Err(e) => warn!(error = e, key = self.key.as_str(), "unable to renew lease"),
+            }
+
+            if held.is_expired(now_ms()) {
+                return LeaseLost::Expired;
+            }
+        }
+    }
+
+    /// renew extends a held lease.
+    ///
+    /// # Returns
+    /// * The lease now held, or None if someone else has written it
+    async fn renew(&self, held: &Lease) -> Result<Option<Lease>, Box<dyn Error>> {
+        let lease = self.next_lease();
+        let written = self
+            .store
+            .set_if_equals(&self.key, Some(&held.encode()), &lease.encode())
+            .await
+            .map_err(|e| e.to_string());
+
+        match written {
+            Ok(true) => return Ok(Some(lease)),
+            Ok(false) => return Ok(None),
+            Err(e) => warn!(
+                error = e,
+                key = self.key.as_str(),
+                "unable to renew lease, checking whether it was written"
+            ),
+        }
+
+        // The write may have landed before the error, in which case the next compare-and-set has
+        // to expect the new lease rather than the old one
+        let current = self.store.get(&self.key).await?;
+        if current.as_deref() == Some(lease.encode().as_str()) {
+            Ok(Some(lease))
+        } else if current.as_deref() == Some(held.encode().as_str()) {
+            Ok(Some(held.clone()))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// renew_interval is how often a held lease is renewed: a third of its TTL, so a couple of
+    /// failed renewals can be survived.
+    pub fn renew_interval(&self) -> Duration {
+        self.ttl / 3
+    }
+
+    fn next_lease(&self) -> Lease {
+        Lease {
+            holder: self.holder.clone(),
+            expires_at_ms: now_ms() + self.ttl.as_millis(),
+        }
+    }
+}
+
+pub fn now_ms() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("time went backwards")
+        .as_millis()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::seqstore::sqlite::SQLite;
+    use crate::settings::config_parser::SQLiteSettings;
+    use async_trait::async_trait;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use tokio::runtime::Runtime;
+
+    /// Flaky writes through to SQLite, but fails the first compare-and-set after doing so.
+    struct Flaky {
+        store: SQLite,
+        failed: AtomicBool,
+    }
+
+    #[async_trait]
+    impl SequenceStore for Flaky {
+        async fn set(&self, key: &str, value: &str) -> Result<(), Box<dyn Error>> {
+            self.store.set(key, value).await
+        }
+
+        async fn get(&self, key: &str) -> Result<Option<String>, Box<dyn Error>> {
+            self.store.get(key).await
+        }
+
+        async fn set_if_equals(
+            &self,
+            key: &str,
+            expected: Option<&str>,
+            value: &str,
+        ) -> Result<bool, Box<dyn Error>> {
+            let written = self.store.set_if_equals(key, expected, value).await?;
+            if !self.failed.swap(true, Ordering::SeqCst) {
+                return Err("connection reset".into());
+            }
+            Ok(written)
+        }
+    }
+
+    fn sqlite() -> SQLite {
+        SQLite::new(&SQLiteSettings {
+            path: ":memory:".to_string(),
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_lease_round_trip() {
+        let lease = Lease {
+            holder: "eu-west-1|blue".to_string(),
+            expires_at_ms: 1234,
+        };
+
+        assert_eq!(Lease::parse(&lease.encode()), Some(lease));
+        assert_eq!(Lease::parse("garbage"), None);
+    }
+
+    #[test]
+    fn test_lease_expiry() {
+        let lease = Lease {
+            holder: "a".to_string(),
+            expires_at_ms: 1000,
+        };
+
+        assert!(!lease.is_expired(999));
+        assert!(lease.is_expired(1000));
+    }
+
+    #[test]
+    fn test_only_one_holder() {
+        let rt = Runtime::new().unwrap();
+        let store: Arc<dyn SequenceStore> = Arc::new(sqlite());
+        let a = LeaseKeeper::new(store.clone(), "lease", "a", Duration::from_secs(15));
+        let b = LeaseKeeper::new(store.clone(), "lease", "b", Duration::from_secs(15));
+
+        rt.block_on(async {
+            let held = a.try_acquire(false).await.unwrap().unwrap();
+            assert!(b.try_acquire(false).await.unwrap().is_none());
+
+            // Forcing takes it over, and then a can't renew
+            assert!(b.try_acquire(true).await.unwrap().is_some());
+            assert_eq!(a.renew(&held).await.unwrap(), None);
+        });
+    }
+
+    #[test]
+    fn test_renew_after_error_that_wrote() {
+        let rt = Runtime::new().unwrap();
+        let store: Arc<dyn SequenceStore> = Arc::new(Flaky {
+            store: sqlite(),
+            failed: AtomicBool::new(false),
+        });
+        let keeper = LeaseKeeper::new(store.clone(), "lease", "a", Duration::from_secs(15));
+        let held = Lease {
+            holder: "a".to_string(),
+            expires_at_ms: now_ms(),
+        };
+
+        rt.block_on(async {
+            store.set("lease", &held.encode()).await.unwrap();
+
+            // The renewal errors after it was written, so it's what we hold now
+            let renewed = keeper.renew(&held).await.unwrap().unwrap();
+            assert!(renewed.expires_at_ms > held.expires_at_ms);
+            assert!(keeper.renew(&renewed).await.unwrap().is_some());
+        });
+    }
+}
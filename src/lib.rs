@@ -0,0 +1,56 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Streams changes from a CouchDB database into MongoDB.
+//!
+//! The `couch2mongo` binary is a thin wrapper around `pipeline::Pipeline`, which can also be
+//! embedded directly and driven as a Stream of applied changes.
+
+pub mod admin;
+pub mod attachments;
+pub mod audit;
+pub mod backfill;
+pub mod batch;
+pub mod check;
+pub mod checkpoint;
+pub mod couchdb;
+pub mod deadletter;
+pub mod document;
+pub mod failover;
+pub mod filters;
+pub mod indexes;
+pub mod lag;
+pub mod lease;
+pub mod lock;
+pub mod logging;
+pub mod meta;
+pub mod metrics;
+pub mod mongo2couch;
+pub mod pipeline;
+pub mod routing;
+pub mod scheduler;
+pub mod secrets;
+pub mod seqstore;
+pub mod settings;
+pub mod sink;
+pub mod status;
+pub mod systemd;
+pub mod throttle;
+pub mod transaction;
+pub mod transform;
+pub mod validation;
+pub mod vault;
+pub mod verify;
+pub mod views;
+pub mod writer;
@@ -0,0 +1,158 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::lease::{Lease, LeaseKeeper, LeaseLost};
+use crate::seqstore::interface::SequenceStore;
+use crate::settings::config_parser::LeaderElectionSettings;
+use std::error::Error;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{error, info};
+
+/// LeaderLock elects a single leader among identically configured replicas, so only one of them
+/// consumes the feed.
+///
+/// The lock is a lease kept in the sequence store next to the checkpoint (see `LeaseKeeper`). The
+/// leader renews it in the background; if the leader dies the lease expires and a waiting replica
+/// takes over.
+pub struct LeaderLock {
+    lease: LeaseKeeper,
+    identity: String,
+    leader: AtomicBool,
+}
+
+impl LeaderLock {
+    /// new creates a new LeaderLock.
+    ///
+    /// # Arguments
+    /// * `settings` - A LeaderElectionSettings struct
+    /// * `store` - The shared sequence store
+    /// * `sequence_key` - The checkpoint key; the lock is stored at `<sequence_key>:leader`
+    ///
+    /// # Returns
+    /// * A shared LeaderLock
+    pub fn new(
+        settings: &LeaderElectionSettings,
+        store: Arc<dyn SequenceStore>,
+        sequence_key: &str,
+    ) -> Arc<LeaderLock> {
+        let identity = settings
+            .identity
+            .clone()
+            .or_else(|| std::env::var("HOSTNAME").ok())
+            .unwrap_or_else(|| format!("{:016x}", rand::random::<u64>()));
+
+        Arc::new(LeaderLock {
+            lease: LeaseKeeper::new(
+                store,
+                &format!("{}:leader", sequence_key),
+                &identity,
+                Duration::from_secs(settings.lease_ttl_secs),
+            ),
+            identity,
+            leader: AtomicBool::new(false),
+        })
+    }
+
+    /// is_leader returns true while this replica holds the lock.
+    pub fn is_leader(&self) -> bool {
+        self.leader.load(Ordering::SeqCst)
+    }
+
+    /// acquire blocks until this replica holds the lock, then keeps it renewed in the background.
+    pub async fn acquire(self: &Arc<Self>) -> Result<(), Box<dyn Error>> {
+        info!(
+            identity = self.identity.as_str(),
+            "waiting to become leader"
+        );
+
+        let held = loop {
+            if let Some(held) = self.lease.try_acquire(false).await? {
+                break held;
+            }
+
+            tokio::time::sleep(self.lease.renew_interval()).await;
+        };
+
+        info!(identity = self.identity.as_str(), "became leader");
+        self.leader.store(true, Ordering::SeqCst);
+
+        let lock = self.clone();
+        tokio::spawn(async move { lock.renew(held).await });
+
+        Ok(())
+    }
+
+    /// renew extends the lease until it's lost, then stops this replica being leader.
+    async fn renew(&self, held: Lease) {
+        match self.lease.keep(held).await {
+            LeaseLost::Taken => {
+                error!("leader lock was taken by another replica, no longer leader")
+            }
+            LeaseLost::Expired => {
+                error!("leader lock expired before it could be renewed, no longer leader")
+            }
+        }
+        self.leader.store(false, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lease::now_ms;
+    use crate::seqstore::sqlite::SQLite;
+    use crate::settings::config_parser::SQLiteSettings;
+    use tokio::runtime::Runtime;
+
+    fn lock(store: &Arc<dyn SequenceStore>, identity: &str) -> Arc<LeaderLock> {
+        LeaderLock::new(
+            &LeaderElectionSettings {
+                identity: Some(identity.to_string()),
+                lease_ttl_secs: 15,
+            },
+            store.clone(),
+            "key",
+        )
+    }
+
+    #[test]
+    fn test_only_one_leader() {
+        let rt = Runtime::new().unwrap();
+        let store: Arc<dyn SequenceStore> = Arc::new(
+            SQLite::new(&SQLiteSettings {
+                path: ":memory:".to_string(),
+            })
+            .unwrap(),
+        );
+        let a = lock(&store, "a");
+        let b = lock(&store, "b");
+
+        rt.block_on(async {
+            assert!(a.lease.try_acquire(false).await.unwrap().is_some());
+            assert!(b.lease.try_acquire(false).await.unwrap().is_none());
+
+            // The leader dies and its lease runs out
+            let expired = Lease {
+                holder: "a".to_string(),
+                expires_at_ms: now_ms() - 1,
+            };
+            store.set("key:leader", &expired.encode()).await.unwrap();
+
+            assert!(b.lease.try_acquire(false).await.unwrap().is_some());
+            assert!(a.lease.try_acquire(false).await.unwrap().is_none());
+        });
+    }
+}
@@ -0,0 +1,132 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// RotatingFile is a log file that's rotated once it reaches a maximum size.
+///
+/// On rotation `app.log` becomes `app.log.1`, `app.log.1` becomes `app.log.2` and so on, and the
+/// oldest beyond `max_files` is removed. Writes aren't split, so a file may go over the maximum by
+/// up to one log line.
+pub struct RotatingFile {
+    path: PathBuf,
+    max_size_bytes: u64,
+    max_files: usize,
+    file: File,
+    size: u64,
+}
+
+impl RotatingFile {
+    /// open opens (or creates) the log file, appending to it.
+    ///
+    /// # Arguments
+    /// * `path` - The log file
+    /// * `max_size_bytes` - The size at which the file is rotated
+    /// * `max_files` - How many rotated files to keep
+    ///
+    /// # Returns
+    /// * A RotatingFile struct
+    pub fn open(path: &Path, max_size_bytes: u64, max_files: usize) -> io::Result<RotatingFile> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+
+        Ok(RotatingFile {
+            path: path.to_path_buf(),
+            max_size_bytes,
+            max_files,
+            file,
+            size,
+        })
+    }
+
+    /// rotated_path returns the path of the nth rotated file.
+    fn rotated_path(&self, n: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", n));
+        path.into()
+    }
+
+    /// rotate moves the current file aside and starts a new one.
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+
+        if self.max_files == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            for n in (1..self.max_files).rev() {
+                let from = self.rotated_path(n);
+                if from.exists() {
+                    fs::rename(&from, self.rotated_path(n + 1))?;
+                }
+            }
+            fs::rename(&self.path, self.rotated_path(1))?;
+        }
+
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.size = 0;
+
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.size > 0 && self.size + buf.len() as u64 > self.max_size_bytes {
+            self.rotate()?;
+        }
+
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rotation() {
+        let dir = std::env::temp_dir().join(format!("couch2mongo-logs-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("couch2mongo.log");
+
+        let mut file = RotatingFile::open(&path, 10, 2).unwrap();
+        for line in ["first\n", "second\n", "third\n", "fourth\n"] {
+            file.write_all(line.as_bytes()).unwrap();
+        }
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "fourth\n");
+        assert_eq!(
+            fs::read_to_string(dir.join("couch2mongo.log.1")).unwrap(),
+            "third\n"
+        );
+        assert_eq!(
+            fs::read_to_string(dir.join("couch2mongo.log.2")).unwrap(),
+            "second\n"
+        );
+        assert!(!dir.join("couch2mongo.log.3").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
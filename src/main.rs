@@ -12,39 +12,125 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-mod seqstore;
-mod settings;
-
-use crate::settings::config_parser::Settings;
-use bson::Document;
-use clap::{command, Parser};
-use couch_rs::types::changes::ChangeEvent;
-use futures_util::StreamExt;
-use mongodb::options::ReplaceOptions;
+use clap::{command, Parser, Subcommand};
+use futures_util::future::join_all;
 use std::error::Error;
 use std::fmt::Debug;
-use tracing::{debug, info, instrument};
-
-/// ChangeEventDetails is a trait that provides some helper methods for
-/// ChangeEvent.
-trait ChangeEventDetails {
-    /// is_design_document returns true if the ChangeEvent is a design document.
-    fn is_design_document(&self) -> bool;
-}
-
-/// ChangeEventDetails is implemented for ChangeEvent.
-impl ChangeEventDetails for ChangeEvent {
-    /// is_design_document returns true if the ChangeEvent is a design document.
-    fn is_design_document(&self) -> bool {
-        self.id.starts_with("_design")
-    }
-}
+use std::sync::Arc;
+use streamcouch::admin::{self, Control};
+use streamcouch::backfill::Backfill;
+use streamcouch::check;
+use streamcouch::couchdb::preflight;
+use streamcouch::metrics;
+use streamcouch::mongo2couch::Mongo2Couch;
+use streamcouch::pipeline::{Pipeline, ShutdownHandle};
+use streamcouch::settings::config_parser::{RunMode, Settings};
+use streamcouch::status;
+use streamcouch::systemd;
+use streamcouch::verify::repair::RepairOptions;
+use streamcouch::verify::Verifier;
+use tokio::sync::Notify;
+use tracing::{info, info_span, instrument, Instrument};
 
 #[derive(Parser, Debug)]
 #[command(author = None, version = None, about = "CouchDB to MongoDB Streamer", long_about = None)]
 struct Args {
-    #[arg(short, long, default_value = "config.toml")]
+    #[arg(short, long, default_value = "config.toml", global = true)]
     config: String,
+
+    /// Log what would be written instead of writing to MongoDB or the sequence store
+    #[arg(long, global = true)]
+    dry_run: bool,
+
+    /// Apply the changes up to the current update sequence, checkpoint and exit
+    #[arg(long)]
+    once: bool,
+
+    /// Checkpoint and exit before applying a change beyond this sequence
+    #[arg(long)]
+    until_seq: Option<String>,
+
+    /// Checkpoint and exit once this time (RFC 3339, eg. 2024-01-31T00:00:00Z) has passed
+    #[arg(long)]
+    until_time: Option<String>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Check the configuration, and that CouchDB, MongoDB and the sequence store are usable
+    Check,
+
+    /// Report how far behind the source database replication is
+    Status {
+        /// Print JSON instead of a table
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// Compare every document in CouchDB with MongoDB, reporting missing, mismatched and extra
+    /// documents by collection
+    Verify {
+        /// Print JSON instead of a table
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// Verify, then copy missing and mismatched documents from CouchDB again
+    Repair {
+        /// Also delete MongoDB documents that have no CouchDB document
+        #[arg(long)]
+        delete_extra: bool,
+
+        /// Most documents to copy, and to delete, per collection
+        #[arg(long)]
+        limit: Option<usize>,
+
+        /// Print JSON instead of tables
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// Copy every document from CouchDB through _all_docs, in key ranges copied concurrently;
+    /// rerun to resume
+    Backfill {
+        /// Make a new plan and start over, rather than resuming the last backfill
+        #[arg(long)]
+        restart: bool,
+
+        /// Print JSON instead of a table
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// Push changes made in MongoDB back to CouchDB, following a MongoDB change stream
+    Mongo2couch,
+
+    /// Inspect or change the stored sequence
+    Seq {
+        #[command(subcommand)]
+        action: SeqAction,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum SeqAction {
+    /// Print the stored sequence
+    Get,
+
+    /// Store a sequence to resume from
+    Set {
+        value: String,
+
+        /// Store the sequence even if CouchDB doesn't accept it
+        #[arg(long)]
+        force: bool,
+    },
+
+    /// Replay from the beginning of the changes feed
+    Reset,
 }
 
 #[instrument]
@@ -53,148 +139,324 @@ async fn main() -> Result<(), Box<dyn Error>> {
     let args = Args::parse();
     let config_file = args.config;
 
-    let s = Settings::new(Some(config_file.to_string()));
-    match s {
-        Ok(_) => {}
+    match args.command {
+        Some(Command::Check) => return run_check(&config_file).await,
+        Some(Command::Status { json }) => return run_status(&config_file, json).await,
+        Some(Command::Seq { action }) => return run_seq(&config_file, action, args.dry_run).await,
+        Some(Command::Verify { json }) => return run_verify(&config_file, json).await,
+        Some(Command::Repair {
+            delete_extra,
+            limit,
+            json,
+        }) => {
+            let options = RepairOptions {
+                delete_extra,
+                limit,
+                dry_run: args.dry_run,
+            };
+            return run_repair(&config_file, options, json).await;
+        }
+        Some(Command::Backfill { restart, json }) => {
+            return run_backfill(&config_file, restart, json, args.dry_run).await
+        }
+        Some(Command::Mongo2couch) => return run_mongo2couch(&config_file, args.dry_run).await,
+        None => {}
+    }
+
+    let pipeline_settings = match Settings::load_pipelines(Some(config_file.to_string())) {
+        Ok(pipeline_settings) => pipeline_settings,
         Err(e) => {
             panic!("unable to load config: {}", e);
         }
+    };
+
+    // Logging and metrics are for the whole process, so come from the top-level settings every
+    // pipeline shares
+    let _log_guard = pipeline_settings[0].configure_logging()?;
+    if let Some(metrics_settings) = &pipeline_settings[0].metrics {
+        metrics::start(metrics_settings)?;
     }
 
-    let unwrapped_settings = s.unwrap();
-    unwrapped_settings.configure_logging();
+    let mut pipelines = vec![];
+    for mut settings in pipeline_settings {
+        settings.dry_run |= args.dry_run;
+        if args.once {
+            settings.run_mode = RunMode::Catchup;
+        }
+        if args.until_seq.is_some() {
+            settings.until_seq = args.until_seq.clone();
+        }
+        if args.until_time.is_some() {
+            settings.until_time = args.until_time.clone();
+        }
 
-    let sequence_store = unwrapped_settings.get_sequence_store().await?;
-    let mut current_sequence = sequence_store
-        .get(&unwrapped_settings.get_sequence_store_key())
-        .await?;
+        let name = settings.get_pipeline_name();
+        let span = info_span!("pipeline", name = name.as_str());
+        pipelines.push((
+            span.clone(),
+            start_pipeline(settings).instrument(span).await?,
+        ));
+    }
+    // Everything is connected and the checkpoints are valid
+    systemd::notify("READY=1\nSTATUS=following the changes feed");
 
-    let db = unwrapped_settings.get_couchdb_database().await?;
+    // Stop cleanly on Ctrl-C or SIGTERM, once the change in hand has been applied
+    let shutdowns: Vec<ShutdownHandle> = pipelines
+        .iter()
+        .map(|(_, pipeline)| pipeline.shutdown_handle())
+        .collect();
+    let signalled = shutdowns.clone();
+    let mut terminate = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?;
+    tokio::spawn(async move {
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {},
+            _ = terminate.recv() => {},
+        }
+        systemd::notify("STOPPING=1");
+        for shutdown in &signalled {
+            shutdown.shutdown();
+        }
+    });
 
-    let mut changes = db.changes(current_sequence.clone().map(serde_json::Value::String));
-    changes.set_infinite(true);
+    let runs = pipelines.into_iter().map(|(span, mut pipeline)| {
+        let shutdowns = shutdowns.clone();
+        async move {
+            while let Some(applied) = pipeline.next().await {
+                if let Err(e) = applied {
+                    // Stop the other pipelines cleanly rather than abandoning them mid-batch
+                    for shutdown in &shutdowns {
+                        shutdown.shutdown();
+                    }
+                    return Err(e);
+                }
+            }
+            Ok(())
+        }
+        .instrument(span)
+    });
 
-    let db = unwrapped_settings.get_mongodb_database().await?;
+    join_all(runs)
+        .await
+        .into_iter()
+        .collect::<Result<Vec<()>, _>>()?;
 
-    let upsert_options = ReplaceOptions::builder().upsert(true).build();
+    Ok(())
+}
 
-    while let Some(change) = changes.next().await {
-        let change_event = change.unwrap();
+/// start_pipeline resolves a pipeline's secrets, publishes its target info, starts its admin API,
+/// if configured, then connects it.
+async fn start_pipeline(mut settings: Settings) -> Result<Pipeline, Box<dyn Error>> {
+    settings.resolve_secrets().await?;
 
-        // Always test to see if the underlying store changed beneath us
-        let test_current_sequence = sequence_store
-            .get(&unwrapped_settings.get_sequence_store_key())
-            .await?;
+    let config_hash = settings.config_fingerprint();
+    info!(config_hash = config_hash.as_str(), "configuration loaded");
+    metrics::set_target_info(
+        &settings.get_pipeline_name(),
+        &settings.source_database,
+        &settings.mongodb_database,
+        &config_hash,
+    );
 
-        // compare test_current_sequence to current_sequence
-        if test_current_sequence != current_sequence {
-            panic!(
-                "sequence mismatch: {:?} != {:?}",
-                test_current_sequence, current_sequence
-            );
-        }
+    // The admin API is started first, so a standby can be promoted through it
+    let (control, paused, flush_requests) = Control::new();
+    if let Some(admin_settings) = &settings.admin {
+        admin::start(admin_settings, control.clone(), settings.redacted()?)?;
+    }
 
-        debug!(
-            id = change_event.id.as_str(),
-            seq = change_event.seq.as_str()
-        );
+    Pipeline::new(settings, control, paused, flush_requests).await
+}
 
-        if change_event.is_design_document() {
-            info!(
-                id = change_event.id.as_str(),
-                seq = change_event.seq.as_str(),
-                "design document"
-            );
-            continue;
-        }
+/// load_settings loads a config file and resolves any secrets it refers to.
+async fn load_settings(config_file: &str) -> Result<Settings, Box<dyn Error>> {
+    let mut settings = Settings::new(Some(config_file.to_string()))?;
+    settings.resolve_secrets().await?;
 
-        let couch_document = change_event.doc.unwrap();
-        let bson_value = bson::to_bson(&couch_document).unwrap();
-        let bson_document = bson_value.as_document().unwrap();
+    Ok(settings)
+}
 
-        let document_id = bson::doc! { "_id": bson_document.get("_id").unwrap() };
+/// run_mongo2couch follows the MongoDB change stream, writing changes back to CouchDB until
+/// stopped with Ctrl-C or SIGTERM.
+async fn run_mongo2couch(config_file: &str, dry_run: bool) -> Result<(), Box<dyn Error>> {
+    let mut settings = Settings::new(Some(config_file.to_string()))?;
+    settings.dry_run |= dry_run;
+    let _log_guard = settings.configure_logging()?;
+    settings.resolve_secrets().await?;
 
-        let collection =
-            db.collection::<Document>(collection_name(&unwrapped_settings, bson_document).as_str());
+    if let Some(metrics_settings) = &settings.metrics {
+        metrics::start(metrics_settings)?;
+    }
 
-        if bson_document.get("_deleted").is_some() {
-            info!(
-                id = change_event.id.as_str(),
-                seq = change_event.seq.as_str(),
-                collection = collection.name(),
-                "deleting document",
-            );
-            collection.delete_one(document_id, None).await?;
-            continue;
+    let mut mongo2couch = Mongo2Couch::new(&settings).await?;
+
+    let shutdown = Arc::new(Notify::new());
+    let notify = shutdown.clone();
+    let mut terminate = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?;
+    tokio::spawn(async move {
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {},
+            _ = terminate.recv() => {},
         }
+        notify.notify_one();
+    });
 
-        info!(
-            id = change_event.id.as_str(),
-            seq = change_event.seq.as_str(),
-            collection = collection.name(),
-            "replacing document",
-        );
+    mongo2couch.run(shutdown).await
+}
 
-        let result = collection
-            .replace_one(
-                document_id,
-                bson_document.clone(),
-                Some(upsert_options.clone()),
-            )
-            .await?;
+/// run_seq gets or changes the stored sequence through the configured sequence store.
+///
+/// A running replicator notices the sequence has been moved at its next checkpoint and stops, so
+/// restart it afterwards to pick up from the new sequence.
+async fn run_seq(
+    config_file: &str,
+    action: SeqAction,
+    dry_run: bool,
+) -> Result<(), Box<dyn Error>> {
+    let mut settings = load_settings(config_file).await?;
+    // dry_run in the config is for replication; only an explicit --dry-run applies here
+    settings.dry_run = dry_run;
 
-        if result.upserted_id.is_some() {
-            info!(
-                id = change_event.id.as_str(),
-                seq = change_event.seq.as_str(),
-                collection = collection.name(),
-                "document inserted",
-            );
-        };
+    let store = settings.get_sequence_store().await?;
+    let key = settings.get_sequence_store_key();
 
-        sequence_store
-            .set(
-                &unwrapped_settings.get_sequence_store_key(),
-                change_event.seq.as_str().unwrap(),
+    let value = match action {
+        SeqAction::Get => {
+            match store.get(&key).await? {
+                Some(sequence) => println!("{}", sequence),
+                None => println!("no sequence stored under {}", key),
+            }
+            return Ok(());
+        }
+        SeqAction::Set { value, force } => {
+            let status = preflight::validate_checkpoint(
+                &settings.get_couchdb_connection().await?,
+                &settings.source_database,
+                &value,
             )
             .await?;
 
-        current_sequence = Some(change_event.seq.as_str().unwrap().to_string());
+            if !status.is_valid() && !force {
+                return Err(format!("{}; use --force to store it anyway", status).into());
+            }
+            value
+        }
+        SeqAction::Reset => "0".to_string(),
+    };
+
+    let previous = store.get(&key).await?;
+    store.set(&key, &value).await?;
+    println!(
+        "{}: {} -> {}",
+        key,
+        previous.as_deref().unwrap_or("(none)"),
+        value
+    );
+
+    Ok(())
+}
+
+/// run_verify prints the differences between CouchDB and MongoDB, exiting non-zero if there are
+/// any.
+async fn run_verify(config_file: &str, json: bool) -> Result<(), Box<dyn Error>> {
+    let settings = load_settings(config_file).await?;
+    let report = Verifier::new(&settings).await?.run().await?;
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        println!("{}", report);
+    }
+
+    if !report.is_consistent() {
+        std::process::exit(1);
     }
 
     Ok(())
 }
 
-/// Returns the collection name to use for the document.
-///
-/// If the `mongodb_collection_field` setting is set, then the value of that field is used as the
-/// collection name. If the `mongodb_collection` setting is set, then the value of that setting is
-/// used as the collection name. If neither setting is set, then the value of the `source_database`
-/// setting is used as the collection name.
-///
-/// # Arguments
-///
-/// * `unwrapped_settings` - The settings object.
-/// * `bson_document` - The BSON document.
-///
-/// # Returns
-///
-/// * `String` - The collection name to use.
-fn collection_name(unwrapped_settings: &Settings, bson_document: &Document) -> String {
-    let c = match unwrapped_settings.mongodb_collection {
-        Some(ref collection) => collection.as_str(),
-        None => unwrapped_settings.source_database.as_str(),
+/// run_repair verifies, repairs what it can, and prints both the report and the repairs.
+async fn run_repair(
+    config_file: &str,
+    options: RepairOptions,
+    json: bool,
+) -> Result<(), Box<dyn Error>> {
+    let settings = load_settings(config_file).await?;
+    let verifier = Verifier::new(&settings).await?;
+    let report = verifier.run().await?;
+    let repairs = verifier.repair(&report, &options).await?;
+
+    if json {
+        let output = serde_json::json!({ "report": report, "repairs": repairs });
+        println!("{}", serde_json::to_string_pretty(&output)?);
+    } else {
+        println!(
+            "{}
+
+{}",
+            report, repairs
+        );
+    }
+
+    Ok(())
+}
+
+/// run_backfill copies every document, resuming a backfill that was interrupted unless restarting,
+/// and prints what was copied.
+async fn run_backfill(
+    config_file: &str,
+    restart: bool,
+    json: bool,
+    dry_run: bool,
+) -> Result<(), Box<dyn Error>> {
+    let mut settings = load_settings(config_file).await?;
+    // dry_run in the config is for replication; only an explicit --dry-run applies here
+    settings.dry_run = dry_run;
+
+    let report = Backfill::new(&settings).await?.run(restart).await?;
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        println!("{}", report);
+    }
+
+    Ok(())
+}
+
+/// run_status prints the replication status, as a table or JSON.
+async fn run_status(config_file: &str, json: bool) -> Result<(), Box<dyn Error>> {
+    let settings = load_settings(config_file).await?;
+    let status = status::collect(&settings).await?;
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&status)?);
+    } else {
+        println!("{}", status);
+    }
+
+    Ok(())
+}
+
+/// run_check prints the result of each check, exiting non-zero if any failed.
+async fn run_check(config_file: &str) -> Result<(), Box<dyn Error>> {
+    let settings = match load_settings(config_file).await {
+        Ok(settings) => settings,
+        Err(e) => {
+            println!(
+                "FAIL  {:<15} unable to load {}: {}",
+                "config", config_file, e
+            );
+            std::process::exit(1);
+        }
     };
 
-    match unwrapped_settings.mongodb_collection_field {
-        Some(ref field) => match bson_document.get(field) {
-            Some(_) => bson_document.get(field).unwrap().as_str().unwrap(),
-            None => c,
-        },
-        None => match unwrapped_settings.mongodb_collection {
-            Some(ref collection) => collection.as_str(),
-            None => c,
-        },
+    let results = check::run(&settings).await;
+    for result in &results {
+        println!("{}", result);
+    }
+
+    if !results.iter().all(|r| r.is_ok()) {
+        std::process::exit(1);
     }
-    .to_string()
+
+    Ok(())
 }
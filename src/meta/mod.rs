@@ -0,0 +1,175 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::seqstore::interface::SequenceStore;
+use bson::{doc, Bson, DateTime, Document};
+use futures_util::TryStreamExt;
+use mongodb::options::UpdateOptions;
+use mongodb::Database;
+use std::error::Error;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// MetaManifest maintains a document per stream in a meta collection of the target database,
+/// describing where the data came from and how it was shaped on the way: routing rules, transform
+/// settings, indexes and the last applied sequence.
+///
+/// Downstream teams can query it instead of reading our configuration. The configuration part is
+/// written at startup, noting when it last changed; the rest is refreshed periodically.
+pub struct MetaManifest {
+    db: Database,
+    collection: String,
+    id: String,
+    manifest: Document,
+    store: Arc<dyn SequenceStore>,
+    sequence_key: String,
+    refresh_interval: Duration,
+}
+
+impl MetaManifest {
+    /// new creates a new MetaManifest.
+    ///
+    /// # Arguments
+    /// * `db` - The MongoDB database
+    /// * `collection` - The meta collection name
+    /// * `sequence_key` - The checkpoint key, which identifies the stream
+    /// * `manifest` - The description of the stream's configuration; must include `config_hash`
+    /// * `store` - The shared sequence store
+    /// * `refresh_interval` - How often to refresh the last applied sequence and indexes
+    ///
+    /// # Returns
+    /// * A shared MetaManifest
+    pub fn new(
+        db: Database,
+        collection: &str,
+        sequence_key: &str,
+        manifest: Document,
+        store: Arc<dyn SequenceStore>,
+        refresh_interval: Duration,
+    ) -> Arc<MetaManifest> {
+        Arc::new(MetaManifest {
+            db,
+            collection: collection.to_string(),
+            id: sequence_key.to_string(),
+            manifest,
+            store,
+            sequence_key: sequence_key.to_string(),
+            refresh_interval,
+        })
+    }
+
+    /// start publishes the configuration, then keeps the rest of the document refreshed in the
+    /// background.
+    pub async fn start(self: &Arc<Self>) -> Result<(), Box<dyn Error>> {
+        self.publish_config().await?;
+        self.refresh().await.map_err(|e| e as Box<dyn Error>)?;
+
+        let meta = self.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(meta.refresh_interval).await;
+
+                if let Err(e) = meta.refresh().await.map_err(|e| e.to_string()) {
+                    warn!(error = e, "unable to refresh meta collection");
+                }
+            }
+        });
+
+        Ok(())
+    }
+
+    /// publish_config writes the configuration, recording when it changed.
+    async fn publish_config(&self) -> Result<(), Box<dyn Error>> {
+        let collection = self.db.collection::<Document>(&self.collection);
+        let previous = collection.find_one(doc! { "_id": &self.id }, None).await?;
+
+        let previous_hash = previous
+            .as_ref()
+            .and_then(|d| d.get_str("config_hash").ok());
+        let config_hash = self.manifest.get_str("config_hash").ok();
+
+        let mut set = self.manifest.clone();
+        let now = DateTime::now();
+        set.insert("started_at", now);
+        if previous_hash != config_hash {
+            info!(
+                collection = self.collection.as_str(),
+                previous = previous_hash,
+                current = config_hash,
+                "configuration changed"
+            );
+            set.insert("config_changed_at", now);
+        }
+
+        collection
+            .update_one(
+                doc! { "_id": &self.id },
+                doc! { "$set": set },
+                UpdateOptions::builder().upsert(true).build(),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    /// refresh records the last applied sequence and the indexes on every collection.
+    async fn refresh(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let last_applied_seq = self
+            .store
+            .get(&self.sequence_key)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        let mut indexes = Document::new();
+        for name in self.db.list_collection_names(None).await? {
+            if name == self.collection {
+                continue;
+            }
+
+            let definitions: Vec<Bson> = self
+                .db
+                .collection::<Document>(&name)
+                .list_indexes(None)
+                .await?
+                .try_collect::<Vec<_>>()
+                .await?
+                .into_iter()
+                .map(|index| {
+                    Bson::Document(doc! {
+                        "name": index.options.and_then(|o| o.name),
+                        "keys": index.keys,
+                    })
+                })
+                .collect();
+
+            indexes.insert(name, definitions);
+        }
+
+        self.db
+            .collection::<Document>(&self.collection)
+            .update_one(
+                doc! { "_id": &self.id },
+                doc! { "$set": {
+                    "last_applied_seq": last_applied_seq,
+                    "indexes": indexes,
+                    "updated_at": DateTime::now(),
+                } },
+                UpdateOptions::builder().upsert(true).build(),
+            )
+            .await?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,285 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::settings::config_parser::MetricsSettings;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, Server, StatusCode};
+use lazy_static::lazy_static;
+use prometheus::{
+    register_int_counter_vec,
+    register_int_gauge_vec,
+    Encoder,
+    IntCounterVec,
+    IntGaugeVec,
+    TextEncoder,
+};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::error::Error;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use tracing::{error, info};
+
+lazy_static! {
+    /// Total bytes written to MongoDB, by collection and operation.
+    pub static ref MONGODB_BYTES_WRITTEN: IntCounterVec = register_int_counter_vec!(
+        "couch2mongo_mongodb_bytes_written_total",
+        "Bytes written to MongoDB by collection and operation",
+        &["collection", "operation"]
+    )
+    .unwrap();
+
+    /// Bytes written to MongoDB during the last reporting interval, by collection.
+    pub static ref MONGODB_BYTES_WRITTEN_INTERVAL: IntGaugeVec = register_int_gauge_vec!(
+        "couch2mongo_mongodb_bytes_written_interval",
+        "Bytes written to MongoDB by collection during the last reporting interval",
+        &["collection"]
+    )
+    .unwrap();
+
+    /// Constant metric describing this replicator, labelled with a fingerprint of its config.
+    pub static ref TARGET_INFO: IntGaugeVec = register_int_gauge_vec!(
+        "couch2mongo_target_info",
+        "Replicator target information, labelled with a fingerprint of the effective configuration",
+        &["pipeline", "source_database", "mongodb_database", "config_hash"]
+    )
+    .unwrap();
+
+    /// Changes applied, by pipeline, target collection and what was done with them.
+    pub static ref CHANGES_APPLIED: IntCounterVec = register_int_counter_vec!(
+        "couch2mongo_changes_applied_total",
+        "Changes applied by pipeline, target collection and operation",
+        &["pipeline", "collection", "operation"]
+    )
+    .unwrap();
+
+    /// Bytes written per collection since the last report.
+    static ref INTERVAL_BYTES: Mutex<HashMap<String, u64>> = Mutex::new(HashMap::new());
+
+    /// Changes applied per pipeline and collection since the last report.
+    static ref INTERVAL_CHANGES: Mutex<HashMap<(String, String), ChangeCounts>> =
+        Mutex::new(HashMap::new());
+}
+
+/// Total bytes written to MongoDB, for the catch-up throughput.
+static BYTES_WRITTEN: AtomicU64 = AtomicU64::new(0);
+
+/// ChangeCounts summarises the changes applied to a collection, for the periodic report.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ChangeCounts {
+    pub replaced: u64,
+    pub inserted: u64,
+    pub deleted: u64,
+    // Anything that didn't change the collection, eg. unchanged, stale or dead lettered
+    pub skipped: u64,
+}
+
+impl ChangeCounts {
+    /// add counts a change with the given operation.
+    pub fn add(&mut self, operation: &str) {
+        match operation {
+            "replaced" => self.replaced += 1,
+            "inserted" => self.inserted += 1,
+            "deleted" => self.deleted += 1,
+            _ => self.skipped += 1,
+        }
+    }
+}
+
+/// record_change counts a change a pipeline applied to a collection.
+///
+/// # Arguments
+/// * `pipeline` - The name of the pipeline
+/// * `collection` - The name of the target collection
+/// * `operation` - What was done, eg. "replaced", "deleted" or "unchanged"
+pub fn record_change(pipeline: &str, collection: &str, operation: &str) {
+    CHANGES_APPLIED
+        .with_label_values(&[pipeline, collection, operation])
+        .inc();
+
+    INTERVAL_CHANGES
+        .lock()
+        .expect("unable to lock interval changes")
+        .entry((pipeline.to_string(), collection.to_string()))
+        .or_default()
+        .add(operation);
+}
+
+/// record_bytes_written records the size of a write against a collection.
+///
+/// This is an approximation of the oplog impact of the write - the size of the BSON document that
+/// was sent, rather than the size of the oplog entry MongoDB generated.
+///
+/// # Arguments
+/// * `collection` - The name of the target collection
+/// * `operation` - The operation, eg. "replace" or "delete"
+/// * `bytes` - The size of the document written
+pub fn record_bytes_written(collection: &str, operation: &str, bytes: usize) {
+    MONGODB_BYTES_WRITTEN
+        .with_label_values(&[collection, operation])
+        .inc_by(bytes as u64);
+    BYTES_WRITTEN.fetch_add(bytes as u64, Ordering::Relaxed);
+
+    *INTERVAL_BYTES
+        .lock()
+        .expect("unable to lock interval bytes")
+        .entry(collection.to_string())
+        .or_insert(0) += bytes as u64;
+}
+
+/// bytes_written returns the total bytes written to MongoDB so far.
+pub fn bytes_written() -> u64 {
+    BYTES_WRITTEN.load(Ordering::Relaxed)
+}
+
+/// set_target_info publishes the target info metric for a pipeline.
+///
+/// # Arguments
+/// * `pipeline` - The name of the pipeline
+/// * `source_database` - The CouchDB database being replicated
+/// * `mongodb_database` - The MongoDB database being written to
+/// * `config_hash` - A fingerprint of the effective routing/transform configuration
+pub fn set_target_info(
+    pipeline: &str,
+    source_database: &str,
+    mongodb_database: &str,
+    config_hash: &str,
+) {
+    TARGET_INFO
+        .with_label_values(&[pipeline, source_database, mongodb_database, config_hash])
+        .set(1);
+}
+
+/// start starts the metrics HTTP server (if a listen address is configured) and the periodic
+/// per-collection write and change report.
+///
+/// # Arguments
+/// * `settings` - A MetricsSettings struct
+pub fn start(settings: &MetricsSettings) -> Result<(), Box<dyn Error>> {
+    if let Some(address) = &settings.listen_address {
+        let address: SocketAddr = address.parse()?;
+        tokio::spawn(serve(address));
+    }
+
+    tokio::spawn(report(settings.report_interval));
+
+    Ok(())
+}
+
+/// serve exposes the default Prometheus registry on `/metrics`.
+async fn serve(address: SocketAddr) {
+    info!(address = address.to_string(), "starting metrics server");
+
+    let make_service =
+        make_service_fn(|_conn| async { Ok::<_, Infallible>(service_fn(handle_request)) });
+
+    if let Err(e) = Server::bind(&address).serve(make_service).await {
+        error!(error = e.to_string(), "metrics server failed");
+    }
+}
+
+async fn handle_request(request: Request<Body>) -> Result<Response<Body>, Infallible> {
+    if request.uri().path() != "/metrics" {
+        let mut not_found = Response::new(Body::from("not found"));
+        *not_found.status_mut() = StatusCode::NOT_FOUND;
+        return Ok(not_found);
+    }
+
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+    encoder
+        .encode(&prometheus::gather(), &mut buffer)
+        .expect("unable to encode metrics");
+
+    Ok(Response::new(Body::from(buffer)))
+}
+
+/// report logs, and publishes as gauges, the bytes written per collection every interval, and logs
+/// a summary of the changes applied to each collection.
+async fn report(interval_secs: u64) {
+    let period = tokio::time::Duration::from_secs(interval_secs);
+    let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+
+    loop {
+        interval.tick().await;
+
+        let written = std::mem::take(
+            &mut *INTERVAL_BYTES
+                .lock()
+                .expect("unable to lock interval bytes"),
+        );
+
+        MONGODB_BYTES_WRITTEN_INTERVAL.reset();
+        for (collection, bytes) in written {
+            MONGODB_BYTES_WRITTEN_INTERVAL
+                .with_label_values(&[collection.as_str()])
+                .set(bytes as i64);
+
+            info!(
+                collection = collection.as_str(),
+                bytes, interval_secs, "bytes written to collection"
+            );
+        }
+
+        let changes = std::mem::take(
+            &mut *INTERVAL_CHANGES
+                .lock()
+                .expect("unable to lock interval changes"),
+        );
+
+        for ((pipeline, collection), counts) in changes {
+            info!(
+                pipeline = pipeline.as_str(),
+                collection = collection.as_str(),
+                replaced = counts.replaced,
+                inserted = counts.inserted,
+                deleted = counts.deleted,
+                skipped = counts.skipped,
+                interval_secs,
+                "changes applied to collection"
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_change_counts() {
+        let mut counts = ChangeCounts::default();
+        for operation in [
+            "replaced",
+            "inserted",
+            "inserted",
+            "deleted",
+            "unchanged",
+            "stale",
+        ] {
+            counts.add(operation);
+        }
+
+        assert_eq!(
+            counts,
+            ChangeCounts {
+                replaced: 1,
+                inserted: 2,
+                deleted: 1,
+                skipped: 2,
+            }
+        );
+    }
+}
@@ -0,0 +1,475 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::checkpoint::Checkpointer;
+use crate::couchdb::CouchConnection;
+use crate::settings::config_parser::{
+    DeletionSettings,
+    DeletionStrategy,
+    Mongo2CouchSettings,
+    Settings,
+};
+use bson::{Bson, Document};
+use futures_util::StreamExt;
+use lazy_static::lazy_static;
+use mongodb::change_stream::event::{ChangeStreamEvent, OperationType, ResumeToken};
+use mongodb::options::{ChangeStreamOptions, FullDocumentType};
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use prometheus::{register_int_counter_vec, IntCounterVec};
+use reqwest::{Method, StatusCode};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::Arc;
+use tokio::sync::Notify;
+use tracing::{debug, info, warn};
+
+lazy_static! {
+    /// Changes from MongoDB applied to CouchDB, by collection and what was done.
+    pub static ref REVERSE_CHANGES: IntCounterVec = register_int_counter_vec!(
+        "couch2mongo_mongo2couch_changes_total",
+        "Changes from the MongoDB change stream applied to CouchDB",
+        &["collection", "operation"]
+    )
+    .unwrap();
+}
+
+/// Characters escaped in document IDs put into CouchDB paths.
+const ID_ESCAPE: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'.')
+    .remove(b'~');
+
+/// Mongo2Couch tails a MongoDB change stream and pushes each inserted, updated, replaced or
+/// deleted document back into CouchDB.
+///
+/// CouchDB needs the current `_rev` to update or delete a document, so each write fetches the
+/// document first and retries if it loses a race with another writer. Documents whose content
+/// already matches CouchDB are skipped, which is what stops changes written to MongoDB by the
+/// forward pipeline from bouncing straight back. The change stream's resume token is checkpointed
+/// in the sequence store, like the CouchDB sequence is in the forward direction.
+pub struct Mongo2Couch {
+    db: mongodb::Database,
+    connection: CouchConnection,
+    database: String,
+    settings: Mongo2CouchSettings,
+    excluded_collections: Vec<String>,
+    deletion: DeletionSettings,
+    ignore_fields: Vec<String>,
+    original_id_field: Option<String>,
+    checkpointer: Checkpointer,
+    resume_after: Option<ResumeToken>,
+    dry_run: bool,
+}
+
+/// ReverseOperation is what was done to CouchDB for a change.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ReverseOperation {
+    Written,
+    Deleted,
+    Unchanged,
+    Skipped,
+}
+
+impl ReverseOperation {
+    fn as_str(&self) -> &'static str {
+        match self {
+            ReverseOperation::Written => "written",
+            ReverseOperation::Deleted => "deleted",
+            ReverseOperation::Unchanged => "unchanged",
+            ReverseOperation::Skipped => "skipped",
+        }
+    }
+}
+
+impl Mongo2Couch {
+    /// new connects to MongoDB, CouchDB and the sequence store, and loads the stored resume token.
+    ///
+    /// # Arguments
+    /// * `settings` - A Settings struct
+    ///
+    /// # Returns
+    /// * A Mongo2Couch struct
+    pub async fn new(settings: &Settings) -> Result<Mongo2Couch, Box<dyn Error>> {
+        let reverse_settings = settings.mongo2couch.clone().unwrap_or_default();
+        let store = settings.get_sequence_store().await?;
+        let key = format!("{}:mongo2couch", settings.get_sequence_store_key());
+
+        let persisted = store.get(&key).await?;
+        let resume_after = persisted.as_deref().map(parse_resume_token).transpose()?;
+        match &persisted {
+            Some(token) => info!(token = token.as_str(), "resuming change stream"),
+            None => info!("no resume token stored, starting from the current time"),
+        }
+
+        let mut ignore_fields = vec!["_id".to_string(), "_rev".to_string()];
+        if let Some(hash_settings) = &settings.content_hash {
+            ignore_fields.push(hash_settings.field.clone());
+        }
+        ignore_fields.extend(settings.sync_metadata_fields());
+
+        Ok(Mongo2Couch {
+            db: settings.get_mongodb_database().await?,
+            connection: settings.get_couchdb_connection().await?,
+            database: reverse_settings
+                .target_database
+                .clone()
+                .unwrap_or(settings.source_database.clone()),
+            settings: reverse_settings,
+            excluded_collections: settings.internal_collections(),
+            deletion: settings.deletion.clone().unwrap_or_default(),
+            ignore_fields,
+            original_id_field: settings
+                .id_handling
+                .as_ref()
+                .map(|s| s.original_field.clone()),
+            checkpointer: Checkpointer::new(
+                store,
+                &key,
+                settings.checkpoint_interval_docs,
+                settings.checkpoint_interval_ms,
+                persisted,
+            ),
+            resume_after,
+            dry_run: settings.dry_run,
+        })
+    }
+
+    /// run follows the change stream until it fails or `shutdown` is notified, checkpointing the
+    /// resume token as changes are applied.
+    pub async fn run(&mut self, shutdown: Arc<Notify>) -> Result<(), Box<dyn Error>> {
+        let options = ChangeStreamOptions::builder()
+            .full_document(Some(FullDocumentType::UpdateLookup))
+            .resume_after(self.resume_after.clone())
+            .build();
+        let pipeline = vec![bson::doc! {
+            "$match": { "operationType": { "$in": ["insert", "update", "replace", "delete"] } }
+        }];
+
+        let mut stream = self.db.watch(pipeline, options).await?;
+        info!(
+            mongodb_database = self.db.name(),
+            couchdb_database = self.database.as_str(),
+            "following the MongoDB change stream"
+        );
+
+        loop {
+            let event = tokio::select! {
+                event = stream.next() => event,
+                _ = shutdown.notified() => break,
+            };
+
+            let event = match event {
+                Some(event) => event?,
+                None => break,
+            };
+
+            let token = format_resume_token(&event.id)?;
+            self.apply(event).await?;
+            self.checkpointer.advance(&token).await?;
+        }
+
+        self.checkpointer.flush().await
+    }
+
+    /// apply writes a single change stream event to CouchDB.
+    async fn apply(&self, event: ChangeStreamEvent<Document>) -> Result<(), Box<dyn Error>> {
+        let collection = match event.ns.as_ref().and_then(|ns| ns.coll.clone()) {
+            Some(collection) => collection,
+            None => return Ok(()),
+        };
+
+        if !self.is_replicated(&collection) {
+            return Ok(());
+        }
+
+        let operation = match event.operation_type {
+            OperationType::Delete => {
+                let id = event
+                    .document_key
+                    .as_ref()
+                    .and_then(|key| key.get("_id"))
+                    .and_then(couch_id);
+                match id {
+                    Some(id) => self.delete(&id).await?,
+                    None => ReverseOperation::Skipped,
+                }
+            }
+            // With update lookup, the document is missing if it was deleted since
+            _ => match &event.full_document {
+                // A soft deleted document is a deletion as far as CouchDB is concerned
+                Some(document)
+                    if self.deletion.strategy == DeletionStrategy::Soft
+                        && document.contains_key(&self.deletion.field) =>
+                {
+                    match document.get("_id").and_then(couch_id) {
+                        Some(id) => self.delete(&id).await?,
+                        None => ReverseOperation::Skipped,
+                    }
+                }
+                Some(document) => match self.to_couch(document) {
+                    Some((id, body)) => self.write(&id, body).await?,
+                    None => ReverseOperation::Skipped,
+                },
+                None => ReverseOperation::Skipped,
+            },
+        };
+
+        REVERSE_CHANGES
+            .with_label_values(&[collection.as_str(), operation.as_str()])
+            .inc();
+        Ok(())
+    }
+
+    /// is_replicated returns true if changes to a collection should be pushed to CouchDB.
+    fn is_replicated(&self, collection: &str) -> bool {
+        if collection.starts_with("system.")
+            || self.excluded_collections.iter().any(|c| c == collection)
+        {
+            return false;
+        }
+
+        if self.deletion.strategy == DeletionStrategy::Tombstone
+            && collection.ends_with(&self.deletion.tombstone_suffix)
+        {
+            return false;
+        }
+
+        self.settings.collections.is_empty()
+            || self.settings.collections.iter().any(|c| c == collection)
+    }
+
+    /// to_couch returns the CouchDB ID and body for a MongoDB document, or None if it has no
+    /// usable ID.
+    fn to_couch(&self, document: &Document) -> Option<(String, Value)> {
+        let id = self
+            .original_id_field
+            .as_ref()
+            .and_then(|field| document.get(field))
+            .or_else(|| document.get("_id"))
+            .and_then(couch_id);
+
+        let id = match id {
+            Some(id) => id,
+            None => {
+                warn!(
+                    id = format!("{:?}", document.get("_id")),
+                    "document id can't be used as a CouchDB id, skipping"
+                );
+                return None;
+            }
+        };
+
+        let mut body = document.clone();
+        for field in self.ignore_fields.iter().chain(&self.original_id_field) {
+            body.remove(field);
+        }
+
+        Some((id, Bson::Document(body).into_relaxed_extjson()))
+    }
+
+    /// current fetches a document from CouchDB, returning None if it doesn't exist.
+    async fn current(&self, id: &str) -> Result<Option<Value>, Box<dyn Error>> {
+        let request = self
+            .connection
+            .req(Method::GET, &self.document_path(id), None);
+        let response = self.connection.send(request, "document").await?;
+
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+
+        Ok(Some(response.error_for_status()?.json().await?))
+    }
+
+    /// write puts a document into CouchDB, on top of whatever revision is there now.
+    async fn write(&self, id: &str, body: Value) -> Result<ReverseOperation, Box<dyn Error>> {
+        for attempt in 1..=self.settings.conflict_attempts {
+            let current = self.current(id).await?;
+
+            if let Some(current) = &current {
+                if same_content(current, &body, &self.ignore_fields) {
+                    debug!(id, "document unchanged in CouchDB, skipping");
+                    return Ok(ReverseOperation::Unchanged);
+                }
+            }
+
+            if self.dry_run {
+                info!(id, "dry run, would write document to CouchDB");
+                return Ok(ReverseOperation::Written);
+            }
+
+            let mut body = body.clone();
+            if let Some(rev) = current.as_ref().and_then(|c| c.get("_rev")) {
+                body["_rev"] = rev.clone();
+            }
+
+            let request = self
+                .connection
+                .req(Method::PUT, &self.document_path(id), None)
+                .json(&body);
+            let response = self.connection.send(request, "document").await?;
+
+            if response.status() == StatusCode::CONFLICT {
+                debug!(
+                    id,
+                    attempt, "document changed in CouchDB while writing, retrying"
+                );
+                continue;
+            }
+
+            response.error_for_status()?;
+            info!(id, "wrote document to CouchDB");
+            return Ok(ReverseOperation::Written);
+        }
+
+        Err(format!("gave up writing {} to CouchDB after repeated conflicts", id).into())
+    }
+
+    /// delete deletes a document's current revision from CouchDB.
+    async fn delete(&self, id: &str) -> Result<ReverseOperation, Box<dyn Error>> {
+        for attempt in 1..=self.settings.conflict_attempts {
+            let rev = match self.current(id).await? {
+                Some(current) => match current.get("_rev").and_then(Value::as_str) {
+                    Some(rev) => rev.to_string(),
+                    None => return Ok(ReverseOperation::Unchanged),
+                },
+                None => return Ok(ReverseOperation::Unchanged),
+            };
+
+            if self.dry_run {
+                info!(id, "dry run, would delete document from CouchDB");
+                return Ok(ReverseOperation::Deleted);
+            }
+
+            let mut params = HashMap::new();
+            params.insert("rev".to_string(), rev);
+            let request =
+                self.connection
+                    .req(Method::DELETE, &self.document_path(id), Some(&params));
+            let response = self.connection.send(request, "document").await?;
+
+            match response.status() {
+                StatusCode::CONFLICT => {
+                    debug!(
+                        id,
+                        attempt, "document changed in CouchDB while deleting, retrying"
+                    );
+                    continue;
+                }
+                StatusCode::NOT_FOUND => return Ok(ReverseOperation::Unchanged),
+                _ => {}
+            }
+
+            response.error_for_status()?;
+            info!(id, "deleted document from CouchDB");
+            return Ok(ReverseOperation::Deleted);
+        }
+
+        Err(format!(
+            "gave up deleting {} from CouchDB after repeated conflicts",
+            id
+        )
+        .into())
+    }
+
+    fn document_path(&self, id: &str) -> String {
+        format!("{}/{}", self.database, utf8_percent_encode(id, ID_ESCAPE))
+    }
+}
+
+/// couch_id returns a MongoDB `_id` as a CouchDB document ID, if it's a string or number.
+/// Design and other underscore prefixed IDs are reserved by CouchDB, so aren't written back.
+fn couch_id(id: &Bson) -> Option<String> {
+    let id = match id {
+        Bson::String(s) => s.clone(),
+        Bson::Int32(i) => i.to_string(),
+        Bson::Int64(i) => i.to_string(),
+        _ => return None,
+    };
+
+    (!id.is_empty() && !id.starts_with('_')).then_some(id)
+}
+
+/// same_content returns true if two documents are equal, ignoring the given fields.
+pub fn same_content(current: &Value, incoming: &Value, ignore_fields: &[String]) -> bool {
+    let strip = |value: &Value| -> Value {
+        let mut value = value.clone();
+        if let Some(object) = value.as_object_mut() {
+            for field in ignore_fields {
+                object.remove(field);
+            }
+        }
+        value
+    };
+
+    strip(current) == strip(incoming)
+}
+
+/// format_resume_token returns a resume token as a string for the sequence store.
+pub fn format_resume_token(token: &ResumeToken) -> Result<String, Box<dyn Error>> {
+    Ok(bson::to_bson(token)?.into_relaxed_extjson().to_string())
+}
+
+/// parse_resume_token reads a resume token stored by `format_resume_token`.
+pub fn parse_resume_token(token: &str) -> Result<ResumeToken, Box<dyn Error>> {
+    let value: Value = serde_json::from_str(token)?;
+    Ok(bson::from_bson(Bson::try_from(value)?)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_couch_id() {
+        assert_eq!(
+            couch_id(&Bson::String("cat:tom".to_string())),
+            Some("cat:tom".to_string())
+        );
+        assert_eq!(couch_id(&Bson::Int32(42)), Some("42".to_string()));
+        assert_eq!(couch_id(&Bson::String("_design/cats".to_string())), None);
+        assert_eq!(couch_id(&Bson::Boolean(true)), None);
+    }
+
+    #[test]
+    fn test_same_content() {
+        let ignore = vec!["_id".to_string(), "_rev".to_string()];
+        let current = json!({ "_id": "cat:tom", "_rev": "2-b", "name": "Tom", "age": 3 });
+
+        assert!(same_content(
+            &current,
+            &json!({ "name": "Tom", "age": 3 }),
+            &ignore
+        ));
+        assert!(!same_content(
+            &current,
+            &json!({ "name": "Tom", "age": 4 }),
+            &ignore
+        ));
+    }
+
+    #[test]
+    fn test_resume_token_round_trip() {
+        let token: ResumeToken = bson::from_bson(Bson::Document(bson::doc! {
+            "_data": "8263F2A1B2000000012B022C0100296E5A1004"
+        }))
+        .unwrap();
+
+        let formatted = format_resume_token(&token).unwrap();
+        assert_eq!(parse_resume_token(&formatted).unwrap(), token);
+    }
+}
@@ -0,0 +1,1755 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::admin::{Control, FlushRequest};
+use crate::attachments::AttachmentStore;
+use crate::audit::AuditLog;
+use crate::backfill::cutover;
+use crate::batch::intent::IntentLog;
+use crate::batch::{self, DeleteBatch};
+use crate::checkpoint::Checkpointer;
+use crate::couchdb::changes::ChangesQueue;
+use crate::couchdb::db_updates::DbUpdatesWatcher;
+use crate::couchdb::{preflight, sequence_number, sequence_string};
+use crate::deadletter::DeadLetterQueue;
+use crate::document::revision;
+use crate::document::shard_key::ShardKeys;
+use crate::failover::Failover;
+use crate::filters::IdFilter;
+use crate::indexes::IndexManager;
+use crate::lag::LagMonitor;
+use crate::lock::LeaderLock;
+use crate::metrics;
+use crate::routing::databases::Databases;
+use crate::routing::CollectionRouter;
+use crate::seqstore::mongodb::is_duplicate_key;
+use crate::settings::config_parser::{
+    DeletionSettings,
+    DeletionStrategy,
+    OversizePolicy,
+    RunMode,
+    Settings,
+    SizeLimitSettings,
+};
+use crate::sink::interface::SinkMessage;
+use crate::sink::Sinks;
+use crate::systemd::{self, Watchdog};
+use crate::throttle::rate::RateLimiter;
+use crate::throttle::BackfillThrottle;
+use crate::transaction::{TransactionBatch, Write};
+use crate::transform;
+use crate::transform::id::IdOutcome;
+use crate::transform::keys::KeyOutcome;
+use crate::transform::size::OVERSIZED_DOCUMENTS;
+use crate::transform::tenant::Tenant;
+use crate::validation::SchemaValidator;
+use crate::vault::VaultCredentials;
+use crate::views::ViewIndexer;
+use crate::writer::mode::write_document;
+use crate::writer::{WriteJob, WriterPool};
+use bson::{doc, Bson, Document};
+use couch_rs::types::changes::ChangeEvent;
+use futures_util::io::Cursor;
+use futures_util::{Stream, TryStreamExt};
+use mongodb::options::{FindOneOptions, GridFsBucketOptions, ReplaceOptions};
+use mongodb::Collection;
+use std::collections::VecDeque;
+use std::error::Error;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::sync::{mpsc, watch, Notify};
+use tokio::time::Instant;
+use tracing::{debug, info, warn};
+
+/// ChangeEventDetails is a trait that provides some helper methods for
+/// ChangeEvent.
+trait ChangeEventDetails {
+    /// is_design_document returns true if the ChangeEvent is a design document.
+    fn is_design_document(&self) -> bool;
+}
+
+/// ChangeEventDetails is implemented for ChangeEvent.
+impl ChangeEventDetails for ChangeEvent {
+    /// is_design_document returns true if the ChangeEvent is a design document.
+    fn is_design_document(&self) -> bool {
+        self.id.starts_with("_design")
+    }
+}
+
+/// Operation is what was done to MongoDB for a change.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Operation {
+    // The document replaced an existing one
+    Replaced,
+    // The document didn't exist yet and was inserted
+    Inserted,
+    // The document was deleted
+    Deleted,
+    // The document was set aside in the dead letter collection
+    DeadLettered,
+    // MongoDB already had a newer revision of the document, so it was left alone
+    Stale,
+    // MongoDB already had the same content, so the write was skipped
+    Unchanged,
+    // The change was only published to the sinks, as write_mongodb is off
+    Published,
+    // The document was over the size limit, so it was skipped
+    Oversized,
+    // The document was deleted, but the deletion strategy is Ignore
+    Ignored,
+    // The document already existed and write_mode is InsertOnly, so it was left alone
+    Existing,
+}
+
+impl Operation {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Operation::Replaced => "replaced",
+            Operation::Inserted => "inserted",
+            Operation::Deleted => "deleted",
+            Operation::DeadLettered => "dead_lettered",
+            Operation::Stale => "stale",
+            Operation::Unchanged => "unchanged",
+            Operation::Published => "published",
+            Operation::Oversized => "oversized",
+            Operation::Ignored => "ignored",
+            Operation::Existing => "existing",
+        }
+    }
+}
+
+/// AppliedChange describes a change from CouchDB that has been applied to MongoDB.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AppliedChange {
+    pub id: String,
+    pub seq: String,
+    pub collection: String,
+    pub operation: Operation,
+}
+
+/// ShutdownHandle asks a running Pipeline to stop.
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    notify: Arc<Notify>,
+}
+
+impl ShutdownHandle {
+    /// shutdown stops the pipeline once the change in hand has been applied. Pending deletions
+    /// and the checkpoint are flushed, then the pipeline ends.
+    pub fn shutdown(&self) {
+        self.notify.notify_one();
+    }
+}
+
+/// Pipeline follows the CouchDB changes feed and applies each change to MongoDB.
+///
+/// Call `next` repeatedly, or turn it into a Stream with `into_stream`, to drive it. Each item is a
+/// change that has been written to MongoDB. Deletions are batched, so they're yielded once their
+/// batch has been applied. An error is fatal; the pipeline shouldn't be driven any further.
+pub struct Pipeline {
+    name: String,
+    settings: Settings,
+    changes: ChangesQueue,
+    db: mongodb::Database,
+    databases: Databases,
+    id_filter: IdFilter,
+    router: CollectionRouter,
+    deletes: DeleteBatch,
+    queued_deletes: Vec<AppliedChange>,
+    transaction: Option<TransactionBatch>,
+    queued_writes: Vec<AppliedChange>,
+    checkpointer: Checkpointer,
+    failover: Option<Arc<Failover>>,
+    leader_lock: Option<Arc<LeaderLock>>,
+    db_updates: Option<Arc<DbUpdatesWatcher>>,
+    backfill_throttle: Option<Arc<BackfillThrottle>>,
+    doc_rate_limiter: Option<RateLimiter>,
+    mongo_ops_rate_limiter: Option<RateLimiter>,
+    lag_monitor: Option<Arc<LagMonitor>>,
+    dead_letters: DeadLetterQueue,
+    audit: Option<AuditLog>,
+    attachments: Option<AttachmentStore>,
+    view_indexer: Option<ViewIndexer>,
+    validator: Option<SchemaValidator>,
+    tenant: Option<Tenant>,
+    indexes: Option<IndexManager>,
+    shard_keys: ShardKeys,
+    writers: Option<WriterPool>,
+    sinks: Arc<Sinks>,
+    mongodb_credentials: Option<Arc<VaultCredentials>>,
+    mongodb_generation: u64,
+    ready: VecDeque<AppliedChange>,
+    shutdown: Arc<Notify>,
+    control: Arc<Control>,
+    paused: watch::Receiver<bool>,
+    flush_requests: mpsc::Receiver<FlushRequest>,
+    until_seq: Option<u64>,
+    until_time: Option<Instant>,
+    next_sequence_check: Option<Instant>,
+    watchdog: Option<Watchdog>,
+    finished: bool,
+}
+
+impl Pipeline {
+    /// new connects to everything, recovers from any interrupted batch and validates the
+    /// checkpoint, ready to follow the feed.
+    ///
+    /// When failover is configured as a standby, this waits until we're promoted. With leader
+    /// election, it waits until we're leader.
+    ///
+    /// # Arguments
+    /// * `settings` - A Settings struct
+    /// * `control` - A Control, with the receiving ends `Control::new` returned; it's made first so
+    ///   the admin API can be serving, eg. to promote a standby, while this waits
+    ///
+    /// # Returns
+    /// * A Pipeline struct
+    pub async fn new(
+        settings: Settings,
+        control: Arc<Control>,
+        paused: watch::Receiver<bool>,
+        flush_requests: mpsc::Receiver<FlushRequest>,
+    ) -> Result<Pipeline, Box<dyn Error>> {
+        let sequence_store = settings.get_sequence_store().await?;
+        settings
+            .probe_sequence_store(sequence_store.as_ref())
+            .await?;
+
+        if settings.dry_run {
+            warn!("dry run, nothing will be written to MongoDB or the sequence store");
+        }
+
+        let failover = match &settings.failover {
+            Some(_) if settings.dry_run => {
+                info!("dry run, not taking part in failover");
+                None
+            }
+            Some(failover_settings) => {
+                let failover = Failover::new(
+                    failover_settings,
+                    sequence_store.clone(),
+                    &settings.get_sequence_store_key(),
+                );
+                control.set_failover(failover.clone());
+
+                let promoter = failover.clone();
+                let mut promote_signal =
+                    tokio::signal::unix::signal(tokio::signal::unix::SignalKind::user_defined1())?;
+                tokio::spawn(async move {
+                    while promote_signal.recv().await.is_some() {
+                        promoter.promote();
+                    }
+                });
+
+                failover.start().await?;
+                Some(failover)
+            }
+            None => None,
+        };
+
+        let leader_lock = match &settings.leader_election {
+            Some(_) if settings.dry_run => {
+                info!("dry run, not taking part in leader election");
+                None
+            }
+            Some(leader_election_settings) => {
+                let leader_lock = LeaderLock::new(
+                    leader_election_settings,
+                    sequence_store.clone(),
+                    &settings.get_sequence_store_key(),
+                );
+                leader_lock.acquire().await?;
+                Some(leader_lock)
+            }
+            None => None,
+        };
+
+        if settings
+            .backfill
+            .as_ref()
+            .is_some_and(|b| b.before_streaming)
+        {
+            cutover::run(&settings, sequence_store.clone()).await?;
+        }
+
+        let current_sequence = sequence_store
+            .get(&settings.get_sequence_store_key())
+            .await?;
+
+        if let Some(sequence) = &current_sequence {
+            Pipeline::validate_checkpoint(&settings, sequence).await?;
+        }
+
+        let db_updates = if settings.watch_db_updates {
+            let watcher = DbUpdatesWatcher::new(
+                settings.get_couchdb_connection().await?,
+                &settings.source_database,
+                sequence_store.clone(),
+                &settings.get_sequence_store_key(),
+            );
+            watcher.start();
+            Some(watcher)
+        } else {
+            None
+        };
+
+        let mongodb_credentials = settings.get_mongodb_credentials().await?;
+        let mongodb_generation = mongodb_credentials.as_ref().map_or(0, |c| c.generation());
+        let databases = settings.get_mongodb_databases().await?;
+        let db = databases.default().clone();
+
+        // Finish off any batch we were part way through applying before picking up the feed
+        let deletion = settings.deletion.clone().unwrap_or_default();
+        let mut deletes = DeleteBatch::new();
+        deletes.set_deletion(deletion.clone());
+        let current_sequence = if settings.intent_log && settings.dry_run {
+            info!("dry run, not recovering any interrupted delete batch");
+            current_sequence
+        } else if settings.intent_log {
+            let intent_log =
+                IntentLog::new(sequence_store.clone(), &settings.get_sequence_store_key());
+            let recovered = batch::recover(&databases, &intent_log, &deletion).await?;
+            deletes.set_intent_log(intent_log);
+
+            match recovered {
+                Some(seq) => {
+                    sequence_store
+                        .set(&settings.get_sequence_store_key(), &seq)
+                        .await?;
+                    Some(seq)
+                }
+                None => current_sequence,
+            }
+        } else {
+            current_sequence
+        };
+
+        let until_seq = settings.get_until_seq()?;
+        let until_time = settings.get_until_time()?.map(|time| {
+            let wait = time.duration_since(SystemTime::now()).unwrap_or_default();
+            info!(
+                until_time = settings.until_time.as_deref().unwrap_or_default(),
+                wait_secs = wait.as_secs(),
+                "stopping at until_time"
+            );
+            Instant::now() + wait
+        });
+
+        if settings.run_mode == RunMode::Catchup {
+            let update_seq = settings
+                .get_couchdb_connection()
+                .await?
+                .update_seq(&settings.source_database)
+                .await?;
+            info!(
+                update_seq = update_seq.as_str(),
+                "catching up to the current update sequence, then stopping"
+            );
+        }
+
+        let changes = Pipeline::open_changes(&settings, current_sequence.clone()).await?;
+
+        let id_filter = settings.get_id_filter()?;
+        let router = settings.get_collection_router()?;
+
+        settings.get_scheduler(&db)?.start();
+
+        if !settings.dry_run {
+            if let Some(meta) = settings.get_meta_manifest(&db, sequence_store.clone())? {
+                meta.start().await?;
+            }
+        }
+
+        let backfill_throttle = settings.get_backfill_throttle().await?;
+        if let Some(throttle) = &backfill_throttle {
+            throttle.start();
+        }
+
+        let (doc_rate_limiter, mongo_ops_rate_limiter) = settings.get_rate_limiters()?;
+
+        let lag_monitor = settings.get_lag_monitor(current_sequence.clone()).await?;
+        if let Some(monitor) = &lag_monitor {
+            monitor.start();
+            control.set_lag_monitor(monitor.clone());
+        }
+
+        let dead_letters = DeadLetterQueue::new(&db, &settings.dead_letter_collection);
+        let audit = settings.get_audit_log(&db)?;
+
+        let attachments = settings.get_attachment_store(&db).await?;
+        let view_indexer = settings.get_view_indexer(&db);
+        let validator = settings.get_schema_validator()?;
+        let tenant = settings.get_tenant()?;
+        let shard_keys = settings.get_shard_keys();
+        let mut indexes = settings.get_index_manager()?;
+        if let Some(indexes) = &mut indexes {
+            indexes.ensure_configured(&db).await?;
+        }
+
+        let sinks = Arc::new(settings.get_sinks().await?);
+        let writers = settings.get_writer_pool()?;
+
+        let transaction = if settings.dry_run || !settings.write_mongodb {
+            None
+        } else {
+            settings.get_transaction_batch(&db, current_sequence.clone())?
+        };
+
+        let mut checkpointer = Checkpointer::new(
+            sequence_store.clone(),
+            &settings.get_sequence_store_key(),
+            settings.checkpoint_interval_docs,
+            settings.checkpoint_interval_ms,
+            current_sequence,
+        );
+        checkpointer.set_sinks(sinks.clone());
+        checkpointer.set_mutation_policy(settings.sequence_mutation_policy.clone());
+        let next_sequence_check = settings
+            .get_sequence_check_interval()
+            .map(|i| Instant::now() + i);
+
+        Ok(Pipeline {
+            name: settings.get_pipeline_name(),
+            settings,
+            changes,
+            db,
+            databases,
+            id_filter,
+            router,
+            deletes,
+            queued_deletes: Vec::new(),
+            transaction,
+            queued_writes: Vec::new(),
+            checkpointer,
+            failover,
+            leader_lock,
+            db_updates,
+            backfill_throttle,
+            doc_rate_limiter,
+            mongo_ops_rate_limiter,
+            lag_monitor,
+            dead_letters,
+            audit,
+            attachments,
+            view_indexer,
+            validator,
+            tenant,
+            indexes,
+            shard_keys,
+            writers,
+            sinks,
+            mongodb_credentials,
+            mongodb_generation,
+            ready: VecDeque::new(),
+            shutdown: Arc::new(Notify::new()),
+            control,
+            paused,
+            flush_requests,
+            until_seq,
+            until_time,
+            next_sequence_check,
+            watchdog: Watchdog::from_env(),
+            finished: false,
+        })
+    }
+
+    /// validate_checkpoint returns an error if CouchDB can't resume the feed from `sequence`.
+    async fn validate_checkpoint(
+        settings: &Settings,
+        sequence: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        let status = preflight::validate_checkpoint(
+            &settings.get_couchdb_connection().await?,
+            &settings.source_database,
+            sequence,
+        )
+        .await?;
+
+        if !status.is_valid() {
+            return Err(format!(
+                "{} (database {}, checkpoint {}); reset the checkpoint to resync",
+                status, settings.source_database, sequence
+            )
+            .into());
+        }
+
+        Ok(())
+    }
+
+    /// open_changes starts reading the changes feed from `sequence`.
+    async fn open_changes(
+        settings: &Settings,
+        sequence: Option<String>,
+    ) -> Result<ChangesQueue, Box<dyn Error>> {
+        let mut changes = ChangesQueue::spawn(
+            settings
+                .get_changes_stream(sequence.map(serde_json::Value::String))
+                .await?,
+            settings.change_queue_size,
+            settings.get_changes_restart_backoff(),
+        );
+        if let Some(coalesce_settings) = &settings.coalesce {
+            changes.set_coalesce(coalesce_settings);
+        }
+
+        Ok(changes)
+    }
+
+    /// shutdown_handle returns a handle that can stop the pipeline from elsewhere.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle {
+            notify: self.shutdown.clone(),
+        }
+    }
+
+    /// control returns the pipeline's Control, for the admin API.
+    pub fn control(&self) -> Arc<Control> {
+        self.control.clone()
+    }
+
+    /// next applies changes until one has been written, and returns it.
+    ///
+    /// # Returns
+    /// * The next applied change, an error, or None once the feed has ended or the pipeline has
+    ///   been shut down
+    pub async fn next(&mut self) -> Option<Result<AppliedChange, Box<dyn Error>>> {
+        loop {
+            if let Some(applied) = self.ready.pop_front() {
+                systemd::record_activity();
+                self.control.record(&applied);
+                metrics::record_change(&self.name, &applied.collection, applied.operation.as_str());
+                if let Some(monitor) = &self.lag_monitor {
+                    monitor.record(&applied.seq);
+                }
+                if let Some(audit) = &self.audit {
+                    if let Err(e) = audit.record(&applied).await {
+                        return Some(Err(e));
+                    }
+                }
+                return Some(Ok(applied));
+            }
+
+            if self.finished {
+                return None;
+            }
+
+            if let Err(e) = self.collect_writes(false).await {
+                self.finished = true;
+                return Some(Err(e));
+            }
+            if !self.ready.is_empty() {
+                continue;
+            }
+
+            match self.step().await {
+                Ok(true) => {}
+                Ok(false) => {
+                    info!("shutting down");
+                    self.finished = true;
+
+                    if let Err(e) = self.flush().await {
+                        return Some(Err(e));
+                    }
+                }
+                Err(e) => {
+                    self.finished = true;
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+
+    /// into_stream turns the pipeline into a Stream of applied changes.
+    pub fn into_stream(self) -> impl Stream<Item = Result<AppliedChange, Box<dyn Error>>> {
+        futures_util::stream::unfold(self, |mut pipeline| async move {
+            pipeline.next().await.map(|item| (item, pipeline))
+        })
+    }
+
+    /// flush applies pending deletions and persists the checkpoint.
+    async fn flush(&mut self) -> Result<(), Box<dyn Error>> {
+        self.collect_writes(true).await?;
+        self.commit_transaction().await?;
+        self.flush_deletes().await?;
+        self.checkpointer.flush().await
+    }
+
+    /// commit_transaction commits the pending transaction, if there's one, along with its
+    /// checkpoint.
+    async fn commit_transaction(&mut self) -> Result<(), Box<dyn Error>> {
+        match &self.transaction {
+            Some(transaction) if !transaction.is_empty() => {}
+            _ => return Ok(()),
+        }
+
+        // Each write, plus the checkpoint
+        let ops = self.queued_writes.len() + 1;
+        if let Some(limiter) = &mut self.mongo_ops_rate_limiter {
+            limiter.acquire(ops).await;
+        }
+
+        // The transaction carries the checkpoint, so anything the sinks buffered must go first
+        self.sinks.flush().await?;
+
+        let transaction = self.transaction.as_mut().unwrap();
+        let operations = transaction.commit().await?;
+        for (mut applied, operation) in self.queued_writes.drain(..).zip(operations) {
+            applied.operation = operation;
+            self.ready.push_back(applied);
+        }
+
+        Ok(())
+    }
+
+    /// queue_write adds a write to the pending transaction, committing it once it's full.
+    async fn queue_write(
+        &mut self,
+        write: Write,
+        applied: AppliedChange,
+    ) -> Result<(), Box<dyn Error>> {
+        if let Some(transaction) = &mut self.transaction {
+            transaction.push(write, &applied.seq);
+            self.queued_writes.push(applied);
+
+            if transaction.is_full() {
+                self.commit_transaction().await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn flush_deletes(&mut self) -> Result<(), Box<dyn Error>> {
+        // Writes to the documents being deleted must land first
+        if !self.deletes.is_empty() {
+            self.collect_writes(true).await?;
+        }
+
+        self.limit_mongo_ops(self.deletes.collections()).await;
+        if let Some(seq) = self.deletes.flush(&self.databases).await? {
+            self.checkpointer.advance(&seq).await?;
+        }
+        self.ready.extend(self.queued_deletes.drain(..));
+
+        Ok(())
+    }
+
+    /// collect_writes picks up the writes the writer pool has finished, moving the checkpoint past
+    /// them.
+    ///
+    /// # Arguments
+    /// * `wait` - Wait for every write in progress, rather than only taking those already done
+    async fn collect_writes(&mut self, wait: bool) -> Result<(), Box<dyn Error>> {
+        let writers = match &mut self.writers {
+            Some(writers) => writers,
+            None => return Ok(()),
+        };
+
+        let (applied, checkpoint) = match wait {
+            true => writers.drain().await?,
+            false => writers.collect()?,
+        };
+        self.ready.extend(applied);
+        if let Some(seq) = checkpoint {
+            self.checkpointer.advance(&seq).await?;
+        }
+
+        Ok(())
+    }
+
+    /// advance moves the checkpoint to a change that's been dealt with. While writes from earlier
+    /// changes are still in progress, it moves once they're done instead.
+    async fn advance(&mut self, seq: &str) -> Result<(), Box<dyn Error>> {
+        match &mut self.writers {
+            Some(writers) if !writers.is_idle() => {
+                writers.skip(seq);
+                Ok(())
+            }
+            _ => self.checkpointer.advance(seq).await,
+        }
+    }
+
+    /// limit_mongo_ops waits until `n` more MongoDB write operations are allowed.
+    async fn limit_mongo_ops(&mut self, n: usize) {
+        if let (Some(limiter), true) = (&mut self.mongo_ops_rate_limiter, n > 0) {
+            limiter.acquire(n).await;
+        }
+    }
+
+    /// answer_flush flushes on behalf of the admin API, and tells it how that went.
+    async fn answer_flush(&mut self, request: FlushRequest) -> Result<(), Box<dyn Error>> {
+        let result = self.flush().await;
+        request
+            .send(result.as_ref().map(|_| ()).map_err(|e| e.to_string()))
+            .ok();
+
+        result
+    }
+
+    /// wait_while_paused flushes and then waits until the pipeline is resumed, answering flush
+    /// requests in the meantime.
+    ///
+    /// # Returns
+    /// * false if the pipeline was shut down while paused
+    async fn wait_while_paused(&mut self) -> Result<bool, Box<dyn Error>> {
+        self.flush().await?;
+        info!("paused");
+
+        loop {
+            let watchdog_due = watchdog_due(&self.watchdog);
+            let request = tokio::select! {
+                _ = self.paused.wait_for(|paused| !paused) => break,
+                Some(request) = self.flush_requests.recv() => Some(request),
+                _ = self.shutdown.notified() => return Ok(false),
+                // Nothing's read from the feed while paused, but we're not hung
+                _ = watchdog_due => None,
+            };
+
+            match request {
+                Some(request) => self.answer_flush(request).await?,
+                None => {
+                    systemd::record_activity();
+                    self.ping_watchdog();
+                }
+            }
+        }
+
+        info!("resumed");
+        Ok(true)
+    }
+
+    /// ping_watchdog pings the systemd watchdog, if it's enabled.
+    fn ping_watchdog(&mut self) {
+        if let Some(watchdog) = &mut self.watchdog {
+            watchdog.ping();
+        }
+    }
+
+    /// check_sequence checks whether the stored checkpoint has been moved, and when to next check.
+    async fn check_sequence(&mut self) -> Result<(), Box<dyn Error>> {
+        self.checkpointer.check().await?;
+        self.next_sequence_check = self
+            .settings
+            .get_sequence_check_interval()
+            .map(|i| Instant::now() + i);
+
+        Ok(())
+    }
+
+    /// seek_if_moved follows the feed from the stored checkpoint, if it was moved and is to be
+    /// adopted. Changes already taken from the feed are applied first, but not checkpointed.
+    async fn seek_if_moved(&mut self) -> Result<(), Box<dyn Error>> {
+        if !self.checkpointer.is_moved() {
+            return Ok(());
+        }
+
+        self.collect_writes(true).await?;
+        self.flush_deletes().await?;
+
+        let sequence = match self.checkpointer.take_moved() {
+            Some(sequence) => sequence,
+            None => return Ok(()),
+        };
+        if let Some(sequence) = &sequence {
+            Pipeline::validate_checkpoint(&self.settings, sequence).await?;
+        }
+
+        info!(
+            seq = sequence.as_deref().unwrap_or("(none)"),
+            "following the changes feed from the moved checkpoint"
+        );
+        self.checkpointer.adopt(sequence.clone());
+        self.changes = Pipeline::open_changes(&self.settings, sequence).await?;
+
+        Ok(())
+    }
+
+    /// step handles the next event from the feed, returning false once there are no more.
+    async fn step(&mut self) -> Result<bool, Box<dyn Error>> {
+        if *self.paused.borrow_and_update() {
+            return self.wait_while_paused().await;
+        }
+
+        self.seek_if_moved().await?;
+
+        let delete_batch_timeout = Duration::from_millis(self.settings.delete_batch_timeout_ms);
+
+        // While deletions or a checkpoint are pending, don't wait on a quiet feed forever before
+        // applying them
+        let idle_timeout = match (self.deletes.is_empty(), self.checkpointer.time_until_due()) {
+            (true, due) => due,
+            (false, Some(due)) => Some(due.min(delete_batch_timeout)),
+            (false, None) => Some(delete_batch_timeout),
+        };
+        let transaction_due = self.transaction.as_ref().and_then(|t| t.time_until_due());
+        let idle_timeout = match (idle_timeout, transaction_due) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+
+        let changes = &mut self.changes;
+        let next = async {
+            match idle_timeout {
+                Some(idle_timeout) => tokio::time::timeout(idle_timeout, changes.next()).await,
+                None => Ok(changes.next().await),
+            }
+        };
+
+        let until_time = self.until_time;
+        let deadline = async {
+            match until_time {
+                Some(until_time) => tokio::time::sleep_until(until_time).await,
+                None => std::future::pending().await,
+            }
+        };
+
+        let next_sequence_check = self.next_sequence_check;
+        let sequence_check = async {
+            match next_sequence_check {
+                Some(at) => tokio::time::sleep_until(at).await,
+                None => std::future::pending().await,
+            }
+        };
+
+        let watchdog_due = watchdog_due(&self.watchdog);
+
+        let writers = &mut self.writers;
+        let write_finished = async {
+            match writers {
+                Some(writers) => writers.wait().await,
+                None => std::future::pending().await,
+            }
+        };
+
+        let flush_requests = &mut self.flush_requests;
+        let paused = &mut self.paused;
+        let mut flush_request = None;
+        let mut check_sequence = false;
+        let mut ping_watchdog = false;
+        let change = tokio::select! {
+            change = next => Some(change),
+            // Go round again, to pick it up
+            _ = write_finished => None,
+            _ = self.shutdown.notified() => return Ok(false),
+            _ = deadline => {
+                info!("reached until_time, stopping");
+                return Ok(false);
+            }
+            Some(request) = flush_requests.recv() => {
+                flush_request = Some(request);
+                None
+            }
+            // Go round again, to pause
+            _ = paused.changed() => None,
+            _ = sequence_check => {
+                check_sequence = true;
+                None
+            }
+            _ = watchdog_due => {
+                ping_watchdog = true;
+                None
+            }
+        };
+
+        if let Some(request) = flush_request {
+            self.answer_flush(request).await?;
+        }
+        if check_sequence {
+            self.check_sequence().await?;
+        }
+        if ping_watchdog {
+            self.ping_watchdog();
+        }
+
+        let change = match change {
+            Some(change) => change,
+            None => return Ok(true),
+        };
+
+        let change_event = match change {
+            Ok(Some(change)) => change?,
+            Ok(None) => return Ok(false),
+            Err(_) => {
+                self.flush().await?;
+                return Ok(true);
+            }
+        };
+
+        if let Some(until_seq) = self.until_seq {
+            let seq = change_event.seq.as_str().unwrap_or_default();
+            if sequence_number(seq).is_some_and(|number| number > until_seq) {
+                info!(seq, until_seq, "reached until_seq, stopping");
+                return Ok(false);
+            }
+        }
+
+        // Never write once another region has taken over
+        if self.failover.as_ref().is_some_and(|f| !f.is_active()) {
+            return Err("failover lease lost, stopping".into());
+        }
+
+        if self.leader_lock.as_ref().is_some_and(|l| !l.is_leader()) {
+            return Err("no longer leader, stopping".into());
+        }
+
+        if self.db_updates.as_ref().is_some_and(|w| w.is_invalid()) {
+            return Err(
+                "source database was deleted or recreated, checkpoint is no longer valid".into(),
+            );
+        }
+
+        if let Some(credentials) = &self.mongodb_credentials {
+            if credentials.generation() != self.mongodb_generation {
+                self.reconnect_mongodb().await?;
+            }
+        }
+
+        self.apply(change_event).await?;
+        Ok(true)
+    }
+
+    /// reconnect_mongodb reconnects to MongoDB with the current Vault credentials, after applying
+    /// pending deletions with the old connection.
+    ///
+    /// Background work started with the pipeline, such as the meta collection refresh and
+    /// scheduled jobs, keeps its original connection.
+    async fn reconnect_mongodb(&mut self) -> Result<(), Box<dyn Error>> {
+        self.commit_transaction().await?;
+        self.flush_deletes().await?;
+
+        self.mongodb_generation = self
+            .mongodb_credentials
+            .as_ref()
+            .map_or(0, |c| c.generation());
+        info!("mongodb credentials rotated, reconnecting");
+
+        self.databases = self.settings.get_mongodb_databases().await?;
+        self.db = self.databases.default().clone();
+        self.dead_letters = DeadLetterQueue::new(&self.db, &self.settings.dead_letter_collection);
+        self.audit = self.settings.get_audit_log(&self.db)?;
+        self.attachments = self.settings.get_attachment_store(&self.db).await?;
+        self.view_indexer = self.settings.get_view_indexer(&self.db);
+        if let Some(transaction) = &mut self.transaction {
+            transaction.set_database(self.db.clone());
+        }
+
+        Ok(())
+    }
+
+    /// incoming_revision returns the revision to compare against the one in MongoDB, if revisions
+    /// are compared.
+    fn incoming_revision(&self, document: &Document) -> Option<String> {
+        if !self.settings.compare_revisions {
+            return None;
+        }
+
+        document.get_str("_rev").ok().map(str::to_string)
+    }
+
+    /// publish sends a change to the configured sinks, if any.
+    ///
+    /// # Arguments
+    /// * `id` - The CouchDB document ID
+    /// * `seq` - The sequence of the change
+    /// * `collection` - The collection the change is routed to
+    /// * `document` - The document as it's written to MongoDB, or None for a deletion
+    async fn publish(
+        &self,
+        id: &str,
+        seq: &str,
+        collection: &str,
+        document: Option<&Document>,
+    ) -> Result<(), Box<dyn Error>> {
+        if self.sinks.is_empty() {
+            return Ok(());
+        }
+
+        if self.settings.dry_run {
+            info!(id, seq, collection, "dry run, would publish change");
+            return Ok(());
+        }
+
+        self.sinks
+            .publish(&SinkMessage {
+                id: id.to_string(),
+                seq: seq.to_string(),
+                database: self.settings.mongodb_database.clone(),
+                collection: collection.to_string(),
+                deleted: document.is_none(),
+                document: document.cloned(),
+            })
+            .await
+    }
+
+    /// spill_document stores a whole oversized document in the GridFS bucket its stub points at.
+    /// The file ID includes the revision, so a retried change finds the file already stored;
+    /// files of earlier revisions are left in place.
+    async fn spill_document(
+        &self,
+        file_id: &str,
+        document: &Document,
+        settings: &SizeLimitSettings,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut options = GridFsBucketOptions::default();
+        options.bucket_name = Some(settings.gridfs_bucket.clone());
+        let bucket = self.db.gridfs_bucket(options);
+
+        let mut existing = bucket.find(bson::doc! { "_id": file_id }, None).await?;
+        if existing.try_next().await?.is_some() {
+            return Ok(());
+        }
+
+        bucket
+            .upload_from_futures_0_3_reader_with_id(
+                file_id.into(),
+                file_id,
+                Cursor::new(bson::to_vec(document)?),
+                None,
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    /// write_design_document copies a design document as it is into the design document
+    /// collection, or deletes it from there if it's been deleted.
+    async fn write_design_document(
+        &mut self,
+        collection: &Collection<Document>,
+        document: Document,
+    ) -> Result<(), Box<dyn Error>> {
+        let id = document.get("_id").cloned().unwrap_or(Bson::Null);
+        let deleted = document.get("_deleted").is_some();
+
+        if self.settings.dry_run || !self.settings.write_mongodb {
+            info!(
+                id = id.to_string(),
+                collection = collection.name(),
+                deleted,
+                "dry run, would write design document"
+            );
+            return Ok(());
+        }
+
+        self.limit_mongo_ops(1).await;
+        match deleted {
+            true => {
+                collection.delete_one(doc! { "_id": &id }, None).await?;
+            }
+            false => {
+                collection
+                    .replace_one(
+                        doc! { "_id": &id },
+                        document,
+                        ReplaceOptions::builder().upsert(true).build(),
+                    )
+                    .await?;
+            }
+        }
+        info!(
+            id = id.to_string(),
+            collection = collection.name(),
+            deleted,
+            "wrote design document"
+        );
+
+        Ok(())
+    }
+
+    /// apply writes a single change to MongoDB.
+    async fn apply(&mut self, change_event: ChangeEvent) -> Result<(), Box<dyn Error>> {
+        debug!(
+            id = change_event.id.as_str(),
+            seq = change_event.seq.as_str()
+        );
+
+        if change_event.is_design_document() {
+            info!(
+                id = change_event.id.as_str(),
+                seq = change_event.seq.as_str(),
+                "design document"
+            );
+
+            if let (Some(indexer), Some(doc)) = (&self.view_indexer, &change_event.doc) {
+                if self.settings.dry_run {
+                    info!(
+                        id = change_event.id.as_str(),
+                        "dry run, would create indexes for views"
+                    );
+                } else {
+                    indexer.apply(&bson::to_document(doc)?).await?;
+                }
+            }
+            if let (Some(collection), Some(doc)) =
+                (&self.settings.design_docs_collection, &change_event.doc)
+            {
+                let collection = self.db.collection::<Document>(collection);
+                self.write_design_document(&collection, bson::to_document(doc)?)
+                    .await?;
+            }
+            return Ok(());
+        }
+
+        if !self.id_filter.is_allowed(&change_event.id) {
+            debug!(
+                id = change_event.id.as_str(),
+                seq = change_event.seq.as_str(),
+                "skipping filtered document"
+            );
+            return Ok(());
+        }
+
+        let seq = sequence_string(&change_event.seq);
+
+        if let Some(throttle) = &self.backfill_throttle {
+            throttle.wait(&seq).await;
+        }
+
+        if let Some(limiter) = &mut self.doc_rate_limiter {
+            limiter.acquire(1).await;
+        }
+
+        let couch_document = change_event.doc.unwrap();
+        let mut bson_document = bson::to_document(&couch_document).unwrap();
+
+        if let Some(id_settings) = &self.settings.id_handling {
+            match transform::id::remap_id(&mut bson_document, id_settings) {
+                IdOutcome::Unchanged => {}
+                IdOutcome::Replaced(id) => {
+                    debug!(
+                        id = change_event.id.as_str(),
+                        seq = seq.as_str(),
+                        mongodb_id = id.as_str(),
+                        "replaced unusable document id"
+                    );
+                }
+                IdOutcome::DeadLetter(reason) => {
+                    // A deletion of a document we never wrote has nothing to do
+                    if bson_document.get("_deleted").is_none() {
+                        if self.settings.dry_run {
+                            info!(
+                                id = change_event.id.as_str(),
+                                seq = seq.as_str(),
+                                reason = reason.as_str(),
+                                "dry run, would dead letter document"
+                            );
+                        } else {
+                            self.limit_mongo_ops(1).await;
+                            self.dead_letters
+                                .send(&change_event.id, &seq, "id", &reason, &bson_document)
+                                .await?;
+                        }
+                        // Earlier changes still pending will move the checkpoint when they're
+                        // written
+                        if self.deletes.is_empty() && self.transaction.is_none() {
+                            self.advance(&seq).await?;
+                        }
+                        self.ready.push_back(AppliedChange {
+                            id: change_event.id.clone(),
+                            seq,
+                            collection: self.settings.dead_letter_collection.clone(),
+                            operation: Operation::DeadLettered,
+                        });
+                    }
+                    return Ok(());
+                }
+            }
+        }
+
+        if let Some(key_settings) = &self.settings.key_sanitization {
+            match transform::keys::sanitize_keys(&mut bson_document, key_settings) {
+                KeyOutcome::Unchanged => {}
+                KeyOutcome::Renamed(fields) => {
+                    debug!(
+                        id = change_event.id.as_str(),
+                        seq = seq.as_str(),
+                        fields = fields.join(","),
+                        "renamed unusable field names"
+                    );
+                }
+                KeyOutcome::DeadLetter(reason) => {
+                    if bson_document.get("_deleted").is_none() {
+                        if self.settings.dry_run {
+                            info!(
+                                id = change_event.id.as_str(),
+                                seq = seq.as_str(),
+                                reason = reason.as_str(),
+                                "dry run, would dead letter document"
+                            );
+                        } else {
+                            // The field names that got it rejected can't be stored as they are
+                            let document = bson::doc! {
+                                "json": serde_json::to_string(&couch_document)?,
+                            };
+                            self.limit_mongo_ops(1).await;
+                            self.dead_letters
+                                .send(&change_event.id, &seq, "keys", &reason, &document)
+                                .await?;
+                        }
+                        // Earlier changes still pending will move the checkpoint when they're
+                        // written
+                        if self.deletes.is_empty() && self.transaction.is_none() {
+                            self.advance(&seq).await?;
+                        }
+                        self.ready.push_back(AppliedChange {
+                            id: change_event.id.clone(),
+                            seq,
+                            collection: self.settings.dead_letter_collection.clone(),
+                            operation: Operation::DeadLettered,
+                        });
+                    }
+                    return Ok(());
+                }
+            }
+        }
+
+        let document_id = bson::doc! { "_id": bson_document.get("_id").unwrap() };
+
+        let destination = self.router.destination(&bson_document);
+        let collection = self
+            .databases
+            .get(&destination)?
+            .collection::<Document>(self.router.collection_name(&bson_document).as_str());
+
+        if bson_document.get("_deleted").is_some() {
+            self.publish(&change_event.id, &seq, collection.name(), None)
+                .await?;
+
+            if !self.settings.write_mongodb {
+                self.advance(&seq).await?;
+                self.ready.push_back(AppliedChange {
+                    id: change_event.id,
+                    seq,
+                    collection: collection.name().to_string(),
+                    operation: Operation::Published,
+                });
+                return Ok(());
+            }
+
+            if let Some(DeletionSettings {
+                strategy: DeletionStrategy::Ignore,
+                ..
+            }) = &self.settings.deletion
+            {
+                debug!(
+                    id = change_event.id.as_str(),
+                    seq = seq.as_str(),
+                    collection = collection.name(),
+                    "ignoring deletion",
+                );
+                // Earlier changes still pending will move the checkpoint when they're written
+                if self.deletes.is_empty() && self.transaction.is_none() {
+                    self.advance(&seq).await?;
+                }
+                self.ready.push_back(AppliedChange {
+                    id: change_event.id,
+                    seq,
+                    collection: collection.name().to_string(),
+                    operation: Operation::Ignored,
+                });
+                return Ok(());
+            }
+        }
+
+        if bson_document.get("_deleted").is_some() && self.settings.dry_run {
+            info!(
+                id = change_event.id.as_str(),
+                seq = seq.as_str(),
+                collection = collection.name(),
+                "dry run, would delete document",
+            );
+            self.advance(&seq).await?;
+            self.ready.push_back(AppliedChange {
+                id: change_event.id,
+                seq,
+                collection: collection.name().to_string(),
+                operation: Operation::Deleted,
+            });
+            return Ok(());
+        }
+
+        if bson_document.get("_deleted").is_some() && self.transaction.is_some() {
+            info!(
+                id = change_event.id.as_str(),
+                seq = seq.as_str(),
+                collection = collection.name(),
+                "deleting document in transaction",
+            );
+            let write = Write::Delete {
+                collection: collection.name().to_string(),
+                id: bson_document.get("_id").unwrap().clone(),
+            };
+            let applied = AppliedChange {
+                id: change_event.id,
+                seq,
+                collection: collection.name().to_string(),
+                operation: Operation::Deleted,
+            };
+            return self.queue_write(write, applied).await;
+        }
+
+        if bson_document.get("_deleted").is_some() {
+            info!(
+                id = change_event.id.as_str(),
+                seq = seq.as_str(),
+                collection = collection.name(),
+                "deleting document",
+            );
+            self.deletes.push(
+                &destination,
+                collection.name(),
+                bson_document.get("_id").unwrap().clone(),
+                bson_document.get_str("_rev").unwrap_or_default(),
+                &seq,
+            );
+            self.queued_deletes.push(AppliedChange {
+                id: change_event.id.clone(),
+                seq,
+                collection: collection.name().to_string(),
+                operation: Operation::Deleted,
+            });
+
+            if self.deletes.len() >= self.settings.delete_batch_size {
+                self.flush_deletes().await?;
+            }
+            return Ok(());
+        }
+
+        if let Some(projection) = self.settings.projections.get(collection.name()) {
+            transform::projection::project(&mut bson_document, projection);
+        }
+
+        if !self.settings.rename.is_empty() {
+            let renamed =
+                transform::rename::rename_fields(&mut bson_document, &self.settings.rename);
+            if !renamed.is_empty() {
+                debug!(
+                    id = change_event.id.as_str(),
+                    seq = seq.as_str(),
+                    fields = renamed.join(","),
+                    "renamed fields"
+                );
+            }
+        }
+
+        if !self.settings.coerce.is_empty() {
+            let failed =
+                transform::coerce::coerce_fields(&mut bson_document, &self.settings.coerce);
+            if !failed.is_empty() {
+                warn!(
+                    id = change_event.id.as_str(),
+                    seq = seq.as_str(),
+                    fields = failed.join(","),
+                    "unable to convert fields, leaving them as they are"
+                );
+            }
+        }
+
+        let invalid = self
+            .validator
+            .as_ref()
+            .and_then(|v| v.validate(collection.name(), &bson_document).err());
+        if let Some(reason) = invalid {
+            if self.settings.dry_run {
+                info!(
+                    id = change_event.id.as_str(),
+                    seq = seq.as_str(),
+                    reason = reason.as_str(),
+                    "dry run, would dead letter document"
+                );
+            } else {
+                self.limit_mongo_ops(1).await;
+                self.dead_letters
+                    .send(&change_event.id, &seq, "schema", &reason, &bson_document)
+                    .await?;
+            }
+            // Earlier changes still pending will move the checkpoint when they're written
+            if self.deletes.is_empty() && self.transaction.is_none() {
+                self.advance(&seq).await?;
+            }
+            self.ready.push_back(AppliedChange {
+                id: change_event.id,
+                seq,
+                collection: self.settings.dead_letter_collection.clone(),
+                operation: Operation::DeadLettered,
+            });
+            return Ok(());
+        }
+
+        if let Some(attachments) = &self.attachments {
+            if self.settings.dry_run {
+                if bson_document.contains_key("_attachments") {
+                    info!(
+                        id = change_event.id.as_str(),
+                        seq = seq.as_str(),
+                        "dry run, would copy attachments"
+                    );
+                }
+            } else {
+                attachments.replicate(&mut bson_document).await?;
+            }
+        }
+
+        let size_limit = self.settings.size_limit.clone().unwrap_or_default();
+        let report = transform::size::enforce_size(&mut bson_document, &size_limit)?;
+
+        if !report.truncated_fields.is_empty() {
+            warn!(
+                id = change_event.id.as_str(),
+                seq = seq.as_str(),
+                original_size = report.original_size,
+                size = report.size,
+                fields = report.truncated_fields.join(","),
+                "truncated oversized document",
+            );
+            OVERSIZED_DOCUMENTS.with_label_values(&["truncated"]).inc();
+        }
+
+        // The limit is MongoDB's, so sinks alone still get the whole document
+        if !report.within_limit(size_limit.max_bytes) && self.settings.write_mongodb {
+            if size_limit.policy != OversizePolicy::GridFS {
+                warn!(
+                    id = change_event.id.as_str(),
+                    seq = seq.as_str(),
+                    size = report.size,
+                    max_bytes = size_limit.max_bytes,
+                    "skipping document over the size limit",
+                );
+                OVERSIZED_DOCUMENTS.with_label_values(&["skipped"]).inc();
+                // Earlier changes still pending will move the checkpoint when they're written
+                if self.deletes.is_empty() && self.transaction.is_none() {
+                    self.advance(&seq).await?;
+                }
+                self.ready.push_back(AppliedChange {
+                    id: change_event.id,
+                    seq,
+                    collection: collection.name().to_string(),
+                    operation: Operation::Oversized,
+                });
+                return Ok(());
+            }
+
+            let file_id = format!(
+                "{}/{}/{}",
+                collection.name(),
+                change_event.id,
+                bson_document.get_str("_rev").unwrap_or_default()
+            );
+            if self.settings.dry_run {
+                info!(
+                    id = change_event.id.as_str(),
+                    seq = seq.as_str(),
+                    size = report.size,
+                    file_id = file_id.as_str(),
+                    "dry run, would store oversized document in GridFS"
+                );
+            } else {
+                self.limit_mongo_ops(1).await;
+                self.spill_document(&file_id, &bson_document, &size_limit)
+                    .await?;
+            }
+            bson_document =
+                transform::size::stub_document(&bson_document, &file_id, report.size, &size_limit);
+            OVERSIZED_DOCUMENTS.with_label_values(&["spilled"]).inc();
+        }
+
+        if let Some(indexes) = &mut self.indexes {
+            indexes
+                .ensure(
+                    &self.databases.get(&destination)?,
+                    collection.name(),
+                    destination.target.as_deref(),
+                )
+                .await?;
+        }
+
+        // Pending deletions must land before this write, in case it recreates one of them
+        if !self.deletes.is_empty() {
+            self.flush_deletes().await?;
+        }
+
+        let mut unchanged_filter = None;
+        if let Some(hash_settings) = &self.settings.content_hash {
+            let hash = transform::hash::content_hash(&bson_document, hash_settings)?;
+            let mut filter = bson::doc! {
+                "_id": bson_document.get("_id").unwrap(),
+                hash_settings.field.as_str(): hash.as_str(),
+            };
+            // A soft deleted document with the same content still needs bringing back
+            if let Some(
+                deletion @ DeletionSettings {
+                    strategy: DeletionStrategy::Soft,
+                    ..
+                },
+            ) = &self.settings.deletion
+            {
+                filter = batch::deletion::soft_delete_filter(filter, deletion);
+            }
+            unchanged_filter = Some(filter);
+            bson_document.insert(hash_settings.field.clone(), hash);
+        }
+
+        // Added after hashing, as they change with every write
+        if let Some(metadata_settings) = &self.settings.sync_metadata {
+            transform::metadata::add_sync_metadata(
+                &mut bson_document,
+                Some(&seq),
+                metadata_settings,
+                bson::DateTime::now(),
+            );
+        }
+        if let Some(tenant) = &self.tenant {
+            tenant.add_field(&mut bson_document);
+        }
+
+        // Within a transaction the check is made when the batch commits, and with writers when
+        // the document is written
+        if let (Some(filter), None, None, true) = (
+            &unchanged_filter,
+            &self.transaction,
+            &self.writers,
+            self.settings.write_mongodb,
+        ) {
+            let options = FindOneOptions::builder()
+                .projection(bson::doc! { "_id": 1 })
+                .build();
+
+            if collection
+                .find_one(filter.clone(), options)
+                .await?
+                .is_some()
+            {
+                debug!(
+                    id = change_event.id.as_str(),
+                    seq = seq.as_str(),
+                    collection = collection.name(),
+                    "document unchanged, skipping",
+                );
+                transform::hash::UNCHANGED_DOCUMENTS
+                    .with_label_values(&[collection.name()])
+                    .inc();
+                self.advance(&seq).await?;
+                self.ready.push_back(AppliedChange {
+                    id: change_event.id,
+                    seq,
+                    collection: collection.name().to_string(),
+                    operation: Operation::Unchanged,
+                });
+                return Ok(());
+            }
+        }
+
+        self.publish(
+            &change_event.id,
+            &seq,
+            collection.name(),
+            Some(&bson_document),
+        )
+        .await?;
+
+        if !self.settings.write_mongodb {
+            self.advance(&seq).await?;
+            self.ready.push_back(AppliedChange {
+                id: change_event.id,
+                seq,
+                collection: collection.name().to_string(),
+                operation: Operation::Published,
+            });
+            return Ok(());
+        }
+
+        if self.settings.dry_run {
+            info!(
+                id = change_event.id.as_str(),
+                seq = seq.as_str(),
+                collection = collection.name(),
+                size = bson::to_vec(&bson_document)?.len(),
+                "dry run, would replace document",
+            );
+            self.advance(&seq).await?;
+            self.ready.push_back(AppliedChange {
+                id: change_event.id,
+                seq,
+                collection: collection.name().to_string(),
+                operation: Operation::Replaced,
+            });
+            return Ok(());
+        }
+
+        if self.transaction.is_some() {
+            info!(
+                id = change_event.id.as_str(),
+                seq = seq.as_str(),
+                collection = collection.name(),
+                "replacing document in transaction",
+            );
+            let write = Write::Replace {
+                collection: collection.name().to_string(),
+                id: bson_document.get("_id").unwrap().clone(),
+                rev: self.incoming_revision(&bson_document),
+                unchanged: unchanged_filter,
+                document: bson_document,
+            };
+            let applied = AppliedChange {
+                id: change_event.id,
+                seq,
+                collection: collection.name().to_string(),
+                operation: Operation::Replaced,
+            };
+            return self.queue_write(write, applied).await;
+        }
+
+        info!(
+            id = change_event.id.as_str(),
+            seq = seq.as_str(),
+            collection = collection.name(),
+            write_mode = self.settings.write_mode.as_str(),
+            "writing document",
+        );
+
+        let mut filter = match self.incoming_revision(&bson_document) {
+            Some(rev) => revision::replace_filter(document_id.get("_id").unwrap(), &rev),
+            None => document_id,
+        };
+        self.shard_keys
+            .add_to_filter(collection.name(), &mut filter, &bson_document);
+
+        self.limit_mongo_ops(1).await;
+
+        if let Some(writers) = &mut self.writers {
+            let applied = AppliedChange {
+                id: change_event.id,
+                seq,
+                collection: collection.name().to_string(),
+                operation: Operation::Replaced,
+            };
+            let job = WriteJob {
+                collection,
+                filter,
+                unchanged: unchanged_filter,
+                document: bson_document,
+                applied,
+            };
+            return writers.submit(job).await;
+        }
+        let size = bson::to_vec(&bson_document)?.len();
+        let write_mode = &self.settings.write_mode;
+        let result =
+            write_document(&collection, filter, bson_document.clone(), write_mode, None).await;
+        metrics::record_bytes_written(collection.name(), write_mode.as_str(), size);
+
+        let operation = match result {
+            // The filter didn't match because MongoDB has a newer revision, so the upsert clashed
+            Err(e) if self.settings.compare_revisions && is_duplicate_key(&e) => {
+                info!(
+                    id = change_event.id.as_str(),
+                    seq = seq.as_str(),
+                    collection = collection.name(),
+                    rev = bson_document.get_str("_rev").unwrap_or_default(),
+                    "skipping stale revision",
+                );
+                Operation::Stale
+            }
+            Err(e) => return Err(e.into()),
+            Ok(Operation::Inserted) => {
+                info!(
+                    id = change_event.id.as_str(),
+                    seq = seq.as_str(),
+                    collection = collection.name(),
+                    "document inserted",
+                );
+                Operation::Inserted
+            }
+            Ok(operation) => operation,
+        };
+
+        self.advance(&seq).await?;
+        self.ready.push_back(AppliedChange {
+            id: change_event.id,
+            seq,
+            collection: collection.name().to_string(),
+            operation,
+        });
+
+        Ok(())
+    }
+}
+
+/// watchdog_due waits until the watchdog should next be pinged, or forever if it's not enabled.
+async fn watchdog_due(watchdog: &Option<Watchdog>) {
+    match watchdog {
+        Some(watchdog) => tokio::time::sleep_until(watchdog.next_ping()).await,
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::config_parser::Settings;
+    use config::{Config, File, FileFormat};
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn test_dead_lettered_change_moves_checkpoint() {
+        let dir = std::env::temp_dir().join(format!("couch2mongo-pipeline-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let schema = dir.join("cats.json");
+        std::fs::write(&schema, r#"{"type": "object", "required": ["name"]}"#).unwrap();
+
+        // A dry run, so nothing is sent to the dead letter collection, and the checkpoint is held
+        // back so it can be seen to have moved
+        let toml = format!(
+            r#"
+            source_url = "http://127.0.0.1:1"
+            source_database = "animals"
+            mongodb_connect_string = "mongodb://127.0.0.1:1"
+            mongodb_database = "animals"
+            mongodb_collection = "cats"
+            sequence_store = "Null"
+            dry_run = true
+            checkpoint_interval_docs = 100
+            checkpoint_interval_ms = 60000
+            [schemas]
+            cats = "{}"
+            "#,
+            schema.display()
+        );
+        let settings: Settings = Config::builder()
+            .add_source(File::from_str(&toml, FileFormat::Toml))
+            .build()
+            .unwrap()
+            .try_deserialize()
+            .unwrap();
+
+        let (control, paused, flush_requests) = Control::new();
+        let mut pipeline = Pipeline::new(settings, control, paused, flush_requests)
+            .await
+            .unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let change: ChangeEvent = serde_json::from_value(json!({
+            "seq": "1-a",
+            "id": "cat:tom",
+            "changes": [],
+            "doc": { "_id": "cat:tom", "_rev": "1-x", "age": 3 },
+        }))
+        .unwrap();
+        pipeline.apply(change).await.unwrap();
+
+        let applied = pipeline.ready.pop_front().unwrap();
+        assert_eq!(applied.operation, Operation::DeadLettered);
+        assert!(pipeline.checkpointer.time_until_due().is_some());
+    }
+}
@@ -0,0 +1,131 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use mongodb::options::DatabaseOptions;
+use mongodb::{Client, Database};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+
+/// Destination is where a document is routed to, beyond its collection.
+///
+/// The default destination is `mongodb_database` on the main cluster.
+#[derive(Debug, Default, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct Destination {
+    // A named MongoDB target, or the main cluster
+    pub target: Option<String>,
+    // A database on it, or its default database
+    pub database: Option<String>,
+}
+
+impl Destination {
+    /// is_default returns true for `mongodb_database` on the main cluster.
+    pub fn is_default(&self) -> bool {
+        self.target.is_none() && self.database.is_none()
+    }
+}
+
+/// Target is another MongoDB cluster documents can be routed to.
+#[derive(Debug, Clone)]
+struct Target {
+    client: Client,
+    database: String,
+}
+
+/// Databases hands out handles to the MongoDB databases documents are routed to.
+///
+/// Documents go to `mongodb_database` on the main cluster unless a field or routing rule picks
+/// another database, or another cluster (target). Handles for other databases are created on first
+/// use, with the same options, and cached.
+#[derive(Debug, Clone)]
+pub struct Databases {
+    client: Client,
+    options: DatabaseOptions,
+    default: Database,
+    targets: HashMap<String, Target>,
+    routed: Arc<Mutex<HashMap<Destination, Database>>>,
+}
+
+impl Databases {
+    /// new creates a new Databases.
+    ///
+    /// # Arguments
+    /// * `client` - The MongoDB client for the main cluster
+    /// * `default` - The name of the database documents go to unless routed elsewhere
+    /// * `options` - Options every database is opened with
+    ///
+    /// # Returns
+    /// * A Databases struct
+    pub fn new(client: Client, default: &str, options: DatabaseOptions) -> Databases {
+        Databases {
+            default: client.database_with_options(default, options.clone()),
+            client,
+            options,
+            targets: HashMap::new(),
+            routed: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// add_target adds a named cluster documents can be routed to.
+    ///
+    /// # Arguments
+    /// * `name` - The target's name, as routing rules refer to it
+    /// * `client` - The MongoDB client for the cluster
+    /// * `database` - The database documents routed to the target go to by default
+    pub fn add_target(&mut self, name: &str, client: Client, database: &str) {
+        self.targets.insert(
+            name.to_string(),
+            Target {
+                client,
+                database: database.to_string(),
+            },
+        );
+    }
+
+    /// default returns the database documents go to unless routed elsewhere.
+    pub fn default(&self) -> &Database {
+        &self.default
+    }
+
+    /// get returns the database for a destination.
+    ///
+    /// # Returns
+    /// * The database, or an error if the destination names a target that isn't configured
+    pub fn get(&self, destination: &Destination) -> Result<Database, Box<dyn Error>> {
+        let (client, default_name) = match &destination.target {
+            Some(name) => {
+                let target = self
+                    .targets
+                    .get(name)
+                    .ok_or_else(|| format!("no MongoDB target named {}", name))?;
+                (&target.client, target.database.as_str())
+            }
+            None => (&self.client, self.default.name()),
+        };
+        let name = destination.database.as_deref().unwrap_or(default_name);
+
+        if destination.target.is_none() && name == self.default.name() {
+            return Ok(self.default.clone());
+        }
+
+        Ok(self
+            .routed
+            .lock()
+            .expect("unable to lock databases")
+            .entry(destination.clone())
+            .or_insert_with(|| client.database_with_options(name, self.options.clone()))
+            .clone())
+    }
+}
@@ -0,0 +1,434 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+pub mod databases;
+pub mod rules;
+
+use crate::document::get_path;
+use crate::routing::databases::Destination;
+use crate::routing::rules::Route;
+use crate::settings::config_parser::{CollectionFallback, RouteSettings};
+use crate::transform::coerce::parse_datetime;
+use bson::{Bson, DateTime, Document};
+use lazy_static::lazy_static;
+use prometheus::{register_int_counter_vec, IntCounterVec};
+use std::error::Error;
+use tracing::debug;
+
+lazy_static! {
+    /// Documents routed, by the rule that picked the collection.
+    pub static ref ROUTE_MATCHES: IntCounterVec = register_int_counter_vec!(
+        "couch2mongo_route_matches_total",
+        "Documents routed by the rule that picked their collection",
+        &["route"]
+    )
+    .unwrap();
+}
+
+/// Stands in for the date in a collection name when the document hasn't got a usable one.
+pub const UNDATED: &str = "undated";
+
+/// CollectionRouter works out which MongoDB collection a document should be written to.
+///
+/// It walks an ordered fallback chain and uses the first step that yields a collection name. Steps
+/// that can't produce a name (eg. the field is missing, or isn't a string) are skipped rather than
+/// panicking.
+#[derive(Debug)]
+pub struct CollectionRouter {
+    chain: Vec<CollectionFallback>,
+    fields: Vec<String>,
+    routes: Vec<Route>,
+    collection: Option<String>,
+    source_database: String,
+    prefix: String,
+    database_field: Option<String>,
+    date_field: Option<String>,
+}
+
+impl CollectionRouter {
+    /// new creates a new CollectionRouter.
+    ///
+    /// # Arguments
+    /// * `chain` - The ordered fallback chain
+    /// * `fields` - Document fields (or dotted paths) holding the collection name, tried in order
+    /// * `routes` - The routing table, evaluated in order
+    /// * `collection` - The static collection name (`mongodb_collection`)
+    /// * `source_database` - The CouchDB database name
+    ///
+    /// # Returns
+    /// * A CollectionRouter, or an error if a routing rule is invalid
+    pub fn new(
+        chain: Vec<CollectionFallback>,
+        fields: Vec<String>,
+        routes: &[RouteSettings],
+        collection: Option<String>,
+        source_database: String,
+    ) -> Result<CollectionRouter, Box<dyn Error>> {
+        Ok(CollectionRouter {
+            chain,
+            fields,
+            routes: routes.iter().map(Route::new).collect::<Result<_, _>>()?,
+            collection,
+            source_database,
+            prefix: String::new(),
+            database_field: None,
+            date_field: None,
+        })
+    }
+
+    /// with_database_field routes documents to the MongoDB database named in a document field
+    /// (or dotted path), when it's present.
+    pub fn with_database_field(mut self, field: Option<String>) -> CollectionRouter {
+        self.database_field = field;
+        self
+    }
+
+    /// with_date_field fills in the date placeholders in collection names, eg. `events_{yyyymm}`,
+    /// from a document field (or dotted path).
+    pub fn with_date_field(mut self, field: Option<String>) -> CollectionRouter {
+        self.date_field = field;
+        self
+    }
+
+    /// destination returns the MongoDB target and database to use for the document.
+    ///
+    /// The target is the first matching routing rule's. The database is taken from the database
+    /// field, then from that rule.
+    pub fn destination(&self, document: &Document) -> Destination {
+        let route = self.routes.iter().find(|r| r.is_match(document));
+        let database = self
+            .database_field
+            .as_deref()
+            .and_then(|field| field_collection_name(document, field))
+            .or_else(|| route.and_then(|r| r.database.clone()));
+
+        Destination {
+            target: route.and_then(|r| r.target.clone()),
+            database,
+        }
+    }
+
+    /// with_prefix puts a prefix in front of every collection name, eg. to keep each tenant's
+    /// collections apart in a shared MongoDB database.
+    pub fn with_prefix(mut self, prefix: String) -> CollectionRouter {
+        self.prefix = prefix;
+        self
+    }
+
+    /// collection_name returns the collection name to use for the document, with the prefix and
+    /// its date filled in.
+    ///
+    /// If no step in the chain matches, the source database name is used.
+    pub fn collection_name(&self, document: &Document) -> String {
+        let name = self.unprefixed_name(document);
+        let name = match &self.date_field {
+            Some(field) => expand_date(&name, get_path(document, field).and_then(parse_datetime)),
+            None => name,
+        };
+
+        format!("{}{}", self.prefix, name)
+    }
+
+    /// unprefixed_name returns the collection name the chain picks for the document.
+    fn unprefixed_name(&self, document: &Document) -> String {
+        for step in &self.chain {
+            if let Some((rule, name)) = self.evaluate(step, document) {
+                debug!(
+                    rule = rule.as_str(),
+                    collection = name.as_str(),
+                    "collection matched"
+                );
+                ROUTE_MATCHES.with_label_values(&[rule.as_str()]).inc();
+                return name;
+            }
+        }
+
+        debug!(
+            collection = self.source_database.as_str(),
+            "no collection rule matched, using source database"
+        );
+        ROUTE_MATCHES
+            .with_label_values(&[CollectionFallback::SourceDatabase.as_str()])
+            .inc();
+        self.source_database.clone()
+    }
+
+    /// evaluate returns the name of the matching rule and the collection it picked, if any.
+    fn evaluate(&self, step: &CollectionFallback, document: &Document) -> Option<(String, String)> {
+        let name = match step {
+            CollectionFallback::Field => self
+                .fields
+                .iter()
+                .find_map(|field| field_collection_name(document, field)),
+            CollectionFallback::Routes => {
+                let route = self.routes.iter().find(|r| r.is_match(document))?;
+                return Some((route.name.clone(), route.collection.clone()));
+            }
+            CollectionFallback::Collection => self.collection.clone(),
+            CollectionFallback::SourceDatabase => Some(self.source_database.clone()),
+        };
+
+        name.map(|n| (step.as_str().to_string(), n))
+    }
+}
+
+/// expand_date replaces {yyyy}, {mm}, {dd}, {yyyymm} and {yyyymmdd} in a collection name with
+/// parts of a date, in UTC.
+///
+/// # Arguments
+/// * `name` - The collection name
+/// * `date` - The date, or None to use `undated` instead
+pub fn expand_date(name: &str, date: Option<DateTime>) -> String {
+    if !name.contains('{') {
+        return name.to_string();
+    }
+
+    // eg. 2024-05-01T12:00:00Z; years outside 0-9999 can't be formatted and count as undated
+    let formatted = date.and_then(|d| d.try_to_rfc3339_string().ok());
+    let (yyyy, mm, dd) = match &formatted {
+        Some(f) => (&f[0..4], &f[5..7], &f[8..10]),
+        None => (UNDATED, UNDATED, UNDATED),
+    };
+    let (yyyymm, yyyymmdd) = match &formatted {
+        Some(_) => (format!("{}{}", yyyy, mm), format!("{}{}{}", yyyy, mm, dd)),
+        None => (UNDATED.to_string(), UNDATED.to_string()),
+    };
+
+    name.replace("{yyyymmdd}", &yyyymmdd)
+        .replace("{yyyymm}", &yyyymm)
+        .replace("{yyyy}", yyyy)
+        .replace("{mm}", mm)
+        .replace("{dd}", dd)
+}
+
+/// field_collection_name returns the value of a field as a collection name, if it's usable as one.
+fn field_collection_name(document: &Document, field: &str) -> Option<String> {
+    match get_path(document, field) {
+        Some(Bson::String(s)) if !s.is_empty() => Some(s.clone()),
+        Some(Bson::Int32(i)) => Some(i.to_string()),
+        Some(Bson::Int64(i)) => Some(i.to_string()),
+        Some(other) => {
+            debug!(
+                field,
+                element_type = format!("{:?}", other.element_type()),
+                "collection field is not usable as a collection name"
+            );
+            None
+        }
+        None => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bson::doc;
+
+    fn default_router(field: Option<&str>, collection: Option<&str>) -> CollectionRouter {
+        CollectionRouter::new(
+            vec![
+                CollectionFallback::Field,
+                CollectionFallback::Collection,
+                CollectionFallback::SourceDatabase,
+            ],
+            field.map(str::to_string).into_iter().collect(),
+            &[],
+            collection.map(str::to_string),
+            "animals".to_string(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_field_wins() {
+        let router = default_router(Some("type"), Some("static"));
+        assert_eq!(router.collection_name(&doc! { "type": "cats" }), "cats");
+    }
+
+    #[test]
+    fn test_missing_field_falls_back_to_collection() {
+        let router = default_router(Some("type"), Some("static"));
+        assert_eq!(router.collection_name(&doc! { "name": "tom" }), "static");
+    }
+
+    #[test]
+    fn test_non_string_field_does_not_panic() {
+        let router = default_router(Some("type"), None);
+        assert_eq!(
+            router.collection_name(&doc! { "type": { "nested": true } }),
+            "animals"
+        );
+        assert_eq!(router.collection_name(&doc! { "type": 42 }), "42");
+    }
+
+    #[test]
+    fn test_custom_chain() {
+        let router = CollectionRouter::new(
+            vec![
+                CollectionFallback::SourceDatabase,
+                CollectionFallback::Field,
+            ],
+            vec!["type".to_string()],
+            &[],
+            None,
+            "animals".to_string(),
+        )
+        .unwrap();
+        assert_eq!(router.collection_name(&doc! { "type": "cats" }), "animals");
+    }
+
+    #[test]
+    fn test_empty_chain_uses_source_database() {
+        let router =
+            CollectionRouter::new(vec![], vec![], &[], None, "animals".to_string()).unwrap();
+        assert_eq!(router.collection_name(&doc! {}), "animals");
+    }
+
+    #[test]
+    fn test_routes() {
+        let routes = vec![RouteSettings {
+            name: Some("cats".to_string()),
+            collection: "felines".to_string(),
+            database: None,
+            target: None,
+            field: Some("type".to_string()),
+            equals: Some("cat".to_string()),
+            matches: None,
+            id_prefix: None,
+            id_regex: None,
+        }];
+        let router = CollectionRouter::new(
+            vec![
+                CollectionFallback::Field,
+                CollectionFallback::Routes,
+                CollectionFallback::Collection,
+            ],
+            vec!["collection".to_string()],
+            &routes,
+            Some("everything_else".to_string()),
+            "animals".to_string(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            router.collection_name(&doc! { "collection": "explicit", "type": "cat" }),
+            "explicit"
+        );
+        assert_eq!(router.collection_name(&doc! { "type": "cat" }), "felines");
+        assert_eq!(
+            router.collection_name(&doc! { "type": "dog" }),
+            "everything_else"
+        );
+    }
+
+    #[test]
+    fn test_destination() {
+        let routes = vec![RouteSettings {
+            name: None,
+            collection: "invoices".to_string(),
+            database: Some("billing".to_string()),
+            target: Some("archive".to_string()),
+            field: Some("type".to_string()),
+            equals: Some("invoice".to_string()),
+            matches: None,
+            id_prefix: None,
+            id_regex: None,
+        }];
+        let router = CollectionRouter::new(vec![], vec![], &routes, None, "animals".to_string())
+            .unwrap()
+            .with_database_field(Some("customer".to_string()));
+
+        assert_eq!(
+            router.destination(&doc! { "customer": "acme", "type": "invoice" }),
+            Destination {
+                target: Some("archive".to_string()),
+                database: Some("acme".to_string()),
+            }
+        );
+        assert_eq!(
+            router.destination(&doc! { "type": "invoice" }),
+            Destination {
+                target: Some("archive".to_string()),
+                database: Some("billing".to_string()),
+            }
+        );
+        assert!(router.destination(&doc! { "type": "cat" }).is_default());
+    }
+
+    #[test]
+    fn test_prefix() {
+        let router = default_router(Some("type"), None).with_prefix("acme_".to_string());
+        assert_eq!(
+            router.collection_name(&doc! { "type": "cats" }),
+            "acme_cats"
+        );
+        assert_eq!(router.collection_name(&doc! {}), "acme_animals");
+    }
+
+    #[test]
+    fn test_date_field() {
+        let router = CollectionRouter::new(
+            vec![CollectionFallback::Collection],
+            vec![],
+            &[],
+            Some("events_{yyyymm}".to_string()),
+            "animals".to_string(),
+        )
+        .unwrap()
+        .with_date_field(Some("meta.created_at".to_string()));
+
+        assert_eq!(
+            router.collection_name(&doc! { "meta": { "created_at": "2024-05-01T12:00:00Z" } }),
+            "events_202405"
+        );
+        assert_eq!(
+            router.collection_name(&doc! { "meta": { "created_at": 1704067200000_i64 } }),
+            "events_202401"
+        );
+        assert_eq!(router.collection_name(&doc! {}), "events_undated");
+    }
+
+    #[test]
+    fn test_expand_date() {
+        let date = DateTime::parse_rfc3339_str("2024-05-01T12:00:00Z").ok();
+        assert_eq!(
+            expand_date("logs_{yyyy}_{mm}_{dd}", date),
+            "logs_2024_05_01"
+        );
+        assert_eq!(expand_date("logs_{yyyymmdd}", date), "logs_20240501");
+        assert_eq!(expand_date("logs", None), "logs");
+    }
+
+    #[test]
+    fn test_nested_field_chain() {
+        let router = CollectionRouter::new(
+            vec![CollectionFallback::Field],
+            vec!["meta.type".to_string(), "kind".to_string()],
+            &[],
+            None,
+            "animals".to_string(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            router.collection_name(&doc! { "meta": { "type": "cats" }, "kind": "pets" }),
+            "cats"
+        );
+        assert_eq!(
+            router.collection_name(&doc! { "meta": { "type": 1.5 }, "kind": "pets" }),
+            "pets"
+        );
+        assert_eq!(router.collection_name(&doc! { "meta": {} }), "animals");
+    }
+}
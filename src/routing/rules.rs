@@ -0,0 +1,188 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::document::get_path;
+use crate::settings::config_parser::RouteSettings;
+use bson::{Bson, Document};
+use regex::Regex;
+use std::error::Error;
+
+/// Route is a compiled routing rule that maps matching documents to a collection, and optionally
+/// a database and MongoDB target.
+///
+/// Every condition configured on the rule must match for the rule to apply.
+#[derive(Debug)]
+pub struct Route {
+    pub name: String,
+    pub collection: String,
+    pub database: Option<String>,
+    pub target: Option<String>,
+    field: Option<String>,
+    equals: Option<String>,
+    matches: Option<Regex>,
+    id_prefix: Option<String>,
+    id_regex: Option<Regex>,
+}
+
+impl Route {
+    /// new compiles a routing rule from its settings.
+    ///
+    /// # Arguments
+    /// * `settings` - A RouteSettings struct
+    ///
+    /// # Returns
+    /// * A Route, or an error if the rule is invalid
+    pub fn new(settings: &RouteSettings) -> Result<Route, Box<dyn Error>> {
+        if settings.field.is_none() && (settings.equals.is_some() || settings.matches.is_some()) {
+            return Err(format!(
+                "route to {} uses equals/matches without a field",
+                settings.collection
+            )
+            .into());
+        }
+
+        if settings.field.is_none() && settings.id_prefix.is_none() && settings.id_regex.is_none() {
+            return Err(format!("route to {} has no conditions", settings.collection).into());
+        }
+
+        Ok(Route {
+            name: settings
+                .name
+                .clone()
+                .unwrap_or_else(|| settings.collection.clone()),
+            collection: settings.collection.clone(),
+            database: settings.database.clone(),
+            target: settings.target.clone(),
+            field: settings.field.clone(),
+            equals: settings.equals.clone(),
+            matches: settings.matches.as_deref().map(Regex::new).transpose()?,
+            id_prefix: settings.id_prefix.clone(),
+            id_regex: settings.id_regex.as_deref().map(Regex::new).transpose()?,
+        })
+    }
+
+    /// is_match returns true if the document satisfies every condition on the rule.
+    pub fn is_match(&self, document: &Document) -> bool {
+        if let Some(field) = &self.field {
+            let value = match get_path(document, field) {
+                Some(Bson::String(s)) => s.clone(),
+                Some(Bson::Int32(i)) => i.to_string(),
+                Some(Bson::Int64(i)) => i.to_string(),
+                Some(Bson::Boolean(b)) => b.to_string(),
+                _ => return false,
+            };
+
+            if self.equals.as_ref().is_some_and(|e| *e != value) {
+                return false;
+            }
+
+            if self.matches.as_ref().is_some_and(|r| !r.is_match(&value)) {
+                return false;
+            }
+        }
+
+        if self.id_prefix.is_none() && self.id_regex.is_none() {
+            return true;
+        }
+
+        let id = match document.get_str("_id") {
+            Ok(id) => id,
+            Err(_) => return false,
+        };
+
+        if self.id_prefix.as_ref().is_some_and(|p| !id.starts_with(p)) {
+            return false;
+        }
+
+        match &self.id_regex {
+            Some(r) => r.is_match(id),
+            None => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bson::doc;
+
+    fn route(settings: RouteSettings) -> Route {
+        Route::new(&settings).unwrap()
+    }
+
+    fn settings(collection: &str) -> RouteSettings {
+        RouteSettings {
+            name: None,
+            collection: collection.to_string(),
+            database: None,
+            target: None,
+            field: None,
+            equals: None,
+            matches: None,
+            id_prefix: None,
+            id_regex: None,
+        }
+    }
+
+    #[test]
+    fn test_field_equals() {
+        let r = route(RouteSettings {
+            field: Some("type".to_string()),
+            equals: Some("cat".to_string()),
+            ..settings("felines")
+        });
+        assert!(r.is_match(&doc! { "_id": "1", "type": "cat" }));
+        assert!(!r.is_match(&doc! { "_id": "1", "type": "dog" }));
+        assert!(!r.is_match(&doc! { "_id": "1" }));
+        assert_eq!(r.name, "felines");
+    }
+
+    #[test]
+    fn test_field_matches() {
+        let r = route(RouteSettings {
+            field: Some("type".to_string()),
+            matches: Some("^(cat|lion)$".to_string()),
+            ..settings("felines")
+        });
+        assert!(r.is_match(&doc! { "type": "lion" }));
+        assert!(!r.is_match(&doc! { "type": "sea lion" }));
+    }
+
+    #[test]
+    fn test_id_prefix_and_regex() {
+        let r = route(RouteSettings {
+            id_prefix: Some("order:".to_string()),
+            id_regex: Some(r"\d+$".to_string()),
+            ..settings("orders")
+        });
+        assert!(r.is_match(&doc! { "_id": "order:123" }));
+        assert!(!r.is_match(&doc! { "_id": "order:abc" }));
+        assert!(!r.is_match(&doc! { "_id": "invoice:123" }));
+    }
+
+    #[test]
+    fn test_invalid_rules() {
+        assert!(Route::new(&settings("nothing")).is_err());
+        assert!(Route::new(&RouteSettings {
+            equals: Some("cat".to_string()),
+            ..settings("felines")
+        })
+        .is_err());
+        assert!(Route::new(&RouteSettings {
+            id_regex: Some("(".to_string()),
+            ..settings("broken")
+        })
+        .is_err());
+    }
+}
@@ -0,0 +1,239 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::scheduler::Job;
+use crate::settings::config_parser::Settings;
+use crate::verify::repair::DeadLetterRetries;
+use crate::verify::{Report, Verifier};
+use async_trait::async_trait;
+use bson::{doc, Document};
+use lazy_static::lazy_static;
+use prometheus::{register_int_gauge_vec, IntGaugeVec};
+use std::error::Error;
+use tokio::runtime::Handle;
+use tokio::task::spawn_blocking;
+use tracing::info;
+
+lazy_static! {
+    /// Number of indexes on each target collection, as of the last index audit.
+    pub static ref COLLECTION_INDEXES: IntGaugeVec = register_int_gauge_vec!(
+        "couch2mongo_collection_indexes",
+        "Number of indexes on each target collection as of the last index audit",
+        &["collection"]
+    )
+    .unwrap();
+
+    /// Checksum of each target collection as of the last checksum job, set to 1 and labelled
+    /// with the checksum.
+    pub static ref COLLECTION_CHECKSUM: IntGaugeVec = register_int_gauge_vec!(
+        "couch2mongo_collection_checksum_info",
+        "Checksum of each target collection as of the last checksum job",
+        &["collection", "md5"]
+    )
+    .unwrap();
+
+    /// Documents in each collection found by the last verification sample, by state: checked,
+    /// missing or mismatched.
+    pub static ref VERIFY_SAMPLE_DOCUMENTS: IntGaugeVec = register_int_gauge_vec!(
+        "couch2mongo_verify_sample_documents",
+        "Documents in each collection found by the last verification sample, by state",
+        &["collection", "state"]
+    )
+    .unwrap();
+}
+
+/// IndexAudit lists the indexes on every collection in the target database, logging them and
+/// publishing the count per collection.
+pub struct IndexAudit {
+    db: mongodb::Database,
+}
+
+impl IndexAudit {
+    pub fn new(db: mongodb::Database) -> IndexAudit {
+        IndexAudit { db }
+    }
+}
+
+#[async_trait]
+impl Job for IndexAudit {
+    fn name(&self) -> &str {
+        "index_audit"
+    }
+
+    async fn run(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        for collection_name in self.db.list_collection_names(None).await? {
+            let indexes = self
+                .db
+                .collection::<Document>(&collection_name)
+                .list_index_names()
+                .await?;
+
+            info!(
+                collection = collection_name.as_str(),
+                indexes = indexes.join(","),
+                "index audit"
+            );
+            COLLECTION_INDEXES
+                .with_label_values(&[collection_name.as_str()])
+                .set(indexes.len() as i64);
+        }
+
+        Ok(())
+    }
+}
+
+/// Checksum publishes MongoDB's `dbHash` of every collection in the target database, so two
+/// copies of it, or one before and after a change, can be compared without reading them.
+///
+/// `dbHash` holds a read lock on the database while it runs, so schedule it sparingly.
+pub struct Checksum {
+    db: mongodb::Database,
+}
+
+impl Checksum {
+    pub fn new(db: mongodb::Database) -> Checksum {
+        Checksum { db }
+    }
+}
+
+#[async_trait]
+impl Job for Checksum {
+    fn name(&self) -> &str {
+        "checksum"
+    }
+
+    async fn run(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let hashes = self.db.run_command(doc! { "dbHash": 1 }, None).await?;
+        let collections = hashes.get_document("collections")?;
+
+        // Only the latest checksums, rather than every one ever seen
+        COLLECTION_CHECKSUM.reset();
+        for (collection_name, md5) in collections {
+            let md5 = md5.as_str().unwrap_or_default();
+            info!(collection = collection_name.as_str(), md5, "checksum");
+            COLLECTION_CHECKSUM
+                .with_label_values(&[collection_name.as_str(), md5])
+                .set(1);
+        }
+
+        Ok(())
+    }
+}
+
+/// VerifySample compares a page of CouchDB documents from a random point with MongoDB, like
+/// `verify` does for every document, publishing how many were missing or mismatched.
+pub struct VerifySample {
+    settings: Settings,
+}
+
+impl VerifySample {
+    pub fn new(settings: Settings) -> VerifySample {
+        VerifySample { settings }
+    }
+}
+
+#[async_trait]
+impl Job for VerifySample {
+    fn name(&self) -> &str {
+        "verify_sample"
+    }
+
+    async fn run(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        // Verifying isn't Send, so it's run to completion on a blocking thread
+        let settings = self.settings.clone();
+        let runtime = Handle::current();
+        let report = spawn_blocking(move || runtime.block_on(sample(&settings))).await??;
+
+        VERIFY_SAMPLE_DOCUMENTS.reset();
+        for (collection_name, collection) in &report.collections {
+            info!(
+                collection = collection_name.as_str(),
+                checked = collection.checked,
+                missing = collection.missing.join(","),
+                mismatched = collection.mismatched.join(","),
+                "verification sample"
+            );
+            for (state, count) in [
+                ("checked", collection.checked as i64),
+                ("missing", collection.missing.len() as i64),
+                ("mismatched", collection.mismatched.len() as i64),
+            ] {
+                VERIFY_SAMPLE_DOCUMENTS
+                    .with_label_values(&[collection_name.as_str(), state])
+                    .set(count);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// sample verifies a sample of documents, with errors as strings so they can cross threads.
+async fn sample(settings: &Settings) -> Result<Report, String> {
+    let verifier = Verifier::new(settings).await.map_err(|e| e.to_string())?;
+    verifier.sample().await.map_err(|e| e.to_string())
+}
+
+/// DeadLetterRetry sweeps the dead letter collection, copying documents that replication would
+/// now accept, eg. after a schema or ID rule was relaxed, and dropping those deleted from CouchDB.
+pub struct DeadLetterRetry {
+    settings: Settings,
+}
+
+impl DeadLetterRetry {
+    pub fn new(settings: Settings) -> DeadLetterRetry {
+        DeadLetterRetry { settings }
+    }
+}
+
+#[async_trait]
+impl Job for DeadLetterRetry {
+    fn name(&self) -> &str {
+        "dead_letter_retry"
+    }
+
+    async fn run(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        if self.settings.dry_run {
+            info!("dry run, not retrying dead letters");
+            return Ok(());
+        }
+
+        // Like verifying, retrying isn't Send
+        let settings = self.settings.clone();
+        let runtime = Handle::current();
+        let retries =
+            spawn_blocking(move || runtime.block_on(retry_dead_letters(&settings))).await??;
+        info!(
+            copied = retries.copied,
+            vanished = retries.vanished,
+            rejected = retries.rejected,
+            "retried dead letters"
+        );
+
+        Ok(())
+    }
+}
+
+/// retry_dead_letters retries the dead letter collection, with errors as strings so they can
+/// cross threads.
+async fn retry_dead_letters(settings: &Settings) -> Result<DeadLetterRetries, String> {
+    let retry = async {
+        let verifier = Verifier::new(settings).await?;
+        let db = settings.get_mongodb_database().await?;
+        let attachments = settings.get_attachment_store(&db).await?;
+        verifier.retry_dead_letters(attachments.as_ref()).await
+    };
+
+    retry.await.map_err(|e| e.to_string())
+}
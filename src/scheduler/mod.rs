@@ -0,0 +1,176 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+pub mod jobs;
+
+use crate::settings::config_parser::JobSettings;
+use async_trait::async_trait;
+use lazy_static::lazy_static;
+use prometheus::{
+    register_histogram_vec,
+    register_int_counter_vec,
+    register_int_gauge_vec,
+    HistogramVec,
+    IntCounterVec,
+    IntGaugeVec,
+};
+use rand::Rng;
+use std::error::Error;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::{debug, info, warn};
+
+lazy_static! {
+    /// Scheduled job runs, by job and outcome.
+    pub static ref JOB_RUNS: IntCounterVec = register_int_counter_vec!(
+        "couch2mongo_scheduler_job_runs_total",
+        "Scheduled job runs by job and outcome",
+        &["job", "outcome"]
+    )
+    .unwrap();
+
+    /// Scheduled job run durations.
+    pub static ref JOB_DURATION: HistogramVec = register_histogram_vec!(
+        "couch2mongo_scheduler_job_duration_seconds",
+        "Scheduled job run duration",
+        &["job"]
+    )
+    .unwrap();
+
+    /// Unix time of each job's last successful run.
+    pub static ref JOB_LAST_SUCCESS: IntGaugeVec = register_int_gauge_vec!(
+        "couch2mongo_scheduler_job_last_success_timestamp_seconds",
+        "Unix time of the last successful run of each job",
+        &["job"]
+    )
+    .unwrap();
+}
+
+/// Job is a periodic maintenance task run inside the streamer process.
+#[async_trait]
+pub trait Job: Send + Sync {
+    /// name identifies the job in logs and metrics.
+    fn name(&self) -> &str;
+
+    /// run performs one execution of the job.
+    async fn run(&self) -> Result<(), Box<dyn Error + Send + Sync>>;
+}
+
+/// Scheduler runs registered jobs on jittered intervals, so a fleet of replicators doesn't hit the
+/// databases in lock-step.
+#[derive(Default)]
+pub struct Scheduler {
+    jobs: Vec<(Arc<dyn Job>, JobSettings)>,
+}
+
+impl Scheduler {
+    /// new creates an empty Scheduler.
+    pub fn new() -> Scheduler {
+        Scheduler::default()
+    }
+
+    /// add registers a job to be run with the given settings.
+    pub fn add(&mut self, job: Arc<dyn Job>, settings: JobSettings) {
+        self.jobs.push((job, settings));
+    }
+
+    /// start spawns a task for every enabled job.
+    pub fn start(self) {
+        for (job, settings) in self.jobs {
+            if !settings.enabled {
+                info!(job = job.name(), "scheduled job disabled");
+                continue;
+            }
+
+            info!(
+                job = job.name(),
+                interval_secs = settings.interval_secs,
+                jitter_secs = settings.jitter_secs,
+                "scheduling job"
+            );
+            tokio::spawn(run_job(job, settings));
+        }
+    }
+}
+
+/// run_job runs a job forever, sleeping for the interval plus a random jitter between runs.
+async fn run_job(job: Arc<dyn Job>, settings: JobSettings) {
+    loop {
+        tokio::time::sleep(next_delay(&settings)).await;
+
+        debug!(job = job.name(), "running scheduled job");
+        let started = Instant::now();
+        let result = job.run().await.map_err(|e| e.to_string());
+        JOB_DURATION
+            .with_label_values(&[job.name()])
+            .observe(started.elapsed().as_secs_f64());
+
+        match result {
+            Ok(()) => {
+                JOB_RUNS.with_label_values(&[job.name(), "success"]).inc();
+                JOB_LAST_SUCCESS.with_label_values(&[job.name()]).set(
+                    SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .map(|d| d.as_secs() as i64)
+                        .unwrap_or_default(),
+                );
+            }
+            Err(e) => {
+                JOB_RUNS.with_label_values(&[job.name(), "failure"]).inc();
+                warn!(job = job.name(), error = e, "scheduled job failed");
+            }
+        }
+    }
+}
+
+/// next_delay returns the interval plus a random jitter of up to `jitter_secs`.
+fn next_delay(settings: &JobSettings) -> Duration {
+    let jitter_ms = match settings.jitter_secs {
+        0 => 0,
+        j => rand::thread_rng().gen_range(0..=j * 1000),
+    };
+
+    Duration::from_secs(settings.interval_secs) + Duration::from_millis(jitter_ms)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_next_delay_within_jitter() {
+        let settings = JobSettings {
+            enabled: true,
+            interval_secs: 60,
+            jitter_secs: 10,
+        };
+
+        for _ in 0..100 {
+            let delay = next_delay(&settings);
+            assert!(delay >= Duration::from_secs(60));
+            assert!(delay <= Duration::from_secs(70));
+        }
+    }
+
+    #[test]
+    fn test_next_delay_without_jitter() {
+        let settings = JobSettings {
+            enabled: true,
+            interval_secs: 5,
+            jitter_secs: 0,
+        };
+
+        assert_eq!(next_delay(&settings), Duration::from_secs(5));
+    }
+}
@@ -0,0 +1,181 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use aws_config::{BehaviorVersion, SdkConfig};
+use serde_json::Value;
+use std::error::Error;
+use tokio::sync::OnceCell;
+use tracing::info;
+
+/// SecretRef is a reference to a secret held outside the config file.
+#[derive(Debug, PartialEq)]
+pub enum SecretRef {
+    /// `awssecret://name`, or `awssecret://name#key` for one key of a JSON secret.
+    SecretsManager { name: String, key: Option<String> },
+    /// `awsssm://name`, a (decrypted) SSM Parameter Store parameter.
+    Parameter { name: String },
+}
+
+impl SecretRef {
+    /// parse returns the reference a setting holds, or None if it's a plain value.
+    pub fn parse(value: &str) -> Option<SecretRef> {
+        if let Some(reference) = value.strip_prefix("awssecret://") {
+            let (name, key) = match reference.split_once('#') {
+                Some((name, key)) => (name, Some(key.to_string())),
+                None => (reference, None),
+            };
+
+            return Some(SecretRef::SecretsManager {
+                name: name.to_string(),
+                key,
+            });
+        }
+
+        value
+            .strip_prefix("awsssm://")
+            .map(|name| SecretRef::Parameter {
+                name: name.to_string(),
+            })
+    }
+}
+
+/// SecretResolver looks up secret references with the AWS SDK, using the default credential
+/// chain.
+pub struct SecretResolver {
+    config: OnceCell<SdkConfig>,
+}
+
+impl SecretResolver {
+    pub fn new() -> SecretResolver {
+        SecretResolver {
+            config: OnceCell::new(),
+        }
+    }
+
+    /// resolve returns the secret a value refers to, or the value itself if it isn't a reference.
+    ///
+    /// # Arguments
+    /// * `value` - A setting's value
+    ///
+    /// # Returns
+    /// * The resolved value
+    pub async fn resolve(&self, value: &str) -> Result<String, Box<dyn Error>> {
+        let reference = match SecretRef::parse(value) {
+            Some(reference) => reference,
+            None => return Ok(value.to_string()),
+        };
+        let config = self
+            .config
+            .get_or_init(|| aws_config::load_defaults(BehaviorVersion::v2023_11_09()))
+            .await;
+
+        match reference {
+            SecretRef::SecretsManager { name, key } => {
+                info!(
+                    name = name.as_str(),
+                    "resolving secret from Secrets Manager"
+                );
+
+                let output = aws_sdk_secretsmanager::Client::new(config)
+                    .get_secret_value()
+                    .secret_id(&name)
+                    .send()
+                    .await?;
+                let secret = output
+                    .secret_string()
+                    .ok_or_else(|| format!("secret {} has no string value", name))?;
+
+                match key {
+                    Some(key) => secret_key(secret, &key)
+                        .ok_or_else(|| format!("secret {} has no string key {}", name, key).into()),
+                    None => Ok(secret.to_string()),
+                }
+            }
+            SecretRef::Parameter { name } => {
+                info!(
+                    name = name.as_str(),
+                    "resolving secret from Parameter Store"
+                );
+
+                let output = aws_sdk_ssm::Client::new(config)
+                    .get_parameter()
+                    .name(&name)
+                    .with_decryption(true)
+                    .send()
+                    .await?;
+
+                output
+                    .parameter()
+                    .and_then(|p| p.value())
+                    .map(str::to_string)
+                    .ok_or_else(|| format!("parameter {} has no value", name).into())
+            }
+        }
+    }
+
+    /// resolve_option resolves an optional setting in place.
+    pub async fn resolve_option(&self, value: &mut Option<String>) -> Result<(), Box<dyn Error>> {
+        if let Some(v) = value {
+            *v = self.resolve(v).await?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for SecretResolver {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// secret_key returns a string key from a secret holding a JSON object, as Secrets Manager does
+/// for key/value secrets.
+fn secret_key(secret: &str, key: &str) -> Option<String> {
+    let value: Value = serde_json::from_str(secret).ok()?;
+
+    value.get(key)?.as_str().map(str::to_string)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            SecretRef::parse("awssecret://prod/couchdb#password"),
+            Some(SecretRef::SecretsManager {
+                name: "prod/couchdb".to_string(),
+                key: Some("password".to_string()),
+            })
+        );
+        assert_eq!(
+            SecretRef::parse("awsssm:///couch2mongo/mongodb"),
+            Some(SecretRef::Parameter {
+                name: "/couch2mongo/mongodb".to_string(),
+            })
+        );
+        assert_eq!(SecretRef::parse("mongodb://localhost:27017"), None);
+    }
+
+    #[test]
+    fn test_secret_key() {
+        let secret = r#"{"username": "couch", "password": "s3cret", "port": 5984}"#;
+
+        assert_eq!(secret_key(secret, "password"), Some("s3cret".to_string()));
+        assert_eq!(secret_key(secret, "port"), None);
+        assert_eq!(secret_key("not json", "password"), None);
+    }
+}
@@ -0,0 +1,135 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::couchdb::CouchConnection;
+use crate::seqstore::interface::SequenceStore;
+use async_trait::async_trait;
+use reqwest::{Method, RequestBuilder, StatusCode};
+use serde_json::{json, Value};
+use std::error::Error;
+
+/// CouchDB is a SequenceStore that keeps checkpoints in `_local` documents in the source database,
+/// the same way CouchDB's own replicator does. No extra datastore is needed at all.
+///
+/// `_local` documents are never replicated and don't appear in the changes feed, so writing the
+/// checkpoint doesn't generate changes for us to consume.
+pub struct CouchDB {
+    pub connection: CouchConnection,
+    pub database: String,
+    pub prefix: String,
+}
+
+impl CouchDB {
+    /// new creates a new CouchDB struct.
+    ///
+    /// # Arguments
+    /// * `connection` - The connection to the source server
+    /// * `database` - The source database name
+    /// * `prefix` - Prefix for the `_local` document IDs
+    ///
+    /// # Returns
+    /// * A CouchDB struct
+    pub fn new(connection: CouchConnection, database: &str, prefix: &str) -> CouchDB {
+        CouchDB {
+            connection,
+            database: database.to_string(),
+            prefix: prefix.to_string(),
+        }
+    }
+
+    fn path(&self, key: &str) -> String {
+        format!("{}/_local/{}{}", self.database, self.prefix, key)
+    }
+
+    fn request(&self, method: Method, key: &str) -> RequestBuilder {
+        self.connection.req(method, &self.path(key), None)
+    }
+
+    /// get_document fetches the `_local` document for a key, if it exists.
+    async fn get_document(&self, key: &str) -> Result<Option<Value>, Box<dyn Error>> {
+        let response = self
+            .connection
+            .send(self.request(Method::GET, key), "local")
+            .await?;
+
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+
+        Ok(Some(response.error_for_status()?.json().await?))
+    }
+}
+
+#[async_trait]
+impl SequenceStore for CouchDB {
+    async fn set(&self, key: &str, value: &str) -> Result<(), Box<dyn Error>> {
+        let mut document = json!({ "value": value });
+
+        if let Some(rev) = self
+            .get_document(key)
+            .await?
+            .and_then(|d| d.get("_rev").cloned())
+        {
+            document["_rev"] = rev;
+        }
+
+        self.connection
+            .send(self.request(Method::PUT, key).json(&document), "local")
+            .await?
+            .error_for_status()?;
+
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<String>, Box<dyn Error>> {
+        Ok(self
+            .get_document(key)
+            .await?
+            .and_then(|d| d.get("value").and_then(|v| v.as_str()).map(str::to_string)))
+    }
+
+    async fn set_if_equals(
+        &self,
+        key: &str,
+        expected: Option<&str>,
+        value: &str,
+    ) -> Result<bool, Box<dyn Error>> {
+        let current = self.get_document(key).await?;
+        let current_value = current
+            .as_ref()
+            .and_then(|d| d.get("value"))
+            .and_then(|v| v.as_str());
+
+        if current_value != expected {
+            return Ok(false);
+        }
+
+        let mut document = json!({ "value": value });
+        if let Some(rev) = current.as_ref().and_then(|d| d.get("_rev")) {
+            document["_rev"] = rev.clone();
+        }
+
+        // The revision makes the write conditional: anyone else updating in between gets us a 409
+        let response = self
+            .connection
+            .send(self.request(Method::PUT, key).json(&document), "local")
+            .await?;
+        if response.status() == StatusCode::CONFLICT {
+            return Ok(false);
+        }
+
+        response.error_for_status()?;
+        Ok(true)
+    }
+}
@@ -0,0 +1,87 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::seqstore::interface::SequenceStore;
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::{Arc, RwLock};
+use tracing::info;
+
+/// DryRun wraps a sequence store so that nothing is written to it.
+///
+/// Reads go to the wrapped store until a key is set, after which the value set is returned, so
+/// the rest of the application behaves as it would on a real run.
+pub struct DryRun {
+    inner: Arc<dyn SequenceStore>,
+    written: RwLock<HashMap<String, String>>,
+}
+
+impl DryRun {
+    pub fn new(inner: Arc<dyn SequenceStore>) -> Self {
+        DryRun {
+            inner,
+            written: RwLock::new(HashMap::new()),
+        }
+    }
+}
+
+#[async_trait]
+impl SequenceStore for DryRun {
+    async fn set(&self, key: &str, value: &str) -> Result<(), Box<dyn Error>> {
+        info!(key, value, "dry run, would set sequence store key");
+        self.written
+            .write()
+            .expect("unable to write to written")
+            .insert(key.to_string(), value.to_string());
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<String>, Box<dyn Error>> {
+        let written = self
+            .written
+            .read()
+            .expect("unable to read from written")
+            .get(key)
+            .cloned();
+
+        match written {
+            Some(value) => Ok(Some(value)),
+            None => self.inner.get(key).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::seqstore::null::Null;
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn test_dry_run_does_not_write_through() {
+        let rt = Runtime::new().unwrap();
+        let inner: Arc<dyn SequenceStore> = Arc::new(Null::new());
+        let store = DryRun::new(inner.clone());
+
+        rt.block_on(async {
+            inner.set("key", "1-abc").await.unwrap();
+            assert_eq!(store.get("key").await.unwrap(), Some("1-abc".to_string()));
+
+            store.set("key", "2-def").await.unwrap();
+            assert_eq!(store.get("key").await.unwrap(), Some("2-def".to_string()));
+            assert_eq!(inner.get("key").await.unwrap(), Some("1-abc".to_string()));
+        });
+    }
+}
@@ -12,26 +12,42 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::couchdb::sequence_number;
 use crate::seqstore::interface::SequenceStore;
-use crate::settings::config_parser::DynamoDBSettings;
+use crate::settings::config_parser::{DynamoDBBillingMode, DynamoDBSettings};
 use async_trait::async_trait;
+use aws_config::sts::AssumeRoleProvider;
 use aws_config::BehaviorVersion;
+use aws_sdk_dynamodb::config::Region;
 use aws_sdk_dynamodb::types::{
     AttributeDefinition,
     AttributeValue,
     BillingMode,
     KeySchemaElement,
     KeyType,
+    ProvisionedThroughput,
     ScalarAttributeType,
+    SseSpecification,
+    SseType,
     TableStatus,
+    Tag,
+    TimeToLiveSpecification,
 };
 use aws_sdk_dynamodb::Client;
+use std::collections::HashMap;
 use std::error::Error;
+use std::time::{SystemTime, UNIX_EPOCH};
 use tracing::info;
 
 pub struct DynamoDB {
     pub client: Client,
     pub table_name: String,
+    pub key_attribute: String,
+    pub value_attribute: String,
+    // Number attribute holding a checkpoint's sequence number, which may only go up
+    pub counter_attribute: String,
+    // Attribute holding the expiry time, and how long after each write it is
+    pub ttl: Option<(String, u64)>,
 }
 
 impl DynamoDB {
@@ -43,26 +59,46 @@ impl DynamoDB {
     /// # Returns
     /// * A DynamoDB struct
     pub async fn new(settings: &DynamoDBSettings) -> DynamoDB {
-        let shared_config = aws_config::load_defaults(BehaviorVersion::v2023_11_09()).await;
+        let mut loader = aws_config::defaults(BehaviorVersion::v2023_11_09());
+        if let Some(region) = &settings.region {
+            loader = loader.region(Region::new(region.clone()));
+        }
+        if let Some(profile) = &settings.profile {
+            loader = loader.profile_name(profile);
+        }
+        let shared_config = loader.load().await;
+
+        let mut builder = aws_sdk_dynamodb::config::Builder::from(&shared_config);
+
+        if let Some(url) = &settings.local_url {
+            info!(url = url.as_str(), "using local DynamoDB");
+            builder = builder.endpoint_url(url);
+        }
 
-        let actual_config = match &settings.local_url {
-            Some(url) => {
-                info!(url = url.as_str(), "using local DynamoDB");
+        // The table can live in another account, reached through a role there
+        if let Some(role_arn) = &settings.assume_role_arn {
+            info!(role_arn = role_arn.as_str(), "assuming role for DynamoDB");
 
-                aws_sdk_dynamodb::config::Builder::from(&shared_config)
-                    .endpoint_url(url)
-                    .build()
+            let mut provider = AssumeRoleProvider::builder(role_arn)
+                .session_name("couch2mongo")
+                .configure(&shared_config);
+            if let Some(external_id) = &settings.external_id {
+                provider = provider.external_id(external_id);
             }
-            None => aws_sdk_dynamodb::config::Builder::from(&shared_config).build(),
-        };
+            builder = builder.credentials_provider(provider.build().await);
+        }
 
         let r = DynamoDB {
-            client: Client::from_conf(actual_config),
+            client: Client::from_conf(builder.build()),
             table_name: settings.table.clone(),
+            key_attribute: settings.key_attribute.clone(),
+            value_attribute: settings.value_attribute.clone(),
+            counter_attribute: settings.counter_attribute.clone(),
+            ttl: settings.ttl_attribute.clone().zip(settings.ttl_seconds),
         };
 
         if settings.create_table {
-            r.create_table().await.unwrap();
+            r.create_table(settings).await.unwrap();
         }
 
         r
@@ -72,10 +108,11 @@ impl DynamoDB {
     ///
     /// # Arguments
     /// * `self` - A DynamoDB struct
+    /// * `settings` - The capacity, encryption and tags for a new table
     ///
     /// # Returns
     /// * An empty Result
-    pub async fn create_table(&self) -> Result<(), Box<dyn Error>> {
+    pub async fn create_table(&self, settings: &DynamoDBSettings) -> Result<(), Box<dyn Error>> {
         let r = self
             .client
             .describe_table()
@@ -84,26 +121,57 @@ impl DynamoDB {
             .await;
 
         if r.is_err() {
-            info!(table_name = self.table_name.as_str(), "creating table");
+            info!(
+                table_name = self.table_name.as_str(),
+                billing_mode = settings.billing_mode.as_str(),
+                "creating table"
+            );
 
-            self.client
+            let mut request = self
+                .client
                 .create_table()
                 .table_name(self.table_name.clone())
                 .attribute_definitions(
                     AttributeDefinition::builder()
-                        .attribute_name("key")
+                        .attribute_name(self.key_attribute.clone())
                         .attribute_type(ScalarAttributeType::S)
                         .build()?,
                 )
                 .key_schema(
                     KeySchemaElement::builder()
-                        .attribute_name("key")
+                        .attribute_name(self.key_attribute.clone())
                         .key_type(KeyType::Hash)
                         .build()?,
                 )
-                .billing_mode(BillingMode::PayPerRequest)
-                .send()
-                .await?;
+                .billing_mode(match settings.billing_mode {
+                    DynamoDBBillingMode::PayPerRequest => BillingMode::PayPerRequest,
+                    DynamoDBBillingMode::Provisioned => BillingMode::Provisioned,
+                });
+
+            if settings.billing_mode == DynamoDBBillingMode::Provisioned {
+                request = request.provisioned_throughput(
+                    ProvisionedThroughput::builder()
+                        .read_capacity_units(settings.read_capacity_units)
+                        .write_capacity_units(settings.write_capacity_units)
+                        .build()?,
+                );
+            }
+
+            if let Some(kms_key_id) = &settings.kms_key_id {
+                request = request.sse_specification(
+                    SseSpecification::builder()
+                        .enabled(true)
+                        .sse_type(SseType::Kms)
+                        .kms_master_key_id(kms_key_id)
+                        .build(),
+                );
+            }
+
+            for (key, value) in &settings.tags {
+                request = request.tags(Tag::builder().key(key).value(value).build()?);
+            }
+
+            request.send().await?;
         }
 
         // Wait for table 'r' to become available, waiting 1 second between checks
@@ -128,8 +196,130 @@ impl DynamoDB {
         }
 
         info!(table_name = self.table_name.as_str(), "table is available");
+
+        // Only enabled on tables we create; a shared table's TTL is left to its owner
+        if r.is_err() {
+            if let Some((attribute, _)) = &self.ttl {
+                info!(
+                    table_name = self.table_name.as_str(),
+                    attribute = attribute.as_str(),
+                    "enabling time to live"
+                );
+
+                self.client
+                    .update_time_to_live()
+                    .table_name(self.table_name.clone())
+                    .time_to_live_specification(
+                        TimeToLiveSpecification::builder()
+                            .attribute_name(attribute.clone())
+                            .enabled(true)
+                            .build()?,
+                    )
+                    .send()
+                    .await?;
+            }
+        }
+
         Ok(())
     }
+
+    /// item returns the attributes to store for a sequence, including its expiry time if a TTL is
+    /// configured and its sequence number if it's a checkpoint.
+    fn item(
+        &self,
+        key: &str,
+        value: &str,
+        counter: Option<u64>,
+    ) -> HashMap<String, AttributeValue> {
+        let mut item = HashMap::from([
+            (
+                self.key_attribute.clone(),
+                AttributeValue::S(key.to_string()),
+            ),
+            (
+                self.value_attribute.clone(),
+                AttributeValue::S(value.to_string()),
+            ),
+        ]);
+
+        if let Some((attribute, seconds)) = &self.ttl {
+            item.insert(
+                attribute.clone(),
+                AttributeValue::N(expires_at(SystemTime::now(), *seconds).to_string()),
+            );
+        }
+
+        if let Some(counter) = counter {
+            item.insert(
+                self.counter_attribute.clone(),
+                AttributeValue::N(counter.to_string()),
+            );
+        }
+
+        item
+    }
+
+    /// put_if writes a sequence if the current one is `expected` and, when `counter` is given, the
+    /// stored sequence number is absent or no greater than it.
+    ///
+    /// # Returns
+    /// * true if the sequence was written, false if a condition failed
+    async fn put_if(
+        &self,
+        key: &str,
+        expected: Option<&str>,
+        value: &str,
+        counter: Option<u64>,
+    ) -> Result<bool, Box<dyn Error>> {
+        let mut request = self
+            .client
+            .put_item()
+            .table_name(self.table_name.clone())
+            .set_item(Some(self.item(key, value, counter)))
+            .condition_expression(condition_expression(expected.is_some(), counter.is_some()))
+            .expression_attribute_names("#v", self.value_attribute.clone());
+
+        if let Some(expected) = expected {
+            request = request
+                .expression_attribute_values(":expected", AttributeValue::S(expected.to_string()));
+        }
+        if let Some(counter) = counter {
+            request = request
+                .expression_attribute_names("#c", self.counter_attribute.clone())
+                .expression_attribute_values(":counter", AttributeValue::N(counter.to_string()));
+        }
+
+        match request.send().await {
+            Ok(_) => Ok(true),
+            Err(e) => match e.into_service_error() {
+                e if e.is_conditional_check_failed_exception() => Ok(false),
+                e => Err(e.into()),
+            },
+        }
+    }
+}
+
+/// condition_expression returns the put_item condition for put_if.
+///
+/// Equal sequence numbers are allowed, as clustered CouchDB can give different sequences the same
+/// number.
+fn condition_expression(expected: bool, counter: bool) -> String {
+    let mut condition = if expected {
+        "#v = :expected".to_string()
+    } else {
+        "attribute_not_exists(#v)".to_string()
+    };
+
+    if counter {
+        condition.push_str(" AND (attribute_not_exists(#c) OR #c <= :counter)");
+    }
+
+    condition
+}
+
+/// expires_at returns the epoch second `seconds` after `now`, as DynamoDB's TTL expects.
+fn expires_at(now: SystemTime, seconds: u64) -> u64 {
+    now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() + seconds
 }
 
 #[async_trait]
@@ -138,8 +328,7 @@ impl SequenceStore for DynamoDB {
         self.client
             .put_item()
             .table_name(self.table_name.clone())
-            .item("key", AttributeValue::S(key.to_string()))
-            .item("value", AttributeValue::S(value.to_string()))
+            .set_item(Some(self.item(key, value, None)))
             .send()
             .await?;
 
@@ -151,13 +340,16 @@ impl SequenceStore for DynamoDB {
             .client
             .get_item()
             .table_name(self.table_name.clone())
-            .key("key", AttributeValue::S(key.to_string()))
+            .key(
+                self.key_attribute.clone(),
+                AttributeValue::S(key.to_string()),
+            )
             .consistent_read(true)
             .send()
             .await?;
 
         match r.item {
-            Some(item) => match item.get("value") {
+            Some(item) => match item.get(&self.value_attribute) {
                 Some(value) => match value.as_s() {
                     Ok(s) => Ok(Some(s.to_string())),
                     Err(_) => Ok(None),
@@ -167,4 +359,47 @@ impl SequenceStore for DynamoDB {
             None => Ok(None),
         }
     }
+
+    async fn set_if_equals(
+        &self,
+        key: &str,
+        expected: Option<&str>,
+        value: &str,
+    ) -> Result<bool, Box<dyn Error>> {
+        self.put_if(key, expected, value, None).await
+    }
+
+    async fn set_checkpoint(
+        &self,
+        key: &str,
+        expected: Option<&str>,
+        value: &str,
+    ) -> Result<bool, Box<dyn Error>> {
+        self.put_if(key, expected, value, sequence_number(value))
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_expires_at() {
+        let now = UNIX_EPOCH + Duration::from_millis(1_700_000_000_500);
+        assert_eq!(expires_at(now, 3600), 1_700_003_600);
+    }
+
+    #[test]
+    fn test_condition_expression() {
+        assert_eq!(
+            condition_expression(false, false),
+            "attribute_not_exists(#v)"
+        );
+        assert_eq!(
+            condition_expression(true, true),
+            "#v = :expected AND (attribute_not_exists(#c) OR #c <= :counter)"
+        );
+    }
 }
@@ -0,0 +1,143 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::seqstore::interface::SequenceStore;
+use crate::settings::config_parser::FileSettings;
+use async_trait::async_trait;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs;
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// File is a SequenceStore that keeps checkpoints in a JSON file, the simplest durable option for
+/// a container with a mounted volume.
+///
+/// Writes go to a temporary file which is fsynced and then renamed over the original, so a crash
+/// mid-write leaves either the old or the new checkpoint, never a torn file.
+pub struct File {
+    pub path: PathBuf,
+    lock: Mutex<()>,
+}
+
+impl File {
+    /// new creates a new File struct.
+    ///
+    /// # Arguments
+    /// * `settings` - A FileSettings struct
+    ///
+    /// # Returns
+    /// * A File struct
+    pub fn new(settings: &FileSettings) -> File {
+        File {
+            path: PathBuf::from(&settings.path),
+            lock: Mutex::new(()),
+        }
+    }
+
+    /// read loads every checkpoint from the file, returning an empty map if it doesn't exist.
+    fn read(&self) -> Result<BTreeMap<String, String>, Box<dyn Error>> {
+        match fs::read(&self.path) {
+            Ok(contents) => Ok(serde_json::from_slice(&contents)?),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(BTreeMap::new()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// write atomically replaces the file with the given checkpoints.
+    fn write(&self, sequences: &BTreeMap<String, String>) -> Result<(), Box<dyn Error>> {
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_path);
+
+        let mut tmp = fs::File::create(&tmp_path)?;
+        tmp.write_all(&serde_json::to_vec_pretty(sequences)?)?;
+        tmp.sync_all()?;
+        drop(tmp);
+
+        fs::rename(&tmp_path, &self.path)?;
+
+        // fsync the directory so the rename itself is durable
+        let directory = match self.path.parent() {
+            Some(p) if !p.as_os_str().is_empty() => p,
+            _ => Path::new("."),
+        };
+        fs::File::open(directory)?.sync_all()?;
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl SequenceStore for File {
+    async fn set(&self, key: &str, value: &str) -> Result<(), Box<dyn Error>> {
+        let _guard = self.lock.lock().expect("unable to lock file");
+
+        let mut sequences = self.read()?;
+        sequences.insert(key.to_string(), value.to_string());
+        self.write(&sequences)
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<String>, Box<dyn Error>> {
+        let _guard = self.lock.lock().expect("unable to lock file");
+
+        Ok(self.read()?.remove(key))
+    }
+
+    async fn set_if_equals(
+        &self,
+        key: &str,
+        expected: Option<&str>,
+        value: &str,
+    ) -> Result<bool, Box<dyn Error>> {
+        let _guard = self.lock.lock().expect("unable to lock file");
+
+        let mut sequences = self.read()?;
+        if sequences.get(key).map(String::as_str) != expected {
+            return Ok(false);
+        }
+
+        sequences.insert(key.to_string(), value.to_string());
+        self.write(&sequences)?;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn test_file_sequence_store() {
+        let rt = Runtime::new().unwrap();
+        let path =
+            std::env::temp_dir().join(format!("couch2mongo-seq-{}.json", std::process::id()));
+        let store = File::new(&FileSettings {
+            path: path.to_string_lossy().to_string(),
+        });
+
+        rt.block_on(async {
+            assert_eq!(store.get("a").await.unwrap(), None);
+
+            store.set("a", "1-abc").await.unwrap();
+            store.set("b", "2-def").await.unwrap();
+            assert_eq!(store.get("a").await.unwrap(), Some("1-abc".to_string()));
+            assert_eq!(store.get("b").await.unwrap(), Some("2-def".to_string()));
+        });
+
+        fs::remove_file(path).unwrap();
+    }
+}
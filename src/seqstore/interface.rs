@@ -16,8 +16,46 @@ use async_trait::async_trait;
 use std::error::Error;
 
 #[async_trait]
-pub trait SequenceStore {
+pub trait SequenceStore: Send + Sync {
     async fn set(&self, key: &str, value: &str) -> Result<(), Box<dyn Error>>;
 
     async fn get(&self, key: &str) -> Result<Option<String>, Box<dyn Error>>;
+
+    /// set_if_equals sets the value only if the current value is `expected` (None meaning the key
+    /// isn't set), so two instances sharing a key fence each other out instead of racing.
+    ///
+    /// The default implementation reads and then writes, which isn't atomic. Stores that can
+    /// compare-and-set natively override it.
+    ///
+    /// # Returns
+    /// * true if the value was set, false if the current value didn't match
+    async fn set_if_equals(
+        &self,
+        key: &str,
+        expected: Option<&str>,
+        value: &str,
+    ) -> Result<bool, Box<dyn Error>> {
+        if self.get(key).await?.as_deref() != expected {
+            return Ok(false);
+        }
+
+        self.set(key, value).await?;
+        Ok(true)
+    }
+
+    /// set_checkpoint moves a checkpoint from `expected` to `value`, like set_if_equals.
+    ///
+    /// Stores that can also refuse to move a checkpoint backwards, so a lagging instance can never
+    /// overwrite a newer checkpoint with an older one, override it.
+    ///
+    /// # Returns
+    /// * true if the checkpoint was set, false if it had been moved elsewhere
+    async fn set_checkpoint(
+        &self,
+        key: &str,
+        expected: Option<&str>,
+        value: &str,
+    ) -> Result<bool, Box<dyn Error>> {
+        self.set_if_equals(key, expected, value).await
+    }
 }
@@ -12,7 +12,13 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+pub mod couchdb;
+pub mod dryrun;
 pub mod dynamodb;
+pub mod file;
 pub mod interface;
+pub mod mongodb;
 pub mod null;
+pub mod probe;
 pub mod redis;
+pub mod sqlite;
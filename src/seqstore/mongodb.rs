@@ -0,0 +1,132 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::seqstore::interface::SequenceStore;
+use async_trait::async_trait;
+use bson::{doc, Document};
+use mongodb::error::{ErrorKind, WriteFailure};
+use mongodb::options::UpdateOptions;
+use mongodb::{Collection, Database};
+use std::error::Error;
+use tracing::info;
+
+/// MongoDB keeps checkpoints in a collection of the target database, as `{_id: key, value: seq}`.
+///
+/// Keeping the checkpoint next to the data is what lets transactional writes commit both together.
+pub struct MongoDB {
+    pub collection: Collection<Document>,
+}
+
+impl MongoDB {
+    /// new creates a new MongoDB struct.
+    ///
+    /// # Arguments
+    /// * `db` - The MongoDB database
+    /// * `collection` - The checkpoint collection name
+    ///
+    /// # Returns
+    /// * A MongoDB struct
+    pub fn new(db: &Database, collection: &str) -> MongoDB {
+        info!(collection, "using MongoDB sequence store");
+
+        MongoDB {
+            collection: db.collection(collection),
+        }
+    }
+}
+
+/// compare_and_set_filter returns the filter, and whether to upsert, for updating a checkpoint
+/// only if it currently holds `expected` (None meaning it isn't set).
+///
+/// When the checkpoint isn't expected to exist the update upserts, so a checkpoint created in the
+/// meantime fails it with a duplicate key error.
+pub fn compare_and_set_filter(key: &str, expected: Option<&str>) -> (Document, bool) {
+    match expected {
+        Some(expected) => (doc! { "_id": key, "value": expected }, false),
+        None => (doc! { "_id": key, "value": { "$exists": false } }, true),
+    }
+}
+
+/// is_duplicate_key returns true if an error is a duplicate key error.
+pub fn is_duplicate_key(e: &mongodb::error::Error) -> bool {
+    matches!(
+        e.kind.as_ref(),
+        ErrorKind::Write(WriteFailure::WriteError(w)) if w.code == 11000
+    )
+}
+
+#[async_trait]
+impl SequenceStore for MongoDB {
+    async fn set(&self, key: &str, value: &str) -> Result<(), Box<dyn Error>> {
+        self.collection
+            .update_one(
+                doc! { "_id": key },
+                doc! { "$set": { "value": value } },
+                UpdateOptions::builder().upsert(true).build(),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<String>, Box<dyn Error>> {
+        let document = self.collection.find_one(doc! { "_id": key }, None).await?;
+
+        Ok(document.and_then(|d| d.get_str("value").ok().map(str::to_string)))
+    }
+
+    async fn set_if_equals(
+        &self,
+        key: &str,
+        expected: Option<&str>,
+        value: &str,
+    ) -> Result<bool, Box<dyn Error>> {
+        let (filter, upsert) = compare_and_set_filter(key, expected);
+
+        let result = self
+            .collection
+            .update_one(
+                filter,
+                doc! { "$set": { "value": value } },
+                UpdateOptions::builder().upsert(upsert).build(),
+            )
+            .await;
+
+        match result {
+            Ok(r) => Ok(r.matched_count == 1 || r.upserted_id.is_some()),
+            Err(e) if is_duplicate_key(&e) => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compare_and_set_filter() {
+        assert_eq!(
+            compare_and_set_filter("animals", Some("12-abc")),
+            (doc! { "_id": "animals", "value": "12-abc" }, false)
+        );
+        assert_eq!(
+            compare_and_set_filter("animals", None),
+            (
+                doc! { "_id": "animals", "value": { "$exists": false } },
+                true
+            )
+        );
+    }
+}
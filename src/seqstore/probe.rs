@@ -0,0 +1,110 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::seqstore::interface::SequenceStore;
+use std::error::Error;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::info;
+
+/// probe writes a value unique to this process to the store and reads it back, so a store that
+/// can't be reached, or that we aren't allowed to write to, fails at startup rather than at the
+/// first checkpoint.
+///
+/// The value is written under `<sequence_key>:probe`, next to the checkpoint, and left there.
+///
+/// # Arguments
+/// * `store` - The sequence store
+/// * `sequence_key` - The checkpoint key
+///
+/// # Returns
+/// * An empty Result, or an error saying which step failed
+pub async fn probe(store: &dyn SequenceStore, sequence_key: &str) -> Result<(), Box<dyn Error>> {
+    let key = format!("{}:probe", sequence_key);
+    let value = format!(
+        "{}-{}",
+        std::process::id(),
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos()
+    );
+
+    store.set(&key, &value).await.map_err(|e| {
+        format!(
+            "unable to write {} to the sequence store, check it's reachable and that we're \
+             allowed to write to it: {}",
+            key, e
+        )
+    })?;
+
+    let read = store.get(&key).await.map_err(|e| {
+        format!(
+            "unable to read {} back from the sequence store, check we're allowed to read from it: \
+             {}",
+            key, e
+        )
+    })?;
+
+    if read.as_deref() != Some(value.as_str()) {
+        return Err(format!(
+            "sequence store returned {:?} for {} after {} was written to it",
+            read, key, value
+        )
+        .into());
+    }
+
+    info!(key = key.as_str(), "sequence store is writable");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::seqstore::sqlite::SQLite;
+    use crate::settings::config_parser::SQLiteSettings;
+    use async_trait::async_trait;
+    use tokio::runtime::Runtime;
+
+    struct ReadOnly;
+
+    #[async_trait]
+    impl SequenceStore for ReadOnly {
+        async fn set(&self, _key: &str, _value: &str) -> Result<(), Box<dyn Error>> {
+            Err("access denied".into())
+        }
+
+        async fn get(&self, _key: &str) -> Result<Option<String>, Box<dyn Error>> {
+            Ok(None)
+        }
+    }
+
+    #[test]
+    fn test_probe() {
+        let rt = Runtime::new().unwrap();
+        let store = SQLite::new(&SQLiteSettings {
+            path: ":memory:".to_string(),
+        })
+        .unwrap();
+
+        rt.block_on(async {
+            probe(&store, "key").await.unwrap();
+            assert!(store.get("key:probe").await.unwrap().is_some());
+            assert_eq!(store.get("key").await.unwrap(), None);
+
+            let e = probe(&ReadOnly, "key").await.unwrap_err().to_string();
+            assert!(e.contains("unable to write key:probe"));
+            assert!(e.contains("access denied"));
+        });
+    }
+}
@@ -0,0 +1,154 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::seqstore::interface::SequenceStore;
+use crate::settings::config_parser::SQLiteSettings;
+use async_trait::async_trait;
+use rusqlite::{params, Connection, OptionalExtension};
+use std::error::Error;
+use std::sync::Mutex;
+use tracing::info;
+
+/// SQLite is an embedded SequenceStore for single-node deployments that have neither Redis nor
+/// AWS available. Checkpoints are kept in a small table in a local database file.
+pub struct SQLite {
+    pub connection: Mutex<Connection>,
+}
+
+impl SQLite {
+    /// new creates a new SQLite struct, creating the database file and table if needed.
+    ///
+    /// # Arguments
+    /// * `settings` - A SQLiteSettings struct
+    ///
+    /// # Returns
+    /// * A SQLite struct
+    pub fn new(settings: &SQLiteSettings) -> Result<SQLite, Box<dyn Error>> {
+        info!(
+            path = settings.path.as_str(),
+            "opening SQLite sequence store"
+        );
+
+        let connection = Connection::open(&settings.path)?;
+        connection.execute(
+            "CREATE TABLE IF NOT EXISTS sequences (key TEXT PRIMARY KEY, value TEXT NOT NULL)",
+            [],
+        )?;
+
+        Ok(SQLite {
+            connection: Mutex::new(connection),
+        })
+    }
+}
+
+#[async_trait]
+impl SequenceStore for SQLite {
+    async fn set(&self, key: &str, value: &str) -> Result<(), Box<dyn Error>> {
+        self.connection
+            .lock()
+            .expect("unable to lock connection")
+            .execute(
+                "INSERT INTO sequences (key, value) VALUES (?1, ?2)
+                 ON CONFLICT(key) DO UPDATE SET value = excluded.value",
+                params![key, value],
+            )?;
+
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<String>, Box<dyn Error>> {
+        let value = self
+            .connection
+            .lock()
+            .expect("unable to lock connection")
+            .query_row(
+                "SELECT value FROM sequences WHERE key = ?1",
+                params![key],
+                |row| row.get(0),
+            )
+            .optional()?;
+
+        Ok(value)
+    }
+
+    async fn set_if_equals(
+        &self,
+        key: &str,
+        expected: Option<&str>,
+        value: &str,
+    ) -> Result<bool, Box<dyn Error>> {
+        let connection = self.connection.lock().expect("unable to lock connection");
+
+        let changed = match expected {
+            Some(expected) => connection.execute(
+                "UPDATE sequences SET value = ?2 WHERE key = ?1 AND value = ?3",
+                params![key, value, expected],
+            )?,
+            None => connection.execute(
+                "INSERT OR IGNORE INTO sequences (key, value) VALUES (?1, ?2)",
+                params![key, value],
+            )?,
+        };
+
+        Ok(changed == 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn test_sqlite_sequence_store() {
+        let rt = Runtime::new().unwrap();
+        let store = SQLite::new(&SQLiteSettings {
+            path: ":memory:".to_string(),
+        })
+        .unwrap();
+
+        rt.block_on(async {
+            assert_eq!(store.get("test_key").await.unwrap(), None);
+
+            store.set("test_key", "1-abc").await.unwrap();
+            assert_eq!(
+                store.get("test_key").await.unwrap(),
+                Some("1-abc".to_string())
+            );
+
+            store.set("test_key", "2-def").await.unwrap();
+            assert_eq!(
+                store.get("test_key").await.unwrap(),
+                Some("2-def".to_string())
+            );
+
+            assert!(!store
+                .set_if_equals("test_key", Some("1-abc"), "3-ghi")
+                .await
+                .unwrap());
+            assert!(store
+                .set_if_equals("test_key", Some("2-def"), "3-ghi")
+                .await
+                .unwrap());
+            assert!(!store
+                .set_if_equals("test_key", None, "4-jkl")
+                .await
+                .unwrap());
+            assert!(store
+                .set_if_equals("other_key", None, "1-abc")
+                .await
+                .unwrap());
+        });
+    }
+}
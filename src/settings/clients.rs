@@ -0,0 +1,110 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use lazy_static::lazy_static;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use tracing::debug;
+
+lazy_static! {
+    /// MongoDB clients, by connection string and TLS settings.
+    pub static ref MONGODB_CLIENTS: ClientCache<mongodb::Client> = ClientCache::new("mongodb");
+    /// CouchDB clients, by server URL and timeout.
+    pub static ref COUCHDB_CLIENTS: ClientCache<couch_rs::Client> = ClientCache::new("couchdb");
+    /// HTTP clients for CouchDB, by TLS, proxy and timeout settings.
+    pub static ref HTTP_CLIENTS: ClientCache<reqwest::Client> = ClientCache::new("http");
+}
+
+/// ClientCache shares clients between pipelines (and parts of a pipeline) that connect to the
+/// same server, so they share its connection pool rather than each opening their own.
+///
+/// Clients are keyed by what they connect to, and remember a fingerprint of the credentials they
+/// were built with. A lookup with different credentials (eg. after Vault rotated them) misses, and
+/// the client built for it replaces the cached one; anything still holding the old client keeps
+/// using it until it's dropped.
+#[derive(Debug)]
+pub struct ClientCache<T> {
+    name: &'static str,
+    clients: Mutex<HashMap<String, (String, T)>>,
+}
+
+impl<T: Clone> ClientCache<T> {
+    fn new(name: &'static str) -> ClientCache<T> {
+        ClientCache {
+            name,
+            clients: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// get returns the cached client for a server, if it was built with the same credentials.
+    ///
+    /// # Arguments
+    /// * `key` - Identifies the server, see `key`
+    /// * `fingerprint` - Identifies the credentials, see `key`
+    pub fn get(&self, key: &str, fingerprint: &str) -> Option<T> {
+        let clients = self.clients.lock().unwrap();
+        let (cached_fingerprint, client) = clients.get(key)?;
+        if cached_fingerprint != fingerprint {
+            return None;
+        }
+
+        debug!(client = self.name, "reusing shared client");
+        Some(client.clone())
+    }
+
+    /// insert caches a client for a server, replacing any built with other credentials.
+    pub fn insert(&self, key: String, fingerprint: String, client: T) {
+        self.clients
+            .lock()
+            .unwrap()
+            .insert(key, (fingerprint, client));
+    }
+}
+
+/// key returns a cache key (or credentials fingerprint) from its parts, without keeping any
+/// secrets among them in memory.
+pub fn key(parts: &[&str]) -> String {
+    let mut hasher = Sha256::new();
+    for part in parts {
+        hasher.update(part.as_bytes());
+        hasher.update([0]);
+    }
+    hex::encode(hasher.finalize())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_client_cache() {
+        let cache: ClientCache<u32> = ClientCache::new("test");
+        let server = key(&["http://couchdb:5984"]);
+        assert_eq!(cache.get(&server, ""), None);
+
+        cache.insert(server.clone(), key(&["admin", "secret"]), 1);
+        assert_eq!(cache.get(&server, &key(&["admin", "secret"])), Some(1));
+        // Rotated credentials miss, and replace the client
+        assert_eq!(cache.get(&server, &key(&["admin", "rotated"])), None);
+        cache.insert(server.clone(), key(&["admin", "rotated"]), 2);
+        assert_eq!(cache.get(&server, &key(&["admin", "secret"])), None);
+        assert_eq!(cache.get(&server, &key(&["admin", "rotated"])), Some(2));
+    }
+
+    #[test]
+    fn test_key_separates_parts() {
+        assert_ne!(key(&["ab", "c"]), key(&["a", "bc"]));
+    }
+}
@@ -0,0 +1,190 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::settings::interpolate;
+use config::ConfigError;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use toml::Value;
+
+/// load reads a TOML config file and every file it pulls in through `include`, returning the
+/// merged document as a TOML string.
+///
+/// `include` is a list of glob patterns, relative to the including file, eg.
+/// `include = ["collections/*.toml"]`. Patterns are processed in order and the files matching each
+/// pattern in lexical order, so the result doesn't depend on directory listing order. Included
+/// files may include further files.
+///
+/// Files are merged in that order on top of the including file: tables are merged key by key,
+/// arrays (such as `routes`) are concatenated, and any other value is replaced. `${NAME}`
+/// placeholders are then expanded from the environment.
+///
+/// # Arguments
+/// * `path` - Path to the top-level config file
+///
+/// # Returns
+/// * The merged configuration as TOML
+pub fn load(path: &Path) -> Result<String, ConfigError> {
+    let mut visited = HashSet::new();
+    let mut merged = load_value(path, &mut visited)?;
+    interpolate::expand(&mut merged)?;
+
+    toml::to_string(&merged).map_err(|e| ConfigError::Foreign(Box::new(e)))
+}
+
+fn load_value(path: &Path, visited: &mut HashSet<PathBuf>) -> Result<Value, ConfigError> {
+    let canonical = path
+        .canonicalize()
+        .map_err(|e| ConfigError::Message(format!("{}: {}", path.display(), e)))?;
+
+    if !visited.insert(canonical) {
+        return Err(ConfigError::Message(format!(
+            "{} is included more than once",
+            path.display()
+        )));
+    }
+
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| ConfigError::Message(format!("{}: {}", path.display(), e)))?;
+    let mut value: Value = contents
+        .parse()
+        .map_err(|e| ConfigError::Message(format!("{}: {}", path.display(), e)))?;
+
+    let includes = match value.as_table_mut().and_then(|t| t.remove("include")) {
+        Some(Value::Array(patterns)) => patterns,
+        Some(_) => {
+            return Err(ConfigError::Message(format!(
+                "{}: include must be an array of glob patterns",
+                path.display()
+            )))
+        }
+        None => return Ok(value),
+    };
+
+    let base = path.parent().unwrap_or_else(|| Path::new("."));
+
+    for pattern in includes {
+        let pattern = pattern.as_str().ok_or_else(|| {
+            ConfigError::Message(format!(
+                "{}: include patterns must be strings",
+                path.display()
+            ))
+        })?;
+
+        let full_pattern = base.join(pattern);
+        let mut files = glob::glob(&full_pattern.to_string_lossy())
+            .map_err(|e| ConfigError::Message(format!("{}: {}", pattern, e)))?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| ConfigError::Message(format!("{}: {}", pattern, e)))?;
+        files.sort();
+
+        for file in files {
+            let included = load_value(&file, visited)?;
+            merge(&mut value, included);
+        }
+    }
+
+    Ok(value)
+}
+
+/// merge merges `other` into `base`: tables recursively, arrays by concatenation, and anything
+/// else by replacement.
+fn merge(base: &mut Value, other: Value) {
+    match (base, other) {
+        (Value::Table(base), Value::Table(other)) => {
+            for (key, value) in other {
+                match base.get_mut(&key) {
+                    Some(existing) => merge(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (Value::Array(base), Value::Array(other)) => base.extend(other),
+        (base, other) => *base = other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(s: &str) -> Value {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut base = parse(
+            r#"
+            source_database = "animals"
+            [[routes]]
+            collection = "felines"
+            [redis]
+            host = "localhost"
+            port = 6379
+            "#,
+        );
+
+        merge(
+            &mut base,
+            parse(
+                r#"
+                source_database = "pets"
+                [[routes]]
+                collection = "canines"
+                [redis]
+                port = 6380
+                "#,
+            ),
+        );
+
+        assert_eq!(base["source_database"].as_str(), Some("pets"));
+        assert_eq!(base["routes"].as_array().unwrap().len(), 2);
+        assert_eq!(base["routes"][1]["collection"].as_str(), Some("canines"));
+        assert_eq!(base["redis"]["host"].as_str(), Some("localhost"));
+        assert_eq!(base["redis"]["port"].as_integer(), Some(6380));
+    }
+
+    #[test]
+    fn test_load_includes_in_lexical_order() {
+        let dir = std::env::temp_dir().join(format!("couch2mongo-includes-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("collections")).unwrap();
+
+        std::fs::write(
+            dir.join("config.toml"),
+            "include = [\"collections/*.toml\"]\nsource_database = \"animals\"\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("collections/b.toml"),
+            "[[routes]]\ncollection = \"b\"\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("collections/a.toml"),
+            "[[routes]]\ncollection = \"a\"\n",
+        )
+        .unwrap();
+
+        let merged = parse(&load(&dir.join("config.toml")).unwrap());
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(merged.get("include").is_none());
+        assert_eq!(merged["source_database"].as_str(), Some("animals"));
+        assert_eq!(merged["routes"][0]["collection"].as_str(), Some("a"));
+        assert_eq!(merged["routes"][1]["collection"].as_str(), Some("b"));
+    }
+}
@@ -0,0 +1,204 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use config::{ConfigError, Map, Source, ValueKind};
+use toml::Value;
+
+/// expand replaces `${NAME}` placeholders in every string in a TOML document with the value of
+/// the environment variable NAME, so secrets needn't be written into config files.
+///
+/// `${NAME:-default}` uses the default when the variable isn't set, and `$${` is a literal `${`;
+/// any other `$` is left as it is. A placeholder for a variable that isn't set, with no default,
+/// is an error.
+///
+/// # Arguments
+/// * `value` - The TOML document, modified in place
+pub fn expand(value: &mut Value) -> Result<(), ConfigError> {
+    expand_with(value, &|name| std::env::var(name).ok())
+}
+
+/// Expanded is a config source whose string values have their `${NAME}` placeholders expanded,
+/// as `expand` does for TOML, so config files in any format can read the environment.
+#[derive(Debug, Clone)]
+pub struct Expanded<S>(pub S);
+
+impl<S: Source + Clone + Send + Sync + 'static> Source for Expanded<S> {
+    fn clone_into_box(&self) -> Box<dyn Source + Send + Sync> {
+        Box::new(self.clone())
+    }
+
+    fn collect(&self) -> Result<Map<String, config::Value>, ConfigError> {
+        let mut values = self.0.collect()?;
+        for value in values.values_mut() {
+            expand_config_value(value, &|name| std::env::var(name).ok())?;
+        }
+
+        Ok(values)
+    }
+}
+
+fn expand_config_value(
+    value: &mut config::Value,
+    lookup: &dyn Fn(&str) -> Option<String>,
+) -> Result<(), ConfigError> {
+    match &mut value.kind {
+        ValueKind::String(s) => *s = expand_str(s, lookup)?,
+        ValueKind::Array(values) => {
+            for v in values {
+                expand_config_value(v, lookup)?;
+            }
+        }
+        ValueKind::Table(table) => {
+            for v in table.values_mut() {
+                expand_config_value(v, lookup)?;
+            }
+        }
+        _ => {}
+    }
+
+    Ok(())
+}
+
+fn expand_with(
+    value: &mut Value,
+    lookup: &dyn Fn(&str) -> Option<String>,
+) -> Result<(), ConfigError> {
+    match value {
+        Value::String(s) => *s = expand_str(s, lookup)?,
+        Value::Array(values) => {
+            for v in values {
+                expand_with(v, lookup)?;
+            }
+        }
+        Value::Table(table) => {
+            for (_, v) in table.iter_mut() {
+                expand_with(v, lookup)?;
+            }
+        }
+        _ => {}
+    }
+
+    Ok(())
+}
+
+fn expand_str(s: &str, lookup: &dyn Fn(&str) -> Option<String>) -> Result<String, ConfigError> {
+    let mut expanded = String::with_capacity(s.len());
+    let mut rest = s;
+
+    while let Some(position) = rest.find('$') {
+        expanded.push_str(&rest[..position]);
+        rest = &rest[position..];
+
+        if let Some(after) = rest.strip_prefix("$${") {
+            expanded.push_str("${");
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix("${") {
+            let end = after.find('}').ok_or_else(|| {
+                ConfigError::Message(format!("unterminated placeholder in \"{}\"", s))
+            })?;
+            let (name, default) = match after[..end].split_once(":-") {
+                Some((name, default)) => (name, Some(default)),
+                None => (&after[..end], None),
+            };
+
+            match (lookup(name), default) {
+                (Some(v), _) => expanded.push_str(&v),
+                (None, Some(default)) => expanded.push_str(default),
+                (None, None) => {
+                    return Err(ConfigError::Message(format!(
+                        "environment variable {} is not set",
+                        name
+                    )))
+                }
+            }
+            rest = &after[end + 1..];
+        } else {
+            expanded.push('$');
+            rest = &rest[1..];
+        }
+    }
+    expanded.push_str(rest);
+
+    Ok(expanded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lookup(name: &str) -> Option<String> {
+        match name {
+            "COUCH_PASSWORD" => Some("s3cret".to_string()),
+            "MONGO_HOST" => Some("mongo.internal".to_string()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_expand_str() {
+        assert_eq!(
+            expand_str("mongodb://${MONGO_HOST}:27017", &lookup).unwrap(),
+            "mongodb://mongo.internal:27017"
+        );
+        assert_eq!(
+            expand_str("${MONGO_PORT:-27017}", &lookup).unwrap(),
+            "27017"
+        );
+        assert_eq!(expand_str("pa$$word$", &lookup).unwrap(), "pa$$word$");
+        assert_eq!(
+            expand_str("$${MONGO_HOST}", &lookup).unwrap(),
+            "${MONGO_HOST}"
+        );
+        assert!(expand_str("${MISSING}", &lookup).is_err());
+        assert!(expand_str("${COUCH_PASSWORD", &lookup).is_err());
+    }
+
+    #[test]
+    fn test_expand_nested_values() {
+        let mut value: Value = r#"
+            couchdb_password = "${COUCH_PASSWORD}"
+            port = 6379
+            [[routes]]
+            collection = "${MONGO_HOST}"
+        "#
+        .parse()
+        .unwrap();
+
+        expand_with(&mut value, &lookup).unwrap();
+
+        assert_eq!(value["couchdb_password"].as_str(), Some("s3cret"));
+        assert_eq!(value["port"].as_integer(), Some(6379));
+        assert_eq!(
+            value["routes"][0]["collection"].as_str(),
+            Some("mongo.internal")
+        );
+    }
+
+    #[test]
+    fn test_expand_config_values() {
+        let mut value = config::Value::new(
+            None,
+            vec![
+                config::Value::new(None, "${COUCH_PASSWORD}"),
+                config::Value::new(None, 6379),
+            ],
+        );
+
+        expand_config_value(&mut value, &lookup).unwrap();
+
+        let values = value.into_array().unwrap();
+        assert_eq!(values[0].clone().into_string().unwrap(), "s3cret");
+        assert_eq!(values[1].clone().into_int().unwrap(), 6379);
+    }
+}
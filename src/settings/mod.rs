@@ -12,4 +12,7 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+pub mod clients;
 pub mod config_parser;
+pub mod includes;
+pub mod interpolate;
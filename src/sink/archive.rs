@@ -0,0 +1,296 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::couchdb::sequence_number;
+use crate::settings::config_parser::{ArchiveSettings, ArchiveStoreInterface};
+use crate::sink::interface::{Sink, SinkMessage};
+use async_trait::async_trait;
+use aws_config::BehaviorVersion;
+use aws_sdk_s3::primitives::ByteStream;
+use serde_json::json;
+use std::error::Error;
+use std::path::PathBuf;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+use tracing::info;
+
+/// Archive appends every change as a line of JSON to objects in S3, or files in a local
+/// directory, starting a new one once the current one reaches `max_bytes` or `max_age_secs`.
+///
+/// The current object is written out whenever the sink is flushed, which happens before every
+/// checkpoint. S3 objects can't be appended to, so each flush rewrites the current object in full.
+pub struct Archive {
+    store: ArchiveStore,
+    prefix: String,
+    max_bytes: usize,
+    max_age: Duration,
+    segment: Mutex<Option<Segment>>,
+}
+
+/// ArchiveStore is where archived changes are written.
+enum ArchiveStore {
+    S3 {
+        client: aws_sdk_s3::Client,
+        bucket: String,
+    },
+    File {
+        directory: PathBuf,
+    },
+}
+
+/// Segment is the object currently being appended to.
+struct Segment {
+    key: String,
+    body: Vec<u8>,
+    written: usize,
+    started: Instant,
+}
+
+impl Archive {
+    /// new creates a new Archive sink.
+    ///
+    /// # Arguments
+    /// * `settings` - An ArchiveSettings struct
+    ///
+    /// # Returns
+    /// * An Archive struct
+    pub async fn new(settings: &ArchiveSettings) -> Result<Archive, Box<dyn Error>> {
+        let store = match settings.store {
+            ArchiveStoreInterface::S3 => {
+                let bucket = settings
+                    .bucket
+                    .clone()
+                    .ok_or("archive.bucket is required when archive.store is S3")?;
+                let shared_config = aws_config::load_defaults(BehaviorVersion::v2023_11_09()).await;
+                let mut config = aws_sdk_s3::config::Builder::from(&shared_config);
+                if let Some(url) = &settings.local_url {
+                    info!(url = url.as_str(), "using local S3 for the archive");
+                    config = config.endpoint_url(url).force_path_style(true);
+                }
+
+                ArchiveStore::S3 {
+                    client: aws_sdk_s3::Client::from_conf(config.build()),
+                    bucket,
+                }
+            }
+            ArchiveStoreInterface::File => {
+                let directory = settings
+                    .directory
+                    .clone()
+                    .ok_or("archive.directory is required when archive.store is File")?;
+
+                ArchiveStore::File {
+                    directory: PathBuf::from(directory),
+                }
+            }
+        };
+
+        Ok(Archive {
+            store,
+            prefix: settings.prefix.clone(),
+            max_bytes: settings.max_bytes,
+            max_age: Duration::from_secs(settings.max_age_secs),
+            segment: Mutex::new(None),
+        })
+    }
+
+    /// write stores whatever has been added to a segment since it was last written.
+    async fn write(&self, segment: &mut Segment) -> Result<(), Box<dyn Error>> {
+        if segment.written == segment.body.len() {
+            return Ok(());
+        }
+
+        match &self.store {
+            ArchiveStore::S3 { client, bucket } => {
+                client
+                    .put_object()
+                    .bucket(bucket)
+                    .key(&segment.key)
+                    .content_type("application/x-ndjson")
+                    .body(ByteStream::from(segment.body.clone()))
+                    .send()
+                    .await?;
+            }
+            ArchiveStore::File { directory } => {
+                let path = directory.join(&segment.key);
+                if let Some(parent) = path.parent() {
+                    tokio::fs::create_dir_all(parent).await?;
+                }
+
+                let mut file = tokio::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&path)
+                    .await?;
+                file.write_all(&segment.body[segment.written..]).await?;
+                file.sync_data().await?;
+            }
+        }
+
+        segment.written = segment.body.len();
+        Ok(())
+    }
+}
+
+/// archive_line returns a change as a line of JSON.
+pub fn archive_line(message: &SinkMessage) -> Result<Vec<u8>, Box<dyn Error>> {
+    let document = message
+        .document
+        .as_ref()
+        .map(|d| bson::Bson::Document(d.clone()).into_relaxed_extjson());
+
+    let mut line = serde_json::to_vec(&json!({
+        "id": message.id,
+        "seq": message.seq,
+        "database": message.database,
+        "collection": message.collection,
+        "deleted": message.deleted,
+        "document": document,
+    }))?;
+    line.push(b'\n');
+
+    Ok(line)
+}
+
+/// segment_key returns the key of a new segment starting with a change, eg.
+/// `archive/animals/1700000000000-1234.jsonl`. Keys sort in the order they were started.
+pub fn segment_key(prefix: &str, message: &SinkMessage, started: SystemTime) -> String {
+    let millis = started
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+
+    format!(
+        "{}{}/{:013}-{}.jsonl",
+        prefix,
+        message.database,
+        millis,
+        sequence_number(&message.seq).unwrap_or_default()
+    )
+}
+
+#[async_trait]
+impl Sink for Archive {
+    fn name(&self) -> &'static str {
+        "archive"
+    }
+
+    async fn publish(&self, message: &SinkMessage) -> Result<(), Box<dyn Error>> {
+        let line = archive_line(message)?;
+        let mut current = self.segment.lock().await;
+
+        if let Some(segment) = current.as_mut() {
+            if segment.body.len() >= self.max_bytes || segment.started.elapsed() >= self.max_age {
+                self.write(segment).await?;
+                info!(
+                    key = segment.key.as_str(),
+                    size = segment.body.len(),
+                    "archive segment complete"
+                );
+                *current = None;
+            }
+        }
+
+        let segment = current.get_or_insert_with(|| Segment {
+            key: segment_key(&self.prefix, message, SystemTime::now()),
+            body: Vec::new(),
+            written: 0,
+            started: Instant::now(),
+        });
+        segment.body.extend_from_slice(&line);
+
+        Ok(())
+    }
+
+    async fn flush(&self) -> Result<(), Box<dyn Error>> {
+        match self.segment.lock().await.as_mut() {
+            Some(segment) => self.write(segment).await,
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::config_parser::ArchiveSettings;
+    use bson::doc;
+    use tokio::runtime::Runtime;
+
+    fn message(seq: &str) -> SinkMessage {
+        SinkMessage {
+            id: "cat:tom".to_string(),
+            seq: seq.to_string(),
+            database: "animals".to_string(),
+            collection: "cats".to_string(),
+            deleted: false,
+            document: Some(doc! { "_id": "cat:tom", "name": "Tom" }),
+        }
+    }
+
+    #[test]
+    fn test_segment_key() {
+        assert_eq!(
+            segment_key(
+                "archive/",
+                &message("12-g1AAAA"),
+                UNIX_EPOCH + Duration::from_millis(1_700_000_000_000)
+            ),
+            "archive/animals/1700000000000-12.jsonl"
+        );
+    }
+
+    #[test]
+    fn test_file_archive_rotates() {
+        let rt = Runtime::new().unwrap();
+        let directory =
+            std::env::temp_dir().join(format!("couch2mongo-archive-{}", std::process::id()));
+        let line_size = archive_line(&message("1-a")).unwrap().len();
+
+        let archive = rt
+            .block_on(Archive::new(&ArchiveSettings {
+                store: ArchiveStoreInterface::File,
+                bucket: None,
+                local_url: None,
+                directory: Some(directory.to_string_lossy().to_string()),
+                prefix: String::new(),
+                max_bytes: line_size * 2,
+                max_age_secs: 3600,
+            }))
+            .unwrap();
+
+        rt.block_on(async {
+            archive.publish(&message("1-a")).await.unwrap();
+            archive.flush().await.unwrap();
+            archive.publish(&message("2-b")).await.unwrap();
+            archive.publish(&message("3-c")).await.unwrap();
+            archive.flush().await.unwrap();
+        });
+
+        let mut files: Vec<_> = std::fs::read_dir(directory.join("animals"))
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .collect();
+        files.sort();
+
+        let lines: Vec<usize> = files
+            .iter()
+            .map(|f| std::fs::read_to_string(f).unwrap().lines().count())
+            .collect();
+        assert_eq!(lines, vec![2, 1]);
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
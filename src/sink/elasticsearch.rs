@@ -0,0 +1,244 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::settings::config_parser::ElasticsearchSettings;
+use crate::sink::interface::{Sink, SinkMessage};
+use async_trait::async_trait;
+use serde_json::{json, Value};
+use std::error::Error;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tracing::debug;
+
+/// Elasticsearch indexes each change into Elasticsearch or OpenSearch with the bulk API.
+///
+/// Changes are buffered and sent once `batch_size` have been published, or when the sink is
+/// flushed, which happens before every checkpoint.
+pub struct Elasticsearch {
+    client: reqwest::Client,
+    url: String,
+    index: String,
+    username: Option<String>,
+    password: Option<String>,
+    batch_size: usize,
+    pending: Mutex<Pending>,
+}
+
+#[derive(Default)]
+struct Pending {
+    body: String,
+    actions: usize,
+}
+
+impl Elasticsearch {
+    /// new creates a new Elasticsearch sink.
+    ///
+    /// # Arguments
+    /// * `settings` - An ElasticsearchSettings struct
+    ///
+    /// # Returns
+    /// * An Elasticsearch struct
+    pub fn new(settings: &ElasticsearchSettings) -> Result<Elasticsearch, Box<dyn Error>> {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_millis(settings.timeout_ms))
+            .build()?;
+
+        Ok(Elasticsearch {
+            client,
+            url: settings.url.trim_end_matches('/').to_string(),
+            index: settings.index.clone(),
+            username: settings.username.clone(),
+            password: settings.password.clone(),
+            batch_size: settings.batch_size.max(1),
+            pending: Mutex::new(Pending::default()),
+        })
+    }
+
+    /// send posts a bulk request, failing if any of its actions failed.
+    async fn send(&self, body: String, actions: usize) -> Result<(), Box<dyn Error>> {
+        let mut request = self
+            .client
+            .post(format!("{}/_bulk", self.url))
+            .header("Content-Type", "application/x-ndjson")
+            .body(body);
+        if let Some(username) = &self.username {
+            request = request.basic_auth(username, self.password.as_ref());
+        }
+
+        let response: Value = request.send().await?.error_for_status()?.json().await?;
+        if let Some(error) = bulk_error(&response) {
+            return Err(format!("elasticsearch bulk request failed: {}", error).into());
+        }
+
+        debug!(actions, "elasticsearch bulk request sent");
+        Ok(())
+    }
+}
+
+/// bulk_actions returns the bulk API lines for a change: an index action and the document, or a
+/// delete action.
+///
+/// Index names are lowercased, as Elasticsearch requires. `_id` is a metadata field that can't be
+/// part of the document body, so it's only used as the action's `_id`.
+pub fn bulk_actions(template: &str, message: &SinkMessage) -> Result<String, Box<dyn Error>> {
+    let index = message.render(template).to_lowercase();
+
+    let document = match &message.document {
+        Some(document) => document,
+        None => {
+            return Ok(format!(
+                "{}\n",
+                json!({ "delete": { "_index": index, "_id": message.id } })
+            ))
+        }
+    };
+
+    let mut body = document.clone();
+    body.remove("_id");
+
+    Ok(format!(
+        "{}\n{}\n",
+        json!({ "index": { "_index": index, "_id": message.id } }),
+        bson::Bson::Document(body).into_relaxed_extjson()
+    ))
+}
+
+/// bulk_error returns the first failure in a bulk API response, if there was one. Deleting a
+/// document that isn't in the index isn't a failure.
+pub fn bulk_error(response: &Value) -> Option<String> {
+    if response.get("errors") != Some(&Value::Bool(true)) {
+        return None;
+    }
+
+    response
+        .get("items")?
+        .as_array()?
+        .iter()
+        .filter_map(|item| item.as_object()?.values().next())
+        .find(|result| {
+            let status = result.get("status").and_then(Value::as_u64).unwrap_or(0);
+            result.get("error").is_some() && status != 404
+        })
+        .map(|result| {
+            format!(
+                "{} in {}: {}",
+                result.get("_id").unwrap_or(&Value::Null),
+                result.get("_index").unwrap_or(&Value::Null),
+                result.get("error").unwrap_or(&Value::Null)
+            )
+        })
+}
+
+#[async_trait]
+impl Sink for Elasticsearch {
+    fn name(&self) -> &'static str {
+        "elasticsearch"
+    }
+
+    async fn publish(&self, message: &SinkMessage) -> Result<(), Box<dyn Error>> {
+        let actions = bulk_actions(&self.index, message)?;
+
+        let mut pending = self.pending.lock().await;
+        pending.body.push_str(&actions);
+        pending.actions += 1;
+
+        if pending.actions >= self.batch_size {
+            let batch = std::mem::take(&mut *pending);
+            self.send(batch.body, batch.actions).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn flush(&self) -> Result<(), Box<dyn Error>> {
+        let mut pending = self.pending.lock().await;
+        if pending.actions == 0 {
+            return Ok(());
+        }
+
+        let batch = std::mem::take(&mut *pending);
+        self.send(batch.body, batch.actions).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bson::doc;
+
+    fn message(document: Option<bson::Document>) -> SinkMessage {
+        SinkMessage {
+            id: "cat:tom".to_string(),
+            seq: "1-a".to_string(),
+            database: "animals".to_string(),
+            collection: "Cats".to_string(),
+            deleted: document.is_none(),
+            document,
+        }
+    }
+
+    #[test]
+    fn test_bulk_actions() {
+        let actions = bulk_actions(
+            "{database}-{collection}",
+            &message(Some(
+                doc! { "_id": "cat:tom", "_rev": "1-a", "name": "Tom" },
+            )),
+        )
+        .unwrap();
+        let lines: Vec<Value> = actions
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+
+        assert_eq!(
+            lines,
+            vec![
+                json!({ "index": { "_index": "animals-cats", "_id": "cat:tom" } }),
+                json!({ "_rev": "1-a", "name": "Tom" }),
+            ]
+        );
+
+        let actions = bulk_actions("{collection}", &message(None)).unwrap();
+        assert_eq!(
+            serde_json::from_str::<Value>(actions.trim_end()).unwrap(),
+            json!({ "delete": { "_index": "cats", "_id": "cat:tom" } })
+        );
+    }
+
+    #[test]
+    fn test_bulk_error() {
+        assert_eq!(bulk_error(&json!({ "errors": false, "items": [] })), None);
+
+        let missing = json!({
+            "errors": true,
+            "items": [
+                { "delete": { "_index": "cats", "_id": "cat:tom", "status": 404, "error": "not found" } }
+            ]
+        });
+        assert_eq!(bulk_error(&missing), None);
+
+        let failed = json!({
+            "errors": true,
+            "items": [
+                { "index": { "_index": "cats", "_id": "cat:tom", "status": 201 } },
+                { "index": { "_index": "cats", "_id": "cat:jerry", "status": 400, "error": "mapping" } }
+            ]
+        });
+        assert_eq!(
+            bulk_error(&failed),
+            Some("\"cat:jerry\" in \"cats\": \"mapping\"".to_string())
+        );
+    }
+}
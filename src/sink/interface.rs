@@ -0,0 +1,74 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use async_trait::async_trait;
+use bson::Document;
+use std::error::Error;
+
+/// SinkMessage is a change as published to a sink.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SinkMessage {
+    pub id: String,
+    pub seq: String,
+    pub database: String,
+    pub collection: String,
+    pub deleted: bool,
+    // The document as it would be written to MongoDB; None for deletions
+    pub document: Option<Document>,
+}
+
+impl SinkMessage {
+    /// render fills in the `{database}` and `{collection}` placeholders of a name template, eg.
+    /// a topic or index name.
+    pub fn render(&self, template: &str) -> String {
+        template
+            .replace("{database}", &self.database)
+            .replace("{collection}", &self.collection)
+    }
+}
+
+#[async_trait]
+pub trait Sink: Send + Sync {
+    /// name returns the name of the sink, for logs and metrics.
+    fn name(&self) -> &'static str;
+
+    /// publish sends a change, returning once the sink has accepted it.
+    async fn publish(&self, message: &SinkMessage) -> Result<(), Box<dyn Error>>;
+
+    /// flush waits for anything the sink has buffered to be delivered.
+    async fn flush(&self) -> Result<(), Box<dyn Error>>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let message = SinkMessage {
+            id: "cat:tom".to_string(),
+            seq: "1-a".to_string(),
+            database: "animals".to_string(),
+            collection: "cats".to_string(),
+            deleted: false,
+            document: None,
+        };
+
+        assert_eq!(
+            message.render("couch2mongo.{database}.{collection}"),
+            "couch2mongo.animals.cats"
+        );
+        assert_eq!(message.render("changes"), "changes");
+    }
+}
@@ -0,0 +1,141 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::settings::config_parser::{KafkaFormat, KafkaSettings};
+use crate::sink::interface::{Sink, SinkMessage};
+use apache_avro::types::{Record, Value};
+use apache_avro::Schema;
+use async_trait::async_trait;
+use rdkafka::config::ClientConfig;
+use rdkafka::producer::{FutureProducer, FutureRecord, Producer};
+use serde_json::json;
+use std::error::Error;
+use std::time::Duration;
+
+/// AVRO_SCHEMA is the schema of Avro encoded messages. The document is carried as relaxed
+/// extended JSON, since CouchDB documents have no fixed shape.
+const AVRO_SCHEMA: &str = r#"{
+    "type": "record",
+    "name": "Change",
+    "namespace": "couch2mongo",
+    "fields": [
+        { "name": "id", "type": "string" },
+        { "name": "seq", "type": "string" },
+        { "name": "database", "type": "string" },
+        { "name": "collection", "type": "string" },
+        { "name": "deleted", "type": "boolean" },
+        { "name": "document", "type": ["null", "string"], "default": null }
+    ]
+}"#;
+
+/// Kafka publishes each change to a Kafka topic, keyed by document ID so changes to a document
+/// stay in order within a partition.
+pub struct Kafka {
+    producer: FutureProducer,
+    topic: String,
+    format: KafkaFormat,
+    schema: Schema,
+    timeout: Duration,
+}
+
+impl Kafka {
+    /// new creates a new Kafka sink.
+    ///
+    /// # Arguments
+    /// * `settings` - A KafkaSettings struct
+    ///
+    /// # Returns
+    /// * A Kafka struct
+    pub fn new(settings: &KafkaSettings) -> Result<Kafka, Box<dyn Error>> {
+        let mut config = ClientConfig::new();
+        config.set("bootstrap.servers", &settings.brokers);
+        // Don't let retries reorder or duplicate a document's changes
+        config.set("enable.idempotence", "true");
+        for (key, value) in &settings.properties {
+            config.set(key, value);
+        }
+
+        Ok(Kafka {
+            producer: config.create()?,
+            topic: settings.topic.clone(),
+            format: settings.format.clone(),
+            schema: Schema::parse_str(AVRO_SCHEMA)?,
+            timeout: Duration::from_millis(settings.timeout_ms),
+        })
+    }
+
+    /// encode serializes a message in the configured format.
+    fn encode(&self, message: &SinkMessage) -> Result<Vec<u8>, Box<dyn Error>> {
+        let document = message
+            .document
+            .as_ref()
+            .map(|d| bson::Bson::Document(d.clone()).into_relaxed_extjson());
+
+        match self.format {
+            KafkaFormat::Json => Ok(serde_json::to_vec(&json!({
+                "id": message.id,
+                "seq": message.seq,
+                "database": message.database,
+                "collection": message.collection,
+                "deleted": message.deleted,
+                "document": document,
+            }))?),
+            KafkaFormat::Avro => {
+                let mut record = Record::new(&self.schema).ok_or("Avro schema is not a record")?;
+                record.put("id", message.id.as_str());
+                record.put("seq", message.seq.as_str());
+                record.put("database", message.database.as_str());
+                record.put("collection", message.collection.as_str());
+                record.put("deleted", message.deleted);
+                record.put(
+                    "document",
+                    match document {
+                        Some(document) => {
+                            Value::Union(1, Box::new(Value::String(document.to_string())))
+                        }
+                        None => Value::Union(0, Box::new(Value::Null)),
+                    },
+                );
+
+                Ok(apache_avro::to_avro_datum(&self.schema, record)?)
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Sink for Kafka {
+    fn name(&self) -> &'static str {
+        "kafka"
+    }
+
+    async fn publish(&self, message: &SinkMessage) -> Result<(), Box<dyn Error>> {
+        let topic = message.render(&self.topic);
+        let payload = self.encode(message)?;
+        let record = FutureRecord::to(&topic)
+            .key(message.id.as_str())
+            .payload(&payload);
+
+        self.producer
+            .send(record, self.timeout)
+            .await
+            .map_err(|(e, _)| format!("unable to publish {} to {}: {}", message.id, topic, e))?;
+
+        Ok(())
+    }
+
+    async fn flush(&self) -> Result<(), Box<dyn Error>> {
+        Ok(self.producer.flush(self.timeout)?)
+    }
+}
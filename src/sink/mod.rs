@@ -0,0 +1,75 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+pub mod archive;
+pub mod elasticsearch;
+pub mod interface;
+pub mod kafka;
+
+use crate::sink::interface::{Sink, SinkMessage};
+use lazy_static::lazy_static;
+use prometheus::{register_int_counter_vec, IntCounterVec};
+use std::error::Error;
+use tracing::debug;
+
+lazy_static! {
+    /// Changes published to sinks, by sink.
+    pub static ref SINK_MESSAGES: IntCounterVec = register_int_counter_vec!(
+        "couch2mongo_sink_messages_total",
+        "Changes published to sinks other than MongoDB",
+        &["sink"]
+    )
+    .unwrap();
+}
+
+/// Sinks publishes each change to every configured sink, in order.
+pub struct Sinks {
+    sinks: Vec<Box<dyn Sink>>,
+}
+
+impl Sinks {
+    /// new creates a new Sinks struct.
+    pub fn new(sinks: Vec<Box<dyn Sink>>) -> Sinks {
+        Sinks { sinks }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sinks.is_empty()
+    }
+
+    /// publish sends a change to every sink, returning once they've all accepted it.
+    pub async fn publish(&self, message: &SinkMessage) -> Result<(), Box<dyn Error>> {
+        for sink in &self.sinks {
+            sink.publish(message).await?;
+            SINK_MESSAGES.with_label_values(&[sink.name()]).inc();
+            debug!(
+                sink = sink.name(),
+                id = message.id.as_str(),
+                seq = message.seq.as_str(),
+                "published change"
+            );
+        }
+
+        Ok(())
+    }
+
+    /// flush waits for every sink to deliver what it has buffered.
+    pub async fn flush(&self) -> Result<(), Box<dyn Error>> {
+        for sink in &self.sinks {
+            sink.flush().await?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,210 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::couchdb::CouchConnection;
+use crate::lag::sequence_lag;
+use crate::lease::Lease;
+use crate::settings::config_parser::Settings;
+use bson::{doc, DateTime, Document};
+use reqwest::Method;
+use serde_derive::Serialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+
+/// Status describes how far behind the source database the replicator is.
+#[derive(Debug, Serialize)]
+pub struct Status {
+    pub source_database: String,
+    pub sequence_key: String,
+    pub update_seq: String,
+    pub checkpoint: Option<String>,
+    // Changes in the feed after the checkpoint, before any filtering
+    pub pending_changes: Option<u64>,
+    // Difference between the numeric parts of the update sequence and the checkpoint
+    pub sequence_lag: Option<u64>,
+    pub leader: Option<LeaderStatus>,
+    // From the meta collection, when it's enabled
+    pub started_at: Option<String>,
+    pub meta_updated_at: Option<String>,
+}
+
+/// LeaderStatus is the current holder of the leader election lease.
+#[derive(Debug, Serialize)]
+pub struct LeaderStatus {
+    pub holder: String,
+    pub lease_expires_at: String,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let or_none = |value: &Option<String>| value.clone().unwrap_or("-".to_string());
+        let count = |value: Option<u64>| value.map_or("-".to_string(), |v| v.to_string());
+
+        writeln!(f, "{:<18}{}", "source database", self.source_database)?;
+        writeln!(f, "{:<18}{}", "sequence key", self.sequence_key)?;
+        writeln!(f, "{:<18}{}", "update seq", self.update_seq)?;
+        writeln!(f, "{:<18}{}", "checkpoint", or_none(&self.checkpoint))?;
+        writeln!(
+            f,
+            "{:<18}{}",
+            "pending changes",
+            count(self.pending_changes)
+        )?;
+        writeln!(f, "{:<18}{}", "sequence lag", count(self.sequence_lag))?;
+        if let Some(leader) = &self.leader {
+            writeln!(
+                f,
+                "{:<18}{} (lease expires {})",
+                "leader", leader.holder, leader.lease_expires_at
+            )?;
+        }
+        writeln!(f, "{:<18}{}", "started at", or_none(&self.started_at))?;
+        write!(
+            f,
+            "{:<18}{}",
+            "meta updated at",
+            or_none(&self.meta_updated_at)
+        )
+    }
+}
+
+/// collect gathers the replication status from CouchDB, the sequence store and, when enabled, the
+/// meta collection.
+///
+/// # Arguments
+/// * `settings` - A Settings struct
+///
+/// # Returns
+/// * A Status struct
+pub async fn collect(settings: &Settings) -> Result<Status, Box<dyn Error>> {
+    let connection = settings.get_couchdb_connection().await?;
+    let update_seq = connection.update_seq(&settings.source_database).await?;
+
+    let store = settings.get_sequence_store().await?;
+    let sequence_key = settings.get_sequence_store_key();
+    let checkpoint = store.get(&sequence_key).await?;
+
+    let pending_changes = pending_changes(
+        &connection,
+        &settings.source_database,
+        checkpoint.as_deref(),
+    )
+    .await?;
+    let sequence_lag = sequence_lag(&update_seq, checkpoint.as_deref());
+
+    let leader = match &settings.leader_election {
+        Some(_) => store
+            .get(&format!("{}:leader", sequence_key))
+            .await?
+            .as_deref()
+            .and_then(Lease::parse)
+            .map(|lease| LeaderStatus {
+                holder: lease.holder,
+                lease_expires_at: timestamp(&DateTime::from_millis(lease.expires_at_ms as i64)),
+            }),
+        None => None,
+    };
+
+    let meta = match &settings.meta {
+        Some(meta_settings) => {
+            settings
+                .get_mongodb_database()
+                .await?
+                .collection::<Document>(&meta_settings.collection)
+                .find_one(doc! { "_id": &sequence_key }, None)
+                .await?
+        }
+        None => None,
+    };
+    let meta_time = |field: &str| {
+        meta.as_ref()
+            .and_then(|m| m.get_datetime(field).ok())
+            .map(timestamp)
+    };
+
+    Ok(Status {
+        source_database: settings.source_database.clone(),
+        sequence_key: sequence_key.clone(),
+        update_seq,
+        started_at: meta_time("started_at"),
+        meta_updated_at: meta_time("updated_at"),
+        checkpoint,
+        pending_changes,
+        sequence_lag,
+        leader,
+    })
+}
+
+/// pending_changes asks CouchDB how many changes there are after the checkpoint.
+async fn pending_changes(
+    connection: &CouchConnection,
+    database: &str,
+    checkpoint: Option<&str>,
+) -> Result<Option<u64>, Box<dyn Error>> {
+    let params = HashMap::from([
+        ("since".to_string(), checkpoint.unwrap_or("0").to_string()),
+        ("limit".to_string(), "1".to_string()),
+    ]);
+
+    let request = connection.req(
+        Method::GET,
+        &format!("{}/_changes", database),
+        Some(&params),
+    );
+    let changes: Value = connection
+        .send(request, "changes")
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+
+    Ok(count_pending(&changes))
+}
+
+/// count_pending returns the number of changes left from a `_changes` response: those returned
+/// plus those still pending.
+fn count_pending(changes: &Value) -> Option<u64> {
+    let pending = changes.get("pending")?.as_u64()?;
+    let returned = changes
+        .get("results")
+        .and_then(Value::as_array)
+        .map_or(0, |r| r.len() as u64);
+
+    Some(pending + returned)
+}
+
+fn timestamp(time: &DateTime) -> String {
+    time.try_to_rfc3339_string()
+        .unwrap_or_else(|_| time.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_count_pending() {
+        let changes = json!({
+            "results": [{ "seq": "101-g1AAAA", "id": "cat", "changes": [] }],
+            "last_seq": "101-g1AAAA",
+            "pending": 19,
+        });
+
+        assert_eq!(count_pending(&changes), Some(20));
+        assert_eq!(count_pending(&json!({ "results": [] })), None);
+    }
+}
@@ -0,0 +1,185 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use lazy_static::lazy_static;
+use std::io;
+use std::os::unix::net::UnixDatagram;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::{debug, warn};
+
+lazy_static! {
+    /// When the pipeline last showed it was alive, by reading from the feed or applying a change.
+    static ref LAST_ACTIVITY: Mutex<Instant> = Mutex::new(Instant::now());
+}
+
+/// notify sends a state, eg. `READY=1`, to systemd if we're running under it as a `Type=notify`
+/// service, which is when it sets `NOTIFY_SOCKET`.
+///
+/// Errors are logged rather than returned, as systemd not hearing from us isn't a reason to stop.
+pub fn notify(state: &str) {
+    let socket_path = match std::env::var("NOTIFY_SOCKET") {
+        Ok(socket_path) if !socket_path.is_empty() => socket_path,
+        _ => return,
+    };
+
+    debug!(state, "notifying systemd");
+    if let Err(e) = send(&socket_path, state) {
+        warn!(
+            error = e.to_string(),
+            socket = socket_path.as_str(),
+            "unable to notify systemd"
+        );
+    }
+}
+
+/// send sends a state to the notification socket at `socket_path`. A path starting with `@` is
+/// in the abstract namespace.
+fn send(socket_path: &str, state: &str) -> io::Result<()> {
+    let socket = UnixDatagram::unbound()?;
+
+    match socket_path.strip_prefix('@') {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+
+            let address = std::os::unix::net::SocketAddr::from_abstract_name(name.as_bytes())?;
+            socket.send_to_addr(state.as_bytes(), &address)?;
+        }
+        #[cfg(not(target_os = "linux"))]
+        Some(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "abstract sockets are only supported on Linux",
+            ));
+        }
+        None => {
+            socket.send_to(state.as_bytes(), socket_path)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// record_activity notes that the pipeline is alive.
+pub fn record_activity() {
+    *LAST_ACTIVITY.lock().expect("unable to lock last activity") = Instant::now();
+}
+
+/// since_activity returns how long it's been since the pipeline last showed it was alive.
+fn since_activity() -> Duration {
+    LAST_ACTIVITY
+        .lock()
+        .expect("unable to lock last activity")
+        .elapsed()
+}
+
+/// Watchdog pings the systemd watchdog while the pipeline is alive.
+///
+/// Pings are sent at half the watchdog timeout, but only if there's been activity within the
+/// timeout, so a feed that has hung (sending neither changes nor heartbeats) gets the service
+/// restarted. WatchdogSec must be longer than `changes_feed.heartbeat_ms`.
+pub struct Watchdog {
+    interval: Duration,
+    timeout: Duration,
+    next_ping: tokio::time::Instant,
+}
+
+impl Watchdog {
+    /// from_env returns a Watchdog if systemd has enabled the watchdog for this process.
+    pub fn from_env() -> Option<Watchdog> {
+        std::env::var("NOTIFY_SOCKET").ok()?;
+        let timeout = watchdog_timeout(
+            std::env::var("WATCHDOG_USEC").ok().as_deref(),
+            std::env::var("WATCHDOG_PID").ok().as_deref(),
+            std::process::id(),
+        )?;
+
+        Some(Watchdog {
+            interval: timeout / 2,
+            timeout,
+            next_ping: tokio::time::Instant::now(),
+        })
+    }
+
+    /// next_ping returns when the watchdog should next be pinged.
+    pub fn next_ping(&self) -> tokio::time::Instant {
+        self.next_ping
+    }
+
+    /// ping pings the watchdog if there's been activity within its timeout.
+    pub fn ping(&mut self) {
+        self.next_ping = tokio::time::Instant::now() + self.interval;
+
+        let idle = since_activity();
+        if idle >= self.timeout {
+            warn!(
+                idle_secs = idle.as_secs(),
+                "no activity on the changes feed, not pinging the systemd watchdog"
+            );
+            return;
+        }
+
+        notify("WATCHDOG=1");
+    }
+}
+
+/// watchdog_timeout returns the watchdog timeout systemd set in WATCHDOG_USEC, if it's meant for
+/// `pid`.
+fn watchdog_timeout(usec: Option<&str>, watchdog_pid: Option<&str>, pid: u32) -> Option<Duration> {
+    if let Some(watchdog_pid) = watchdog_pid {
+        if watchdog_pid.parse::<u32>().ok()? != pid {
+            return None;
+        }
+    }
+
+    match usec?.parse::<u64>().ok()? {
+        0 => None,
+        usec => Some(Duration::from_micros(usec)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_watchdog_timeout() {
+        assert_eq!(
+            watchdog_timeout(Some("30000000"), None, 10),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(
+            watchdog_timeout(Some("30000000"), Some("10"), 10),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(watchdog_timeout(Some("30000000"), Some("11"), 10), None);
+        assert_eq!(watchdog_timeout(Some("0"), None, 10), None);
+        assert_eq!(watchdog_timeout(None, None, 10), None);
+    }
+
+    #[test]
+    fn test_send() {
+        let path = std::env::temp_dir().join(format!("couch2mongo-notify-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixDatagram::bind(&path).unwrap();
+
+        send(path.to_str().unwrap(), "READY=1").unwrap();
+        let mut buffer = [0; 64];
+        let n = listener.recv(&mut buffer).unwrap();
+        assert_eq!(&buffer[..n], b"READY=1");
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
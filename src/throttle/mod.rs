@@ -0,0 +1,333 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+pub mod rate;
+
+use crate::couchdb::sequence_number;
+use crate::settings::config_parser::BackfillThrottleSettings;
+use bson::{doc, Bson, Document};
+use lazy_static::lazy_static;
+use prometheus::{register_int_gauge, IntGauge};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{debug, info, warn};
+
+lazy_static! {
+    /// Current pause between writes imposed by the backfill throttle.
+    pub static ref THROTTLE_DELAY_MS: IntGauge = register_int_gauge!(
+        "couch2mongo_backfill_throttle_delay_ms",
+        "Current pause between writes imposed by the backfill throttle"
+    )
+    .unwrap();
+}
+
+/// HealthSignals is what we read from MongoDB to judge whether it's keeping up.
+#[derive(Debug, Default, PartialEq)]
+pub struct HealthSignals {
+    pub queued_operations: Option<i64>,
+    pub dirty_cache_ratio: Option<f64>,
+    pub replication_lag_secs: Option<i64>,
+}
+
+impl HealthSignals {
+    /// from_server_status reads the global lock queue and WiredTiger cache pressure from the
+    /// output of `serverStatus`.
+    pub fn from_server_status(status: &Document) -> HealthSignals {
+        let queued_operations = status
+            .get_document("globalLock")
+            .and_then(|l| l.get_document("currentQueue"))
+            .ok()
+            .and_then(|q| q.get("total"))
+            .and_then(as_f64)
+            .map(|n| n as i64);
+
+        let dirty_cache_ratio = status
+            .get_document("wiredTiger")
+            .and_then(|w| w.get_document("cache"))
+            .ok()
+            .and_then(|cache| {
+                let dirty = cache
+                    .get("tracked dirty bytes in the cache")
+                    .and_then(as_f64)?;
+                let maximum = cache.get("maximum bytes configured").and_then(as_f64)?;
+
+                (maximum > 0.0).then(|| dirty / maximum)
+            });
+
+        HealthSignals {
+            queued_operations,
+            dirty_cache_ratio,
+            replication_lag_secs: None,
+        }
+    }
+
+    /// replication_lag_secs returns how far the slowest secondary is behind the primary, from
+    /// the output of `replSetGetStatus`.
+    pub fn replication_lag_secs(status: &Document) -> Option<i64> {
+        let members = status.get_array("members").ok()?;
+        let optime = |state: &str| {
+            members
+                .iter()
+                .filter_map(Bson::as_document)
+                .filter(|m| m.get_str("stateStr") == Ok(state))
+                .filter_map(|m| m.get_datetime("optimeDate").ok())
+                .map(|d| d.timestamp_millis())
+                .collect::<Vec<i64>>()
+        };
+
+        let primary = *optime("PRIMARY").first()?;
+        let slowest = optime("SECONDARY").into_iter().min()?;
+
+        Some((primary - slowest).max(0) / 1000)
+    }
+
+    /// distress returns a description of each signal over its threshold.
+    pub fn distress(&self, settings: &BackfillThrottleSettings) -> Vec<String> {
+        let mut reasons = Vec::new();
+
+        if let Some(queued) = self.queued_operations {
+            if queued > settings.max_queued_operations {
+                reasons.push(format!("{} queued operations", queued));
+            }
+        }
+        if let Some(ratio) = self.dirty_cache_ratio {
+            if ratio > settings.max_dirty_cache_ratio {
+                reasons.push(format!("cache {:.0}% dirty", ratio * 100.0));
+            }
+        }
+        if let Some(lag) = self.replication_lag_secs {
+            if lag > settings.max_replication_lag_secs {
+                reasons.push(format!("secondaries {}s behind", lag));
+            }
+        }
+
+        reasons
+    }
+}
+
+fn as_f64(value: &Bson) -> Option<f64> {
+    match value {
+        Bson::Int32(n) => Some(*n as f64),
+        Bson::Int64(n) => Some(*n as f64),
+        Bson::Double(n) => Some(*n),
+        _ => None,
+    }
+}
+
+/// BackfillThrottle slows the initial backfill down when MongoDB is struggling to keep up.
+///
+/// Health is polled in the background. While the target shows distress the pause between writes
+/// doubles (up to `max_delay_ms`), and once it recovers the pause halves back down to nothing.
+/// Throttling only applies until the feed catches up with where the source database was when we
+/// started; after that writes trickle in at the rate CouchDB produces them. The `backfill` command
+/// pauses between batches instead, until it's copied every range.
+pub struct BackfillThrottle {
+    settings: BackfillThrottleSettings,
+    admin: mongodb::Database,
+    until: Option<u64>,
+    delay_ms: AtomicU64,
+    finished: AtomicBool,
+}
+
+impl BackfillThrottle {
+    /// new creates a new BackfillThrottle.
+    ///
+    /// # Arguments
+    /// * `settings` - A BackfillThrottleSettings struct
+    /// * `admin` - The MongoDB `admin` database
+    /// * `until` - The source sequence number at which the backfill is finished
+    ///
+    /// # Returns
+    /// * A shared BackfillThrottle
+    pub fn new(
+        settings: &BackfillThrottleSettings,
+        admin: mongodb::Database,
+        until: Option<u64>,
+    ) -> Arc<BackfillThrottle> {
+        Arc::new(BackfillThrottle {
+            settings: settings.clone(),
+            admin,
+            until,
+            delay_ms: AtomicU64::new(0),
+            finished: AtomicBool::new(until.is_none()),
+        })
+    }
+
+    /// start polls MongoDB's health in the background until the backfill finishes.
+    pub fn start(self: &Arc<Self>) {
+        if self.finished.load(Ordering::SeqCst) {
+            return;
+        }
+
+        let throttle = self.clone();
+        tokio::spawn(async move {
+            while !throttle.finished.load(Ordering::SeqCst) {
+                match throttle.poll().await {
+                    Ok(signals) => throttle.adjust(&signals.distress(&throttle.settings)),
+                    Err(e) => warn!(error = e, "unable to read MongoDB health"),
+                }
+
+                tokio::time::sleep(Duration::from_millis(throttle.settings.poll_interval_ms)).await;
+            }
+        });
+    }
+
+    /// wait pauses before writing the change at `seq`, if the target needs relief.
+    pub async fn wait(&self, seq: &str) {
+        if self.finished.load(Ordering::SeqCst) {
+            return;
+        }
+
+        if let (Some(until), Some(current)) = (self.until, sequence_number(seq)) {
+            if current >= until {
+                info!(seq, "backfill complete, no longer throttling");
+                self.finish();
+                return;
+            }
+        }
+
+        self.pause().await;
+    }
+
+    /// pause waits out the current delay, for writes that don't follow the changes feed, such as
+    /// the `_all_docs` backfill.
+    pub async fn pause(&self) {
+        let delay_ms = self.delay_ms.load(Ordering::SeqCst);
+        if delay_ms > 0 {
+            tokio::time::sleep(Duration::from_millis(delay_ms)).await;
+        }
+    }
+
+    /// finish stops throttling and polling MongoDB's health.
+    pub fn finish(&self) {
+        self.finished.store(true, Ordering::SeqCst);
+        self.delay_ms.store(0, Ordering::SeqCst);
+        THROTTLE_DELAY_MS.set(0);
+    }
+
+    async fn poll(&self) -> Result<HealthSignals, String> {
+        let status = self
+            .admin
+            .run_command(doc! { "serverStatus": 1 }, None)
+            .await
+            .map_err(|e| e.to_string())?;
+        let mut signals = HealthSignals::from_server_status(&status);
+
+        // Standalone servers have no replica set to ask about
+        if let Ok(repl_status) = self
+            .admin
+            .run_command(doc! { "replSetGetStatus": 1 }, None)
+            .await
+        {
+            signals.replication_lag_secs = HealthSignals::replication_lag_secs(&repl_status);
+        }
+
+        debug!(signals = format!("{:?}", signals), "MongoDB health");
+        Ok(signals)
+    }
+
+    /// adjust backs off while there's distress, and recovers once it clears.
+    fn adjust(&self, distress: &[String]) {
+        let previous = self.delay_ms.load(Ordering::SeqCst);
+        let delay_ms = next_delay_ms(previous, !distress.is_empty(), &self.settings);
+
+        if delay_ms > previous {
+            warn!(
+                delay_ms,
+                reasons = distress.join(", "),
+                "MongoDB is under pressure, slowing backfill"
+            );
+        } else if delay_ms == 0 && previous > 0 {
+            info!("MongoDB has recovered, resuming full speed backfill");
+        }
+
+        self.delay_ms.store(delay_ms, Ordering::SeqCst);
+        THROTTLE_DELAY_MS.set(delay_ms as i64);
+    }
+}
+
+/// next_delay_ms doubles the delay under distress and halves it otherwise.
+fn next_delay_ms(previous: u64, distressed: bool, settings: &BackfillThrottleSettings) -> u64 {
+    if distressed {
+        (previous * 2)
+            .max(settings.min_delay_ms)
+            .min(settings.max_delay_ms)
+    } else if previous / 2 < settings.min_delay_ms {
+        0
+    } else {
+        previous / 2
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings() -> BackfillThrottleSettings {
+        BackfillThrottleSettings {
+            poll_interval_ms: 5000,
+            max_queued_operations: 100,
+            max_dirty_cache_ratio: 0.15,
+            max_replication_lag_secs: 10,
+            min_delay_ms: 10,
+            max_delay_ms: 1000,
+        }
+    }
+
+    #[test]
+    fn test_server_status() {
+        let status = doc! {
+            "globalLock": { "currentQueue": { "total": 150, "readers": 0, "writers": 150 } },
+            "wiredTiger": { "cache": {
+                "tracked dirty bytes in the cache": 30_i64,
+                "maximum bytes configured": 100.0,
+            } },
+        };
+        let signals = HealthSignals::from_server_status(&status);
+
+        assert_eq!(signals.queued_operations, Some(150));
+        assert_eq!(signals.dirty_cache_ratio, Some(0.3));
+        assert_eq!(signals.distress(&settings()).len(), 2);
+        assert!(HealthSignals::from_server_status(&doc! {})
+            .distress(&settings())
+            .is_empty());
+    }
+
+    #[test]
+    fn test_replication_lag() {
+        let at = |secs: i64| bson::DateTime::from_millis(secs * 1000);
+        let status = doc! { "members": [
+            { "stateStr": "PRIMARY", "optimeDate": at(100) },
+            { "stateStr": "SECONDARY", "optimeDate": at(95) },
+            { "stateStr": "SECONDARY", "optimeDate": at(70) },
+            { "stateStr": "ARBITER" },
+        ] };
+
+        assert_eq!(HealthSignals::replication_lag_secs(&status), Some(30));
+        assert_eq!(HealthSignals::replication_lag_secs(&doc! {}), None);
+    }
+
+    #[test]
+    fn test_next_delay() {
+        let settings = settings();
+
+        assert_eq!(next_delay_ms(0, true, &settings), 10);
+        assert_eq!(next_delay_ms(10, true, &settings), 20);
+        assert_eq!(next_delay_ms(800, true, &settings), 1000);
+        assert_eq!(next_delay_ms(1000, false, &settings), 500);
+        assert_eq!(next_delay_ms(10, false, &settings), 0);
+        assert_eq!(next_delay_ms(0, false, &settings), 0);
+    }
+}
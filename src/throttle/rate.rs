@@ -0,0 +1,127 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use lazy_static::lazy_static;
+use prometheus::{register_int_counter_vec, IntCounterVec};
+use std::error::Error;
+use std::time::{Duration, Instant};
+use tracing::debug;
+
+lazy_static! {
+    /// Time spent waiting on rate limits, by limiter.
+    pub static ref RATE_LIMIT_WAIT_MS: IntCounterVec = register_int_counter_vec!(
+        "couch2mongo_rate_limit_wait_ms_total",
+        "Time spent waiting on rate limits, by limiter",
+        &["limiter"]
+    )
+    .unwrap();
+}
+
+/// RateLimiter is a token bucket limiting how often something happens.
+///
+/// The bucket holds up to one second's worth of tokens, so short bursts are allowed after a quiet
+/// spell. Taking more tokens than are available puts the bucket into debt, which later callers
+/// wait out, so a large batch is paid for rather than refused.
+#[derive(Debug)]
+pub struct RateLimiter {
+    name: &'static str,
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    /// new creates a new RateLimiter, starting with a full bucket.
+    ///
+    /// # Arguments
+    /// * `name` - The name of the limiter, for metrics and logs
+    /// * `per_second` - How many tokens are added each second
+    ///
+    /// # Returns
+    /// * A RateLimiter, or an error if the rate isn't a positive number
+    pub fn new(name: &'static str, per_second: f64) -> Result<RateLimiter, Box<dyn Error>> {
+        if !per_second.is_finite() || per_second <= 0.0 {
+            return Err(format!("{} must be a positive number, not {}", name, per_second).into());
+        }
+
+        let capacity = per_second.max(1.0);
+        Ok(RateLimiter {
+            name,
+            rate: per_second,
+            capacity,
+            tokens: capacity,
+            updated: Instant::now(),
+        })
+    }
+
+    /// acquire waits until `n` tokens can be taken, and takes them.
+    pub async fn acquire(&mut self, n: usize) {
+        let delay = self.reserve(n, Instant::now());
+        if delay.is_zero() {
+            return;
+        }
+
+        debug!(
+            limiter = self.name,
+            delay_ms = delay.as_millis() as u64,
+            "waiting on rate limit"
+        );
+        RATE_LIMIT_WAIT_MS
+            .with_label_values(&[self.name])
+            .inc_by(delay.as_millis() as u64);
+        tokio::time::sleep(delay).await;
+    }
+
+    /// reserve takes `n` tokens, returning how long to wait until they've been paid for.
+    fn reserve(&mut self, n: usize, now: Instant) -> Duration {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.updated = now;
+
+        self.tokens -= n as f64;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reserve() {
+        let mut limiter = RateLimiter::new("test", 10.0).unwrap();
+        let start = limiter.updated;
+
+        // A full bucket allows a burst of one second's worth
+        assert_eq!(limiter.reserve(10, start), Duration::ZERO);
+        assert_eq!(limiter.reserve(1, start), Duration::from_millis(100));
+        assert_eq!(limiter.reserve(1, start), Duration::from_millis(200));
+
+        // Debt is paid off over time, and the bucket never holds more than its capacity
+        let later = start + Duration::from_secs(60);
+        assert_eq!(limiter.reserve(10, later), Duration::ZERO);
+        assert!(limiter.reserve(1, later) > Duration::ZERO);
+    }
+
+    #[test]
+    fn test_invalid_rate() {
+        assert!(RateLimiter::new("test", 0.0).is_err());
+        assert!(RateLimiter::new("test", f64::NAN).is_err());
+    }
+}
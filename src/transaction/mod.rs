@@ -0,0 +1,426 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::batch::deletion;
+use crate::document::revision;
+use crate::document::shard_key::ShardKeys;
+use crate::metrics;
+use crate::pipeline::Operation;
+use crate::seqstore::mongodb::{compare_and_set_filter, is_duplicate_key};
+use crate::settings::config_parser::{
+    DeletionSettings,
+    DeletionStrategy,
+    TransactionSettings,
+    WriteMode,
+};
+use crate::transform::hash::UNCHANGED_DOCUMENTS;
+use crate::writer::mode::write_document;
+use bson::{doc, Bson, Document};
+use mongodb::error::{TRANSIENT_TRANSACTION_ERROR, UNKNOWN_TRANSACTION_COMMIT_RESULT};
+use mongodb::options::{
+    FindOneOptions,
+    ReplaceOptions,
+    TransactionOptions,
+    UpdateOptions,
+    WriteConcern,
+};
+use mongodb::{ClientSession, Collection, Database};
+use std::error::Error;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+/// Write is a single document write, waiting to be committed.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Write {
+    Replace {
+        collection: String,
+        id: Bson,
+        // The incoming revision, when a newer one in MongoDB must be left alone
+        rev: Option<String>,
+        // A filter matching the document in MongoDB if it already has this content
+        unchanged: Option<Document>,
+        document: Document,
+    },
+    Delete {
+        collection: String,
+        id: Bson,
+    },
+}
+
+/// TransactionBatch commits batches of writes together with the checkpoint they advance to, in a
+/// single multi-document transaction.
+///
+/// Either the writes and the checkpoint both land or neither does, so a restart resumes exactly
+/// after the last committed batch instead of replaying it. This needs a replica set (or sharded
+/// cluster) and the MongoDB sequence store, so the checkpoint is in the same database.
+pub struct TransactionBatch {
+    db: Database,
+    checkpoints: Collection<Document>,
+    key: String,
+    persisted: Option<String>,
+    writes: Vec<Write>,
+    last_seq: Option<String>,
+    started: Option<Instant>,
+    batch_size: usize,
+    timeout: Duration,
+    attempts: u32,
+    write_concern: Option<WriteConcern>,
+    deletion: DeletionSettings,
+    write_mode: WriteMode,
+    shard_keys: ShardKeys,
+}
+
+impl TransactionBatch {
+    /// new creates a new TransactionBatch.
+    ///
+    /// # Arguments
+    /// * `db` - The MongoDB database
+    /// * `checkpoint_collection` - The MongoDB sequence store's collection
+    /// * `key` - The checkpoint key
+    /// * `persisted` - The checkpoint as last persisted, if any
+    /// * `settings` - A TransactionSettings struct
+    /// * `write_concern` - The write concern for commits
+    ///
+    /// # Returns
+    /// * A TransactionBatch struct
+    pub fn new(
+        db: Database,
+        checkpoint_collection: &str,
+        key: &str,
+        persisted: Option<String>,
+        settings: &TransactionSettings,
+        write_concern: Option<WriteConcern>,
+    ) -> TransactionBatch {
+        TransactionBatch {
+            checkpoints: db.collection(checkpoint_collection),
+            db,
+            key: key.to_string(),
+            persisted,
+            writes: Vec::new(),
+            last_seq: None,
+            started: None,
+            batch_size: settings.batch_size.max(1),
+            timeout: Duration::from_millis(settings.batch_timeout_ms),
+            attempts: settings.attempts.max(1),
+            write_concern,
+            deletion: DeletionSettings::default(),
+            write_mode: WriteMode::Replace,
+            shard_keys: ShardKeys::default(),
+        }
+    }
+
+    /// set_deletion sets the deletion strategy deletes are applied with.
+    pub fn set_deletion(&mut self, deletion: DeletionSettings) {
+        self.deletion = deletion;
+    }
+
+    /// set_write_mode sets how documents are written.
+    pub fn set_write_mode(&mut self, write_mode: WriteMode) {
+        self.write_mode = write_mode;
+    }
+
+    /// set_shard_keys sets the shard keys documents are replaced by, along with their `_id`.
+    pub fn set_shard_keys(&mut self, shard_keys: ShardKeys) {
+        self.shard_keys = shard_keys;
+    }
+
+    /// set_database switches to a new connection to the database, eg. after credentials rotate.
+    pub fn set_database(&mut self, db: Database) {
+        self.checkpoints = db.collection(self.checkpoints.name());
+        self.db = db;
+    }
+
+    /// push adds a write to the batch, advancing the checkpoint it'll commit to.
+    pub fn push(&mut self, write: Write, seq: &str) {
+        if self.writes.is_empty() {
+            self.started = Some(Instant::now());
+        }
+
+        self.writes.push(write);
+        self.last_seq = Some(seq.to_string());
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.writes.is_empty()
+    }
+
+    /// is_full returns true once the batch should be committed.
+    pub fn is_full(&self) -> bool {
+        self.writes.len() >= self.batch_size
+    }
+
+    /// time_until_due returns how long until a partial batch should be committed, if there's one.
+    pub fn time_until_due(&self) -> Option<Duration> {
+        self.started
+            .map(|started| self.timeout.saturating_sub(started.elapsed()))
+    }
+
+    /// commit writes the batch and the checkpoint in one transaction, retrying transient errors.
+    ///
+    /// # Returns
+    /// * What each write did, in order, or an error if the transaction couldn't be committed or the
+    ///   checkpoint was moved by another instance
+    pub async fn commit(&mut self) -> Result<Vec<Operation>, Box<dyn Error>> {
+        let seq = match &self.last_seq {
+            Some(seq) if !self.writes.is_empty() => seq.clone(),
+            _ => return Ok(Vec::new()),
+        };
+
+        let mut session = self.checkpoints.client().start_session(None).await?;
+        let options = TransactionOptions::builder()
+            .write_concern(self.write_concern.clone())
+            .build();
+
+        let mut attempt = 1;
+        let operations = loop {
+            session.start_transaction(options.clone()).await?;
+
+            let result = match self.run(&mut session, &seq).await {
+                Ok(Some(operations)) => commit_with_retry(&mut session, self.attempts)
+                    .await
+                    .map(|_| Some(operations)),
+                Ok(None) => {
+                    session.abort_transaction().await.ok();
+                    Ok(None)
+                }
+                Err(e) => {
+                    session.abort_transaction().await.ok();
+                    Err(e)
+                }
+            };
+
+            match result {
+                Ok(Some(operations)) => break operations,
+                Ok(None) => {
+                    return Err(format!(
+                        "checkpoint {} was moved by another instance, stopping",
+                        self.key
+                    )
+                    .into())
+                }
+                Err(e)
+                    if e.contains_label(TRANSIENT_TRANSACTION_ERROR) && attempt < self.attempts =>
+                {
+                    warn!(
+                        attempt,
+                        error = e.to_string(),
+                        "transient transaction error, retrying"
+                    );
+                    attempt += 1;
+                }
+                Err(e) => return Err(e.into()),
+            }
+        };
+
+        info!(
+            writes = self.writes.len(),
+            seq = seq.as_str(),
+            "committed transaction"
+        );
+
+        self.persisted = Some(seq);
+        self.writes.clear();
+        self.last_seq = None;
+        self.started = None;
+
+        Ok(operations)
+    }
+
+    /// run applies the writes and moves the checkpoint within the session's transaction.
+    ///
+    /// # Returns
+    /// * What each write did, or None if the checkpoint wasn't where we left it
+    async fn run(
+        &self,
+        session: &mut ClientSession,
+        seq: &str,
+    ) -> Result<Option<Vec<Operation>>, mongodb::error::Error> {
+        let mut operations = Vec::with_capacity(self.writes.len());
+
+        for write in &self.writes {
+            match write {
+                Write::Replace {
+                    collection,
+                    id,
+                    rev,
+                    unchanged,
+                    document,
+                } => {
+                    // A failed upsert would abort the transaction, so look for a newer revision
+                    // first; the transaction keeps the check and the write consistent
+                    let newer = rev.as_deref().and_then(|r| revision::newer_filter(id, r));
+                    if self.exists(collection, newer, session).await? {
+                        info!(
+                            collection = collection.as_str(),
+                            rev = rev.as_deref().unwrap_or_default(),
+                            "skipping stale revision"
+                        );
+                        operations.push(Operation::Stale);
+                        continue;
+                    }
+
+                    if self.exists(collection, unchanged.clone(), session).await? {
+                        UNCHANGED_DOCUMENTS.with_label_values(&[collection]).inc();
+                        operations.push(Operation::Unchanged);
+                        continue;
+                    }
+
+                    // Likewise a duplicate key, so InsertOnly checks for the document first
+                    if self.write_mode == WriteMode::InsertOnly
+                        && self
+                            .exists(collection, Some(doc! { "_id": id }), session)
+                            .await?
+                    {
+                        operations.push(Operation::Existing);
+                        continue;
+                    }
+
+                    let size = bson::to_vec(document).map_or(0, |b| b.len());
+                    let operation = write_document(
+                        &self.db.collection(collection),
+                        self.shard_keys.filter(collection, id, document),
+                        document.clone(),
+                        &self.write_mode,
+                        Some(session),
+                    )
+                    .await?;
+
+                    metrics::record_bytes_written(collection, self.write_mode.as_str(), size);
+                    operations.push(operation);
+                }
+                Write::Delete { collection, id } => {
+                    self.delete(collection, id, session).await?;
+                    operations.push(Operation::Deleted);
+                }
+            }
+        }
+
+        let (filter, upsert) = compare_and_set_filter(&self.key, self.persisted.as_deref());
+        let result = self
+            .checkpoints
+            .update_one_with_session(
+                filter,
+                doc! { "$set": { "value": seq } },
+                UpdateOptions::builder().upsert(upsert).build(),
+                session,
+            )
+            .await;
+
+        match result {
+            Ok(r) if r.matched_count == 1 || r.upserted_id.is_some() => Ok(Some(operations)),
+            Ok(_) => Ok(None),
+            Err(e) if is_duplicate_key(&e) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// delete applies the deletion strategy to a document within the session's transaction.
+    async fn delete(
+        &self,
+        collection: &str,
+        id: &Bson,
+        session: &mut ClientSession,
+    ) -> Result<(), mongodb::error::Error> {
+        let source = self.db.collection::<Document>(collection);
+
+        match self.deletion.strategy {
+            DeletionStrategy::Hard => {
+                source
+                    .delete_one_with_session(doc! { "_id": id }, None, session)
+                    .await?;
+            }
+            DeletionStrategy::Soft => {
+                source
+                    .update_one_with_session(
+                        deletion::soft_delete_filter(doc! { "_id": id }, &self.deletion),
+                        deletion::soft_delete_update(&self.deletion),
+                        None,
+                        session,
+                    )
+                    .await?;
+            }
+            DeletionStrategy::Tombstone => {
+                let document = source
+                    .find_one_with_session(doc! { "_id": id }, None, session)
+                    .await?;
+                if let Some(document) = document {
+                    self.db
+                        .collection::<Document>(&deletion::tombstone_collection(
+                            collection,
+                            &self.deletion,
+                        ))
+                        .replace_one_with_session(
+                            doc! { "_id": id },
+                            deletion::tombstone(document, &self.deletion),
+                            ReplaceOptions::builder().upsert(true).build(),
+                            session,
+                        )
+                        .await?;
+                    source
+                        .delete_one_with_session(doc! { "_id": id }, None, session)
+                        .await?;
+                }
+            }
+            DeletionStrategy::Ignore => {}
+        }
+
+        Ok(())
+    }
+
+    /// exists returns true if a document matches the filter, if there's one.
+    async fn exists(
+        &self,
+        collection: &str,
+        filter: Option<Document>,
+        session: &mut ClientSession,
+    ) -> Result<bool, mongodb::error::Error> {
+        let filter = match filter {
+            Some(filter) => filter,
+            None => return Ok(false),
+        };
+
+        let options = FindOneOptions::builder()
+            .projection(doc! { "_id": 1 })
+            .build();
+        let found = self
+            .db
+            .collection::<Document>(collection)
+            .find_one_with_session(filter, options, session)
+            .await?;
+
+        Ok(found.is_some())
+    }
+}
+
+/// commit_with_retry commits the session's transaction, retrying when the outcome is unknown.
+async fn commit_with_retry(
+    session: &mut ClientSession,
+    attempts: u32,
+) -> Result<(), mongodb::error::Error> {
+    let mut attempt = 1;
+
+    loop {
+        match session.commit_transaction().await {
+            Err(e) if e.contains_label(UNKNOWN_TRANSACTION_COMMIT_RESULT) && attempt < attempts => {
+                warn!(
+                    attempt,
+                    error = e.to_string(),
+                    "transaction commit result unknown, retrying"
+                );
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
@@ -0,0 +1,266 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::document::get_path_mut;
+use crate::settings::config_parser::Coercion;
+use bson::{Bson, DateTime, Decimal128, Document};
+use std::collections::HashMap;
+
+/// Exponent bias of the IEEE 754 decimal128 format.
+const DECIMAL128_EXPONENT_BIAS: i32 = 6176;
+
+/// Largest biased exponent of the IEEE 754 decimal128 format.
+const DECIMAL128_MAX_BIASED_EXPONENT: i32 = 12287;
+
+/// Most significant digits a decimal128 holds.
+const DECIMAL128_MAX_DIGITS: usize = 34;
+
+/// coerce_fields converts fields to the configured BSON types, eg. the string `"42"` to the
+/// integer 42, or the double 12.99 to the Decimal128 12.99.
+///
+/// A field holding an array has each element converted. Missing and null fields are left alone,
+/// as is any value that can't be converted without losing information.
+///
+/// # Arguments
+/// * `document` - The document to change
+/// * `rules` - The type to convert to, by dotted path
+///
+/// # Returns
+/// * The paths holding a value that couldn't be converted
+pub fn coerce_fields(document: &mut Document, rules: &HashMap<String, Coercion>) -> Vec<String> {
+    let mut failed = vec![];
+
+    for (field, coercion) in rules {
+        let converted = match get_path_mut(document, field) {
+            Some(Bson::Array(values)) => {
+                // Every element is converted, even after one fails
+                let mut converted = true;
+                for value in values.iter_mut() {
+                    converted &= coerce_value(value, coercion);
+                }
+                converted
+            }
+            Some(value) => coerce_value(value, coercion),
+            None => true,
+        };
+
+        if !converted {
+            failed.push(field.clone());
+        }
+    }
+
+    failed.sort();
+    failed
+}
+
+/// coerce_value converts a value in place, returning false if it couldn't be.
+fn coerce_value(value: &mut Bson, coercion: &Coercion) -> bool {
+    let converted = match (coercion, &*value) {
+        (_, Bson::Null) => return true,
+        (Coercion::Int64, Bson::Int64(_))
+        | (Coercion::Double, Bson::Double(_))
+        | (Coercion::Decimal128, Bson::Decimal128(_))
+        | (Coercion::DateTime, Bson::DateTime(_)) => return true,
+
+        (Coercion::Int64, Bson::String(s)) => s.trim().parse().ok().map(Bson::Int64),
+        (Coercion::Int64, Bson::Int32(i)) => Some(Bson::Int64(*i as i64)),
+        (Coercion::Int64, Bson::Double(f))
+            if f.fract() == 0.0 && f.abs() < 9_007_199_254_740_992.0 =>
+        {
+            Some(Bson::Int64(*f as i64))
+        }
+
+        (Coercion::Double, Bson::String(s)) => s
+            .trim()
+            .parse::<f64>()
+            .ok()
+            .filter(|f| f.is_finite())
+            .map(Bson::Double),
+        (Coercion::Double, Bson::Int32(i)) => Some(Bson::Double(*i as f64)),
+        (Coercion::Double, Bson::Int64(i)) => Some(Bson::Double(*i as f64)),
+
+        (Coercion::Decimal128, Bson::String(s)) => parse_decimal128(s).map(Bson::Decimal128),
+        (Coercion::Decimal128, Bson::Int32(i)) => {
+            parse_decimal128(&i.to_string()).map(Bson::Decimal128)
+        }
+        (Coercion::Decimal128, Bson::Int64(i)) => {
+            parse_decimal128(&i.to_string()).map(Bson::Decimal128)
+        }
+        // The shortest representation that round trips, so 12.99 stays 12.99
+        (Coercion::Decimal128, Bson::Double(f)) if f.is_finite() => {
+            parse_decimal128(&f.to_string()).map(Bson::Decimal128)
+        }
+
+        (Coercion::DateTime, value) => parse_datetime(value).map(Bson::DateTime),
+
+        _ => None,
+    };
+
+    match converted {
+        Some(converted) => {
+            *value = converted;
+            true
+        }
+        None => false,
+    }
+}
+
+/// parse_datetime returns a value as a date: a BSON date, an RFC 3339 string like
+/// `2024-05-01T12:00:00Z`, or an integer number of milliseconds since the epoch.
+///
+/// # Returns
+/// * The date, or None if the value isn't one of those
+pub fn parse_datetime(value: &Bson) -> Option<DateTime> {
+    match value {
+        Bson::DateTime(d) => Some(*d),
+        Bson::String(s) => DateTime::parse_rfc3339_str(s.trim()).ok(),
+        Bson::Int32(i) => Some(DateTime::from_millis(*i as i64)),
+        Bson::Int64(i) => Some(DateTime::from_millis(*i)),
+        _ => None,
+    }
+}
+
+/// parse_decimal128 parses a decimal number, eg. `-12.50` or `1.5e3`, into a Decimal128 holding
+/// exactly that value, keeping trailing zeros.
+///
+/// # Returns
+/// * The Decimal128, or None if the string isn't a number or doesn't fit without rounding
+pub fn parse_decimal128(s: &str) -> Option<Decimal128> {
+    let s = s.trim();
+    let (negative, s) = match s.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, s.strip_prefix('+').unwrap_or(s)),
+    };
+
+    let (mantissa, mut exponent) = match s.split_once(['e', 'E']) {
+        Some((mantissa, exponent)) => (mantissa, exponent.parse::<i32>().ok()?),
+        None => (s, 0),
+    };
+    let (integer, fraction) = mantissa.split_once('.').unwrap_or((mantissa, ""));
+    if (integer.is_empty() && fraction.is_empty())
+        || !integer
+            .bytes()
+            .chain(fraction.bytes())
+            .all(|b| b.is_ascii_digit())
+    {
+        return None;
+    }
+    exponent = exponent.checked_sub(fraction.len() as i32)?;
+
+    let mut digits = format!("{}{}", integer, fraction)
+        .trim_start_matches('0')
+        .to_string();
+    while digits.len() > DECIMAL128_MAX_DIGITS && digits.ends_with('0') {
+        digits.pop();
+        exponent += 1;
+    }
+    if digits.len() > DECIMAL128_MAX_DIGITS {
+        return None;
+    }
+
+    let biased = exponent.checked_add(DECIMAL128_EXPONENT_BIAS)?;
+    if !(0..=DECIMAL128_MAX_BIASED_EXPONENT).contains(&biased) {
+        return None;
+    }
+
+    let coefficient: u128 = if digits.is_empty() {
+        0
+    } else {
+        digits.parse().ok()?
+    };
+    let high = (u64::from(negative) << 63) | ((biased as u64) << 49) | (coefficient >> 64) as u64;
+    let low = coefficient as u64;
+
+    let mut bytes = [0u8; 16];
+    bytes[..8].copy_from_slice(&low.to_le_bytes());
+    bytes[8..].copy_from_slice(&high.to_le_bytes());
+    Some(Decimal128::from_bytes(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bson::doc;
+
+    fn decimal(negative: bool, coefficient: u64, exponent: i32) -> Decimal128 {
+        let high = (u64::from(negative) << 63) | (((exponent + 6176) as u64) << 49);
+        let mut bytes = [0u8; 16];
+        bytes[..8].copy_from_slice(&coefficient.to_le_bytes());
+        bytes[8..].copy_from_slice(&high.to_le_bytes());
+        Decimal128::from_bytes(bytes)
+    }
+
+    #[test]
+    fn test_parse_decimal128() {
+        assert_eq!(parse_decimal128("12.99"), Some(decimal(false, 1299, -2)));
+        assert_eq!(parse_decimal128("-12.50"), Some(decimal(true, 1250, -2)));
+        assert_eq!(parse_decimal128(" 1.5e3 "), Some(decimal(false, 15, 2)));
+        assert_eq!(parse_decimal128(".5"), Some(decimal(false, 5, -1)));
+        assert_eq!(parse_decimal128("0"), Some(decimal(false, 0, 0)));
+
+        assert_eq!(parse_decimal128("twelve"), None);
+        assert_eq!(parse_decimal128("1.2.3"), None);
+        assert_eq!(parse_decimal128(""), None);
+        assert_eq!(parse_decimal128("1e9999"), None);
+        assert_eq!(parse_decimal128(&"1".repeat(35)), None);
+    }
+
+    #[test]
+    fn test_coerce_fields() {
+        let mut d = doc! {
+            "quantity": " 42 ",
+            "weight": "1.5",
+            "price": 12.99,
+            "totals": ["1.10", 2],
+            "meta": { "count": 3.0 },
+            "code": "abc",
+            "note": null,
+            "created_at": "2024-05-01T12:00:00Z",
+            "updated_at": 1714564800000_i64,
+        };
+        let rules: HashMap<String, Coercion> = [
+            ("quantity", Coercion::Int64),
+            ("weight", Coercion::Double),
+            ("price", Coercion::Decimal128),
+            ("totals", Coercion::Decimal128),
+            ("meta.count", Coercion::Int64),
+            ("code", Coercion::Int64),
+            ("note", Coercion::Double),
+            ("created_at", Coercion::DateTime),
+            ("updated_at", Coercion::DateTime),
+            ("missing", Coercion::Double),
+        ]
+        .into_iter()
+        .map(|(field, coercion)| (field.to_string(), coercion))
+        .collect();
+
+        let failed = coerce_fields(&mut d, &rules);
+
+        assert_eq!(failed, vec!["code".to_string()]);
+        assert_eq!(
+            d,
+            doc! {
+                "quantity": 42_i64,
+                "weight": 1.5,
+                "price": decimal(false, 1299, -2),
+                "totals": [decimal(false, 110, -2), decimal(false, 2, 0)],
+                "meta": { "count": 3_i64 },
+                "code": "abc",
+                "note": null,
+                "created_at": DateTime::from_millis(1714564800000),
+                "updated_at": DateTime::from_millis(1714564800000),
+            }
+        );
+    }
+}
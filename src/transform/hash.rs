@@ -0,0 +1,112 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::settings::config_parser::ContentHashSettings;
+use bson::{Bson, Document};
+use lazy_static::lazy_static;
+use prometheus::{register_int_counter_vec, IntCounterVec};
+use sha2::{Digest, Sha256};
+use std::error::Error;
+
+lazy_static! {
+    /// Writes skipped because the document hadn't changed, by collection.
+    pub static ref UNCHANGED_DOCUMENTS: IntCounterVec = register_int_counter_vec!(
+        "couch2mongo_unchanged_documents_total",
+        "Document writes skipped because the content hash matched the one in MongoDB",
+        &["collection"]
+    )
+    .unwrap();
+}
+
+/// content_hash returns a hash of a document's content, independent of field order.
+///
+/// Ignored fields (eg. `_rev`, which changes even when nothing else does) and the hash field
+/// itself are left out, and nested documents have their keys sorted, so the same content always
+/// hashes the same.
+///
+/// # Arguments
+/// * `document` - The document to hash
+/// * `settings` - A ContentHashSettings struct
+///
+/// # Returns
+/// * The hex encoded SHA-256 of the canonical document
+pub fn content_hash(
+    document: &Document,
+    settings: &ContentHashSettings,
+) -> Result<String, Box<dyn Error>> {
+    let mut content = Document::new();
+    for (key, value) in document {
+        if key == &settings.field || settings.ignore_fields.contains(key) {
+            continue;
+        }
+        content.insert(key, value.clone());
+    }
+
+    let canonical = bson::to_vec(&canonicalize_document(&content))?;
+    Ok(hex::encode(Sha256::digest(canonical)))
+}
+
+/// canonicalize_document returns a copy of a document with its keys sorted, recursively.
+fn canonicalize_document(document: &Document) -> Document {
+    let mut keys: Vec<&String> = document.keys().collect();
+    keys.sort();
+
+    keys.into_iter()
+        .map(|key| (key.clone(), canonicalize(document.get(key).unwrap())))
+        .collect()
+}
+
+/// canonicalize sorts the keys of any documents within a value.
+fn canonicalize(value: &Bson) -> Bson {
+    match value {
+        Bson::Document(d) => Bson::Document(canonicalize_document(d)),
+        Bson::Array(a) => Bson::Array(a.iter().map(canonicalize).collect()),
+        other => other.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bson::doc;
+
+    fn settings() -> ContentHashSettings {
+        ContentHashSettings {
+            field: "_content_hash".to_string(),
+            ignore_fields: vec!["_rev".to_string()],
+        }
+    }
+
+    #[test]
+    fn test_content_hash_ignores_order_and_rev() {
+        let a = doc! { "_id": "cat:tom", "_rev": "1-a", "name": "tom", "meta": { "x": 1, "y": [{ "b": 2, "a": 1 }] } };
+        let b = doc! { "meta": { "y": [{ "a": 1, "b": 2 }], "x": 1 }, "name": "tom", "_rev": "2-b", "_id": "cat:tom", "_content_hash": "old" };
+
+        assert_eq!(
+            content_hash(&a, &settings()).unwrap(),
+            content_hash(&b, &settings()).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_content_hash_changes_with_content() {
+        let a = doc! { "_id": "cat:tom", "name": "tom", "tags": [1, 2] };
+        let b = doc! { "_id": "cat:tom", "name": "tom", "tags": [2, 1] };
+
+        assert_ne!(
+            content_hash(&a, &settings()).unwrap(),
+            content_hash(&b, &settings()).unwrap()
+        );
+    }
+}
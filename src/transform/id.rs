@@ -0,0 +1,146 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::settings::config_parser::{IdSettings, IdStrategy};
+use bson::{Bson, Document};
+use sha2::{Digest, Sha256};
+
+/// IdOutcome describes what remap_id did with a document's ID.
+#[derive(Debug, PartialEq)]
+pub enum IdOutcome {
+    // The ID is usable as it is
+    Unchanged,
+    // The ID was replaced with this one
+    Replaced(String),
+    // The document should be dead lettered, for this reason
+    DeadLetter(String),
+}
+
+/// remap_id replaces a CouchDB ID that MongoDB can't comfortably use as an `_id`.
+///
+/// Empty IDs and IDs longer than `max_bytes` (MongoDB versions before 4.2 refuse index keys over
+/// 1024 bytes) are handled by the configured strategy. The replacement is derived only from the
+/// original ID, so a later deletion of the same document maps to the same `_id`. The original ID is
+/// kept in `original_field`.
+///
+/// # Arguments
+/// * `document` - The document, modified in place
+/// * `settings` - An IdSettings struct
+///
+/// # Returns
+/// * An IdOutcome
+pub fn remap_id(document: &mut Document, settings: &IdSettings) -> IdOutcome {
+    let id = match document.get("_id") {
+        Some(Bson::String(id)) => id.clone(),
+        _ => return IdOutcome::Unchanged,
+    };
+
+    let reason = if id.is_empty() {
+        "document ID is empty".to_string()
+    } else if id.len() > settings.max_bytes {
+        format!(
+            "document ID is {} bytes, over the {} byte limit",
+            id.len(),
+            settings.max_bytes
+        )
+    } else {
+        return IdOutcome::Unchanged;
+    };
+
+    let replacement = match settings.strategy {
+        IdStrategy::Hash => hash(&id),
+        IdStrategy::Prefix => {
+            let prefixed = format!("{}{}", settings.prefix, id);
+            if prefixed.len() > settings.max_bytes {
+                format!("{}{}", settings.prefix, hash(&id))
+            } else {
+                prefixed
+            }
+        }
+        IdStrategy::DeadLetter => return IdOutcome::DeadLetter(reason),
+    };
+
+    document.insert("_id", replacement.clone());
+    document.insert(settings.original_field.clone(), id);
+
+    IdOutcome::Replaced(replacement)
+}
+
+fn hash(id: &str) -> String {
+    hex::encode(Sha256::digest(id.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bson::doc;
+
+    fn settings(strategy: IdStrategy) -> IdSettings {
+        IdSettings {
+            strategy,
+            max_bytes: 16,
+            prefix: "couch:".to_string(),
+            original_field: "_couch_id".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_usable_id_is_unchanged() {
+        let mut document = doc! { "_id": "cat:tom" };
+
+        assert_eq!(
+            remap_id(&mut document, &settings(IdStrategy::Hash)),
+            IdOutcome::Unchanged
+        );
+        assert_eq!(document, doc! { "_id": "cat:tom" });
+    }
+
+    #[test]
+    fn test_hash() {
+        let long_id = "x".repeat(17);
+        let mut document = doc! { "_id": long_id.as_str() };
+
+        let outcome = remap_id(&mut document, &settings(IdStrategy::Hash));
+        assert_eq!(outcome, IdOutcome::Replaced(hash(&long_id)));
+        assert_eq!(document.get_str("_id").unwrap(), hash(&long_id));
+        assert_eq!(document.get_str("_couch_id").unwrap(), long_id);
+    }
+
+    #[test]
+    fn test_prefix() {
+        let mut document = doc! { "_id": "" };
+        assert_eq!(
+            remap_id(&mut document, &settings(IdStrategy::Prefix)),
+            IdOutcome::Replaced("couch:".to_string())
+        );
+
+        let long_id = "x".repeat(17);
+        let mut document = doc! { "_id": long_id.as_str() };
+        assert_eq!(
+            remap_id(&mut document, &settings(IdStrategy::Prefix)),
+            IdOutcome::Replaced(format!("couch:{}", hash(&long_id)))
+        );
+    }
+
+    #[test]
+    fn test_dead_letter() {
+        let mut document = doc! { "_id": "" };
+
+        assert!(matches!(
+            remap_id(&mut document, &settings(IdStrategy::DeadLetter)),
+            IdOutcome::DeadLetter(_)
+        ));
+        assert_eq!(document, doc! { "_id": "" });
+    }
+}
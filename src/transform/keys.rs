@@ -0,0 +1,197 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::settings::config_parser::{KeySettings, KeyStrategy};
+use bson::{Bson, Document};
+
+/// KeyOutcome describes what sanitize_keys did with a document's field names.
+#[derive(Debug, PartialEq)]
+pub enum KeyOutcome {
+    // Every field name is usable as it is
+    Unchanged,
+    // These fields (as dotted paths of their original names) were renamed
+    Renamed(Vec<String>),
+    // The document should be dead lettered, for this reason
+    DeadLetter(String),
+}
+
+/// sanitize_keys renames fields MongoDB can't safely store or query, at any depth.
+///
+/// CouchDB accepts any field name, but in MongoDB a `.` in a name is read as a path separator, a
+/// leading `$` as an operator, and a NUL byte ends the name. With the Replace strategy each of
+/// those characters is replaced with `replacement`; with DeadLetter, or when a new name would
+/// clash with a field that's already there, the document is set aside instead.
+///
+/// # Arguments
+/// * `document` - The document, modified in place
+/// * `settings` - A KeySettings struct
+///
+/// # Returns
+/// * A KeyOutcome
+pub fn sanitize_keys(document: &mut Document, settings: &KeySettings) -> KeyOutcome {
+    let mut renamed = vec![];
+
+    match sanitize_document(document, settings, "", &mut renamed) {
+        Ok(()) if renamed.is_empty() => KeyOutcome::Unchanged,
+        Ok(()) => KeyOutcome::Renamed(renamed),
+        Err(reason) => KeyOutcome::DeadLetter(reason),
+    }
+}
+
+fn sanitize_document(
+    document: &mut Document,
+    settings: &KeySettings,
+    path: &str,
+    renamed: &mut Vec<String>,
+) -> Result<(), String> {
+    let needs_renaming = document
+        .keys()
+        .any(|k| sanitized_key(k, settings).is_some());
+    let original_keys: Vec<String> = match needs_renaming {
+        true => document.keys().cloned().collect(),
+        false => vec![],
+    };
+
+    for (key, mut value) in std::mem::take(document) {
+        let field_path = match path {
+            "" => key.clone(),
+            _ => format!("{}.{}", path, key),
+        };
+        sanitize_value(&mut value, settings, &field_path, renamed)?;
+
+        let new_key = match (needs_renaming, sanitized_key(&key, settings)) {
+            (true, Some(new_key)) => {
+                if settings.strategy == KeyStrategy::DeadLetter {
+                    return Err(format!("field {} has a name MongoDB can't use", field_path));
+                }
+                if original_keys.contains(&new_key) || document.contains_key(&new_key) {
+                    return Err(format!(
+                        "field {} would be renamed to {}, which already exists",
+                        field_path, new_key
+                    ));
+                }
+                renamed.push(field_path);
+                new_key
+            }
+            _ => key,
+        };
+
+        document.insert(new_key, value);
+    }
+
+    Ok(())
+}
+
+fn sanitize_value(
+    value: &mut Bson,
+    settings: &KeySettings,
+    path: &str,
+    renamed: &mut Vec<String>,
+) -> Result<(), String> {
+    match value {
+        Bson::Document(document) => sanitize_document(document, settings, path, renamed),
+        Bson::Array(values) => {
+            for (i, value) in values.iter_mut().enumerate() {
+                sanitize_value(value, settings, &format!("{}.{}", path, i), renamed)?;
+            }
+            Ok(())
+        }
+        _ => Ok(()),
+    }
+}
+
+/// sanitized_key returns the usable name for a field, or None if its name is fine as it is.
+fn sanitized_key(key: &str, settings: &KeySettings) -> Option<String> {
+    if !key.contains(['.', '\0']) && !key.starts_with('$') {
+        return None;
+    }
+
+    let mut sanitized = key.replace(['.', '\0'], &settings.replacement);
+    if let Some(rest) = sanitized.strip_prefix('$') {
+        sanitized = format!("{}{}", settings.replacement, rest);
+    }
+
+    Some(sanitized)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bson::doc;
+
+    fn settings(strategy: KeyStrategy) -> KeySettings {
+        KeySettings {
+            strategy,
+            replacement: "_".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_usable_keys_unchanged() {
+        let mut document = doc! { "_id": "cat:tom", "name": "Tom", "a": { "b": [1, { "c": 2 }] } };
+        let original = document.clone();
+
+        assert_eq!(
+            sanitize_keys(&mut document, &settings(KeyStrategy::Replace)),
+            KeyOutcome::Unchanged
+        );
+        assert_eq!(document, original);
+    }
+
+    #[test]
+    fn test_replace_nested() {
+        let mut document = doc! {
+            "_id": "cat:tom",
+            "v1.2": true,
+            "meta": { "$type": "cat", "toys": [{ "ball.red": 1 }] },
+        };
+
+        assert_eq!(
+            sanitize_keys(&mut document, &settings(KeyStrategy::Replace)),
+            KeyOutcome::Renamed(vec![
+                "v1.2".to_string(),
+                "meta.$type".to_string(),
+                "meta.toys.0.ball.red".to_string(),
+            ])
+        );
+        assert_eq!(
+            document,
+            doc! {
+                "_id": "cat:tom",
+                "v1_2": true,
+                "meta": { "_type": "cat", "toys": [{ "ball_red": 1 }] },
+            }
+        );
+    }
+
+    #[test]
+    fn test_clash_dead_letters() {
+        let mut document = doc! { "a.b": 1, "a_b": 2 };
+
+        assert!(matches!(
+            sanitize_keys(&mut document, &settings(KeyStrategy::Replace)),
+            KeyOutcome::DeadLetter(_)
+        ));
+    }
+
+    #[test]
+    fn test_dead_letter_strategy() {
+        let mut document = doc! { "ok": { "$bad": 1 } };
+
+        assert_eq!(
+            sanitize_keys(&mut document, &settings(KeyStrategy::DeadLetter)),
+            KeyOutcome::DeadLetter("field ok.$bad has a name MongoDB can't use".to_string())
+        );
+    }
+}
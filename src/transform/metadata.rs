@@ -0,0 +1,87 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::settings::config_parser::SyncMetadataSettings;
+use bson::{DateTime, Document};
+
+/// add_sync_metadata records when and from which change a document was written, in the fields
+/// configured. A field whose name is empty is left out.
+///
+/// # Arguments
+/// * `document` - The document about to be written
+/// * `seq` - The sequence of the change, if it came from the changes feed
+/// * `settings` - A SyncMetadataSettings struct
+/// * `now` - The time of the write
+pub fn add_sync_metadata(
+    document: &mut Document,
+    seq: Option<&str>,
+    settings: &SyncMetadataSettings,
+    now: DateTime,
+) {
+    if !settings.synced_at_field.is_empty() {
+        document.insert(settings.synced_at_field.clone(), now);
+    }
+
+    if let (false, Some(seq)) = (settings.seq_field.is_empty(), seq) {
+        document.insert(settings.seq_field.clone(), seq);
+    }
+
+    if !settings.rev_field.is_empty() {
+        if let Ok(rev) = document.get_str("_rev") {
+            let rev = rev.to_string();
+            document.insert(settings.rev_field.clone(), rev);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bson::doc;
+
+    #[test]
+    fn test_add_sync_metadata() {
+        let now = DateTime::from_millis(1_700_000_000_000);
+        let mut d = doc! { "_id": "tom", "_rev": "2-b" };
+        add_sync_metadata(
+            &mut d,
+            Some("12-abc"),
+            &SyncMetadataSettings::default(),
+            now,
+        );
+
+        assert_eq!(
+            d,
+            doc! {
+                "_id": "tom",
+                "_rev": "2-b",
+                "_synced_at": now,
+                "_couch_seq": "12-abc",
+                "_couch_rev": "2-b",
+            }
+        );
+    }
+
+    #[test]
+    fn test_fields_can_be_left_out() {
+        let settings = SyncMetadataSettings {
+            synced_at_field: "".to_string(),
+            ..Default::default()
+        };
+        let mut d = doc! { "_id": "tom" };
+        add_sync_metadata(&mut d, None, &settings, DateTime::now());
+
+        assert_eq!(d, doc! { "_id": "tom" });
+    }
+}
@@ -0,0 +1,126 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::document::{get_path, insert_path, remove_path};
+use crate::settings::config_parser::ProjectionSettings;
+use bson::Document;
+
+/// Fields kept whatever the projection, as replication relies on them.
+const ALWAYS_KEPT: [&str; 3] = ["_id", "_rev", "_deleted"];
+
+/// project keeps or drops fields of a document before it's written.
+///
+/// With `include` set, only those fields (and `_id`, `_rev` and `_deleted`) are kept. Fields in
+/// `exclude` are then removed. Both take dotted paths; missing fields are ignored.
+///
+/// # Arguments
+/// * `document` - The document to project, modified in place
+/// * `settings` - A ProjectionSettings struct
+pub fn project(document: &mut Document, settings: &ProjectionSettings) {
+    if !settings.include.is_empty() {
+        let mut projected = Document::new();
+        let fields = ALWAYS_KEPT
+            .iter()
+            .copied()
+            .chain(settings.include.iter().map(String::as_str));
+
+        for field in fields {
+            // Keep a top-level key containing dots as it is, rather than nesting it
+            match document.get(field) {
+                Some(value) => {
+                    projected.insert(field, value.clone());
+                }
+                None => {
+                    if let Some(value) = get_path(document, field) {
+                        insert_path(&mut projected, field, value.clone());
+                    }
+                }
+            }
+        }
+        *document = projected;
+    }
+
+    for field in &settings.exclude {
+        if !ALWAYS_KEPT.contains(&field.as_str()) {
+            remove_path(document, field);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bson::doc;
+
+    fn settings(include: &[&str], exclude: &[&str]) -> ProjectionSettings {
+        ProjectionSettings {
+            include: include.iter().map(|f| f.to_string()).collect(),
+            exclude: exclude.iter().map(|f| f.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn test_exclude() {
+        let mut d = doc! {
+            "_id": "tom",
+            "name": "Tom",
+            "render_cache": "<html>",
+            "meta": { "thumbnail": "...", "type": "cat" },
+        };
+        project(
+            &mut d,
+            &settings(&[], &["render_cache", "meta.thumbnail", "_id"]),
+        );
+
+        assert_eq!(
+            d,
+            doc! { "_id": "tom", "name": "Tom", "meta": { "type": "cat" } }
+        );
+    }
+
+    #[test]
+    fn test_include() {
+        let mut d = doc! {
+            "_id": "tom",
+            "_rev": "1-a",
+            "name": "Tom",
+            "render_cache": "<html>",
+            "meta": { "thumbnail": "...", "type": "cat" },
+            "a.b": 1,
+        };
+        project(
+            &mut d,
+            &settings(&["name", "meta.type", "a.b", "missing"], &[]),
+        );
+
+        assert_eq!(
+            d,
+            doc! {
+                "_id": "tom",
+                "_rev": "1-a",
+                "name": "Tom",
+                "meta": { "type": "cat" },
+                "a.b": 1,
+            }
+        );
+    }
+
+    #[test]
+    fn test_include_then_exclude() {
+        let mut d = doc! { "_id": "tom", "meta": { "thumbnail": "...", "type": "cat" } };
+        project(&mut d, &settings(&["meta"], &["meta.thumbnail"]));
+
+        assert_eq!(d, doc! { "_id": "tom", "meta": { "type": "cat" } });
+    }
+}
@@ -0,0 +1,97 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::document::{insert_path, remove_path};
+use bson::Document;
+use std::collections::HashMap;
+
+/// rename_fields moves fields to new names, eg. `created_at` to `createdAt` or `meta.owner_id` to
+/// `ownerId`.
+///
+/// Every field is taken out before any is put back, so the result doesn't depend on the order of
+/// the rules, and swapping two fields works. A renamed field replaces any field already at its new
+/// path. Missing fields are ignored, as are fields starting with `_`, which replication relies on.
+///
+/// # Arguments
+/// * `document` - The document to change
+/// * `renames` - New dotted paths, by current dotted path
+///
+/// # Returns
+/// * The paths that were renamed
+pub fn rename_fields(document: &mut Document, renames: &HashMap<String, String>) -> Vec<String> {
+    let mut moved: Vec<(&str, _)> = renames
+        .iter()
+        .filter(|(from, to)| from != to && !from.starts_with('_'))
+        .filter_map(|(from, to)| Some((to.as_str(), (from.clone(), remove_path(document, from)?))))
+        .collect();
+    moved.sort_by(|a, b| a.0.cmp(b.0));
+
+    moved
+        .into_iter()
+        .map(|(to, (from, value))| {
+            insert_path(document, to, value);
+            from
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bson::doc;
+
+    fn renames(rules: &[(&str, &str)]) -> HashMap<String, String> {
+        rules
+            .iter()
+            .map(|(from, to)| (from.to_string(), to.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_rename_nested() {
+        let mut d = doc! {
+            "_id": "tom",
+            "created_at": 1,
+            "meta": { "owner_id": "jerry", "type": "cat" },
+        };
+        let renamed = rename_fields(
+            &mut d,
+            &renames(&[
+                ("created_at", "createdAt"),
+                ("meta.owner_id", "meta.ownerId"),
+                ("missing", "found"),
+                ("_id", "id"),
+            ]),
+        );
+
+        assert_eq!(renamed.len(), 2);
+        assert_eq!(
+            d,
+            doc! {
+                "_id": "tom",
+                "meta": { "type": "cat", "ownerId": "jerry" },
+                "createdAt": 1,
+            }
+        );
+    }
+
+    #[test]
+    fn test_swap() {
+        let mut d = doc! { "a": 1, "b": 2 };
+        rename_fields(&mut d, &renames(&[("a", "b"), ("b", "a")]));
+
+        assert_eq!(d.get_i32("a").unwrap(), 2);
+        assert_eq!(d.get_i32("b").unwrap(), 1);
+    }
+}
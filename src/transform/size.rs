@@ -0,0 +1,300 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::document::get_path_mut;
+use crate::settings::config_parser::{OversizePolicy, SizeLimitSettings};
+use bson::{doc, Bson, Document};
+use lazy_static::lazy_static;
+use prometheus::{register_int_counter_vec, IntCounterVec};
+use std::error::Error;
+
+lazy_static! {
+    /// Documents over the size limit, by what was done with them.
+    pub static ref OVERSIZED_DOCUMENTS: IntCounterVec = register_int_counter_vec!(
+        "couch2mongo_oversized_documents_total",
+        "Documents over the size limit, by whether they were truncated, skipped or spilled to GridFS",
+        &["outcome"]
+    )
+    .unwrap();
+}
+
+/// SizeReport describes what enforce_size did to a document.
+#[derive(Debug, Default, PartialEq)]
+pub struct SizeReport {
+    // Size of the document before any truncation
+    pub original_size: usize,
+
+    // Size of the document after truncation
+    pub size: usize,
+
+    // Fields that were truncated
+    pub truncated_fields: Vec<String>,
+}
+
+impl SizeReport {
+    /// within_limit returns true if the document now fits in `max_bytes`.
+    pub fn within_limit(&self, max_bytes: usize) -> bool {
+        self.size <= max_bytes
+    }
+}
+
+/// enforce_size truncates the configured fields of an oversized document until it fits. Only the
+/// Truncate policy truncates; with the others the report just gives the document's size.
+///
+/// Fields are processed in the configured order and only as far as needed. Arrays keep as many
+/// leading elements as fit, strings keep as many leading characters as fit, and any other value is
+/// replaced with null. A marker sub-document (eg. `_truncated`) records each truncated field's
+/// original length so consumers can tell the data is incomplete.
+///
+/// # Arguments
+/// * `document` - The document to check, modified in place
+/// * `settings` - A SizeLimitSettings struct
+///
+/// # Returns
+/// * A SizeReport describing the result
+pub fn enforce_size(
+    document: &mut Document,
+    settings: &SizeLimitSettings,
+) -> Result<SizeReport, Box<dyn Error>> {
+    let original_size = bson::to_vec(document)?.len();
+    let mut report = SizeReport {
+        original_size,
+        size: original_size,
+        truncated_fields: vec![],
+    };
+
+    if original_size <= settings.max_bytes || settings.policy != OversizePolicy::Truncate {
+        return Ok(report);
+    }
+
+    let mut marker = Document::new();
+
+    for field in &settings.truncate_fields {
+        if report.size <= settings.max_bytes {
+            break;
+        }
+
+        let original_length = match get_path_mut(document, field) {
+            Some(Bson::Array(a)) => a.len(),
+            Some(Bson::String(s)) => s.chars().count(),
+            Some(Bson::Null) | None => continue,
+            Some(other) => {
+                let original_type = format!("{:?}", other.element_type());
+                *other = Bson::Null;
+                marker.insert(
+                    field.replace('.', "_"),
+                    doc! { "original_type": original_type },
+                );
+                document.insert(settings.marker_field.clone(), marker.clone());
+                report.size = bson::to_vec(document)?.len();
+                report.truncated_fields.push(field.clone());
+                continue;
+            }
+        };
+
+        // Record the field in the marker up front, so its size is accounted for while shrinking
+        let key = field.replace('.', "_");
+        marker.insert(
+            key.clone(),
+            doc! { "original_length": original_length as i64, "kept": original_length as i64 },
+        );
+        document.insert(settings.marker_field.clone(), marker.clone());
+
+        let kept = shrink_to_fit(document, field, original_length, settings.max_bytes)?;
+        marker.insert(
+            key,
+            doc! { "original_length": original_length as i64, "kept": kept as i64 },
+        );
+        document.insert(settings.marker_field.clone(), marker.clone());
+
+        report.size = bson::to_vec(document)?.len();
+        report.truncated_fields.push(field.clone());
+    }
+
+    Ok(report)
+}
+
+/// stub_document returns what the GridFS policy writes in place of an oversized document: its ID
+/// and revision, and where the whole document was stored.
+///
+/// # Arguments
+/// * `document` - The oversized document
+/// * `file_id` - The ID of the GridFS file holding it
+/// * `size` - The document's BSON size
+/// * `settings` - A SizeLimitSettings struct
+///
+/// # Returns
+/// * The stub document
+pub fn stub_document(
+    document: &Document,
+    file_id: &str,
+    size: usize,
+    settings: &SizeLimitSettings,
+) -> Document {
+    let mut stub = Document::new();
+    for field in ["_id", "_rev"] {
+        if let Some(value) = document.get(field) {
+            stub.insert(field, value.clone());
+        }
+    }
+    stub.insert(
+        settings.stub_field.clone(),
+        doc! {
+            "bucket": &settings.gridfs_bucket,
+            "file_id": file_id,
+            "size": size as i64,
+        },
+    );
+
+    stub
+}
+
+/// shrink_to_fit binary searches for the longest prefix of an array or string field that keeps the
+/// document under `max_bytes`, leaving the field truncated to that length.
+fn shrink_to_fit(
+    document: &mut Document,
+    field: &str,
+    length: usize,
+    max_bytes: usize,
+) -> Result<usize, Box<dyn Error>> {
+    let original = get_path_mut(document, field).unwrap().clone();
+
+    let (mut low, mut high) = (0, length);
+    while low < high {
+        let mid = (low + high + 1) / 2;
+        truncate_value(document, field, &original, mid);
+
+        if bson::to_vec(document)?.len() <= max_bytes {
+            low = mid;
+        } else {
+            high = mid - 1;
+        }
+    }
+
+    truncate_value(document, field, &original, low);
+    Ok(low)
+}
+
+/// truncate_value sets `field` to the first `length` elements/characters of `original`.
+fn truncate_value(document: &mut Document, field: &str, original: &Bson, length: usize) {
+    let truncated = match original {
+        Bson::Array(a) => Bson::Array(a[..length].to_vec()),
+        Bson::String(s) => Bson::String(s.chars().take(length).collect()),
+        other => other.clone(),
+    };
+
+    *get_path_mut(document, field).unwrap() = truncated;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(max_bytes: usize, fields: &[&str]) -> SizeLimitSettings {
+        SizeLimitSettings {
+            max_bytes,
+            truncate_fields: fields.iter().map(|f| f.to_string()).collect(),
+            marker_field: "_truncated".to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_small_document_is_untouched() {
+        let mut d = doc! { "_id": "a", "logs": ["x", "y"] };
+        let report = enforce_size(&mut d, &settings(1024, &["logs"])).unwrap();
+
+        assert!(report.truncated_fields.is_empty());
+        assert_eq!(d, doc! { "_id": "a", "logs": ["x", "y"] });
+    }
+
+    #[test]
+    fn test_array_is_truncated_to_fit() {
+        let logs: Vec<String> = (0..1000).map(|i| format!("log line {}", i)).collect();
+        let mut d = doc! { "_id": "a", "meta": { "logs": logs }, "keep": "me" };
+        let s = settings(2048, &["missing", "meta.logs"]);
+
+        let report = enforce_size(&mut d, &s).unwrap();
+
+        assert!(report.within_limit(2048));
+        assert_eq!(report.truncated_fields, vec!["meta.logs".to_string()]);
+        assert_eq!(d.get_str("keep").unwrap(), "me");
+
+        let kept = d
+            .get_document("meta")
+            .unwrap()
+            .get_array("logs")
+            .unwrap()
+            .len();
+        assert!(kept > 0 && kept < 1000);
+
+        let marker = d
+            .get_document("_truncated")
+            .unwrap()
+            .get_document("meta_logs")
+            .unwrap();
+        assert_eq!(marker.get_i64("original_length").unwrap(), 1000);
+        assert_eq!(marker.get_i64("kept").unwrap(), kept as i64);
+    }
+
+    #[test]
+    fn test_string_is_truncated() {
+        let mut d = doc! { "_id": "a", "body": "é".repeat(2000) };
+        let report = enforce_size(&mut d, &settings(1024, &["body"])).unwrap();
+
+        assert!(report.within_limit(1024));
+        assert!(d.get_str("body").unwrap().chars().all(|c| c == 'é'));
+    }
+
+    #[test]
+    fn test_still_too_large() {
+        let mut d = doc! { "_id": "a", "body": "x".repeat(2000) };
+        let report = enforce_size(&mut d, &settings(1024, &["other"])).unwrap();
+
+        assert!(!report.within_limit(1024));
+        assert!(d.get("_truncated").is_none());
+    }
+
+    #[test]
+    fn test_other_policies_do_not_truncate() {
+        let mut d = doc! { "_id": "a", "body": "x".repeat(2000) };
+        let mut s = settings(1024, &["body"]);
+        s.policy = OversizePolicy::Skip;
+
+        let report = enforce_size(&mut d, &s).unwrap();
+
+        assert!(!report.within_limit(1024));
+        assert!(report.truncated_fields.is_empty());
+        assert_eq!(d.get_str("body").unwrap().len(), 2000);
+    }
+
+    #[test]
+    fn test_stub_document() {
+        let d = doc! { "_id": "a", "_rev": "2-b", "body": "x".repeat(2000) };
+        let stub = stub_document(&d, "cats/a/2-b", 2030, &SizeLimitSettings::default());
+
+        assert_eq!(
+            stub,
+            doc! {
+                "_id": "a",
+                "_rev": "2-b",
+                "_oversized": {
+                    "bucket": "oversized_documents",
+                    "file_id": "cats/a/2-b",
+                    "size": 2030_i64,
+                },
+            }
+        );
+    }
+}
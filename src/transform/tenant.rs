@@ -0,0 +1,115 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::settings::config_parser::TenantSettings;
+use bson::Document;
+use regex::Regex;
+use std::error::Error;
+
+/// Tenant is the tenant a source database belongs to, when many CouchDB databases are replicated
+/// into one MongoDB database.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tenant {
+    pub id: String,
+    pub collection_prefix: String,
+    field: String,
+}
+
+impl Tenant {
+    /// new works out the tenant of a source database.
+    ///
+    /// # Arguments
+    /// * `settings` - A TenantSettings struct
+    /// * `database` - The source database name
+    ///
+    /// # Returns
+    /// * The tenant, or an error if `database_pattern` is invalid or doesn't match the database
+    pub fn new(settings: &TenantSettings, database: &str) -> Result<Tenant, Box<dyn Error>> {
+        let id = match &settings.database_pattern {
+            Some(pattern) => {
+                let captures = Regex::new(pattern)?.captures(database).ok_or_else(|| {
+                    format!(
+                        "tenant.database_pattern {} doesn't match database {}",
+                        pattern, database
+                    )
+                })?;
+                // The first capture group, or the whole match without one
+                captures
+                    .get(1)
+                    .or_else(|| captures.get(0))
+                    .map(|m| m.as_str().to_string())
+                    .unwrap_or_default()
+            }
+            None => database.to_string(),
+        };
+
+        let collection_prefix = settings
+            .collection_prefix
+            .replace("{database}", database)
+            .replace("{tenant}", &id);
+
+        Ok(Tenant {
+            id,
+            collection_prefix,
+            field: settings.field.clone(),
+        })
+    }
+
+    /// add_field sets the tenant field in a document, unless the field name is empty.
+    pub fn add_field(&self, document: &mut Document) {
+        if !self.field.is_empty() {
+            document.insert(self.field.clone(), self.id.as_str());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bson::doc;
+
+    #[test]
+    fn test_tenant_from_database_name() {
+        let settings = TenantSettings {
+            collection_prefix: "{tenant}_".to_string(),
+            field: "tenant_id".to_string(),
+            database_pattern: Some("^customer_(.+)$".to_string()),
+        };
+        let tenant = Tenant::new(&settings, "customer_acme").unwrap();
+        assert_eq!(tenant.id, "acme");
+        assert_eq!(tenant.collection_prefix, "acme_");
+
+        let mut d = doc! { "_id": "tom" };
+        tenant.add_field(&mut d);
+        assert_eq!(d, doc! { "_id": "tom", "tenant_id": "acme" });
+
+        assert!(Tenant::new(&settings, "animals").is_err());
+    }
+
+    #[test]
+    fn test_defaults_to_whole_database_name() {
+        let settings = TenantSettings {
+            collection_prefix: "{database}.".to_string(),
+            field: "".to_string(),
+            database_pattern: None,
+        };
+        let tenant = Tenant::new(&settings, "animals").unwrap();
+        assert_eq!(tenant.id, "animals");
+        assert_eq!(tenant.collection_prefix, "animals.");
+
+        let mut d = doc! { "_id": "tom" };
+        tenant.add_field(&mut d);
+        assert_eq!(d, doc! { "_id": "tom" });
+    }
+}
@@ -0,0 +1,153 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use bson::{Bson, Document};
+use jsonschema::JSONSchema;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+
+/// Validation errors listed in a dead letter's reason, at most.
+const LISTED_ERRORS: usize = 10;
+
+/// SchemaValidator checks documents against the JSON Schema of the collection they're routed to.
+///
+/// Documents are validated as relaxed extended JSON, after projections, renames and coercions but
+/// before attachments are copied, so dates appear as `{"$date": ...}` and decimals as
+/// `{"$numberDecimal": ...}`. Collections without a schema accept anything.
+pub struct SchemaValidator {
+    schemas: HashMap<String, JSONSchema>,
+}
+
+impl SchemaValidator {
+    /// new loads and compiles the schemas.
+    ///
+    /// # Arguments
+    /// * `schemas` - Paths to JSON Schema files, by collection name
+    ///
+    /// # Returns
+    /// * A SchemaValidator struct, or an error if a schema can't be read or isn't valid
+    pub fn new(schemas: &HashMap<String, String>) -> Result<SchemaValidator, Box<dyn Error>> {
+        let mut compiled = HashMap::new();
+        for (collection, path) in schemas {
+            let schema: serde_json::Value = serde_json::from_slice(
+                &fs::read(path).map_err(|e| format!("unable to read schema {}: {}", path, e))?,
+            )?;
+            compiled.insert(collection.clone(), compile(&schema, path)?);
+        }
+
+        Ok(SchemaValidator { schemas: compiled })
+    }
+
+    /// validate checks a document against its collection's schema.
+    ///
+    /// # Arguments
+    /// * `collection` - The collection the document is routed to
+    /// * `document` - The document as it would be written
+    ///
+    /// # Returns
+    /// * Ok, or a description of what's wrong with the document
+    pub fn validate(&self, collection: &str, document: &Document) -> Result<(), String> {
+        let schema = match self.schemas.get(collection) {
+            Some(schema) => schema,
+            None => return Ok(()),
+        };
+
+        let instance = Bson::Document(document.clone()).into_relaxed_extjson();
+        let result = schema.validate(&instance);
+        let errors = match result {
+            Ok(()) => return Ok(()),
+            Err(errors) => errors,
+        };
+
+        let mut messages: Vec<String> = errors
+            .map(|e| {
+                let path = e.instance_path.to_string();
+                let path = if path.is_empty() {
+                    "/".to_string()
+                } else {
+                    path
+                };
+                format!("{}: {}", path, e)
+            })
+            .collect();
+        let count = messages.len();
+        messages.truncate(LISTED_ERRORS);
+        if count > LISTED_ERRORS {
+            messages.push(format!("and {} more", count - LISTED_ERRORS));
+        }
+
+        Err(format!("schema validation failed: {}", messages.join("; ")))
+    }
+}
+
+/// compile compiles a schema, naming the file it came from in any error.
+fn compile(schema: &serde_json::Value, path: &str) -> Result<JSONSchema, String> {
+    JSONSchema::compile(schema).map_err(|e| format!("schema {} is invalid: {}", path, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bson::doc;
+    use serde_json::json;
+
+    fn validator() -> SchemaValidator {
+        let schema = json!({
+            "type": "object",
+            "required": ["name"],
+            "properties": {
+                "name": { "type": "string" },
+                "age": { "type": "integer", "minimum": 0 },
+            },
+        });
+
+        SchemaValidator {
+            schemas: HashMap::from([("cats".to_string(), compile(&schema, "cats.json").unwrap())]),
+        }
+    }
+
+    #[test]
+    fn test_valid_document() {
+        let validator = validator();
+
+        assert!(validator
+            .validate("cats", &doc! { "_id": "tom", "name": "Tom", "age": 3 })
+            .is_ok());
+        assert!(validator.validate("dogs", &doc! { "_id": "rex" }).is_ok());
+    }
+
+    #[test]
+    fn test_invalid_document() {
+        let reason = validator()
+            .validate("cats", &doc! { "_id": "tom", "age": -1 })
+            .unwrap_err();
+
+        assert!(
+            reason.contains("/age: -1 is less than the minimum of 0"),
+            "{}",
+            reason
+        );
+        assert!(
+            reason.contains("/: \"name\" is a required property"),
+            "{}",
+            reason
+        );
+    }
+
+    #[test]
+    fn test_invalid_schema() {
+        assert!(compile(&json!({ "type": "nonsense" }), "bad.json").is_err());
+    }
+}
@@ -0,0 +1,368 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::settings::config_parser::{VaultCredentialSettings, VaultEngine, VaultSettings};
+use lazy_static::lazy_static;
+use prometheus::{register_int_counter_vec, IntCounterVec};
+use reqwest::Method;
+use serde_json::{json, Value};
+use std::error::Error;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tracing::{info, warn};
+
+lazy_static! {
+    /// Times credentials from Vault changed, by what they're for.
+    pub static ref VAULT_ROTATIONS: IntCounterVec = register_int_counter_vec!(
+        "couch2mongo_vault_rotations_total",
+        "Times credentials from Vault changed",
+        &["name"]
+    )
+    .unwrap();
+}
+
+/// Credentials is a username and password.
+#[derive(Clone, PartialEq)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+impl std::fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Never print the password
+        f.debug_struct("Credentials")
+            .field("username", &self.username)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Lease is a Vault lease on dynamic credentials.
+#[derive(Debug, Clone, PartialEq)]
+struct Lease {
+    id: String,
+    duration: Duration,
+    renewable: bool,
+}
+
+/// VaultCredentials keeps a set of credentials from Vault current.
+///
+/// Credentials from the KV v2 engine are re-read periodically. Credentials from the database
+/// engine come with a lease, which is renewed at two thirds of its duration; once Vault stops
+/// extending it (it's nearing its max TTL) or renewal fails, new credentials are fetched before the
+/// old ones expire. Each change bumps the generation, so users holding connections know to
+/// reconnect.
+pub struct VaultCredentials {
+    name: String,
+    client: reqwest::Client,
+    address: String,
+    token: String,
+    namespace: Option<String>,
+    settings: VaultCredentialSettings,
+    current: RwLock<Credentials>,
+    generation: AtomicU64,
+}
+
+impl std::fmt::Debug for VaultCredentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Never print the token or credentials
+        f.debug_struct("VaultCredentials")
+            .field("name", &self.name)
+            .field("address", &self.address)
+            .field("settings", &self.settings)
+            .finish_non_exhaustive()
+    }
+}
+
+impl VaultCredentials {
+    /// new creates a new VaultCredentials, fetches the first credentials and starts keeping them
+    /// current in the background.
+    ///
+    /// # Arguments
+    /// * `name` - What the credentials are for, used in logs and metrics
+    /// * `vault_settings` - A VaultSettings struct
+    /// * `settings` - A VaultCredentialSettings struct
+    ///
+    /// # Returns
+    /// * A shared VaultCredentials
+    pub async fn new(
+        name: &str,
+        vault_settings: &VaultSettings,
+        settings: &VaultCredentialSettings,
+    ) -> Result<Arc<VaultCredentials>, Box<dyn Error>> {
+        let token = match &vault_settings.token {
+            Some(token) => token.clone(),
+            None => std::env::var("VAULT_TOKEN")
+                .map_err(|_| "vault.token isn't set, and neither is VAULT_TOKEN")?,
+        };
+
+        let mut vault = VaultCredentials {
+            name: name.to_string(),
+            client: reqwest::Client::new(),
+            address: vault_settings.address.trim_end_matches('/').to_string(),
+            token,
+            namespace: vault_settings.namespace.clone(),
+            settings: settings.clone(),
+            current: RwLock::new(Credentials {
+                username: String::new(),
+                password: String::new(),
+            }),
+            generation: AtomicU64::new(0),
+        };
+
+        let (credentials, lease) = vault.fetch().await.map_err(|e| e as Box<dyn Error>)?;
+        info!(
+            name,
+            username = credentials.username.as_str(),
+            "fetched credentials from vault"
+        );
+        vault.current = RwLock::new(credentials);
+
+        let vault = Arc::new(vault);
+        let background = vault.clone();
+        tokio::spawn(async move {
+            background.keep_current(lease).await;
+        });
+
+        Ok(vault)
+    }
+
+    /// credentials returns the current credentials.
+    pub fn credentials(&self) -> Credentials {
+        self.current
+            .read()
+            .expect("unable to read credentials")
+            .clone()
+    }
+
+    /// generation returns a number that changes whenever the credentials do.
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::SeqCst)
+    }
+
+    async fn keep_current(&self, mut lease: Option<Lease>) {
+        let refresh_interval = Duration::from_secs(self.settings.refresh_interval_secs);
+        let ttl = lease.as_ref().map(|l| l.duration);
+
+        loop {
+            let wait = match &lease {
+                Some(l) => (l.duration * 2 / 3).max(Duration::from_secs(1)),
+                None => refresh_interval,
+            };
+            tokio::time::sleep(wait).await;
+
+            if let (Some(current), Some(ttl)) = (&lease, ttl) {
+                if current.renewable {
+                    match self.renew(current, ttl).await {
+                        Ok(renewed) if renewed.duration * 2 >= ttl => {
+                            lease = Some(renewed);
+                            continue;
+                        }
+                        Ok(_) => info!(name = self.name.as_str(), "vault lease nearing max ttl"),
+                        Err(e) => warn!(
+                            name = self.name.as_str(),
+                            error = e.to_string(),
+                            "unable to renew vault lease"
+                        ),
+                    }
+                }
+            }
+
+            match self.fetch().await {
+                Ok((credentials, new_lease)) => {
+                    lease = new_lease;
+                    self.update(credentials);
+                }
+                Err(e) => {
+                    warn!(
+                        name = self.name.as_str(),
+                        error = e.to_string(),
+                        "unable to fetch credentials from vault"
+                    );
+                    // Try again soon rather than waiting out a lease we may no longer hold
+                    lease = lease.map(|l| Lease {
+                        duration: Duration::from_secs(3),
+                        renewable: false,
+                        ..l
+                    });
+                }
+            }
+        }
+    }
+
+    fn update(&self, credentials: Credentials) {
+        let mut current = self.current.write().expect("unable to write credentials");
+        if *current == credentials {
+            return;
+        }
+
+        info!(
+            name = self.name.as_str(),
+            username = credentials.username.as_str(),
+            "credentials rotated"
+        );
+        *current = credentials;
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        VAULT_ROTATIONS
+            .with_label_values(&[self.name.as_str()])
+            .inc();
+    }
+
+    fn request(&self, method: Method, path: &str) -> reqwest::RequestBuilder {
+        let request = self
+            .client
+            .request(method, format!("{}/v1/{}", self.address, path))
+            .header("X-Vault-Token", &self.token);
+
+        match &self.namespace {
+            Some(namespace) => request.header("X-Vault-Namespace", namespace),
+            None => request,
+        }
+    }
+
+    async fn fetch(&self) -> Result<(Credentials, Option<Lease>), Box<dyn Error + Send + Sync>> {
+        let path = match self.settings.engine {
+            VaultEngine::KV => format!("{}/data/{}", self.settings.mount, self.settings.path),
+            VaultEngine::Database => {
+                format!("{}/creds/{}", self.settings.mount, self.settings.path)
+            }
+        };
+
+        let body: Value = self
+            .request(Method::GET, &path)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        parse_secret(&self.settings, &body).map_err(|e| e.into())
+    }
+
+    async fn renew(
+        &self,
+        lease: &Lease,
+        ttl: Duration,
+    ) -> Result<Lease, Box<dyn Error + Send + Sync>> {
+        let body: Value = self
+            .request(Method::PUT, "sys/leases/renew")
+            .json(&json!({ "lease_id": lease.id, "increment": ttl.as_secs() }))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        parse_lease(&body).ok_or_else(|| "renewal response has no lease".into())
+    }
+}
+
+/// parse_secret reads credentials, and their lease if they have one, from a Vault response.
+fn parse_secret(
+    settings: &VaultCredentialSettings,
+    body: &Value,
+) -> Result<(Credentials, Option<Lease>), String> {
+    let data = match settings.engine {
+        VaultEngine::KV => body.pointer("/data/data"),
+        VaultEngine::Database => body.get("data"),
+    }
+    .ok_or("vault response has no data")?;
+
+    let field = |key: &str| {
+        data.get(key)
+            .and_then(Value::as_str)
+            .map(str::to_string)
+            .ok_or(format!("vault secret has no {}", key))
+    };
+    let credentials = Credentials {
+        username: field(&settings.username_key)?,
+        password: field(&settings.password_key)?,
+    };
+
+    Ok((credentials, parse_lease(body)))
+}
+
+/// parse_lease reads the lease from a Vault response, if there's one.
+fn parse_lease(body: &Value) -> Option<Lease> {
+    let id = body.get("lease_id")?.as_str()?;
+    if id.is_empty() {
+        return None;
+    }
+
+    Some(Lease {
+        id: id.to_string(),
+        duration: Duration::from_secs(body.get("lease_duration")?.as_u64()?),
+        renewable: body
+            .get("renewable")
+            .and_then(Value::as_bool)
+            .unwrap_or(false),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(engine: VaultEngine) -> VaultCredentialSettings {
+        VaultCredentialSettings {
+            engine,
+            mount: "mount".to_string(),
+            path: "couch2mongo".to_string(),
+            username_key: "username".to_string(),
+            password_key: "password".to_string(),
+            refresh_interval_secs: 300,
+        }
+    }
+
+    #[test]
+    fn test_parse_kv_secret() {
+        let body = json!({
+            "lease_id": "",
+            "lease_duration": 0,
+            "data": {
+                "data": { "username": "couch", "password": "s3cret" },
+                "metadata": { "version": 3 },
+            },
+        });
+
+        let (credentials, lease) = parse_secret(&settings(VaultEngine::KV), &body).unwrap();
+        assert_eq!(credentials.username, "couch");
+        assert_eq!(credentials.password, "s3cret");
+        assert_eq!(lease, None);
+    }
+
+    #[test]
+    fn test_parse_database_secret() {
+        let body = json!({
+            "lease_id": "database/creds/couch2mongo/abc",
+            "lease_duration": 3600,
+            "renewable": true,
+            "data": { "username": "v-couch2mongo-xyz", "password": "generated" },
+        });
+
+        let (credentials, lease) = parse_secret(&settings(VaultEngine::Database), &body).unwrap();
+        assert_eq!(credentials.username, "v-couch2mongo-xyz");
+        assert_eq!(
+            lease,
+            Some(Lease {
+                id: "database/creds/couch2mongo/abc".to_string(),
+                duration: Duration::from_secs(3600),
+                renewable: true,
+            })
+        );
+
+        assert!(parse_secret(&settings(VaultEngine::KV), &body).is_err());
+    }
+}
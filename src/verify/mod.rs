@@ -0,0 +1,545 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+pub mod repair;
+
+use crate::batch::deletion;
+use crate::couchdb::CouchConnection;
+use crate::document::shard_key::ShardKeys;
+use crate::filters::IdFilter;
+use crate::routing::CollectionRouter;
+use crate::settings::config_parser::{ContentHashSettings, DeletionStrategy, Settings};
+use crate::transform;
+use crate::transform::id::IdOutcome;
+use crate::transform::keys::KeyOutcome;
+use crate::transform::tenant::Tenant;
+use crate::validation::SchemaValidator;
+use bson::{doc, Bson, Document};
+use futures_util::TryStreamExt;
+use mongodb::options::FindOptions;
+use rand::Rng;
+use reqwest::Method;
+use serde_derive::Serialize;
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::error::Error;
+use std::fmt;
+use tracing::{info, warn};
+
+/// Documents read from `_all_docs` at a time.
+const PAGE_SIZE: usize = 500;
+
+/// IDs listed per kind of discrepancy when a report is printed as a table.
+const LISTED_IDS: usize = 10;
+
+/// Report is the result of comparing CouchDB with MongoDB, by collection.
+#[derive(Debug, Default, Serialize)]
+pub struct Report {
+    pub collections: BTreeMap<String, CollectionReport>,
+    // Documents left out of the comparison, because they would be dead lettered or are oversized
+    pub skipped: u64,
+}
+
+/// CollectionReport lists the discrepancies found in one collection.
+#[derive(Debug, Default, Serialize)]
+pub struct CollectionReport {
+    // CouchDB documents routed to the collection
+    pub checked: u64,
+    // CouchDB documents that aren't in MongoDB, by CouchDB ID
+    pub missing: Vec<String>,
+    // CouchDB documents whose content differs in MongoDB, by CouchDB ID
+    pub mismatched: Vec<String>,
+    // MongoDB documents with no CouchDB document, by MongoDB _id
+    pub extra: Vec<Bson>,
+}
+
+impl CollectionReport {
+    pub fn is_consistent(&self) -> bool {
+        self.missing.is_empty() && self.mismatched.is_empty() && self.extra.is_empty()
+    }
+}
+
+impl Report {
+    /// is_consistent returns true if no discrepancies were found.
+    pub fn is_consistent(&self) -> bool {
+        self.collections
+            .values()
+            .all(CollectionReport::is_consistent)
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{:<30}{:>10}{:>10}{:>12}{:>10}",
+            "collection", "checked", "missing", "mismatched", "extra"
+        )?;
+        for (name, collection) in &self.collections {
+            writeln!(
+                f,
+                "{:<30}{:>10}{:>10}{:>12}{:>10}",
+                name,
+                collection.checked,
+                collection.missing.len(),
+                collection.mismatched.len(),
+                collection.extra.len()
+            )?;
+        }
+
+        for (name, collection) in &self.collections {
+            let extra: Vec<String> = collection.extra.iter().map(id_key).collect();
+            for (kind, ids) in [
+                ("missing", &collection.missing),
+                ("mismatched", &collection.mismatched),
+                ("extra", &extra),
+            ] {
+                if ids.is_empty() {
+                    continue;
+                }
+
+                let mut listed = ids.iter().take(LISTED_IDS).cloned().collect::<Vec<_>>();
+                if ids.len() > LISTED_IDS {
+                    listed.push(format!("... {} more", ids.len() - LISTED_IDS));
+                }
+                writeln!(f, "\n{} {}: {}", name, kind, listed.join(", "))?;
+            }
+        }
+
+        write!(
+            f,
+            "\nskipped {} documents that would be dead lettered or are oversized",
+            self.skipped
+        )
+    }
+}
+
+/// Verifier compares the documents in CouchDB with those in MongoDB, applying the same filters,
+/// transforms and routing as replication does.
+pub struct Verifier<'a> {
+    settings: &'a Settings,
+    connection: CouchConnection,
+    db: mongodb::Database,
+    id_filter: IdFilter,
+    router: CollectionRouter,
+    validator: Option<SchemaValidator>,
+    hash_settings: ContentHashSettings,
+    tenant: Option<Tenant>,
+    shard_keys: ShardKeys,
+}
+
+/// Expected is a CouchDB document as replication would have written it.
+struct Expected {
+    couch_id: String,
+    id: Bson,
+    hash: String,
+}
+
+impl<'a> Verifier<'a> {
+    /// new creates a new Verifier.
+    ///
+    /// # Arguments
+    /// * `settings` - A Settings struct
+    ///
+    /// # Returns
+    /// * A Verifier struct
+    pub async fn new(settings: &'a Settings) -> Result<Verifier<'a>, Box<dyn Error>> {
+        if settings.routes_databases() {
+            return Err(
+                "verify only compares mongodb_database, so can't be used with \
+                 mongodb_database_field or routes to another database or target"
+                    .into(),
+            );
+        }
+
+        // Compare content the way the content hash does, also leaving out the fields replication
+        // fills in itself
+        let mut hash_settings = settings
+            .content_hash
+            .clone()
+            .unwrap_or(ContentHashSettings {
+                field: "_content_hash".to_string(),
+                ignore_fields: vec!["_rev".to_string()],
+            });
+        if settings.attachments.is_some() {
+            hash_settings.ignore_fields.push("_attachments".to_string());
+        }
+        hash_settings
+            .ignore_fields
+            .extend(settings.sync_metadata_fields());
+
+        Ok(Verifier {
+            settings,
+            connection: settings.get_couchdb_connection().await?,
+            db: settings.get_mongodb_database().await?,
+            id_filter: settings.get_id_filter()?,
+            router: settings.get_collection_router()?,
+            validator: settings.get_schema_validator()?,
+            hash_settings,
+            tenant: settings.get_tenant()?,
+            shard_keys: settings.get_shard_keys(),
+        })
+    }
+
+    /// run walks `_all_docs` and MongoDB, returning the discrepancies found.
+    pub async fn run(&self) -> Result<Report, Box<dyn Error>> {
+        if self.settings.changes_selector.is_some() {
+            warn!(
+                "changes_selector isn't applied when verifying, so unselected documents show as \
+                 missing"
+            );
+        }
+        let doc_ids = self.doc_ids()?;
+
+        let mut report = Report::default();
+        let mut seen: HashMap<String, HashSet<String>> = HashMap::new();
+        let mut start_key: Option<String> = None;
+
+        loop {
+            let skip = if start_key.is_some() { 1 } else { 0 };
+            let rows = self.page(start_key.as_deref(), skip).await?;
+            let last_page = rows.len() < PAGE_SIZE;
+            start_key = rows
+                .last()
+                .and_then(|r| r.get("id"))
+                .and_then(Value::as_str)
+                .map(str::to_string);
+
+            self.check(rows, doc_ids.as_ref(), &mut report, &mut seen)
+                .await?;
+
+            info!(
+                last_id = start_key.as_deref().unwrap_or_default(),
+                collections = report.collections.len(),
+                "verified page"
+            );
+
+            if last_page {
+                break;
+            }
+        }
+
+        for (collection, seen) in &seen {
+            let extra = self.extra(collection, seen).await?;
+            report
+                .collections
+                .entry(collection.clone())
+                .or_default()
+                .extra = extra;
+        }
+
+        Ok(report)
+    }
+
+    /// sample verifies a page of `_all_docs` from a random point, as a cheap spot check. Extra
+    /// documents aren't looked for, as that needs every CouchDB ID.
+    pub async fn sample(&self) -> Result<Report, Box<dyn Error>> {
+        let doc_ids = self.doc_ids()?;
+
+        let params = HashMap::from([("limit".to_string(), "0".to_string())]);
+        let total = self
+            .all_docs(params)
+            .await?
+            .get("total_rows")
+            .and_then(Value::as_u64)
+            .unwrap_or_default() as usize;
+        let skip = rand::thread_rng().gen_range(0..total.saturating_sub(PAGE_SIZE).max(1));
+
+        let rows = self.page(None, skip).await?;
+        let mut report = Report::default();
+        self.check(rows, doc_ids.as_ref(), &mut report, &mut HashMap::new())
+            .await?;
+
+        Ok(report)
+    }
+
+    fn doc_ids(&self) -> Result<Option<HashSet<String>>, Box<dyn Error>> {
+        Ok(self
+            .settings
+            .get_changes_doc_ids()?
+            .map(|ids| ids.into_iter().collect()))
+    }
+
+    /// check compares a page of `_all_docs` rows with MongoDB, adding what it finds to the
+    /// report, and the MongoDB IDs looked up to `seen`.
+    async fn check(
+        &self,
+        rows: Vec<Value>,
+        doc_ids: Option<&HashSet<String>>,
+        report: &mut Report,
+        seen: &mut HashMap<String, HashSet<String>>,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut expected: BTreeMap<String, Vec<Expected>> = BTreeMap::new();
+        for row in rows {
+            let document = match row.get("doc") {
+                Some(document) if document.is_object() => document.clone(),
+                _ => continue,
+            };
+            let id = row.get("id").and_then(Value::as_str).unwrap_or_default();
+
+            if id.starts_with("_design")
+                || !self.id_filter.is_allowed(id)
+                || doc_ids.is_some_and(|ids| !ids.contains(id))
+            {
+                continue;
+            }
+
+            match self.expected(id, document)? {
+                Some((collection, document)) => {
+                    expected.entry(collection).or_default().push(document)
+                }
+                None => report.skipped += 1,
+            }
+        }
+
+        for (collection, documents) in expected {
+            let collection_report = report.collections.entry(collection.clone()).or_default();
+            let seen = seen.entry(collection.clone()).or_default();
+            self.compare(&collection, documents, collection_report, seen)
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    /// page reads a page of `_all_docs`, starting after skipping `skip` rows from `start_key`.
+    async fn page(
+        &self,
+        start_key: Option<&str>,
+        skip: usize,
+    ) -> Result<Vec<Value>, Box<dyn Error>> {
+        let mut params = HashMap::from([
+            ("include_docs".to_string(), "true".to_string()),
+            ("limit".to_string(), PAGE_SIZE.to_string()),
+        ]);
+        if let Some(start_key) = start_key {
+            params.insert("startkey".to_string(), Value::from(start_key).to_string());
+        }
+        if skip > 0 {
+            params.insert("skip".to_string(), skip.to_string());
+        }
+
+        Ok(self
+            .all_docs(params)
+            .await?
+            .get("rows")
+            .and_then(Value::as_array)
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    /// all_docs reads `_all_docs` with the given parameters.
+    async fn all_docs(&self, params: HashMap<String, String>) -> Result<Value, Box<dyn Error>> {
+        let request = self.connection.req(
+            Method::GET,
+            &format!("{}/_all_docs", self.settings.source_database),
+            Some(&params),
+        );
+
+        Ok(self
+            .connection
+            .send(request, "all_docs")
+            .await?
+            .error_for_status()?
+            .json()
+            .await?)
+    }
+
+    /// transform returns the collection a CouchDB document is routed to and the document as
+    /// replication writes it, before attachments are copied. Returns None if replication would
+    /// dead letter it or it's over the size limit.
+    pub fn transform(
+        &self,
+        document: &Value,
+    ) -> Result<Option<(String, Document)>, Box<dyn Error>> {
+        let mut document = bson::to_document(document)?;
+
+        if let Some(id_settings) = &self.settings.id_handling {
+            if let IdOutcome::DeadLetter(_) = transform::id::remap_id(&mut document, id_settings) {
+                return Ok(None);
+            }
+        }
+
+        if let Some(key_settings) = &self.settings.key_sanitization {
+            if let KeyOutcome::DeadLetter(_) =
+                transform::keys::sanitize_keys(&mut document, key_settings)
+            {
+                return Ok(None);
+            }
+        }
+
+        let collection = self.router.collection_name(&document);
+
+        if let Some(projection) = self.settings.projections.get(&collection) {
+            transform::projection::project(&mut document, projection);
+        }
+        transform::rename::rename_fields(&mut document, &self.settings.rename);
+        transform::coerce::coerce_fields(&mut document, &self.settings.coerce);
+
+        if let Some(validator) = &self.validator {
+            if validator.validate(&collection, &document).is_err() {
+                return Ok(None);
+            }
+        }
+
+        // Oversized documents are skipped, or replaced with a stub whose file only replication
+        // stores, so neither can be compared
+        let size_limit = self.settings.size_limit.clone().unwrap_or_default();
+        let report = transform::size::enforce_size(&mut document, &size_limit)?;
+        if !report.within_limit(size_limit.max_bytes) {
+            return Ok(None);
+        }
+
+        Ok(Some((collection, document)))
+    }
+
+    /// expected returns the collection a CouchDB document is routed to and what to compare in
+    /// MongoDB, or None if transform leaves it out.
+    fn expected(
+        &self,
+        couch_id: &str,
+        document: Value,
+    ) -> Result<Option<(String, Expected)>, Box<dyn Error>> {
+        let (collection, document) = match self.transform(&document)? {
+            Some(transformed) => transformed,
+            None => return Ok(None),
+        };
+
+        Ok(Some((
+            collection,
+            Expected {
+                couch_id: couch_id.to_string(),
+                id: document.get("_id").cloned().unwrap_or(Bson::Null),
+                hash: transform::hash::content_hash(&document, &self.hash_settings)?,
+            },
+        )))
+    }
+
+    /// compare looks a page of expected documents up in MongoDB.
+    async fn compare(
+        &self,
+        collection: &str,
+        documents: Vec<Expected>,
+        report: &mut CollectionReport,
+        seen: &mut HashSet<String>,
+    ) -> Result<(), Box<dyn Error>> {
+        let ids: Vec<Bson> = documents.iter().map(|d| d.id.clone()).collect();
+        let mut cursor = self
+            .db
+            .collection::<Document>(collection)
+            .find(doc! { "_id": { "$in": ids } }, None)
+            .await?;
+
+        let mut hashes = HashMap::new();
+        while let Some(document) = cursor.try_next().await? {
+            let key = id_key(document.get("_id").unwrap_or(&Bson::Null));
+            hashes.insert(
+                key,
+                transform::hash::content_hash(&document, &self.hash_settings)?,
+            );
+        }
+
+        for expected in documents {
+            let key = id_key(&expected.id);
+            report.checked += 1;
+
+            match hashes.get(&key) {
+                None => report.missing.push(expected.couch_id),
+                Some(hash) if *hash != expected.hash => report.mismatched.push(expected.couch_id),
+                Some(_) => {}
+            }
+            seen.insert(key);
+        }
+
+        Ok(())
+    }
+
+    /// extra returns the IDs of documents in a MongoDB collection that weren't seen in CouchDB.
+    async fn extra(
+        &self,
+        collection: &str,
+        seen: &HashSet<String>,
+    ) -> Result<Vec<Bson>, Box<dyn Error>> {
+        // Documents kept on purpose after their deletion aren't extra
+        let deletion_settings = self.settings.deletion.clone().unwrap_or_default();
+        let filter = match deletion_settings.strategy {
+            DeletionStrategy::Ignore => return Ok(vec![]),
+            DeletionStrategy::Soft => deletion::soft_delete_filter(doc! {}, &deletion_settings),
+            _ => doc! {},
+        };
+
+        let options = FindOptions::builder().projection(doc! { "_id": 1 }).build();
+        let mut cursor = self
+            .db
+            .collection::<Document>(collection)
+            .find(filter, options)
+            .await?;
+
+        let mut extra = vec![];
+        while let Some(document) = cursor.try_next().await? {
+            let id = document.get("_id").cloned().unwrap_or(Bson::Null);
+
+            // Documents belonging to another shard, or filtered out, aren't ours to judge
+            if let Bson::String(s) = &id {
+                if !self.id_filter.is_allowed(s) {
+                    continue;
+                }
+            }
+
+            if !seen.contains(&id_key(&id)) {
+                extra.push(id);
+            }
+        }
+
+        Ok(extra)
+    }
+}
+
+/// id_key returns a MongoDB `_id` as a string that can be compared and hashed.
+pub fn id_key(id: &Bson) -> String {
+    match id {
+        Bson::String(s) => s.clone(),
+        other => other.clone().into_relaxed_extjson().to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bson::oid::ObjectId;
+
+    #[test]
+    fn test_id_key() {
+        assert_eq!(id_key(&Bson::String("cat:tom".to_string())), "cat:tom");
+
+        let oid = ObjectId::parse_str("65a1b2c3d4e5f60718293a4b").unwrap();
+        assert_eq!(
+            id_key(&Bson::ObjectId(oid)),
+            "{\"$oid\":\"65a1b2c3d4e5f60718293a4b\"}"
+        );
+    }
+
+    #[test]
+    fn test_report() {
+        let mut report = Report::default();
+        report
+            .collections
+            .insert("cats".to_string(), CollectionReport::default());
+        assert!(report.is_consistent());
+
+        report.collections.get_mut("cats").unwrap().missing = vec!["cat:tom".to_string()];
+        assert!(!report.is_consistent());
+        assert!(report.to_string().contains("cats missing: cat:tom"));
+    }
+}
@@ -0,0 +1,329 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::attachments::AttachmentStore;
+use crate::pipeline::Operation;
+use crate::transform;
+use crate::verify::{id_key, Report, Verifier};
+use crate::writer::mode::write_document;
+use bson::{doc, Bson, Document};
+use futures_util::TryStreamExt;
+use mongodb::options::FindOptions;
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use reqwest::{Method, StatusCode};
+use serde_derive::Serialize;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use tracing::{info, warn};
+
+/// RepairOptions controls what a repair pass is allowed to change.
+#[derive(Debug, Clone, Default)]
+pub struct RepairOptions {
+    // Delete MongoDB documents that have no CouchDB document
+    pub delete_extra: bool,
+    // Most documents to copy, and to delete, per collection
+    pub limit: Option<usize>,
+    // Log what would be changed instead of changing it
+    pub dry_run: bool,
+}
+
+/// Repairs is what a repair pass changed, by collection.
+#[derive(Debug, Default, Serialize)]
+pub struct Repairs {
+    pub collections: BTreeMap<String, CollectionRepairs>,
+}
+
+/// CollectionRepairs is what a repair pass changed in one collection.
+#[derive(Debug, Default, Serialize)]
+pub struct CollectionRepairs {
+    // Missing or mismatched documents copied from CouchDB
+    pub copied: u64,
+    // Documents that were gone from CouchDB by the time they were copied
+    pub vanished: u64,
+    // Extra documents deleted
+    pub deleted: u64,
+    // Discrepancies left alone because of the limit
+    pub remaining: u64,
+}
+
+/// DeadLetterRetries is what retrying the dead letter collection did.
+#[derive(Debug, Default, Serialize)]
+pub struct DeadLetterRetries {
+    // Documents copied and taken out of the dead letter collection
+    pub copied: u64,
+    // Documents deleted from CouchDB since, also taken out
+    pub vanished: u64,
+    // Documents that would still be dead lettered
+    pub rejected: u64,
+}
+
+impl fmt::Display for Repairs {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:<30}{:>10}{:>10}{:>10}{:>11}",
+            "collection", "copied", "vanished", "deleted", "remaining"
+        )?;
+        for (name, collection) in &self.collections {
+            write!(
+                f,
+                "\n{:<30}{:>10}{:>10}{:>10}{:>11}",
+                name,
+                collection.copied,
+                collection.vanished,
+                collection.deleted,
+                collection.remaining
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+impl<'a> Verifier<'a> {
+    /// repair fixes the discrepancies in a report: missing and mismatched documents are copied
+    /// from CouchDB again, and extra documents are deleted if `delete_extra` is set.
+    ///
+    /// # Arguments
+    /// * `report` - The report from `run`
+    /// * `options` - A RepairOptions struct
+    ///
+    /// # Returns
+    /// * What was changed, by collection
+    pub async fn repair(
+        &self,
+        report: &Report,
+        options: &RepairOptions,
+    ) -> Result<Repairs, Box<dyn Error>> {
+        let attachments = match options.dry_run {
+            true => None,
+            false => self.settings.get_attachment_store(&self.db).await?,
+        };
+        let limit = options.limit.unwrap_or(usize::MAX);
+        let mut repairs = Repairs::default();
+
+        for (name, collection_report) in &report.collections {
+            let repaired = repairs.collections.entry(name.clone()).or_default();
+            let collection = self.db.collection::<bson::Document>(name);
+
+            let to_copy: Vec<&String> = collection_report
+                .missing
+                .iter()
+                .chain(&collection_report.mismatched)
+                .collect();
+            repaired.remaining += to_copy.len().saturating_sub(limit) as u64;
+
+            for couch_id in to_copy.into_iter().take(limit) {
+                let couch_document = match self.fetch(couch_id).await? {
+                    Some(couch_document) => couch_document,
+                    None => {
+                        repaired.vanished += 1;
+                        continue;
+                    }
+                };
+
+                let (routed, document) = match self.transform(&couch_document)? {
+                    Some(transformed) => transformed,
+                    None => continue,
+                };
+                if routed != *name {
+                    // It's been edited since verification and belongs elsewhere now
+                    warn!(
+                        id = couch_id.as_str(),
+                        collection = name.as_str(),
+                        routed = routed.as_str(),
+                        "document now routes to another collection, skipping"
+                    );
+                    continue;
+                }
+
+                if options.dry_run {
+                    info!(
+                        id = couch_id.as_str(),
+                        collection = name.as_str(),
+                        "dry run, would copy document"
+                    );
+                    repaired.copied += 1;
+                    continue;
+                }
+
+                self.copy(name, document, attachments.as_ref()).await?;
+                info!(
+                    id = couch_id.as_str(),
+                    collection = name.as_str(),
+                    "copied document"
+                );
+                repaired.copied += 1;
+            }
+
+            if !options.delete_extra {
+                repaired.remaining += collection_report.extra.len() as u64;
+                continue;
+            }
+
+            let extra: Vec<Bson> = collection_report
+                .extra
+                .iter()
+                .take(limit)
+                .cloned()
+                .collect();
+            repaired.remaining += (collection_report.extra.len() - extra.len()) as u64;
+            if extra.is_empty() {
+                continue;
+            }
+
+            if options.dry_run {
+                for id in &extra {
+                    info!(
+                        id = id_key(id),
+                        collection = name.as_str(),
+                        "dry run, would delete extra document"
+                    );
+                }
+                repaired.deleted += extra.len() as u64;
+                continue;
+            }
+
+            let result = collection
+                .delete_many(doc! { "_id": { "$in": extra } }, None)
+                .await?;
+            info!(
+                collection = name.as_str(),
+                deleted = result.deleted_count,
+                "deleted extra documents"
+            );
+            repaired.deleted += result.deleted_count;
+        }
+
+        Ok(repairs)
+    }
+
+    /// copy writes a document from `transform` to MongoDB the way replication would, copying its
+    /// attachments first.
+    ///
+    /// # Arguments
+    /// * `collection` - The collection the document is routed to
+    /// * `document` - The transformed document
+    /// * `attachments` - The store to copy attachments to, if they're copied
+    pub async fn copy(
+        &self,
+        collection: &str,
+        mut document: Document,
+        attachments: Option<&AttachmentStore>,
+    ) -> Result<Operation, Box<dyn Error>> {
+        if let Some(attachments) = attachments {
+            attachments.replicate(&mut document).await?;
+        }
+        if let Some(hash_settings) = &self.settings.content_hash {
+            let hash = transform::hash::content_hash(&document, hash_settings)?;
+            document.insert(hash_settings.field.clone(), hash);
+        }
+        if let Some(metadata_settings) = &self.settings.sync_metadata {
+            transform::metadata::add_sync_metadata(
+                &mut document,
+                None,
+                metadata_settings,
+                bson::DateTime::now(),
+            );
+        }
+        if let Some(tenant) = &self.tenant {
+            tenant.add_field(&mut document);
+        }
+
+        let id = document.get("_id").cloned().unwrap_or(Bson::Null);
+        let operation = write_document(
+            &self.db.collection(collection),
+            self.shard_keys.filter(collection, &id, &document),
+            document,
+            &self.settings.write_mode,
+            None,
+        )
+        .await?;
+
+        Ok(operation)
+    }
+
+    /// retry_dead_letters copies the current revision of each dead lettered document again,
+    /// removing it from the dead letter collection once it's copied, or deleted from CouchDB.
+    /// Documents still rejected, eg. until their schema is relaxed, stay.
+    ///
+    /// # Arguments
+    /// * `attachments` - The store to copy attachments to, if they're copied
+    ///
+    /// # Returns
+    /// * How many dead letters were copied, had vanished and are still rejected
+    pub async fn retry_dead_letters(
+        &self,
+        attachments: Option<&AttachmentStore>,
+    ) -> Result<DeadLetterRetries, Box<dyn Error>> {
+        let dead_letters = self
+            .db
+            .collection::<Document>(&self.settings.dead_letter_collection);
+        let options = FindOptions::builder()
+            .projection(doc! { "_id": 1, "source_id": 1 })
+            .build();
+        let entries: Vec<Document> = dead_letters
+            .find(doc! {}, options)
+            .await?
+            .try_collect()
+            .await?;
+
+        let mut retries = DeadLetterRetries::default();
+        for entry in entries {
+            let source_id = match entry.get_str("source_id") {
+                Ok(source_id) => source_id,
+                Err(_) => continue,
+            };
+            match self.fetch(source_id).await? {
+                None => retries.vanished += 1,
+                Some(couch_document) => match self.transform(&couch_document)? {
+                    Some((collection, document)) => {
+                        self.copy(&collection, document, attachments).await?;
+                        retries.copied += 1;
+                    }
+                    None => {
+                        retries.rejected += 1;
+                        continue;
+                    }
+                },
+            }
+
+            dead_letters
+                .delete_one(doc! { "_id": entry.get("_id").cloned() }, None)
+                .await?;
+        }
+
+        Ok(retries)
+    }
+
+    /// fetch reads the current revision of a document from CouchDB, returning None if it has been
+    /// deleted.
+    async fn fetch(&self, id: &str) -> Result<Option<Value>, Box<dyn Error>> {
+        let path = format!(
+            "{}/{}",
+            self.settings.source_database,
+            utf8_percent_encode(id, NON_ALPHANUMERIC)
+        );
+        let request = self.connection.req(Method::GET, &path, None);
+        let response = self.connection.send(request, "document").await?;
+
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+
+        Ok(Some(response.error_for_status()?.json().await?))
+    }
+}
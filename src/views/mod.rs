@@ -0,0 +1,301 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use bson::{doc, Bson, Document};
+use lazy_static::lazy_static;
+use mongodb::options::IndexOptions;
+use mongodb::{Database, IndexModel};
+use regex::Regex;
+use std::error::Error;
+use tracing::{info, warn};
+
+lazy_static! {
+    static ref MAP_PARAMETER: Regex =
+        Regex::new(r"^\s*function\s*\(\s*([A-Za-z_$][\w$]*)").unwrap();
+    static ref EMIT: Regex = Regex::new(r"\bemit\s*\(").unwrap();
+    static ref PROPERTY: Regex =
+        Regex::new(r#"^(?:\.([A-Za-z_$][\w$]*)|\[\s*(?:"([^"]*)"|'([^']*)')\s*\])"#).unwrap();
+}
+
+/// ViewIndexer creates MongoDB indexes matching the views in CouchDB design documents.
+///
+/// Only views whose map function emits a field of the document, or an array of fields, can be
+/// translated; Mango (`"language": "query"`) views are translated from their field list. Anything
+/// else is logged and left alone. Indexes are never dropped, so removing a view leaves its index
+/// in place.
+pub struct ViewIndexer {
+    db: Database,
+    collections: Vec<String>,
+    index_prefix: String,
+}
+
+impl ViewIndexer {
+    /// new creates a new ViewIndexer.
+    ///
+    /// # Arguments
+    /// * `db` - The MongoDB database
+    /// * `collections` - The collections to create indexes on
+    /// * `index_prefix` - The prefix for index names
+    ///
+    /// # Returns
+    /// * A ViewIndexer struct
+    pub fn new(db: Database, collections: Vec<String>, index_prefix: &str) -> ViewIndexer {
+        ViewIndexer {
+            db,
+            collections,
+            index_prefix: index_prefix.to_string(),
+        }
+    }
+
+    /// apply creates indexes for each view in a design document that can be translated.
+    ///
+    /// # Arguments
+    /// * `design_document` - The design document
+    ///
+    /// # Returns
+    /// * The number of indexes created or confirmed
+    pub async fn apply(&self, design_document: &Document) -> Result<usize, Box<dyn Error>> {
+        let id = design_document.get_str("_id")?;
+        if design_document.get_bool("_deleted").unwrap_or(false) {
+            info!(id, "design document deleted, leaving its indexes in place");
+            return Ok(0);
+        }
+
+        let views = match design_document.get_document("views") {
+            Ok(views) => views,
+            Err(_) => return Ok(0),
+        };
+        let language = design_document.get_str("language").unwrap_or("javascript");
+        let design_name = id.trim_start_matches("_design/");
+
+        let mut created = 0;
+        for (view, definition) in views {
+            let fields = match definition {
+                Bson::Document(definition) => translate_view(language, definition),
+                _ => Err("view definition is not an object".to_string()),
+            };
+
+            let keys = match fields {
+                Ok(keys) => keys,
+                Err(reason) => {
+                    warn!(id, view, reason, "could not translate view into an index");
+                    continue;
+                }
+            };
+
+            let name = format!("{}{}_{}", self.index_prefix, design_name, view);
+            for collection in &self.collections {
+                let model = IndexModel::builder()
+                    .keys(keys.clone())
+                    .options(IndexOptions::builder().name(name.clone()).build())
+                    .build();
+                self.db
+                    .collection::<Document>(collection)
+                    .create_index(model, None)
+                    .await?;
+
+                info!(
+                    id,
+                    view,
+                    collection = collection.as_str(),
+                    index = name.as_str(),
+                    keys = keys.to_string(),
+                    "created index for view"
+                );
+                created += 1;
+            }
+        }
+
+        Ok(created)
+    }
+}
+
+/// translate_view returns the index keys matching a view, or why it can't be translated.
+fn translate_view(language: &str, definition: &Document) -> Result<Document, String> {
+    match language {
+        "javascript" => {
+            let map = definition
+                .get_str("map")
+                .map_err(|_| "view has no map function".to_string())?;
+            let fields = translate_map(map)?;
+
+            let mut keys = Document::new();
+            for field in fields {
+                keys.insert(field, 1);
+            }
+            Ok(keys)
+        }
+        "query" => {
+            let fields = definition
+                .get_document("map")
+                .and_then(|map| map.get_document("fields"))
+                .map_err(|_| "query view has no fields".to_string())?;
+
+            let mut keys = Document::new();
+            for (field, direction) in fields {
+                let direction = match direction.as_str() {
+                    Some("desc") => -1,
+                    _ => 1,
+                };
+                keys.insert(field, direction);
+            }
+            Ok(keys)
+        }
+        other => Err(format!("views in {} aren't supported", other)),
+    }
+}
+
+/// translate_map returns the fields a map function emits as its key, in order.
+///
+/// The function must call emit once, with a key that's a field of the document (eg. `doc.type`
+/// or `doc["owner"].name`) or an array of them.
+fn translate_map(map: &str) -> Result<Vec<String>, String> {
+    let parameter = MAP_PARAMETER
+        .captures(map)
+        .map(|c| c[1].to_string())
+        .ok_or("map is not a function of the document")?;
+
+    let mut emits = EMIT.find_iter(map);
+    let emit = emits.next().ok_or("map never calls emit")?;
+    if emits.next().is_some() {
+        return Err("map calls emit more than once".to_string());
+    }
+
+    let arguments = split_arguments(&map[emit.end()..]);
+    let key = arguments.first().ok_or("emit has no key")?.trim();
+
+    let elements = match key.strip_prefix('[').and_then(|k| k.strip_suffix(']')) {
+        Some(inner) => split_arguments(&format!("{})", inner)),
+        None => vec![key.to_string()],
+    };
+
+    let mut fields = Vec::new();
+    for element in elements {
+        let element = element.trim();
+        let field = field_path(&parameter, element)
+            .ok_or_else(|| format!("emitted key {} is not a document field", element))?;
+        fields.push(field);
+    }
+
+    if fields.is_empty() {
+        return Err("emitted key is empty".to_string());
+    }
+    if fields == ["_id"] {
+        return Err("emitted key is _id, which is already indexed".to_string());
+    }
+
+    Ok(fields)
+}
+
+/// split_arguments splits source starting just after an opening parenthesis into its top-level
+/// comma-separated arguments, stopping at the matching closing parenthesis.
+fn split_arguments(source: &str) -> Vec<String> {
+    let mut arguments = Vec::new();
+    let mut current = String::new();
+    let mut depth = 0;
+    let mut quote: Option<char> = None;
+    let mut escaped = false;
+
+    for c in source.chars() {
+        if let Some(q) = quote {
+            current.push(c);
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == q {
+                quote = None;
+            }
+            continue;
+        }
+
+        match c {
+            '"' | '\'' => quote = Some(c),
+            '(' | '[' | '{' => depth += 1,
+            ')' | ']' | '}' if depth == 0 => break,
+            ')' | ']' | '}' => depth -= 1,
+            ',' if depth == 0 => {
+                arguments.push(std::mem::take(&mut current));
+                continue;
+            }
+            _ => {}
+        }
+        current.push(c);
+    }
+
+    if !current.trim().is_empty() {
+        arguments.push(current);
+    }
+    arguments
+}
+
+/// field_path returns the dotted path of an expression like `doc.a["b"].c`, if that's what it is.
+fn field_path(parameter: &str, expression: &str) -> Option<String> {
+    let mut rest = expression.strip_prefix(parameter)?;
+    let mut path = Vec::new();
+
+    while !rest.is_empty() {
+        let captures = PROPERTY.captures(rest)?;
+        let name = captures
+            .get(1)
+            .or_else(|| captures.get(2))
+            .or_else(|| captures.get(3))?;
+        path.push(name.as_str().to_string());
+        rest = &rest[captures.get(0)?.end()..];
+    }
+
+    if path.is_empty() {
+        None
+    } else {
+        Some(path.join("."))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_translate_simple_keys() {
+        assert_eq!(
+            translate_map("function (doc) { if (doc.type == 'cat') { emit(doc.name, null); } }"),
+            Ok(vec!["name".to_string()])
+        );
+        assert_eq!(
+            translate_map(r#"function(d) { emit([d.owner["last name"], d.age], 1) }"#),
+            Ok(vec!["owner.last name".to_string(), "age".to_string()])
+        );
+    }
+
+    #[test]
+    fn test_untranslatable_maps() {
+        assert!(translate_map("function (doc) { emit(doc.name.toLowerCase(), 1) }").is_err());
+        assert!(translate_map("function (doc) { emit(doc.a); emit(doc.b); }").is_err());
+        assert!(translate_map("function (doc) { emit(doc._id, null) }").is_err());
+        assert!(translate_map("function (doc) { log(doc) }").is_err());
+    }
+
+    #[test]
+    fn test_translate_query_view() {
+        let definition = doc! {
+            "map": { "fields": { "type": "asc", "created": "desc" } },
+            "reduce": "_count",
+        };
+
+        assert_eq!(
+            translate_view("query", &definition),
+            Ok(doc! { "type": 1, "created": -1 })
+        );
+    }
+}
@@ -0,0 +1,348 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+pub mod mode;
+
+use crate::metrics;
+use crate::pipeline::{AppliedChange, Operation};
+use crate::seqstore::mongodb::is_duplicate_key;
+use crate::settings::config_parser::WriteMode;
+use crate::transform::hash::UNCHANGED_DOCUMENTS;
+use crate::writer::mode::write_document;
+use bson::Document;
+use lazy_static::lazy_static;
+use mongodb::options::FindOneOptions;
+use mongodb::Collection;
+use prometheus::{register_int_gauge, register_int_gauge_vec, IntGauge, IntGaugeVec};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::VecDeque;
+use std::error::Error;
+use std::hash::{Hash, Hasher};
+use tokio::sync::mpsc;
+use tracing::{debug, info};
+
+lazy_static! {
+    /// Writers in the pool.
+    pub static ref WRITER_WORKERS: IntGauge = register_int_gauge!(
+        "couch2mongo_writer_workers",
+        "Writers in the parallel writer pool"
+    )
+    .unwrap();
+
+    /// Writes queued for each writer, including the one in progress.
+    pub static ref WRITER_QUEUE_DEPTH: IntGaugeVec = register_int_gauge_vec!(
+        "couch2mongo_writer_queue_depth",
+        "Writes queued for each writer in the parallel writer pool",
+        &["worker"]
+    )
+    .unwrap();
+}
+
+/// WriteJob is a document to replace in MongoDB, and the change it comes from.
+pub struct WriteJob {
+    pub collection: Collection<Document>,
+    // Matches the document to replace, and only if the incoming revision isn't stale
+    pub filter: Document,
+    // A filter matching the document in MongoDB if it already has this content
+    pub unchanged: Option<Document>,
+    pub document: Document,
+    pub applied: AppliedChange,
+}
+
+/// Pending is a change in feed order, and whether it's been written yet.
+struct Pending {
+    seq: String,
+    done: bool,
+}
+
+/// WriterPool replaces documents on several workers at once, while keeping the writes to each
+/// document in order.
+///
+/// Changes are assigned to a worker by a hash of their document ID, so writes to different
+/// documents proceed in parallel but writes to the same document are applied one after the other.
+/// Writes finish out of order, so the checkpoint only moves past a change once it, and every
+/// change before it, has been written.
+pub struct WriterPool {
+    senders: Vec<mpsc::Sender<(u64, WriteJob)>>,
+    results: mpsc::UnboundedReceiver<(u64, Result<AppliedChange, mongodb::error::Error>)>,
+    pending: VecDeque<Pending>,
+    first_ticket: u64,
+    completed: Vec<AppliedChange>,
+    error: Option<mongodb::error::Error>,
+}
+
+impl WriterPool {
+    /// new starts the workers.
+    ///
+    /// # Arguments
+    /// * `workers` - The number of workers
+    /// * `queue_depth` - The most writes queued for each worker before changes are held back
+    /// * `compare_revisions` - Whether a filter that doesn't match means the revision is stale
+    /// * `write_mode` - How documents are written
+    ///
+    /// # Returns
+    /// * A WriterPool struct
+    pub fn new(
+        workers: usize,
+        queue_depth: usize,
+        compare_revisions: bool,
+        write_mode: WriteMode,
+    ) -> WriterPool {
+        let (results_sender, results) = mpsc::unbounded_channel();
+        let mut senders = Vec::new();
+
+        for worker in 0..workers.max(1) {
+            let (sender, mut receiver) = mpsc::channel::<(u64, WriteJob)>(queue_depth.max(1));
+            let results_sender = results_sender.clone();
+            let queue_depth = WRITER_QUEUE_DEPTH.with_label_values(&[&worker.to_string()]);
+            let write_mode = write_mode.clone();
+
+            tokio::spawn(async move {
+                while let Some((ticket, job)) = receiver.recv().await {
+                    let result = write(job, compare_revisions, &write_mode).await;
+                    queue_depth.dec();
+                    if results_sender.send((ticket, result)).is_err() {
+                        return;
+                    }
+                }
+            });
+            senders.push(sender);
+        }
+
+        WRITER_WORKERS.set(senders.len() as i64);
+
+        WriterPool {
+            senders,
+            results,
+            pending: VecDeque::new(),
+            first_ticket: 0,
+            completed: Vec::new(),
+            error: None,
+        }
+    }
+
+    /// is_idle returns true if there are no writes in progress.
+    pub fn is_idle(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// submit queues a write on the worker for its document, waiting if that worker's queue is
+    /// full.
+    pub async fn submit(&mut self, job: WriteJob) -> Result<(), Box<dyn Error>> {
+        let worker = worker_for(&job.applied.id, self.senders.len());
+        let ticket = self.first_ticket + self.pending.len() as u64;
+        self.pending.push_back(Pending {
+            seq: job.applied.seq.clone(),
+            done: false,
+        });
+
+        WRITER_QUEUE_DEPTH
+            .with_label_values(&[&worker.to_string()])
+            .inc();
+        if self.senders[worker].send((ticket, job)).await.is_err() {
+            return Err("writer stopped".into());
+        }
+
+        Ok(())
+    }
+
+    /// skip records a change that has nothing to write, so the checkpoint moves past it once the
+    /// writes before it are done.
+    pub fn skip(&mut self, seq: &str) {
+        self.pending.push_back(Pending {
+            seq: seq.to_string(),
+            done: true,
+        });
+    }
+
+    /// wait waits for the next write to finish, or forever if there are none in progress.
+    pub async fn wait(&mut self) {
+        if self.is_idle() {
+            return std::future::pending().await;
+        }
+
+        if let Some((ticket, result)) = self.results.recv().await {
+            self.record(ticket, result);
+        }
+    }
+
+    /// collect returns the writes that have finished, and the sequence the checkpoint can move
+    /// to, without waiting.
+    pub fn collect(
+        &mut self,
+    ) -> Result<(Vec<AppliedChange>, Option<String>), mongodb::error::Error> {
+        while let Ok((ticket, result)) = self.results.try_recv() {
+            self.record(ticket, result);
+        }
+
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+
+        let mut checkpoint = None;
+        while self.pending.front().is_some_and(|p| p.done) {
+            checkpoint = self.pending.pop_front().map(|p| p.seq);
+            self.first_ticket += 1;
+        }
+
+        Ok((std::mem::take(&mut self.completed), checkpoint))
+    }
+
+    /// drain waits for every write in progress to finish, and returns them with the sequence the
+    /// checkpoint can move to.
+    pub async fn drain(
+        &mut self,
+    ) -> Result<(Vec<AppliedChange>, Option<String>), mongodb::error::Error> {
+        let mut applied = Vec::new();
+        let mut checkpoint = None;
+
+        loop {
+            let (completed, seq) = self.collect()?;
+            applied.extend(completed);
+            checkpoint = seq.or(checkpoint);
+
+            if self.is_idle() {
+                return Ok((applied, checkpoint));
+            }
+            self.wait().await;
+        }
+    }
+
+    /// record notes that a write has finished.
+    fn record(&mut self, ticket: u64, result: Result<AppliedChange, mongodb::error::Error>) {
+        if let Some(pending) = self.pending.get_mut((ticket - self.first_ticket) as usize) {
+            pending.done = true;
+        }
+
+        match result {
+            Ok(applied) => self.completed.push(applied),
+            Err(e) => {
+                self.error.get_or_insert(e);
+            }
+        }
+    }
+}
+
+/// worker_for returns the worker a document's writes go to.
+fn worker_for(id: &str, workers: usize) -> usize {
+    let mut hasher = DefaultHasher::new();
+    id.hash(&mut hasher);
+
+    (hasher.finish() % workers as u64) as usize
+}
+
+/// write applies a WriteJob, returning the change with what was done.
+async fn write(
+    job: WriteJob,
+    compare_revisions: bool,
+    write_mode: &WriteMode,
+) -> Result<AppliedChange, mongodb::error::Error> {
+    let WriteJob {
+        collection,
+        filter,
+        unchanged,
+        document,
+        mut applied,
+    } = job;
+
+    if let Some(unchanged) = unchanged {
+        let options = FindOneOptions::builder()
+            .projection(bson::doc! { "_id": 1 })
+            .build();
+
+        if collection.find_one(unchanged, options).await?.is_some() {
+            debug!(
+                id = applied.id.as_str(),
+                seq = applied.seq.as_str(),
+                collection = collection.name(),
+                "document unchanged, skipping",
+            );
+            UNCHANGED_DOCUMENTS
+                .with_label_values(&[collection.name()])
+                .inc();
+            applied.operation = Operation::Unchanged;
+            return Ok(applied);
+        }
+    }
+
+    let size = bson::to_vec(&document).map_or(0, |b| b.len());
+    let result = write_document(&collection, filter, document, write_mode, None).await;
+    metrics::record_bytes_written(collection.name(), write_mode.as_str(), size);
+
+    applied.operation = match result {
+        // The filter didn't match because MongoDB has a newer revision, so the upsert clashed
+        Err(e) if compare_revisions && is_duplicate_key(&e) => {
+            info!(
+                id = applied.id.as_str(),
+                seq = applied.seq.as_str(),
+                collection = collection.name(),
+                "skipping stale revision",
+            );
+            Operation::Stale
+        }
+        Err(e) => return Err(e),
+        Ok(Operation::Inserted) => {
+            info!(
+                id = applied.id.as_str(),
+                seq = applied.seq.as_str(),
+                collection = collection.name(),
+                "document inserted",
+            );
+            Operation::Inserted
+        }
+        Ok(operation) => operation,
+    };
+
+    Ok(applied)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_worker_for() {
+        // The same document always goes to the same worker
+        assert_eq!(worker_for("cat:tom", 8), worker_for("cat:tom", 8));
+        assert_eq!(worker_for("cat:tom", 1), 0);
+        assert!((0..100).all(|i| worker_for(&format!("cat:{}", i), 4) < 4));
+    }
+
+    #[tokio::test]
+    async fn test_checkpoint_waits_for_earlier_writes() {
+        let mut pool = WriterPool::new(2, 10, false, WriteMode::Replace);
+        pool.pending.push_back(Pending {
+            seq: "1".to_string(),
+            done: false,
+        });
+        pool.skip("2");
+
+        let (_, checkpoint) = pool.collect().unwrap();
+        assert_eq!(checkpoint, None);
+
+        pool.record(
+            0,
+            Ok(AppliedChange {
+                id: "cat:tom".to_string(),
+                seq: "1".to_string(),
+                collection: "cats".to_string(),
+                operation: Operation::Replaced,
+            }),
+        );
+        let (applied, checkpoint) = pool.collect().unwrap();
+        assert_eq!(applied.len(), 1);
+        assert_eq!(checkpoint, Some("2".to_string()));
+        assert!(pool.is_idle());
+    }
+}
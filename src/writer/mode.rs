@@ -0,0 +1,91 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::pipeline::Operation;
+use crate::seqstore::mongodb::is_duplicate_key;
+use crate::settings::config_parser::WriteMode;
+use bson::{doc, Document};
+use mongodb::options::{ReplaceOptions, UpdateOptions};
+use mongodb::{ClientSession, Collection};
+
+/// write_document writes a document the way the write mode says.
+///
+/// # Arguments
+/// * `collection` - The collection to write to
+/// * `filter` - Matches the document to replace or merge into; InsertOnly doesn't use it
+/// * `document` - The document
+/// * `mode` - The write mode
+/// * `session` - The session to write in, when in a transaction
+///
+/// # Returns
+/// * Inserted if the document was new, Replaced if an existing one was replaced or merged into, or
+///   Existing if InsertOnly found one already there. A filter that doesn't match an existing
+///   document (eg. a stale revision) is a duplicate key error.
+pub async fn write_document(
+    collection: &Collection<Document>,
+    filter: Document,
+    document: Document,
+    mode: &WriteMode,
+    session: Option<&mut ClientSession>,
+) -> Result<Operation, mongodb::error::Error> {
+    let upserted = match mode {
+        WriteMode::Replace => {
+            let options = ReplaceOptions::builder().upsert(true).build();
+            let result = match session {
+                Some(session) => {
+                    collection
+                        .replace_one_with_session(filter, document, options, session)
+                        .await?
+                }
+                None => collection.replace_one(filter, document, options).await?,
+            };
+            result.upserted_id.is_some()
+        }
+        WriteMode::Merge => {
+            let mut fields = document;
+            fields.remove("_id");
+            let update = doc! { "$set": fields };
+            let options = UpdateOptions::builder().upsert(true).build();
+            let result = match session {
+                Some(session) => {
+                    collection
+                        .update_one_with_session(filter, update, options, session)
+                        .await?
+                }
+                None => collection.update_one(filter, update, options).await?,
+            };
+            result.upserted_id.is_some()
+        }
+        WriteMode::InsertOnly => {
+            let result = match session {
+                Some(session) => {
+                    collection
+                        .insert_one_with_session(document, None, session)
+                        .await
+                }
+                None => collection.insert_one(document, None).await,
+            };
+            match result {
+                Ok(_) => true,
+                Err(e) if is_duplicate_key(&e) => return Ok(Operation::Existing),
+                Err(e) => return Err(e),
+            }
+        }
+    };
+
+    Ok(match upserted {
+        true => Operation::Inserted,
+        false => Operation::Replaced,
+    })
+}